@@ -0,0 +1,178 @@
+// A file for the automatic choice of the rendition of a listener
+// The lag of the listener behind the live edge is followed: the listener, whose lag
+// grows, could not take the rendition and goes down, the one, who stays at the live
+// edge for a while, goes up. The bursts are not timed, the buffers of actix and of the
+// socket take the small ones right away, so their time tells nothing about the connection
+// Only the Opus renditions are switched between, they share the same Ogg stream,
+// the lossless one needs its own container and is only chosen explicitly
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::time::{Duration, Instant};
+
+use crate::audio_coding::{Codec, Rendition};
+
+// The listener, whose lag has grown by this much, could not keep up with the rendition
+const LAG_GROWTH_LIMIT: Duration = Duration::from_secs(2);
+
+// The listener with a shorter lag is at the live edge
+const LIVE_EDGE_LAG: Duration = Duration::from_millis(500);
+
+// The listener has to be at the live edge this long before it goes up,
+// it is also the shortest time between the switches, so the listener does not flap
+const MIN_SWITCH_INTERVAL: Duration = Duration::from_secs(10);
+
+
+
+// What the listener has asked for: a rendition or the automatic choice
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenditionChoice {
+    Fixed(Rendition),
+    Auto,
+}
+
+impl RenditionChoice {
+    // ?rendition=lossless|opus_high|opus_low|auto
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "auto" => Some(RenditionChoice::Auto),
+            other => Rendition::from_name(other).map(RenditionChoice::Fixed),
+        }
+    }
+}
+
+
+
+// The lag of the listener since the last switch, the time between the push
+// of the chunk and the moment the listener has read it
+// The lowest lag is where the listener has got to, the growth over it
+// means the connection is slower than the rendition
+
+#[derive(Debug, Default)]
+pub struct LagMeter {
+    lowest: Option<Duration>,
+    last: Duration,
+    live_since: Option<Instant>,
+}
+
+impl LagMeter {
+    pub fn sample(&mut self, lag: Duration, now: Instant) {
+        self.lowest = Some(self.lowest.map_or(lag, |lowest| lowest.min(lag)));
+        self.last = lag;
+
+        if lag <= LIVE_EDGE_LAG {
+            self.live_since.get_or_insert(now);
+        } else {
+            self.live_since = None;
+        }
+    }
+
+    pub fn growth(&self) -> Duration {
+        self.last.saturating_sub(self.lowest.unwrap_or_default())
+    }
+
+    // How long the listener has been at the live edge without a break
+    pub fn live_for(&self, now: Instant) -> Duration {
+        self.live_since.map_or(Duration::ZERO, |since| now.duration_since(since))
+    }
+}
+
+
+
+// The choice between the Opus renditions of the stream
+// The listener starts on the lowest one, the higher ones are tried
+// once it has caught up with the pre-roll and stays at the live edge
+
+#[derive(Debug)]
+pub struct AdaptiveSelector {
+    ladder: Vec<Rendition>,
+    current: usize,
+    meter: LagMeter,
+    last_switch: Option<Instant>,
+}
+
+impl AdaptiveSelector {
+    // None when there is nothing to choose from
+    pub fn new(available: &[Rendition]) -> Option<Self> {
+        let mut ladder: Vec<Rendition> = available.iter().copied()
+            .filter(|r| r.codec() == Codec::Opus)
+            .collect();
+        ladder.sort_by_key(|r| r.bitrate());
+        ladder.dedup();
+
+        if ladder.is_empty() {
+            return None;
+        }
+
+        Some(AdaptiveSelector { ladder, current: 0, meter: LagMeter::default(), last_switch: None })
+    }
+
+    pub fn current(&self) -> Rendition {
+        self.ladder[self.current]
+    }
+
+    // Taking the lag of the read the listener has just got, lagging means the listener
+    // has fallen behind the live edge (the oldest chunks had to be dropped)
+    // Returns the new rendition, when the listener should switch
+
+    pub fn on_read(&mut self, lag: Duration, lagging: bool, now: Instant) -> Option<Rendition> {
+        self.meter.sample(lag, now);
+
+        let too_slow = lagging || self.meter.growth() > LAG_GROWTH_LIMIT;
+        if too_slow && self.current > 0 {
+            return Some(self.switch(self.current - 1, now));
+        }
+
+        // Going up waits a while, the listener has just been switched
+        let settled = self.last_switch.is_none_or(|at| now.duration_since(at) >= MIN_SWITCH_INTERVAL);
+        let live = self.meter.live_for(now) >= MIN_SWITCH_INTERVAL;
+
+        if settled && live && !lagging && self.current + 1 < self.ladder.len() {
+            return Some(self.switch(self.current + 1, now));
+        }
+
+        None
+    }
+
+    // The lag of the new rendition is followed from the start
+    fn switch(&mut self, index: usize, now: Instant) -> Rendition {
+        self.current = index;
+        self.last_switch = Some(now);
+        self.meter = LagMeter::default();
+        self.ladder[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selector_follows_lag() {
+        assert!(AdaptiveSelector::new(&[Rendition::Lossless]).is_none(), "NOTHING TO SWITCH BETWEEN");
+
+        let mut selector = AdaptiveSelector::new(&[Rendition::Lossless, Rendition::OpusHigh,
+                                                   Rendition::OpusLow]).unwrap();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert_eq!(selector.current(), Rendition::OpusLow, "STARTS LOW");
+
+        // The pre-roll is behind the live edge, the fast listener catches up with it
+        assert_eq!(selector.on_read(Duration::from_secs(3), false, start), None);
+        assert_eq!(selector.on_read(Duration::from_millis(20), false, at(1)), None);
+        assert_eq!(selector.on_read(Duration::from_millis(20), false, at(5)), None, "NOT LIVE FOR LONG ENOUGH");
+        assert_eq!(selector.on_read(Duration::from_millis(20), false, at(11)), Some(Rendition::OpusHigh));
+        assert_eq!(selector.on_read(Duration::from_millis(20), false, at(30)), None, "ALREADY ON THE TOP");
+
+        // The lag grows, the connection could not take the rendition
+        assert_eq!(selector.on_read(Duration::from_secs(1), false, at(31)), None);
+        assert_eq!(selector.on_read(Duration::from_millis(2500), false, at(32)), Some(Rendition::OpusLow));
+
+        // The lagging listener goes down right away, and it waits before going up again
+        assert_eq!(selector.on_read(Duration::ZERO, false, at(33)), None);
+        assert_eq!(selector.on_read(Duration::ZERO, false, at(40)), None);
+        assert_eq!(selector.on_read(Duration::ZERO, false, at(43)), Some(Rendition::OpusHigh));
+        assert_eq!(selector.on_read(Duration::ZERO, true, at(44)), Some(Rendition::OpusLow));
+    }
+}
@@ -0,0 +1,285 @@
+// A file for the live analysis feed of the streams
+// Every decoded chunk (the PCM ingest, the FLAC chunks and the Opus packets) is analyzed,
+// the listeners get its levels and spectrum over the server-sent events,
+// so the players could draw the visualizer without decoding anything
+// The silence is counted in the media time, the stream silent for longer
+// than STREAM_SILENCE_TIMEOUT_SECS is flagged, and the reaper ends it
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::audio_coding::analysis::PcmAnalysis;
+use crate::audio_coding::{analyze_pcm, AudioCodingError, AudioFormat, LoudnessSlot, OpusStreamDecoder, OPUS_SAMPLE_RATE};
+use crate::shared::{lock, Slot};
+
+// The chunk with every peak under this is the silence
+pub const SILENCE_THRESHOLD_DBFS: f64 = -60.0;
+
+// How long a stream could be silent before it is flagged (and ended)
+// STREAM_SILENCE_TIMEOUT_SECS changes it, 0 turns the flagging off
+pub const DEFAULT_SILENCE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// The frames a slow listener could fall behind by, the older ones are skipped for it
+const FEED_CAPACITY: usize = 32;
+
+// The comment sent when nothing happens, so the proxies keep the connection
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// The Opus packets are only 20 ms, their PCM is gathered and analyzed this often
+const OPUS_ANALYSIS_WINDOW: Duration = Duration::from_millis(500);
+
+// The silence timeout from STREAM_SILENCE_TIMEOUT_SECS, None when the flagging is off
+pub fn silence_timeout() -> Option<Duration> {
+    let timeout = std::env::var("STREAM_SILENCE_TIMEOUT_SECS").ok()
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SILENCE_TIMEOUT);
+
+    Some(timeout).filter(|timeout| !timeout.is_zero())
+}
+
+
+
+// A single event of the feed, the analysis of one chunk
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AnalysisFrame {
+    // The media position of the chunk from the start of the stream
+    pub position_ms: u64,
+    #[serde(flatten)]
+    pub analysis: PcmAnalysis,
+    pub silent: bool,
+    // How long the stream has been silent, including this chunk
+    pub silent_for_ms: u64,
+    // The stream is silent for longer than the timeout
+    pub flagged: bool,
+}
+
+#[derive(Debug, Default)]
+struct AnalysisState {
+    last: Option<AnalysisFrame>,
+    silent_for: Duration,
+}
+
+// The analysis of the stream, shared by the ingest and the listeners
+// The stream owns the sender, so the listeners are finished with the stream
+
+#[derive(Clone, Debug)]
+pub struct AnalysisFeed {
+    sender: broadcast::Sender<AnalysisFrame>,
+    state: Arc<Mutex<AnalysisState>>,
+    silence_timeout: Option<Duration>,
+}
+
+impl AnalysisFeed {
+    pub fn new(silence_timeout: Option<Duration>) -> Self {
+        AnalysisFeed {
+            sender: broadcast::channel(FEED_CAPACITY).0,
+            state: Arc::new(Mutex::new(AnalysisState::default())),
+            silence_timeout,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AnalysisState> {
+        lock(&self.state)
+    }
+
+    // Adding the analysis of the chunk, which starts at the given position
+    pub fn publish(&self, analysis: PcmAnalysis, position: Duration) -> AnalysisFrame {
+        let mut state = self.lock();
+
+        let silent = analysis.is_silent(SILENCE_THRESHOLD_DBFS);
+        state.silent_for = if silent {
+            state.silent_for + Duration::from_millis(analysis.duration_ms)
+        } else {
+            Duration::ZERO
+        };
+
+        let frame = AnalysisFrame {
+            position_ms: position.as_millis() as u64,
+            analysis,
+            silent,
+            silent_for_ms: state.silent_for.as_millis() as u64,
+            flagged: self.silence_timeout.is_some_and(|timeout| state.silent_for >= timeout),
+        };
+        state.last = Some(frame.clone());
+
+        // Nobody listening is not an error
+        let _ = self.sender.send(frame.clone());
+        frame
+    }
+
+    // Adding the analysis of the PCM, which ends at the given position
+    pub fn publish_until(&self, analysis: PcmAnalysis, end: Duration) -> AnalysisFrame {
+        let start = end.saturating_sub(Duration::from_millis(analysis.duration_ms));
+        self.publish(analysis, start)
+    }
+
+    pub fn last(&self) -> Option<AnalysisFrame> {
+        self.lock().last.clone()
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.lock().silent_for
+    }
+
+    // Whether the stream has been silent for too long, the reaper ends such streams
+    pub fn is_flagged(&self) -> bool {
+        self.silence_timeout.is_some_and(|timeout| self.silent_for() >= timeout)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AnalysisFrame> {
+        self.sender.subscribe()
+    }
+}
+
+fn event(frame: &AnalysisFrame) -> Bytes {
+    let json = serde_json::to_string(frame).unwrap_or_default();
+    Bytes::from(format!("event: analysis\ndata: {}\n\n", json))
+}
+
+// The server-sent events of the feed, the last frame goes first
+// The listener, who falls behind, skips the frames it has missed
+// The events end together with the stream
+
+pub fn events(feed: &AnalysisFeed) -> HttpResponse {
+    let mut receiver = feed.subscribe();
+    let last = feed.last();
+
+    let body = async_stream::stream! {
+        if let Some(frame) = last {
+            yield Ok::<_, actix_web::Error>(event(&frame));
+        }
+
+        loop {
+            match tokio::time::timeout(KEEPALIVE_INTERVAL, receiver.recv()).await {
+                Ok(Ok(frame)) => yield Ok(event(&frame)),
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) => break,
+                Err(_) => yield Ok(Bytes::from_static(b": keepalive\n\n")),
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+
+
+// The Opus packets of the broadcasters (/load_chunk and WHIP) decoded for the analysis
+// and the loudness meter. The packets themselves go to the listeners as they are,
+// so the normalization is not applied to them
+// The streams without the declared format are decoded in stereo, like WebRTC sends them
+
+#[derive(Debug)]
+struct OpusDecoding {
+    decoder: OpusStreamDecoder,
+    pending: Vec<i32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OpusMonitor {
+    decoding: Slot<OpusDecoding>,
+}
+
+impl OpusMonitor {
+    // Decoding the packet and measuring its loudness
+    // Returns the analysis of the window, which ends with this packet, once the window is full
+
+    pub fn decode(&self, packet: &[u8], format: Option<AudioFormat>, 
+                  loudness: &LoudnessSlot) -> Result<Option<PcmAnalysis>, AudioCodingError> {
+        let channels = format.map_or(2, |format| format.channels.min(2));
+        let format = AudioFormat { channels, bits_per_sample: 16, sample_rate: OPUS_SAMPLE_RATE };
+        let window = (OPUS_ANALYSIS_WINDOW.as_secs_f64() * OPUS_SAMPLE_RATE as f64) as usize * channels;
+
+        self.decoding.with(|decoding| decoding.decoder.channels() == channels,
+                           || Ok(OpusDecoding { decoder: OpusStreamDecoder::new(channels)?, pending: Vec::new() }),
+                           |decoding| {
+            let pcm = loudness.process(decoding.decoder.decode(packet)?, &format, None)?;
+            decoding.pending.extend(pcm);
+
+            if decoding.pending.len() < window {
+                return Ok(None);
+            }
+
+            let analysis = analyze_pcm(&decoding.pending, &format);
+            decoding.pending.clear();
+            Ok(Some(analysis))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::analysis::FLOOR_DBFS;
+
+    fn chunk(peak_dbfs: f64, duration_ms: u64) -> PcmAnalysis {
+        PcmAnalysis {
+            duration_ms,
+            rms_dbfs: vec![peak_dbfs - 3.0],
+            peak_dbfs: vec![peak_dbfs],
+            bands: vec![FLOOR_DBFS; 4],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silence_is_counted_and_flagged() {
+        let feed = AnalysisFeed::new(Some(Duration::from_secs(3)));
+        let mut receiver = feed.subscribe();
+
+        feed.publish(chunk(-70.0, 2000), Duration::ZERO);
+        assert_eq!(feed.silent_for(), Duration::from_secs(2));
+        assert!(!feed.is_flagged());
+
+        // The sound starts the counting over
+        feed.publish(chunk(-20.0, 2000), Duration::from_secs(2));
+        assert_eq!(feed.silent_for(), Duration::ZERO);
+
+        feed.publish(chunk(-80.0, 2000), Duration::from_secs(4));
+        let frame = feed.publish(chunk(FLOOR_DBFS, 2000), Duration::from_secs(6));
+        assert!(frame.silent && frame.flagged, "4 SECONDS OF THE SILENCE ARE OVER THE TIMEOUT");
+        assert!(feed.is_flagged());
+
+        let positions: Vec<u64> = (0..4).map(|_| receiver.try_recv().unwrap().position_ms).collect();
+        assert_eq!(positions, vec![0, 2000, 4000, 6000]);
+        assert_eq!(feed.last().unwrap().silent_for_ms, 4000);
+
+        // Without the timeout the silence is only reported
+        let unflagged = AnalysisFeed::new(None);
+        assert!(!unflagged.publish(chunk(FLOOR_DBFS, 60000), Duration::ZERO).flagged);
+        assert!(!unflagged.is_flagged());
+    }
+
+    #[test]
+    fn test_opus_packets_are_analyzed_in_windows() {
+        use crate::audio_coding::test_signals::{sine, to_pcm};
+        use crate::audio_coding::{encode_pcm, Codec, OpusEncoders};
+
+        let format = AudioFormat { channels: 2, bits_per_sample: 16, sample_rate: OPUS_SAMPLE_RATE };
+        let tone = to_pcm(&sine(1000.0, 0.25, OPUS_SAMPLE_RATE, 2, OPUS_SAMPLE_RATE as usize), 16);
+        let packets = encode_pcm(Codec::Opus, &tone, &format, &OpusEncoders::default()).unwrap();
+
+        let (monitor, loudness) = (OpusMonitor::default(), LoudnessSlot::default());
+        let windows: Vec<PcmAnalysis> = packets.iter()
+            .filter_map(|packet| monitor.decode(packet, None, &loudness).unwrap())
+            .collect();
+
+        // 50 packets of 20 ms are two windows of 500 ms
+        assert_eq!(windows.len(), 2, "A WINDOW EVERY 25 PACKETS");
+        assert!(windows.iter().all(|window| window.duration_ms == 500 && !window.is_silent(SILENCE_THRESHOLD_DBFS)));
+        assert!(loudness.report().is_some(), "THE PACKETS ARE METERED");
+
+        assert!(monitor.decode(&[0xff], None, &loudness).is_err(), "THE BROKEN PACKET IS AN ERROR");
+    }
+}
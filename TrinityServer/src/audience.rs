@@ -0,0 +1,353 @@
+// A file for counting the listeners of the live streams
+// Every connected listener holds a guard, the count goes down when the guard
+// is dropped (the response stream or the WebRTC forwarding is finished)
+// The audience of every stream is written to the database once a minute,
+// so the streamers could see their joins, leaves and listen-minutes later
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::db::init_db;
+use crate::shared::lock;
+use crate::streamer::ActiveStreams;
+
+// How often the audience of the streams is written to the database
+pub const AUDIENCE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long the ended stream waits for its listeners to go away,
+// before its last period is written anyway
+const FINISH_WAIT: Duration = Duration::from_secs(30);
+const FINISH_POLL: Duration = Duration::from_millis(500);
+
+// The growth of the audience is measured over this window for the trending streams
+pub const TRENDING_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+
+
+#[derive(Debug)]
+struct AudienceState {
+    current: usize,
+    peak: usize,
+    period_peak: usize,
+    joins: u64,
+    leaves: u64,
+    listen_time: Duration,
+    last_change: Instant,
+    period_start: SystemTime,
+    // The listener count after every change within the trending window,
+    // plus the last one before it, which is the count at the start of the window
+    changes: VecDeque<(Instant, usize)>,
+}
+
+impl AudienceState {
+    // Adding the time everyone has listened since the last change
+    // The listen time is the integral of the listener count over the time
+    fn settle(&mut self) {
+        let now = Instant::now();
+        self.listen_time += now.duration_since(self.last_change) * self.current as u32;
+        self.last_change = now;
+    }
+
+    fn record_change(&mut self) {
+        let now = Instant::now();
+        self.changes.push_back((now, self.current));
+
+        while self.changes.len() > 1 
+                && now.duration_since(self.changes[1].0) >= TRENDING_WINDOW {
+            self.changes.pop_front();
+        }
+    }
+}
+
+// The audience of the period, which is written to the database
+#[derive(Debug)]
+pub struct AudiencePeriod {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub joins: u64,
+    pub leaves: u64,
+    pub listen_time: Duration,
+    pub peak_listeners: usize,
+}
+
+
+
+// The shared counter of the listeners of a single stream
+
+#[derive(Clone, Debug)]
+pub struct Audience {
+    state: Arc<Mutex<AudienceState>>,
+}
+
+impl Audience {
+    pub fn new() -> Self {
+        Audience {
+            state: Arc::new(Mutex::new(AudienceState {
+                current: 0,
+                peak: 0,
+                period_peak: 0,
+                joins: 0,
+                leaves: 0,
+                listen_time: Duration::ZERO,
+                last_change: Instant::now(),
+                period_start: SystemTime::now(),
+                changes: VecDeque::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, AudienceState> {
+        lock(&self.state)
+    }
+
+    // A new listener, it is counted until the guard is dropped
+    pub fn join(&self) -> ListenerGuard {
+        let mut state = self.state();
+        state.settle();
+        state.current += 1;
+        state.joins += 1;
+        state.peak = state.peak.max(state.current);
+        state.period_peak = state.period_peak.max(state.current);
+        state.record_change();
+
+        ListenerGuard { audience: self.clone() }
+    }
+
+    fn leave(&self) {
+        let mut state = self.state();
+        state.settle();
+        state.current = state.current.saturating_sub(1);
+        state.leaves += 1;
+        state.record_change();
+    }
+
+    // The amount of the listeners right now
+    pub fn current(&self) -> usize {
+        self.state().current
+    }
+
+    // The most listeners the stream has had at once
+    pub fn peak(&self) -> usize {
+        self.state().peak
+    }
+
+    // How many listeners the stream has got (or lost) over the given window
+    // A stream younger than the window is compared to its start with no listeners
+
+    pub fn growth(&self, window: Duration) -> i64 {
+        let state = self.state();
+        let now = Instant::now();
+
+        let before = state.changes.iter()
+            .take_while(|(at, _)| now.duration_since(*at) >= window)
+            .last()
+            .map(|(_, count)| *count)
+            .unwrap_or(0);
+
+        state.current as i64 - before as i64
+    }
+
+    // Taking the audience since the previous call, the next period starts now
+    // None when nobody has listened during the period, there is nothing to write
+
+    pub fn take_period(&self) -> Option<AudiencePeriod> {
+        let mut state = self.state();
+        state.settle();
+
+        let now = SystemTime::now();
+        let period = AudiencePeriod {
+            period_start: DateTime::<Utc>::from(state.period_start),
+            period_end: DateTime::<Utc>::from(now),
+            joins: state.joins,
+            leaves: state.leaves,
+            listen_time: state.listen_time,
+            peak_listeners: state.period_peak,
+        };
+
+        state.period_start = now;
+        state.period_peak = state.current;
+        state.joins = 0;
+        state.leaves = 0;
+        state.listen_time = Duration::ZERO;
+
+        let active = period.joins > 0 || period.leaves > 0 || !period.listen_time.is_zero();
+        Some(period).filter(|_| active)
+    }
+}
+
+// Held by the connected listener, leaving the audience when dropped
+
+#[derive(Debug)]
+pub struct ListenerGuard {
+    audience: Audience,
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        self.audience.leave();
+    }
+}
+
+
+
+// The rows of the audience history, as the dashboard gets them
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct AudienceRow {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub joins: i64,
+    pub leaves: i64,
+    pub listen_minutes: f64,
+    pub peak_listeners: i32,
+}
+
+// Writing the periods of the streams, (stream name, owner, period)
+async fn record_periods(periods: Vec<(String, String, AudiencePeriod)>) -> Result<(), String> {
+    if periods.is_empty() {
+        return Ok(());
+    }
+
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    for (stream_name, owner, period) in periods {
+        sqlx::query(
+            "INSERT INTO audience_stats (id, stream_name, owner, period_start, period_end,
+                                         joins, leaves, listen_ms, peak_listeners)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9);")
+            .bind(Uuid::new_v4())
+            .bind(&stream_name)
+            .bind(&owner)
+            .bind(period.period_start)
+            .bind(period.period_end)
+            .bind(period.joins as i64)
+            .bind(period.leaves as i64)
+            .bind(period.listen_time.as_millis() as i64)
+            .bind(period.peak_listeners as i32)
+            .execute(&pool)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+// The audience history of the stream since the given moment, the oldest first
+// Without the owner (for admins) the streams of every owner with this name are shown
+
+pub async fn audience_history(owner: Option<&str>, stream_name: &str, since: DateTime<Utc>)
+                                                        -> Result<Vec<AudienceRow>, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_as::<_, AudienceRow>(
+        "SELECT period_start, period_end, joins, leaves,
+                listen_ms::DOUBLE PRECISION / 60000.0 AS listen_minutes, peak_listeners
+        FROM audience_stats
+        WHERE stream_name = $1 AND ($2::TEXT IS NULL OR owner = $2) AND period_end >= $3
+        ORDER BY period_start")
+        .bind(stream_name)
+        .bind(owner)
+        .bind(since)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+
+
+// The background task, which writes the audience of the live streams
+
+pub async fn run_recorder(stream_list: ActiveStreams) {
+    let mut interval = tokio::time::interval(AUDIENCE_FLUSH_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let periods: Vec<_> = stream_list.audiences().into_iter()
+            .filter_map(|(name, owner, audience)| audience.take_period()
+                .map(|period| (name, owner, period)))
+            .collect();
+
+        if let Err(e) = record_periods(periods).await {
+            error!("Failed to write the audience of the streams: {}", e);
+        }
+    }
+}
+
+// The last period of the ended stream
+// The listeners finish their responses a little later than the stream ends,
+// so their leaves are waited for a while
+
+pub async fn finish(stream_name: String, owner: String, audience: Audience) {
+    let started = Instant::now();
+    while audience.current() > 0 && started.elapsed() < FINISH_WAIT {
+        tokio::time::sleep(FINISH_POLL).await;
+    }
+
+    let Some(period) = audience.take_period() else {
+        return;
+    };
+
+    info!("Stream {} has ended with the peak of {} listeners", stream_name, audience.peak());
+
+    if let Err(e) = record_periods(vec![(stream_name.clone(), owner, period)]).await {
+        error!("Failed to write the audience of {}: {}", stream_name, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners_are_counted_until_dropped() {
+        let audience = Audience::new();
+        assert!(audience.take_period().is_none(), "NOTHING TO WRITE WITHOUT LISTENERS");
+
+        let first = audience.join();
+        let second = audience.join();
+        assert_eq!(audience.current(), 2);
+
+        drop(first);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(audience.current(), 1);
+        assert_eq!(audience.peak(), 2);
+
+        let period = audience.take_period().expect("THE PERIOD HAD LISTENERS");
+        assert_eq!((period.joins, period.leaves, period.peak_listeners), (2, 1, 2));
+        assert!(period.listen_time >= Duration::from_millis(20));
+
+        // The listener is still there, so the next period has the listen time too
+        drop(second);
+        let period = audience.take_period().expect("THE LISTENER HAS LEFT");
+        assert_eq!((period.joins, period.leaves, period.peak_listeners), (0, 1, 1));
+        assert_eq!(audience.current(), 0);
+        assert_eq!(audience.peak(), 2, "PEAK IS KEPT FOR THE WHOLE STREAM");
+    }
+
+    #[test]
+    fn test_growth_over_window() {
+        let audience = Audience::new();
+        let _first = audience.join();
+        let _second = audience.join();
+        assert_eq!(audience.growth(TRENDING_WINDOW), 2, "NEW STREAM GROWS FROM ZERO");
+
+        std::thread::sleep(Duration::from_millis(30));
+        let third = audience.join();
+        assert_eq!(audience.growth(Duration::from_millis(20)), 1);
+
+        drop(third);
+        assert_eq!(audience.growth(Duration::from_millis(20)), 0);
+        assert_eq!(audience.growth(Duration::ZERO), 0);
+    }
+}
@@ -5,73 +5,535 @@
 
 // Trinitypeer, 2025, by Trinitycore
 
-// A function to convert the Raw PCM data, got from the server
-// which actually got it from the client and then then get 
-// compressed it straight to the flac format
+pub(crate) mod opus;
+pub(crate) mod flac_decode;
+pub(crate) mod flac_metadata;
+pub(crate) mod wav;
+pub(crate) mod convert;
+pub(crate) mod loudness;
+pub(crate) mod effects;
+pub(crate) mod analysis;
+#[cfg(test)]
+pub(crate) mod test_signals;
+
+pub use opus::{opus_packet_samples, OggOpusWriter, OpusEncodeOptions, OpusEncoders, OpusStreamDecoder};
+pub use flac_decode::{consume_samples_md5, decode_flac, read_metadata, renumber_frame,
+                      DecodedFlac, FlacDecodeError, StreamInfo};
+pub use wav::{decode_wav, is_wav, DecodedWav, WavError};
+pub use convert::{ConverterSlot, InputFormat, SampleFormat, OPUS_SAMPLE_RATE};
+pub use loudness::{measure_pcm, LoudnessReport, LoudnessSlot, NormalizeSettings};
+pub use effects::{EffectChain, EffectProcessor, EffectSettings};
+pub use analysis::{analyze_pcm, PcmAnalysis};
 
 use flacenc::{self, component::{Stream, BitRepr}, error::{EncodeError, Verify}};
 use log::{error, info, warn};
 use pretty_env_logger;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+
+
+// The codec the stream emits, the listeners need it to know
+// what they actually receive (and the HTTP response needs the content type)
+// FLAC chunks are complete FLAC streams, Opus chunks are single Opus packets
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Flac,
+    Opus,
+}
+
+impl Codec {
+    // The MIME type of a single chunk
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Codec::Flac => "audio/flac",
+            Codec::Opus => "audio/opus",
+        }
+    }
+
+    // The content type of the HTTP listener stream
+    // Opus packets are sent inside of Ogg, so any player could read them
+    // FLAC chunks go in our own framing (every chunk is a whole FLAC stream
+    // after its 4-byte length), it is not a FLAC file, so it is not labelled as one
+    pub fn stream_content_type(&self) -> &'static str {
+        match self {
+            Codec::Flac => "application/x-trinitypeer-chunks",
+            Codec::Opus => "audio/ogg; codecs=opus",
+        }
+    }
+
+    // The duration of the audio in the chunk, when the chunk tells it
+    // Every Opus packet has it in the TOC byte, the FLAC chunk only
+    // in case it is a complete FLAC stream with the amount of samples
+
+    pub fn chunk_duration(&self, chunk: &[u8]) -> Option<Duration> {
+        match self {
+            Codec::Opus => Some(opus_packet_samples(chunk))
+                .filter(|samples| *samples > 0)
+                .map(|samples| Duration::from_secs_f64(samples as f64 / opus::OPUS_GRANULE_RATE as f64)),
+            Codec::Flac => read_metadata(chunk).ok().and_then(|metadata| metadata.info.duration()),
+        }
+    }
+
+    // Whether the player could start decoding from this chunk
+    // Every Opus packet is decodable by itself, the FLAC chunk has to start
+    // with the stream marker or with the sync code of a frame
+
+    pub fn is_decodable_start(&self, chunk: &[u8]) -> bool {
+        match self {
+            Codec::Opus => !chunk.is_empty(),
+            Codec::Flac => chunk.starts_with(b"fLaC")
+                || (chunk.len() >= 2 && chunk[0] == 0xff && chunk[1] & 0xfe == 0xf8),
+        }
+    }
+}
+
+
+
+// The errors of the audio coding, they are returned instead of None,
+// so the caller could tell the streamer what exactly was wrong
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioCodingError {
+    InvalidChannels(usize),
+    InvalidBitsPerSample(usize),
+    InvalidSampleRate(u32),
+    InvalidBlockSize(usize),
+    InvalidCompressionLevel(u8),
+    InvalidComplexity(u8),
+    InvalidFrameSize(usize),
+    UnalignedSamples { samples: usize, channels: usize },
+    SampleOutOfRange { sample: i32, bits_per_sample: usize },
+    Config(String),
+    Encode(String),
+    Decode(String),
+    Write(String),
+}
+
+impl std::fmt::Display for AudioCodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioCodingError::InvalidChannels(c) => write!(f, "Unsupported amount of channels: {}", c),
+            AudioCodingError::InvalidBitsPerSample(b) => write!(f, "Unsupported bits per sample: {}", b),
+            AudioCodingError::InvalidSampleRate(r) => write!(f, "Unsupported sample rate: {}", r),
+            AudioCodingError::InvalidBlockSize(b) => write!(f, "Unsupported block size: {}", b),
+            AudioCodingError::InvalidCompressionLevel(l) => write!(f, "Compression level must be 0-8, got {}", l),
+            AudioCodingError::InvalidComplexity(c) => write!(f, "Opus complexity must be 0-10, got {}", c),
+            AudioCodingError::InvalidFrameSize(s) => write!(f, "Unsupported frame size: {}", s),
+            AudioCodingError::UnalignedSamples { samples, channels } => 
+                write!(f, "{} samples can not be split into {} channels", samples, channels),
+            AudioCodingError::SampleOutOfRange { sample, bits_per_sample } => 
+                write!(f, "Sample {} does not fit into {} bits", sample, bits_per_sample),
+            AudioCodingError::Config(e) => write!(f, "Invalid encoder config: {}", e),
+            AudioCodingError::Encode(e) => write!(f, "Failed to encode: {}", e),
+            AudioCodingError::Decode(e) => write!(f, "Failed to decode: {}", e),
+            AudioCodingError::Write(e) => write!(f, "Failed to write the encoded data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AudioCodingError {}
+
+
+
+// The format of the raw PCM data
+// Channels: the number of audio channels (1 is mono, 2 is stereo, up to 8 for FLAC)
+// Bits per sample: the number of bits used to represent each sample
+// 16 bits is a better choice for live audio, decent quality and normal bandwidth
+// Sample Rate is the rate at which the audio is sampled, affecting the quality
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AudioFormat {
+    pub channels: usize,
+    pub bits_per_sample: usize,
+    pub sample_rate: u32,
+}
+
+impl Default for AudioFormat {
+    fn default() -> Self {
+        AudioFormat {
+            channels: 2,
+            bits_per_sample: 16,
+            sample_rate: 44100,
+        }
+    }
+}
+
+impl AudioFormat {
+    // Checking the format is something FLAC can store
+    pub fn validate(&self) -> Result<(), AudioCodingError> {
+        if !(1..=8).contains(&self.channels) {
+            return Err(AudioCodingError::InvalidChannels(self.channels));
+        }
+
+        if ![8, 12, 16, 20, 24].contains(&self.bits_per_sample) {
+            return Err(AudioCodingError::InvalidBitsPerSample(self.bits_per_sample));
+        }
+
+        if !(1..=655350).contains(&self.sample_rate) {
+            return Err(AudioCodingError::InvalidSampleRate(self.sample_rate));
+        }
+
+        Ok(())
+    }
+
+    // Checking the interleaved samples match the format
+    pub fn validate_samples(&self, pcm_data: &[i32]) -> Result<(), AudioCodingError> {
+        if pcm_data.len() % self.channels != 0 {
+            return Err(AudioCodingError::UnalignedSamples { 
+                samples: pcm_data.len(), 
+                channels: self.channels,
+            });
+        }
+
+        let max = (1i64 << (self.bits_per_sample - 1)) - 1;
+        let min = -(1i64 << (self.bits_per_sample - 1));
+
+        match pcm_data.iter().find(|s| !(min..=max).contains(&(**s as i64))) {
+            Some(sample) => Err(AudioCodingError::SampleOutOfRange { 
+                sample: *sample, 
+                bits_per_sample: self.bits_per_sample,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+
+
+// All the settings of the FLAC encoder
+// Block size is the amount of samples (per channel) in a single FLAC frame
+// Compression level is 0 (fastest) to 8 (smallest), the same as in the flac tool
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlacEncodeOptions {
+    pub format: AudioFormat,
+    pub block_size: usize,
+    pub compression_level: u8,
+}
+
+impl Default for FlacEncodeOptions {
+    fn default() -> Self {
+        FlacEncodeOptions {
+            format: AudioFormat::default(),
+            block_size: 4096,
+            compression_level: 5,
+        }
+    }
+}
+
+impl FlacEncodeOptions {
+    pub fn new(format: AudioFormat) -> Self {
+        FlacEncodeOptions {
+            format,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AudioCodingError> {
+        self.format.validate()?;
+
+        if !(16..=65535).contains(&self.block_size) {
+            return Err(AudioCodingError::InvalidBlockSize(self.block_size));
+        }
+
+        if self.compression_level > 8 {
+            return Err(AudioCodingError::InvalidCompressionLevel(self.compression_level));
+        }
+
+        Ok(())
+    }
+
+    // Translating the options to the config of flacenc
+    // The lower levels skip LPC and stereo decorrelation, the higher ones
+    // search for a longer LPC predictor
+
+    fn encoder_config(&self) -> flacenc::config::Encoder {
+        let mut config = flacenc::config::Encoder::default();
+        let level = self.compression_level;
+
+        config.block_size = self.block_size;
+
+        config.stereo_coding.use_leftside = level >= 1;
+        config.stereo_coding.use_rightside = level >= 1;
+        config.stereo_coding.use_midside = level >= 1;
+
+        config.subframe_coding.use_lpc = level >= 3;
+        config.subframe_coding.qlpc.lpc_order = match level {
+            0..=4 => 6,
+            5..=6 => 8,
+            _ => 12,
+        };
 
-pub fn encode_pcm_to_flac(pcm_data: &[i32], sample_rate: u32)  -> Option<Vec<u8>> {
-    // Setting all the needed settings
-    // Channels: the number of audio channels (2 is stereo, please do not touch it)
-    // Bits per sample: the number of bits used to represent each sample
-    // 16 bits is a better choice for live audio, decent quality and normal bandwidth
-    // Sample Rate is the rate at which the audio is sampled, affecting the quality
+        config
+    }
+}
 
-    let (channels, bits_per_sample, sample_rate) = (2, 16, 44100);    
-    let config = flacenc::config::Encoder::default().into_verified();
+
+
+// A function to convert the Raw PCM data, got from the server
+// which actually got it from the client and then then get 
+// compressed it straight to the flac format
+// The samples are interleaved (L R L R ... for stereo)
+
+pub fn encode_pcm_to_flac(pcm_data: &[i32], options: &FlacEncodeOptions) 
+                                            -> Result<Vec<u8>, AudioCodingError> {
+    // Everything is validated up front, so the encoder never gets
+    // the data it would choke on
+
+    options.validate()?;
+    options.format.validate_samples(pcm_data)?;
+
+    let format = options.format;
+    let config = options.encoder_config().into_verified();
 
 
 
     // As stated in their documentation, the config must be verified
     // to make sure it is correct and will not cause any problems
-    // In case the config is not verified, the program will panic
-    // We will prevent it by just returning None and logging the error
-
-    match config {
-        Ok(config_verified) => {
-            // Source is a converted Vec PCM data
-            let source = flacenc::source::MemSource::from_samples(
-        pcm_data, channels, bits_per_sample, sample_rate);
-            
-            // This is our encoder, which will encode the PCM data to FLAC
-            let flac_stream = flacenc::encode_with_fixed_block_size(
-                &config_verified, source, config_verified.block_size
-            );
-
-            match flac_stream {
-                Ok(flac_data) => {
-                    info!("Encoded the data");
-
-                    // Writing the data to the sink, as it has the method for converting
-                    // the data to the Vec<u8> format (as_slice)
-
-                    let mut sink = flacenc::bitsink::ByteSink::new();
-
-                    if flac_data.write(&mut sink).is_err() {
-                        error!("Failed to write data to sink");
-                        return None;
-                    }
-
-
-
-                    // If the error was not raised, we can return the data
-                    // as a Vec<u8> format
-
-                    Some(sink.as_slice().to_vec())
-                }
-                Err(e) => {
-                    error!("Failed to encode PCM data: {}", e);
-                    None
-                }
-            }
-        },
-        Err(e) => {
-            error!("Failed to verify config: {}", e.1);
-            None
-        }
-    } 
-}
\ No newline at end of file
+    // In case the config is not verified, the error is returned
+
+    let config_verified = config.map_err(|e| AudioCodingError::Config(e.1.to_string()))?;
+
+    // Source is a converted Vec PCM data
+    let source = flacenc::source::MemSource::from_samples(
+        pcm_data, format.channels, format.bits_per_sample, format.sample_rate as usize);
+
+    // This is our encoder, which will encode the PCM data to FLAC
+    let flac_data = flacenc::encode_with_fixed_block_size(
+        &config_verified, source, config_verified.block_size
+    ).map_err(|e| {
+        error!("Failed to encode PCM data: {}", e);
+        AudioCodingError::Encode(e.to_string())
+    })?;
+
+    info!("Encoded the data");
+
+    // Writing the data to the sink, as it has the method for converting
+    // the data to the Vec<u8> format (as_slice)
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+
+    if let Err(e) = flac_data.write(&mut sink) {
+        error!("Failed to write data to sink");
+        return Err(AudioCodingError::Write(format!("{:?}", e)));
+    }
+
+    Ok(sink.as_slice().to_vec())
+}
+
+
+
+// Reading the interleaved little-endian signed PCM, as it comes from the streamer
+// 8, 16 and 24 bits per sample are stored in 1, 2 and 3 bytes,
+// 12 and 20 bits are expected to be padded to 2 and 3 bytes
+
+pub fn pcm_from_le_bytes(bytes: &[u8], format: &AudioFormat) -> Result<Vec<i32>, AudioCodingError> {
+    format.validate()?;
+
+    let width = format.bits_per_sample.div_ceil(8);
+    let frame = width * format.channels;
+
+    if bytes.len() % frame != 0 {
+        return Err(AudioCodingError::UnalignedSamples { 
+            samples: bytes.len() / width, 
+            channels: format.channels,
+        });
+    }
+
+    Ok(bytes.chunks_exact(width)
+        .map(|b| match width {
+            1 => b[0] as i8 as i32,
+            2 => i16::from_le_bytes([b[0], b[1]]) as i32,
+            // Sign extension of the 24-bit value
+            _ => i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8,
+        })
+        .collect())
+}
+
+// Encoding the PCM with the codec of the stream
+// Returns the chunks to push, FLAC gives a single complete FLAC stream,
+// Opus gives a packet for every 20 ms frame, the encoder of the stream
+// keeps the rest of the PCM for the next chunk
+
+pub fn encode_pcm(codec: Codec, pcm_data: &[i32], format: &AudioFormat, encoders: &OpusEncoders) 
+                                    -> Result<Vec<Vec<u8>>, AudioCodingError> {
+    match codec {
+        Codec::Flac => {
+            let flac = encode_pcm_to_flac(pcm_data, &FlacEncodeOptions::new(*format))?;
+            Ok(vec![flac])
+        }
+        Codec::Opus => encode_pcm_opus(pcm_data, format, Rendition::of_codec(codec), encoders),
+    }
+}
+
+fn encode_pcm_opus(pcm_data: &[i32], format: &AudioFormat, rendition: Rendition, encoders: &OpusEncoders) 
+                                    -> Result<Vec<Vec<u8>>, AudioCodingError> {
+    format.validate()?;
+    format.validate_samples(pcm_data)?;
+
+    // Opus works with 16-bit samples only
+    let shift = format.bits_per_sample as i32 - 16;
+    let pcm_16: Vec<i16> = pcm_data.iter()
+        .map(|s| if shift >= 0 { (s >> shift) as i16 } else { (s << -shift) as i16 })
+        .collect();
+
+    let options = OpusEncodeOptions {
+        sample_rate: format.sample_rate,
+        channels: format.channels,
+        // 20 ms frames, the same as WebRTC uses
+        frame_size: format.sample_rate as usize / 50,
+        bitrate: rendition.bitrate(),
+        ..Default::default()
+    };
+
+    encoders.encode(rendition, options, &pcm_16)
+}
+
+
+
+// The versions of the same stream for the different connections
+// The stream itself is one of them (FLAC is lossless, Opus is the high one),
+// the others are encoded from the same PCM next to it
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rendition {
+    Lossless,
+    OpusHigh,
+    OpusLow,
+}
+
+impl Rendition {
+    pub fn codec(&self) -> Codec {
+        match self {
+            Rendition::Lossless => Codec::Flac,
+            Rendition::OpusHigh | Rendition::OpusLow => Codec::Opus,
+        }
+    }
+
+    // The bitrate in bits per second, the lossless one is the CD quality estimate
+    pub fn bitrate(&self) -> u32 {
+        match self {
+            Rendition::Lossless => 1411200,
+            Rendition::OpusHigh => OpusEncodeOptions::default().bitrate,
+            Rendition::OpusLow => 32000,
+        }
+    }
+
+    // The rendition the stream itself is, when it is sent with the codec
+    pub fn of_codec(codec: Codec) -> Self {
+        match codec {
+            Codec::Flac => Rendition::Lossless,
+            Codec::Opus => Rendition::OpusHigh,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "lossless" => Some(Rendition::Lossless),
+            "opus_high" => Some(Rendition::OpusHigh),
+            "opus_low" => Some(Rendition::OpusLow),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode_rendition(rendition: Rendition, pcm_data: &[i32], format: &AudioFormat, encoders: &OpusEncoders) 
+                                    -> Result<Vec<Vec<u8>>, AudioCodingError> {
+    match rendition {
+        Rendition::Lossless => encode_pcm(Codec::Flac, pcm_data, format, encoders),
+        Rendition::OpusHigh | Rendition::OpusLow => encode_pcm_opus(pcm_data, format, rendition, encoders),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_signals::{rms, sine, to_pcm};
+
+    #[test]
+    fn test_formats_are_validated_up_front() {
+        let mono_podcast = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 44100 };
+        let mic_input = AudioFormat { channels: 2, bits_per_sample: 16, sample_rate: 48000 };
+        let studio = AudioFormat { channels: 2, bits_per_sample: 24, sample_rate: 96000 };
+
+        for format in [mono_podcast, mic_input, studio] {
+            assert_eq!(FlacEncodeOptions::new(format).validate(), Ok(()));
+        }
+
+        let bad_channels = AudioFormat { channels: 0, ..Default::default() };
+        assert_eq!(encode_pcm_to_flac(&[], &FlacEncodeOptions::new(bad_channels)),
+                   Err(AudioCodingError::InvalidChannels(0)));
+
+        let bad_level = FlacEncodeOptions { compression_level: 9, ..Default::default() };
+        assert_eq!(encode_pcm_to_flac(&[], &bad_level),
+                   Err(AudioCodingError::InvalidCompressionLevel(9)));
+    }
+
+    #[test]
+    fn test_samples_are_validated_up_front() {
+        let options = FlacEncodeOptions::default();
+
+        assert_eq!(encode_pcm_to_flac(&[0, 0, 0], &options),
+                   Err(AudioCodingError::UnalignedSamples { samples: 3, channels: 2 }));
+        assert_eq!(encode_pcm_to_flac(&[0, 40000], &options),
+                   Err(AudioCodingError::SampleOutOfRange { sample: 40000, bits_per_sample: 16 }));
+    }
+
+    #[test]
+    fn test_pcm_from_le_bytes() {
+        let stereo_16 = AudioFormat::default();
+        assert_eq!(pcm_from_le_bytes(&[0x01, 0x00, 0xff, 0xff], &stereo_16), Ok(vec![1, -1]));
+        assert!(pcm_from_le_bytes(&[0x01, 0x00], &stereo_16).is_err(), "HALF OF A FRAME");
+
+        let mono_24 = AudioFormat { channels: 1, bits_per_sample: 24, sample_rate: 48000 };
+        assert_eq!(pcm_from_le_bytes(&[0x00, 0x00, 0x80, 0xff, 0xff, 0x7f], &mono_24),
+                   Ok(vec![-8388608, 8388607]));
+    }
+
+    // Decoding the Opus packets back to the 16-bit PCM at 48 kHz
+    fn decode_opus(packets: &[Vec<u8>], channels: usize) -> Vec<i32> {
+        let mut decoder = OpusStreamDecoder::new(channels).unwrap();
+        packets.iter().flat_map(|packet| decoder.decode(packet).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_opus_round_trip() {
+        let mono = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 48000 };
+        let stereo_24 = AudioFormat { channels: 2, bits_per_sample: 24, sample_rate: 48000 };
+        let encoders = OpusEncoders::default();
+
+        for format in [mono, stereo_24] {
+            // A second of 1 kHz at the half of the full scale
+            let pcm = to_pcm(&sine(1000.0, 0.5, 48000, format.channels, 48000), format.bits_per_sample);
+
+            // Two chunks, the first one does not end on a whole frame
+            let (first, second) = pcm.split_at(30000 * format.channels);
+            let mut packets = encode_pcm(Codec::Opus, first, &format, &encoders).unwrap();
+            assert_eq!(packets.len(), 31, "{:?}: THE REST WAITS FOR THE NEXT CHUNK", format);
+            packets.extend(encode_pcm(Codec::Opus, second, &format, &encoders).unwrap());
+
+            assert_eq!(packets.len(), 50, "{:?}: 20 MS PACKETS", format);
+            assert!(packets.iter().all(|p| opus_packet_samples(p) == 960));
+
+            let decoded = decode_opus(&packets, format.channels);
+            assert_eq!(decoded.len(), pcm.len(), "{:?}: THE SAME LENGTH AS THE INPUT", format);
+
+            // The start is the delay of the codec, the level is compared after it
+            let settled = &decoded[4800 * format.channels..];
+            let level = rms(settled) / 32767.0;
+            let expected = 0.5 / 2f64.sqrt();
+            assert!((20.0 * (level / expected).log10()).abs() < 1.0, "{:?}: RMS {} INSTEAD OF {}", format, level, expected);
+        }
+    }
+
+    #[test]
+    fn test_opus_complexity_is_validated() {
+        let options = OpusEncodeOptions { complexity: 11, ..Default::default() };
+        assert_eq!(opus::OpusStreamEncoder::new(options).unwrap_err(), AudioCodingError::InvalidComplexity(11));
+        assert!(AudioCodingError::InvalidComplexity(11).to_string().contains("0-10"));
+    }
+}
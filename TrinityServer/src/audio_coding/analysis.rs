@@ -0,0 +1,223 @@
+// A file for the analysis of the PCM for the visualizers of the players
+// Every chunk gets its levels (RMS and peak of every channel) and a coarse
+// spectrum: the power of the logarithmic bands from 20 Hz up, averaged over
+// the FFT windows of the chunk. Everything is in dBFS, the full scale sine is 0
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::f64::consts::PI;
+
+use serde::Serialize;
+
+use super::AudioFormat;
+
+// The bands of the spectrum, spread evenly over the octaves
+pub const SPECTRUM_BANDS: usize = 16;
+const LOWEST_BAND_HZ: f64 = 20.0;
+const HIGHEST_BAND_HZ: f64 = 20000.0;
+
+// 4096 samples are around 10 Hz per bin at 44.1 kHz, the lowest bands still get a bin
+const FFT_SIZE: usize = 4096;
+
+// The long chunks are not analyzed window by window, a few windows spread over them are enough
+const MAX_SPECTRUM_WINDOWS: usize = 32;
+
+// Anything quieter is shown as this, the silence has no logarithm
+pub const FLOOR_DBFS: f64 = -100.0;
+
+fn to_dbfs(amplitude: f64) -> f64 {
+    (20.0 * amplitude.log10()).max(FLOOR_DBFS)
+}
+
+
+
+// The levels and the spectrum of a single chunk
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PcmAnalysis {
+    pub duration_ms: u64,
+    // A value for every channel
+    pub rms_dbfs: Vec<f64>,
+    pub peak_dbfs: Vec<f64>,
+    // SPECTRUM_BANDS values from the lowest band, the edges are in band_edges()
+    pub bands: Vec<f64>,
+}
+
+impl PcmAnalysis {
+    // The loudest peak of all the channels
+    pub fn peak(&self) -> f64 {
+        self.peak_dbfs.iter().copied().fold(FLOOR_DBFS, f64::max)
+    }
+
+    pub fn is_silent(&self, threshold_dbfs: f64) -> bool {
+        self.peak() < threshold_dbfs
+    }
+}
+
+// The edges of the bands in Hz, SPECTRUM_BANDS + 1 of them
+// The top is the Nyquist frequency, when it is under 20 kHz
+
+pub fn band_edges(sample_rate: u32) -> Vec<f64> {
+    let top = HIGHEST_BAND_HZ.min(sample_rate as f64 / 2.0);
+    (0..=SPECTRUM_BANDS)
+        .map(|i| LOWEST_BAND_HZ * (top / LOWEST_BAND_HZ).powf(i as f64 / SPECTRUM_BANDS as f64))
+        .collect()
+}
+
+// The radix-2 FFT in place, the length is a power of two
+
+fn fft(re: &mut [f64], im: &mut [f64], twiddles: &[(f64, f64)]) {
+    let n = re.len();
+
+    // The bit reversed order first
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let stride = n / len;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = twiddles[k * stride];
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// The spectrum of the mono mix, the short chunks are padded with silence
+
+fn spectrum(mono: &[f64], sample_rate: u32) -> Vec<f64> {
+    let window: Vec<f64> = (0..FFT_SIZE).map(|i| (PI * i as f64 / FFT_SIZE as f64).sin().powi(2)).collect();
+    let window_energy: f64 = window.iter().map(|w| w * w).sum();
+    let twiddles: Vec<(f64, f64)> = (0..FFT_SIZE / 2)
+        .map(|k| (-2.0 * PI * k as f64 / FFT_SIZE as f64).sin_cos())
+        .collect();
+
+    let windows = (mono.len() / FFT_SIZE).clamp(1, MAX_SPECTRUM_WINDOWS);
+    let step = if windows > 1 { (mono.len() - FFT_SIZE) / (windows - 1) } else { 0 };
+
+    let mut power = vec![0.0; FFT_SIZE / 2];
+    let (mut re, mut im) = (vec![0.0; FFT_SIZE], vec![0.0; FFT_SIZE]);
+
+    for start in (0..windows).map(|w| w * step) {
+        for i in 0..FFT_SIZE {
+            re[i] = mono.get(start + i).copied().unwrap_or(0.0) * window[i];
+            im[i] = 0.0;
+        }
+        fft(&mut re, &mut im, &twiddles);
+
+        for (k, bin) in power.iter_mut().enumerate() {
+            *bin += (re[k] * re[k] + im[k] * im[k]) / windows as f64;
+        }
+    }
+
+    // Parseval: the sine of the full scale gives 0 dB in its band
+    let scale = 4.0 / (FFT_SIZE as f64 * window_energy);
+    let bin_hz = sample_rate as f64 / FFT_SIZE as f64;
+
+    band_edges(sample_rate).windows(2)
+        .map(|edges| {
+            let low = ((edges[0] / bin_hz).ceil() as usize).clamp(1, power.len() - 1);
+            // The narrow bands at the bottom get at least one bin
+            let high = ((edges[1] / bin_hz).ceil() as usize).clamp(low + 1, power.len());
+            let energy: f64 = power[low..high].iter().sum();
+            (10.0 * (energy * scale).log10()).max(FLOOR_DBFS)
+        })
+        .collect()
+}
+
+// Analyzing the interleaved PCM of the given format
+
+pub fn analyze_pcm(pcm: &[i32], format: &AudioFormat) -> PcmAnalysis {
+    let channels = format.channels.max(1);
+    let scale = 1.0 / (1i64 << (format.bits_per_sample - 1)) as f64;
+    let frames = pcm.len() / channels;
+
+    let mut sums = vec![0.0; channels];
+    let mut peaks = vec![0.0f64; channels];
+    let mut mono = Vec::with_capacity(frames);
+
+    for frame in pcm.chunks_exact(channels) {
+        let mut mix = 0.0;
+        for (channel, sample) in frame.iter().enumerate() {
+            let value = *sample as f64 * scale;
+            sums[channel] += value * value;
+            peaks[channel] = peaks[channel].max(value.abs());
+            mix += value;
+        }
+        mono.push(mix / channels as f64);
+    }
+
+    PcmAnalysis {
+        duration_ms: (frames as u64 * 1000) / format.sample_rate.max(1) as u64,
+        rms_dbfs: sums.iter().map(|sum| to_dbfs((sum / frames.max(1) as f64).sqrt())).collect(),
+        peak_dbfs: peaks.iter().map(|peak| to_dbfs(*peak)).collect(),
+        bands: spectrum(&mono, format.sample_rate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::{sine, to_pcm};
+
+    fn tone(frequency: f64, format: &AudioFormat, frames: usize) -> Vec<i32> {
+        to_pcm(&sine(frequency, 0.5, format.sample_rate, format.channels, frames), format.bits_per_sample)
+    }
+
+    #[test]
+    fn test_levels_of_the_tone_and_the_silence() {
+        let format = AudioFormat::default();
+        let analysis = analyze_pcm(&tone(1000.0, &format, 44100), &format);
+
+        assert_eq!(analysis.duration_ms, 1000);
+        for channel in 0..2 {
+            assert!((analysis.rms_dbfs[channel] + 9.03).abs() < 0.05, "RMS {}", analysis.rms_dbfs[channel]);
+            assert!((analysis.peak_dbfs[channel] + 6.02).abs() < 0.05, "PEAK {}", analysis.peak_dbfs[channel]);
+        }
+        assert!(!analysis.is_silent(-60.0));
+
+        let silence = analyze_pcm(&vec![0; 8820], &format);
+        assert_eq!(silence.peak(), FLOOR_DBFS);
+        assert!(silence.bands.iter().all(|band| *band == FLOOR_DBFS), "NOTHING IN THE SPECTRUM");
+        assert!(silence.is_silent(-60.0));
+    }
+
+    #[test]
+    fn test_tone_lands_in_its_band() {
+        for (frequency, rate) in [(1000.0, 44100), (100.0, 48000), (8000.0, 32000)] {
+            let format = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: rate };
+            let analysis = analyze_pcm(&tone(frequency, &format, rate as usize / 2), &format);
+            let edges = band_edges(rate);
+
+            let band = edges.windows(2).position(|e| e[0] <= frequency && frequency < e[1]).unwrap();
+            let loudest = (0..SPECTRUM_BANDS).max_by(|a, b| analysis.bands[*a].total_cmp(&analysis.bands[*b])).unwrap();
+
+            assert_eq!(loudest, band, "{} HZ: {:?}", frequency, analysis.bands);
+            assert!((analysis.bands[band] + 6.02).abs() < 0.5, "{} HZ: {} DB", frequency, analysis.bands[band]);
+            assert!(analysis.bands.iter().enumerate()
+                        .all(|(i, value)| i.abs_diff(band) <= 1 || *value < -40.0),
+                    "{} HZ LEAKS: {:?}", frequency, analysis.bands);
+        }
+    }
+}
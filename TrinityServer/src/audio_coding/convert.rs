@@ -0,0 +1,534 @@
+// A file for the conversion of the PCM, before it is encoded
+// The broadcasters send whatever their capture gives (48 kHz from the browsers,
+// mono from the microphones, floats from the DAWs), the stream has its own format
+// The samples are read to floats, the channels are mixed, the rate is converted
+// and the samples are quantized back to the integers of the stream with dither
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+use super::{pcm_from_le_bytes, AudioCodingError, AudioFormat, Codec};
+use crate::shared::Slot;
+
+// Opus works with 48 kHz inside, so its streams are always encoded with it
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+
+// The precision of the float samples is more than any of the integer formats,
+// so they are always dithered
+const FLOAT_PRECISION_BITS: usize = 32;
+
+
+
+// The samples the broadcaster sends
+// The integers are little-endian and signed, 8, 16 and 24 bits are stored
+// in 1, 2 and 3 bytes, 12 and 20 bits are padded to 2 and 3 bytes
+// The floats are little-endian 32-bit, from -1.0 to 1.0
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int(usize),
+    F32,
+}
+
+impl SampleFormat {
+    // X-Audio-Sample-Format: i16|i24|f32
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "i16" => Some(SampleFormat::Int(16)),
+            "i24" => Some(SampleFormat::Int(24)),
+            "f32" => Some(SampleFormat::F32),
+            _ => None,
+        }
+    }
+
+    fn precision_bits(&self) -> usize {
+        match self {
+            SampleFormat::Int(bits) => *bits,
+            SampleFormat::F32 => FLOAT_PRECISION_BITS,
+        }
+    }
+}
+
+// The format of the PCM the broadcaster sends
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputFormat {
+    pub channels: usize,
+    pub sample_format: SampleFormat,
+    pub sample_rate: u32,
+}
+
+impl InputFormat {
+    // The same format as the integer PCM, which is stored in the stream
+    pub fn of_pcm(format: &AudioFormat) -> Self {
+        InputFormat {
+            channels: format.channels,
+            sample_format: SampleFormat::Int(format.bits_per_sample),
+            sample_rate: format.sample_rate,
+        }
+    }
+
+    // The integer input is the same PCM as the stream could have,
+    // the floats are never stored as they are
+    fn as_pcm(&self) -> Option<AudioFormat> {
+        match self.sample_format {
+            SampleFormat::Int(bits_per_sample) => Some(AudioFormat {
+                channels: self.channels,
+                bits_per_sample,
+                sample_rate: self.sample_rate,
+            }),
+            SampleFormat::F32 => None,
+        }
+    }
+
+    // The floats are checked as the 24-bit PCM, the rest is the same
+    pub fn validate(&self) -> Result<(), AudioCodingError> {
+        let bits_per_sample = match self.sample_format {
+            SampleFormat::Int(bits) => bits,
+            SampleFormat::F32 => 24,
+        };
+
+        AudioFormat { channels: self.channels, bits_per_sample, sample_rate: self.sample_rate }.validate()
+    }
+
+    // The format the stream gets, when the broadcaster has not declared one
+    // FLAC keeps the input as it is (the floats become 24-bit),
+    // Opus needs 16-bit samples at 48 kHz and up to two channels
+
+    pub fn stream_format(&self, codec: Codec) -> AudioFormat {
+        let bits_per_sample = match self.sample_format {
+            SampleFormat::Int(bits) => bits,
+            SampleFormat::F32 => 24,
+        };
+
+        match codec {
+            Codec::Flac => AudioFormat { channels: self.channels, bits_per_sample, sample_rate: self.sample_rate },
+            Codec::Opus => AudioFormat {
+                channels: if self.channels == 1 { 1 } else { 2 },
+                bits_per_sample: 16,
+                sample_rate: OPUS_SAMPLE_RATE,
+            },
+        }
+    }
+}
+
+
+
+// How good the sample rate conversion is, RESAMPLE_QUALITY=fast|medium|high
+// Fast is the linear interpolation, the others are windowed sinc filters
+// with a longer window for the higher quality (and more CPU for each sample)
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleQuality {
+    Fast,
+    #[default]
+    Medium,
+    High,
+}
+
+impl ResampleQuality {
+    pub fn from_env() -> Self {
+        std::env::var("RESAMPLE_QUALITY").ok()
+            .and_then(|quality| serde_json::from_value(serde_json::Value::String(quality)).ok())
+            .unwrap_or_default()
+    }
+
+    // The half of the filter, in the samples of the input
+    fn half_taps(&self) -> usize {
+        match self {
+            ResampleQuality::Fast => 1,
+            ResampleQuality::Medium => 16,
+            ResampleQuality::High => 64,
+        }
+    }
+}
+
+
+
+// The sample rate conversion of the interleaved samples
+// The resampler keeps the end of the previous chunk, so the filter continues
+// over the border of the chunks without a click
+// The output comes later than the input by the half of the filter
+
+#[derive(Debug)]
+pub struct Resampler {
+    channels: usize,
+    quality: ResampleQuality,
+    // The input frames per an output frame
+    step: f64,
+    // The width of the filter and its cutoff, both in the input frames
+    radius: f64,
+    cutoff: f64,
+    buffer: Vec<f32>,
+    // The position of the next output frame in the buffer
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32, quality: ResampleQuality) -> Self {
+        let ratio = to_rate as f64 / from_rate as f64;
+
+        // When the rate goes down, the filter cuts off at the new Nyquist frequency,
+        // so it is wider in the input frames
+        let cutoff = ratio.min(1.0);
+        let radius = match quality {
+            ResampleQuality::Fast => 1.0,
+            _ => quality.half_taps() as f64 / cutoff,
+        };
+
+        // The first output frame is at the first input one, the filter sees silence before it
+        let padding = radius.ceil() as usize;
+
+        Resampler {
+            channels,
+            quality,
+            step: 1.0 / ratio,
+            radius,
+            cutoff,
+            buffer: vec![0.0; padding * channels],
+            position: padding as f64,
+        }
+    }
+
+    // The weight of the input frame at the distance x from the output one
+    fn kernel(&self, x: f64) -> f64 {
+        if x.abs() >= self.radius {
+            return 0.0;
+        }
+
+        if self.quality == ResampleQuality::Fast {
+            return 1.0 - x.abs();
+        }
+
+        let sinc = match x * self.cutoff {
+            t if t.abs() < 1e-9 => 1.0,
+            t => (PI * t).sin() / (PI * t),
+        };
+
+        // The Blackman window
+        let w = PI * x / self.radius;
+        sinc * (0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos())
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.buffer.extend_from_slice(input);
+
+        let channels = self.channels;
+        let frames = self.buffer.len() / channels;
+        let mut output = Vec::with_capacity((input.len() as f64 / self.step) as usize + channels);
+        let mut sums = vec![0.0f64; channels];
+
+        // Every output frame needs the input up to the end of the filter
+        while self.position + self.radius < frames as f64 {
+            let first = (self.position - self.radius).ceil().max(0.0) as usize;
+            let last = (self.position + self.radius).floor() as usize;
+
+            sums.iter_mut().for_each(|sum| *sum = 0.0);
+            let mut weights = 0.0;
+
+            for frame in first..=last {
+                let weight = self.kernel(self.position - frame as f64);
+                weights += weight;
+
+                let samples = &self.buffer[frame * channels..(frame + 1) * channels];
+                for (sum, sample) in sums.iter_mut().zip(samples) {
+                    *sum += weight * *sample as f64;
+                }
+            }
+
+            // The weights are normalized, so the silence and the DC stay the same
+            let weights = if weights.abs() < 1e-9 { 1.0 } else { weights };
+            output.extend(sums.iter().map(|sum| (sum / weights) as f32));
+
+            self.position += self.step;
+        }
+
+        // Only the frames the next filter could still reach are kept
+        let consumed = (self.position - self.radius).floor().max(0.0) as usize;
+        let consumed = consumed.min(frames);
+        self.buffer.drain(..consumed * channels);
+        self.position -= consumed as f64;
+
+        output
+    }
+}
+
+
+
+// The triangular (TPDF) dither of one step of the output
+// The quantization error becomes the noise instead of the distortion
+// It has its own generator, so the output is the same on every run
+
+#[derive(Debug)]
+pub(super) struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub(super) fn new() -> Self {
+        Dither { state: 0x9e3779b9 }
+    }
+
+    // xorshift32, from -0.5 to 0.5
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f64 / u32::MAX as f64 - 0.5
+    }
+
+    fn next(&mut self) -> f64 {
+        self.uniform() + self.uniform()
+    }
+}
+
+// Reading the interleaved samples to the floats from -1.0 to 1.0
+
+pub fn read_samples(bytes: &[u8], input: &InputFormat) -> Result<Vec<f32>, AudioCodingError> {
+    input.validate()?;
+
+    match input.as_pcm() {
+        Some(format) => {
+            let scale = 1.0 / (1i64 << (format.bits_per_sample - 1)) as f32;
+            Ok(pcm_from_le_bytes(bytes, &format)?.iter().map(|s| *s as f32 * scale).collect())
+        }
+        None => {
+            if !bytes.len().is_multiple_of(4 * input.channels) {
+                return Err(AudioCodingError::UnalignedSamples {
+                    samples: bytes.len() / 4,
+                    channels: input.channels,
+                });
+            }
+
+            // The broken floats are silence, the loud ones are clipped
+            Ok(bytes.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .map(|s| if s.is_finite() { s.clamp(-1.0, 1.0) } else { 0.0 })
+                .collect())
+        }
+    }
+}
+
+// Mixing the interleaved samples to the other amount of the channels
+// The mono is copied to every channel, the downmix to mono is the average
+// of the channels, so the stereo does not clip
+
+pub fn mix_channels(samples: &[f32], from: usize, to: usize) -> Result<Vec<f32>, AudioCodingError> {
+    if from == to {
+        return Ok(samples.to_vec());
+    }
+
+    match (from, to) {
+        (1, _) => Ok(samples.iter().flat_map(|s| std::iter::repeat_n(*s, to)).collect()),
+        (_, 1) => Ok(samples.chunks_exact(from)
+            .map(|frame| frame.iter().sum::<f32>() / from as f32)
+            .collect()),
+        _ => Err(AudioCodingError::InvalidChannels(to)),
+    }
+}
+
+// Quantizing the floats to the integer samples of the given size
+
+pub(super) fn quantize(samples: &[f32], bits_per_sample: usize, mut dither: Option<&mut Dither>) -> Vec<i32> {
+    let scale = (1i64 << (bits_per_sample - 1)) as f64;
+
+    samples.iter()
+        .map(|sample| {
+            let noise = dither.as_mut().map_or(0.0, |dither| dither.next());
+            (*sample as f64 * scale + noise).round().clamp(-scale, scale - 1.0) as i32
+        })
+        .collect()
+}
+
+
+
+// The whole conversion from the input of the broadcaster to the format of the stream
+
+#[derive(Debug)]
+pub struct PcmConverter {
+    input: InputFormat,
+    output: AudioFormat,
+    resampler: Option<Resampler>,
+    dither: Dither,
+}
+
+impl PcmConverter {
+    pub fn new(input: InputFormat, output: AudioFormat, quality: ResampleQuality)
+                                                        -> Result<Self, AudioCodingError> {
+        input.validate()?;
+        output.validate()?;
+
+        // Only the mono and the downmix to mono are mixed, the rest has to match
+        if input.channels != output.channels && input.channels != 1 && output.channels != 1 {
+            return Err(AudioCodingError::InvalidChannels(input.channels));
+        }
+
+        let resampler = (input.sample_rate != output.sample_rate)
+            .then(|| Resampler::new(output.channels, input.sample_rate, output.sample_rate, quality));
+
+        Ok(PcmConverter { input, output, resampler, dither: Dither::new() })
+    }
+
+    pub fn is_for(&self, input: &InputFormat, output: &AudioFormat) -> bool {
+        self.input == *input && self.output == *output
+    }
+
+    // Converting the bytes, as the broadcaster sends them
+    // The input in the format of the stream is taken as it is, bit for bit
+
+    pub fn convert(&mut self, bytes: &[u8]) -> Result<Vec<i32>, AudioCodingError> {
+        if self.input.as_pcm() == Some(self.output) {
+            return pcm_from_le_bytes(bytes, &self.output);
+        }
+
+        let samples = read_samples(bytes, &self.input)?;
+        Ok(self.process(&samples))
+    }
+
+    // Converting the integer PCM (the decoded FLAC), its size is the one of the input
+
+    pub fn convert_pcm(&mut self, pcm: &[i32]) -> Result<Vec<i32>, AudioCodingError> {
+        let format = self.input.as_pcm()
+            .ok_or(AudioCodingError::InvalidBitsPerSample(FLOAT_PRECISION_BITS))?;
+        format.validate_samples(pcm)?;
+
+        if format == self.output {
+            return Ok(pcm.to_vec());
+        }
+
+        let scale = 1.0 / (1i64 << (format.bits_per_sample - 1)) as f32;
+        let samples: Vec<f32> = pcm.iter().map(|s| *s as f32 * scale).collect();
+        Ok(self.process(&samples))
+    }
+
+    fn process(&mut self, samples: &[f32]) -> Vec<i32> {
+        // Every sample is mixed already, mix_channels could not fail here
+        let mixed = mix_channels(samples, self.input.channels, self.output.channels)
+            .unwrap_or_default();
+
+        let resampled = match self.resampler.as_mut() {
+            Some(resampler) => resampler.process(&mixed),
+            None => mixed,
+        };
+
+        // The dither is needed only when the samples do not fit the output exactly
+        let exact = self.resampler.is_none()
+            && self.output.channels >= self.input.channels
+            && self.input.sample_format.precision_bits() <= self.output.bits_per_sample;
+
+        let dither = (!exact).then_some(&mut self.dither);
+        quantize(&resampled, self.output.bits_per_sample, dither)
+    }
+}
+
+
+
+// The converter of the stream, kept between the chunks
+// It is created again, when the broadcaster changes the format
+
+#[derive(Clone, Debug, Default)]
+pub struct ConverterSlot {
+    converter: Slot<PcmConverter>,
+}
+
+impl ConverterSlot {
+    fn with<T>(&self, input: InputFormat, output: AudioFormat,
+               convert: impl FnOnce(&mut PcmConverter) -> Result<T, AudioCodingError>)
+                                                            -> Result<T, AudioCodingError> {
+        self.converter.with(|converter| converter.is_for(&input, &output),
+                            || PcmConverter::new(input, output, ResampleQuality::from_env()),
+                            convert)
+    }
+
+    pub fn convert(&self, input: InputFormat, output: AudioFormat, bytes: &[u8])
+                                                    -> Result<Vec<i32>, AudioCodingError> {
+        self.with(input, output, |converter| converter.convert(bytes))
+    }
+
+    pub fn convert_pcm(&self, input: AudioFormat, output: AudioFormat, pcm: &[i32])
+                                                    -> Result<Vec<i32>, AudioCodingError> {
+        self.with(InputFormat::of_pcm(&input), output, |converter| converter.convert_pcm(pcm))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::rms;
+
+    // The resampler takes the floats
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<f32> {
+        crate::audio_coding::test_signals::sine(frequency, 0.5, rate, 1, frames).iter().map(|s| *s as f32).collect()
+    }
+
+    #[test]
+    fn test_resampling_keeps_the_tone_across_chunks() {
+        for quality in [ResampleQuality::Fast, ResampleQuality::Medium, ResampleQuality::High] {
+            let input = sine(1000.0, 48000, 48000);
+            let mut resampler = Resampler::new(1, 48000, 44100, quality);
+
+            // Fed in the uneven chunks, like the ingest gets them
+            let output: Vec<f32> = input.chunks(4801).flat_map(|chunk| resampler.process(chunk)).collect();
+            // The end of the input stays in the filter
+            let expected = 44100 - (resampler.radius / resampler.step) as usize;
+            assert!(output.len().abs_diff(expected) <= 2, "{:?}: {} FRAMES", quality, output.len());
+
+            // The tone has the same level, the borders of the chunks would break it
+            let steady = &output[200..output.len() - 200];
+            assert!((rms(steady) - 0.5 / 2f64.sqrt()).abs() < 0.01, "{:?}: LEVEL {}", quality, rms(steady));
+
+            let reference = sine(1000.0, 44100, output.len());
+            let error = rms(&steady.iter().zip(&reference[200..]).map(|(a, b)| a - b).collect::<Vec<_>>());
+            assert!(error < 0.02, "{:?}: ERROR {}", quality, error);
+        }
+    }
+
+    #[test]
+    fn test_downsampling_removes_what_does_not_fit() {
+        // 20 kHz does not fit into 16 kHz, the filter has to remove it instead of folding it down
+        let input = sine(7000.0, 48000, 48000).iter().zip(sine(20000.0, 48000, 48000))
+            .map(|(a, b)| a + b).collect::<Vec<_>>();
+        let mut resampler = Resampler::new(1, 48000, 16000, ResampleQuality::High);
+        let output = resampler.process(&input);
+
+        let steady = &output[500..output.len() - 500];
+        assert!((rms(steady) - 0.5 / 2f64.sqrt()).abs() < 0.02, "ONLY 7 KHZ IS LEFT: {}", rms(steady));
+    }
+
+    #[test]
+    fn test_channels_and_samples() {
+        assert_eq!(mix_channels(&[0.5, -0.5], 1, 2), Ok(vec![0.5, 0.5, -0.5, -0.5]));
+        assert_eq!(mix_channels(&[0.5, 0.25, -1.0, 1.0], 2, 1), Ok(vec![0.375, 0.0]));
+        assert!(mix_channels(&[0.0; 6], 3, 2).is_err(), "ONLY MONO AND STEREO ARE MIXED");
+
+        let f32_mono = InputFormat { channels: 1, sample_format: SampleFormat::F32, sample_rate: 48000 };
+        let bytes: Vec<u8> = [0.5f32, -1.0, f32::NAN, 2.0].iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(read_samples(&bytes, &f32_mono), Ok(vec![0.5, -1.0, 0.0, 1.0]));
+
+        // The same format is kept bit for bit, without the dither
+        let stereo_16 = AudioFormat::default();
+        let mut converter = PcmConverter::new(InputFormat::of_pcm(&stereo_16), stereo_16,
+                                              ResampleQuality::Fast).unwrap();
+        assert_eq!(converter.convert(&[0x01, 0x00, 0xff, 0xff]), Ok(vec![1, -1]));
+
+        // The mono i24 becomes the stereo i16, the dither is within one step of the output
+        let mono_24 = InputFormat { channels: 1, sample_format: SampleFormat::Int(24), sample_rate: 44100 };
+        let mut converter = PcmConverter::new(mono_24, stereo_16, ResampleQuality::Fast).unwrap();
+        let pcm = converter.convert(&[0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]).unwrap();
+        assert_eq!(pcm.len(), 4);
+        assert!(pcm[..2].iter().all(|s| (s - 16384).abs() <= 1), "{:?}", pcm);
+        assert!(pcm[2..].iter().all(|s| (s + 16384).abs() <= 1), "{:?}", pcm);
+
+        // The float silence stays the silence on average
+        let silence = vec![0u8; 4 * 1000];
+        let mut converter = PcmConverter::new(f32_mono, AudioFormat { channels: 1, ..stereo_16 },
+                                              ResampleQuality::Fast).unwrap();
+        let pcm = converter.convert(&silence).unwrap();
+        assert!(pcm.iter().all(|s| s.abs() <= 1), "DITHER IS ONE STEP AT MOST");
+        assert!(pcm.iter().sum::<i32>().abs() < 100);
+    }
+}
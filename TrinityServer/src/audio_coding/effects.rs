@@ -0,0 +1,738 @@
+// A file for the effects of the track playback
+// The listener could slow the track down (or speed it up), stretch it without
+// changing the pitch, shift the pitch, add the reverb, the EQ and the gain
+// The chain works on the floats: stretch, speed, EQ, reverb and gain,
+// the output is quantized back with the dither of its own generator,
+// so the same track with the same effects always gives the same samples
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+use super::convert::{quantize, Dither, ResampleQuality, Resampler};
+use super::loudness::{from_db, Biquad};
+use super::{AudioCodingError, AudioFormat};
+
+// The speed and the tempo are from the half to the double,
+// the pitch is shifted by an octave at most
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 2.0;
+pub const MAX_PITCH_SEMITONES: f64 = 12.0;
+pub const MAX_GAIN_DB: f64 = 24.0;
+
+// The EQ has a few peaking bands, every one of them with its own width
+const MAX_EQ_BANDS: usize = 8;
+const DEFAULT_EQ_Q: f64 = 1.0;
+
+// The time stretch moves the pieces of 40 ms, overlapped by the half,
+// every piece is searched for within 10 ms, so it continues the previous one
+const STRETCH_FRAME_SECS: f64 = 0.04;
+const STRETCH_SEARCH_SECS: f64 = 0.01;
+
+// The reverb is Freeverb with less filters, the delays are for 44.1 kHz,
+// the odd channels have theirs a bit longer, so the reverb is wide
+const COMB_DELAYS: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_DELAYS: [usize; 2] = [556, 441];
+const REVERB_STEREO_SPREAD: usize = 23;
+const REVERB_FEEDBACK: f64 = 0.84;
+const REVERB_DAMPING: f64 = 0.2;
+const REVERB_INPUT_GAIN: f64 = 0.045;
+
+// The reverb rings after the end of the track, until it is 60 dB down
+const REVERB_TAIL_DB: f64 = -60.0;
+
+// The end of the track is drained out of the stretch and the speed change
+// with the blocks of the silence, 50 ms each
+const DRAIN_BLOCK_SECS: f64 = 0.05;
+const MAX_DRAIN_BLOCKS: usize = 8;
+
+
+
+// The ready-made chains, ?preset=slowed is the "slowed + reverb" of the internet
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    Slowed,
+    Nightcore,
+}
+
+impl Preset {
+    fn chain(&self) -> EffectChain {
+        match self {
+            Preset::Slowed => EffectChain { speed: 0.85, reverb: 0.3, ..Default::default() },
+            Preset::Nightcore => EffectChain { speed: 1.25, ..Default::default() },
+        }
+    }
+}
+
+// A single peaking band of the EQ
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EqBand {
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+impl EqBand {
+    // "frequency:gain[:q]", the bands are separated by the commas: ?eq=100:3,3000:-2:0.7
+    pub fn parse_list(bands: &str) -> Result<Vec<EqBand>, String> {
+        let bands: Vec<EqBand> = bands.split(',')
+            .filter(|band| !band.trim().is_empty())
+            .map(EqBand::parse)
+            .collect::<Result<_, _>>()?;
+
+        if bands.len() > MAX_EQ_BANDS {
+            return Err(format!("The EQ has {} bands at most", MAX_EQ_BANDS));
+        }
+
+        Ok(bands)
+    }
+
+    fn parse(band: &str) -> Result<EqBand, String> {
+        let invalid = || format!("Invalid EQ band \"{}\", it is frequency:gain[:q]", band);
+        let values: Vec<f64> = band.split(':')
+            .map(|value| value.trim().parse::<f64>().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+
+        match values[..] {
+            [frequency, gain_db] => Ok(EqBand { frequency, gain_db, q: DEFAULT_EQ_Q }),
+            [frequency, gain_db, q] => Ok(EqBand { frequency, gain_db, q }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(20.0..=20000.0).contains(&self.frequency) {
+            return Err("The EQ frequency is from 20 to 20000 Hz".to_string());
+        }
+
+        if !(-MAX_GAIN_DB..=MAX_GAIN_DB).contains(&self.gain_db) {
+            return Err(format!("The EQ gain is from -{0} to {0} dB", MAX_GAIN_DB));
+        }
+
+        if !(0.1..=10.0).contains(&self.q) {
+            return Err("The EQ Q is from 0.1 to 10".to_string());
+        }
+
+        Ok(())
+    }
+
+    // The peaking filter of the Audio EQ Cookbook
+    // The bands above the Nyquist frequency of the track are pulled under it
+    fn filter(&self, sample_rate: u32) -> Biquad {
+        let frequency = self.frequency.min(sample_rate as f64 * 0.45);
+        let a = 10f64.powf(self.gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * self.q);
+
+        Biquad::new([1.0 + alpha * a, -2.0 * w0.cos(), 1.0 - alpha * a],
+                    [1.0 + alpha / a, -2.0 * w0.cos(), 1.0 - alpha / a])
+    }
+}
+
+
+
+// The effects the listener asks for in the query
+// ?preset=slowed|nightcore, ?speed=0.8 (the tempo and the pitch together),
+// ?tempo=1.2 (without the pitch), ?pitch=-3 (the semitones, without the tempo),
+// ?reverb=0.3 (the wet part), ?eq=100:3,3000:-2:0.7 and ?gain=-3 (dB)
+// The preset is the base, the other parameters change it
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct EffectSettings {
+    pub preset: Option<Preset>,
+    pub speed: Option<f64>,
+    pub tempo: Option<f64>,
+    pub pitch: Option<f64>,
+    pub reverb: Option<f64>,
+    pub eq: Option<String>,
+    pub gain: Option<f64>,
+}
+
+impl EffectSettings {
+    pub fn chain(&self) -> Result<EffectChain, String> {
+        let base = self.preset.map(|preset| preset.chain()).unwrap_or_default();
+
+        let eq = match &self.eq {
+            Some(eq) => EqBand::parse_list(eq)?,
+            None => base.eq,
+        };
+
+        EffectChain {
+            speed: self.speed.unwrap_or(base.speed),
+            tempo: self.tempo.unwrap_or(base.tempo),
+            pitch: self.pitch.unwrap_or(base.pitch),
+            reverb: self.reverb.unwrap_or(base.reverb),
+            eq,
+            gain_db: self.gain.unwrap_or(base.gain_db),
+        }.validated()
+    }
+}
+
+// The validated chain, the default one changes nothing
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectChain {
+    pub speed: f64,
+    pub tempo: f64,
+    pub pitch: f64,
+    pub reverb: f64,
+    pub eq: Vec<EqBand>,
+    pub gain_db: f64,
+}
+
+impl Default for EffectChain {
+    fn default() -> Self {
+        EffectChain { speed: 1.0, tempo: 1.0, pitch: 0.0, reverb: 0.0, eq: Vec::new(), gain_db: 0.0 }
+    }
+}
+
+impl EffectChain {
+    pub fn validated(self) -> Result<Self, String> {
+        if !(MIN_SPEED..=MAX_SPEED).contains(&self.speed) || !(MIN_SPEED..=MAX_SPEED).contains(&self.tempo) {
+            return Err(format!("The speed and the tempo are from {} to {}", MIN_SPEED, MAX_SPEED));
+        }
+
+        if !(-MAX_PITCH_SEMITONES..=MAX_PITCH_SEMITONES).contains(&self.pitch) {
+            return Err(format!("The pitch is from -{0} to {0} semitones", MAX_PITCH_SEMITONES));
+        }
+
+        if !(0.0..=1.0).contains(&self.reverb) {
+            return Err("The reverb is from 0 to 1".to_string());
+        }
+
+        if !(-MAX_GAIN_DB..=MAX_GAIN_DB).contains(&self.gain_db) {
+            return Err(format!("The gain is from -{0} to {0} dB", MAX_GAIN_DB));
+        }
+
+        self.eq.iter().try_for_each(EqBand::validate)?;
+
+        Ok(self)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == EffectChain::default()
+    }
+
+    // How much longer the audio gets, 2.0 for ?speed=0.5
+    pub fn duration_ratio(&self) -> f64 {
+        1.0 / (self.speed * self.tempo)
+    }
+
+    fn pitch_ratio(&self) -> f64 {
+        2f64.powf(self.pitch / 12.0)
+    }
+}
+
+
+
+// The time stretch without the change of the pitch (WSOLA)
+// The input is cut into the overlapping pieces, which are put together with
+// the other step than they were taken with. Every piece is moved a bit,
+// so its waveform continues the previous piece, and the tones do not beat
+// The last piece stays in the buffer until the next input comes
+
+#[derive(Debug)]
+struct Stretcher {
+    channels: usize,
+    overlap: usize,
+    search: usize,
+    // The step of the pieces in the input frames, the output step is the overlap
+    step: f64,
+    // The periodic Hann window, its halves sum to one
+    window: Vec<f64>,
+    buffer: Vec<f32>,
+    // Where the next piece would be taken without the search, in the buffer frames
+    nominal: f64,
+    previous: Option<usize>,
+    // The faded out end of the previous piece
+    tail: Vec<f64>,
+}
+
+impl Stretcher {
+    // The ratio is the length of the output for a second of the input
+    fn new(channels: usize, sample_rate: u32, ratio: f64) -> Self {
+        let overlap = ((sample_rate as f64 * STRETCH_FRAME_SECS / 2.0) as usize).max(8);
+        let frame = overlap * 2;
+
+        Stretcher {
+            channels,
+            overlap,
+            search: (sample_rate as f64 * STRETCH_SEARCH_SECS) as usize,
+            step: overlap as f64 / ratio,
+            window: (0..frame).map(|i| (PI * i as f64 / frame as f64).sin().powi(2)).collect(),
+            buffer: Vec::new(),
+            nominal: 0.0,
+            previous: None,
+            tail: vec![0.0; overlap * channels],
+        }
+    }
+
+    fn mono(&self, frame: usize) -> f64 {
+        self.buffer[frame * self.channels..(frame + 1) * self.channels].iter().map(|s| *s as f64).sum()
+    }
+
+    // The start of the piece from first..=last, which is the most alike
+    // to the natural continuation of the previous piece
+    // The correlation is normalized, so the loud places are not preferred
+    fn best_match(&self, target: usize, first: usize, last: usize) -> usize {
+        let reference: Vec<f64> = (0..self.overlap).step_by(2).map(|i| self.mono(target + i)).collect();
+        let region: Vec<f64> = (first..last + self.overlap).map(|frame| self.mono(frame)).collect();
+
+        let mut best = (first, f64::MIN);
+        for start in first..=last {
+            let candidate = region[start - first..].iter().step_by(2);
+            let (correlation, energy) = reference.iter().zip(candidate)
+                .fold((0.0, 0.0), |(c, e), (r, s)| (c + r * s, e + s * s));
+            let score = correlation / (energy + 1e-9).sqrt();
+
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+
+        best.0
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.buffer.extend_from_slice(input);
+
+        let channels = self.channels;
+        let overlap = self.overlap;
+        let frames = self.buffer.len() / channels;
+        let mut output = Vec::with_capacity((input.len() as f64 * overlap as f64 / self.step) as usize + channels);
+
+        loop {
+            let nominal = self.nominal.round() as usize;
+            let first = nominal.saturating_sub(self.search);
+            let last = nominal + self.search;
+
+            // The search and the natural continuation both need a whole piece after them
+            let needed = (last + 2 * overlap).max(self.previous.map_or(0, |previous| previous + 2 * overlap));
+            if needed > frames {
+                break;
+            }
+
+            let start = match self.previous {
+                Some(previous) => self.best_match(previous + overlap, first, last),
+                None => nominal,
+            };
+
+            // The very first piece does not fade in
+            for i in 0..overlap {
+                let weight = if self.previous.is_some() { self.window[i] } else { 1.0 };
+                for channel in 0..channels {
+                    let sample = self.buffer[(start + i) * channels + channel] as f64;
+                    output.push((self.tail[i * channels + channel] + weight * sample) as f32);
+                }
+            }
+
+            for i in 0..overlap {
+                for channel in 0..channels {
+                    let sample = self.buffer[(start + overlap + i) * channels + channel] as f64;
+                    self.tail[i * channels + channel] = self.window[overlap + i] * sample;
+                }
+            }
+
+            self.previous = Some(start);
+            self.nominal += self.step;
+        }
+
+        // Only the frames the next search and continuation could still reach are kept
+        if let Some(previous) = self.previous {
+            let consumed = previous.min((self.nominal.round() as usize).saturating_sub(self.search));
+            self.buffer.drain(..consumed * channels);
+            self.previous = Some(previous - consumed);
+            self.nominal -= consumed as f64;
+        }
+
+        output
+    }
+}
+
+
+
+// The reverb of every channel: the parallel combs with the damping
+// and the allpasses after them
+
+#[derive(Debug)]
+struct Comb {
+    line: Vec<f64>,
+    position: usize,
+    damped: f64,
+}
+
+impl Comb {
+    fn process(&mut self, x: f64) -> f64 {
+        let output = self.line[self.position];
+        self.damped = output * (1.0 - REVERB_DAMPING) + self.damped * REVERB_DAMPING;
+        self.line[self.position] = x + self.damped * REVERB_FEEDBACK;
+        self.position = (self.position + 1) % self.line.len();
+        output
+    }
+}
+
+#[derive(Debug)]
+struct Allpass {
+    line: Vec<f64>,
+    position: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, x: f64) -> f64 {
+        let delayed = self.line[self.position];
+        self.line[self.position] = x + delayed * 0.5;
+        self.position = (self.position + 1) % self.line.len();
+        delayed - x
+    }
+}
+
+#[derive(Debug)]
+struct Reverb {
+    wet: f64,
+    combs: Vec<Vec<Comb>>,
+    allpasses: Vec<Vec<Allpass>>,
+}
+
+impl Reverb {
+    fn new(channels: usize, sample_rate: u32, wet: f64) -> Self {
+        let line = |delay: usize, channel: usize| {
+            let delay = delay + if channel % 2 == 1 { REVERB_STEREO_SPREAD } else { 0 };
+            vec![0.0; ((delay as f64 * sample_rate as f64 / 44100.0).round() as usize).max(1)]
+        };
+
+        Reverb {
+            wet,
+            combs: (0..channels)
+                .map(|channel| COMB_DELAYS.iter()
+                    .map(|delay| Comb { line: line(*delay, channel), position: 0, damped: 0.0 })
+                    .collect())
+                .collect(),
+            allpasses: (0..channels)
+                .map(|channel| ALLPASS_DELAYS.iter()
+                    .map(|delay| Allpass { line: line(*delay, channel), position: 0 })
+                    .collect())
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, x: f64, channel: usize) -> f64 {
+        let input = x * REVERB_INPUT_GAIN;
+        let combs: f64 = self.combs[channel].iter_mut().map(|comb| comb.process(input)).sum();
+        let reverb = self.allpasses[channel].iter_mut().fold(combs, |sample, allpass| allpass.process(sample));
+
+        x * (1.0 - self.wet) + reverb * self.wet
+    }
+
+    // How long the longest comb needs to go 60 dB down after the input has stopped
+    fn tail_frames(&self) -> usize {
+        let longest = self.combs.iter().flatten().map(|comb| comb.line.len()).max().unwrap_or(0);
+        let rounds = (REVERB_TAIL_DB / 20.0) / REVERB_FEEDBACK.log10();
+        (longest as f64 * rounds).ceil() as usize
+    }
+}
+
+
+
+// The whole chain for the PCM of one format, kept between the blocks,
+// so the filters, the reverb and the stretch continue over the borders
+// The stretch and the speed delay the audio a bit, the end of the last
+// block stays inside of them until finish() drains it with the reverb tail
+
+#[derive(Debug)]
+pub struct EffectProcessor {
+    format: AudioFormat,
+    identity: bool,
+    stretcher: Option<Stretcher>,
+    resampler: Option<Resampler>,
+    // A filter for every band of every channel, band by band
+    eq: Vec<Biquad>,
+    reverb: Option<Reverb>,
+    gain: f64,
+    dither: Dither,
+    // The output is cut to the length of the input times this at the end,
+    // the silence, which drains the stretch and the speed, does not go out
+    duration_ratio: f64,
+    input_frames: u64,
+    output_frames: u64,
+}
+
+impl EffectProcessor {
+    pub fn new(format: &AudioFormat, chain: &EffectChain, quality: ResampleQuality)
+                                                        -> Result<Self, AudioCodingError> {
+        format.validate()?;
+
+        let channels = format.channels;
+        let rate = format.sample_rate;
+
+        // The pitch is the stretch followed by the speed change of the same ratio,
+        // the length changes only with the tempo and the speed
+        let pitch_ratio = chain.pitch_ratio();
+        let stretch = pitch_ratio / chain.tempo;
+        let speed = chain.speed * pitch_ratio;
+
+        let stretcher = (stretch != 1.0).then(|| Stretcher::new(channels, rate, stretch));
+        // The audio is played faster, when it is taken as the audio of the higher rate
+        let resampler = (speed != 1.0)
+            .then(|| Resampler::new(channels, (rate as f64 * speed).round() as u32, rate, quality));
+
+        let eq = chain.eq.iter()
+            .flat_map(|band| std::iter::repeat_n(band.filter(rate), channels))
+            .collect();
+
+        Ok(EffectProcessor {
+            format: *format,
+            identity: chain.is_identity(),
+            stretcher,
+            resampler,
+            eq,
+            reverb: (chain.reverb > 0.0).then(|| Reverb::new(channels, rate, chain.reverb)),
+            gain: from_db(chain.gain_db),
+            dither: Dither::new(),
+            duration_ratio: chain.duration_ratio(),
+            input_frames: 0,
+            output_frames: 0,
+        })
+    }
+
+    pub fn process(&mut self, pcm: &[i32]) -> Vec<i32> {
+        // Nothing to do, the samples are kept bit for bit
+        if self.identity {
+            return pcm.to_vec();
+        }
+
+        let scale = 1.0 / (1i64 << (self.format.bits_per_sample - 1)) as f32;
+        let samples: Vec<f32> = pcm.iter().map(|s| *s as f32 * scale).collect();
+        self.input_frames += (pcm.len() / self.format.channels) as u64;
+
+        let samples = self.move_in_time(samples);
+        self.output_frames += (samples.len() / self.format.channels) as u64;
+        self.color(samples)
+    }
+
+    // The end of the track: what is still inside of the stretch and the speed change,
+    // followed by the tail of the reverb. Nothing is left in the chain after it
+    pub fn finish(&mut self) -> Vec<i32> {
+        if self.identity {
+            return Vec::new();
+        }
+
+        // The silence after the end pushes the rest out, a few blocks are more than
+        // the pieces of the stretch and the filter of the speed change ever keep
+        let channels = self.format.channels;
+        let expected = (self.input_frames as f64 * self.duration_ratio).round() as u64;
+        let left = expected.saturating_sub(self.output_frames) as usize;
+
+        let silence = vec![0.0; (self.format.sample_rate as f64 * DRAIN_BLOCK_SECS) as usize * channels];
+        let mut samples = Vec::new();
+        for _ in 0..MAX_DRAIN_BLOCKS {
+            if samples.len() >= left * channels {
+                break;
+            }
+            samples.extend(self.move_in_time(silence.clone()));
+        }
+
+        samples.truncate(left * channels);
+        self.output_frames += (samples.len() / channels) as u64;
+
+        if let Some(reverb) = &self.reverb {
+            samples.resize(samples.len() + reverb.tail_frames() * channels, 0.0);
+        }
+
+        self.color(samples)
+    }
+
+    // The stretch and the speed change, both change the length of the audio
+    fn move_in_time(&mut self, mut samples: Vec<f32>) -> Vec<f32> {
+        if let Some(stretcher) = &mut self.stretcher {
+            samples = stretcher.process(&samples);
+        }
+
+        if let Some(resampler) = &mut self.resampler {
+            samples = resampler.process(&samples);
+        }
+
+        samples
+    }
+
+    // The EQ, the reverb and the gain, sample by sample
+    fn color(&mut self, mut samples: Vec<f32>) -> Vec<i32> {
+        let channels = self.format.channels;
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let channel = i % channels;
+            let mut x = *sample as f64;
+
+            for filter in self.eq.iter_mut().skip(channel).step_by(channels) {
+                x = filter.process(x);
+            }
+
+            if let Some(reverb) = &mut self.reverb {
+                x = reverb.process(x, channel);
+            }
+
+            *sample = (x * self.gain) as f32;
+        }
+
+        // Whatever goes over the full scale is clipped by the quantization
+        quantize(&samples, self.format.bits_per_sample, Some(&mut self.dither))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::{self, rms, to_pcm};
+
+    const RATE: u32 = 44100;
+
+    fn format(channels: usize) -> AudioFormat {
+        AudioFormat { channels, bits_per_sample: 16, sample_rate: RATE }
+    }
+
+    fn sine(frequency: f64, amplitude: f64, channels: usize, frames: usize) -> Vec<i32> {
+        to_pcm(&test_signals::sine(frequency, amplitude, RATE, channels, frames), 16)
+    }
+
+    fn process(chain: &EffectChain, pcm: &[i32], channels: usize) -> Vec<i32> {
+        let mut processor = EffectProcessor::new(&format(channels), chain, ResampleQuality::Medium).unwrap();
+        // Processed in the blocks, like the playback does, the end is drained after them
+        let mut output: Vec<i32> = pcm.chunks(4096 * channels).flat_map(|block| processor.process(block)).collect();
+        output.extend(processor.finish());
+        output
+    }
+
+    fn amplitude(mono: &[i32]) -> f64 {
+        rms(mono) * 2f64.sqrt() / 32767.0
+    }
+
+    // The frequency from the rising zero crossings
+    fn frequency(mono: &[i32]) -> f64 {
+        let crossings: Vec<usize> = mono.windows(2).enumerate()
+            .filter(|(_, pair)| pair[0] < 0 && pair[1] >= 0)
+            .map(|(i, _)| i)
+            .collect();
+        let periods = (crossings.len() - 1) as f64;
+        periods * RATE as f64 / (crossings[crossings.len() - 1] - crossings[0]) as f64
+    }
+
+    #[test]
+    fn test_settings_are_validated() {
+        assert!(EffectSettings::default().chain().unwrap().is_identity(), "NO EFFECTS BY DEFAULT");
+
+        let slowed = EffectSettings { preset: Some(Preset::Slowed), ..Default::default() }.chain().unwrap();
+        assert_eq!((slowed.speed, slowed.reverb), (0.85, 0.3));
+
+        let changed = EffectSettings { preset: Some(Preset::Slowed), speed: Some(0.7), ..Default::default() };
+        assert_eq!(changed.chain().unwrap().speed, 0.7, "THE PARAMETERS CHANGE THE PRESET");
+
+        let eq = EqBand::parse_list("100:3, 3000:-2:0.7").unwrap();
+        assert_eq!(eq, vec![EqBand { frequency: 100.0, gain_db: 3.0, q: 1.0 },
+                            EqBand { frequency: 3000.0, gain_db: -2.0, q: 0.7 }]);
+
+        for invalid in [EffectSettings { speed: Some(3.0), ..Default::default() },
+                        EffectSettings { pitch: Some(-13.0), ..Default::default() },
+                        EffectSettings { reverb: Some(1.5), ..Default::default() },
+                        EffectSettings { eq: Some("100".to_string()), ..Default::default() },
+                        EffectSettings { eq: Some("5:3".to_string()), ..Default::default() },
+                        EffectSettings { gain: Some(f64::NAN), ..Default::default() }] {
+            assert!(invalid.chain().is_err(), "{:?} IS NOT ACCEPTED", invalid);
+        }
+    }
+
+    #[test]
+    fn test_gain_and_eq_against_the_reference() {
+        let input = sine(1000.0, 0.5, 2, RATE as usize);
+
+        // Without the effects the samples are not touched at all
+        assert_eq!(process(&EffectChain::default(), &input, 2), input);
+
+        // -6.02 dB is the half, only the dither is added
+        let half = EffectChain { gain_db: 20.0 * 0.5f64.log10(), ..Default::default() };
+        let output = process(&half, &input, 2);
+        assert_eq!(output.len(), input.len());
+        for (output, input) in output.iter().zip(&input) {
+            assert!((*output as f64 - *input as f64 / 2.0).abs() <= 1.5, "{} IS THE HALF OF {}", output, input);
+        }
+
+        // +6 dB at 1 kHz doubles the tone, 100 Hz stays as it was
+        let eq = EffectChain { eq: vec![EqBand { frequency: 1000.0, gain_db: 6.0, q: 1.0 }], ..Default::default() };
+        let boosted = process(&eq, &sine(1000.0, 0.25, 1, RATE as usize), 1);
+        let low = process(&eq, &sine(100.0, 0.25, 1, RATE as usize), 1);
+        assert!((amplitude(&boosted[4410..]) - 0.25 * from_db(6.0)).abs() < 0.005,
+                "BOOSTED: {}", amplitude(&boosted[4410..]));
+        assert!((amplitude(&low[4410..]) - 0.25).abs() < 0.01, "LOW: {}", amplitude(&low[4410..]));
+    }
+
+    #[test]
+    fn test_speed_tempo_and_pitch() {
+        let input = sine(440.0, 0.5, 2, RATE as usize * 2);
+        let left = |pcm: &[i32]| pcm.iter().step_by(2).copied().collect::<Vec<_>>();
+
+        // (speed, tempo, pitch) -> (the length, the frequency)
+        let cases = [((0.5, 1.0, 0.0), (2.0, 220.0)),
+                     ((1.25, 1.0, 0.0), (0.8, 550.0)),
+                     ((1.0, 0.5, 0.0), (2.0, 440.0)),
+                     ((1.0, 1.5, 0.0), (1.0 / 1.5, 440.0)),
+                     ((1.0, 1.0, 12.0), (1.0, 880.0)),
+                     ((1.0, 1.0, -5.0), (1.0, 440.0 * 2f64.powf(-5.0 / 12.0)))];
+
+        for ((speed, tempo, pitch), (length, hz)) in cases {
+            let chain = EffectChain { speed, tempo, pitch, ..Default::default() };
+            let output = left(&process(&chain, &input, 2));
+
+            // The end of the input is drained out of the stretch and the filter
+            let expected = RATE as f64 * 2.0 * length;
+            assert!((output.len() as f64 - expected).abs() <= 1.0,
+                    "{:?}: {} FRAMES, NOT {}", chain, output.len(), expected);
+
+            let steady = &output[2000..output.len() - 2000];
+            assert!((frequency(steady) - hz).abs() < hz * 0.01, "{:?}: {} HZ, NOT {}", chain, frequency(steady), hz);
+            assert!((amplitude(steady) - 0.5).abs() < 0.05, "{:?}: THE LEVEL IS {}", chain, amplitude(steady));
+        }
+    }
+
+    #[test]
+    fn test_finish_drains_the_reverb_tail() {
+        let chain = EffectChain { reverb: 0.5, ..Default::default() };
+        let input = sine(440.0, 0.5, 2, RATE as usize / 2);
+        let left = |pcm: &[i32]| pcm.iter().step_by(2).copied().collect::<Vec<_>>();
+
+        let mut processor = EffectProcessor::new(&format(2), &chain, ResampleQuality::Medium).unwrap();
+        assert_eq!(processor.process(&input).len(), input.len(), "THE REVERB KEEPS THE LENGTH");
+
+        let tail = left(&processor.finish());
+        assert!(tail.len() > RATE as usize, "THE TAIL IS ONLY {} FRAMES", tail.len());
+
+        let start = amplitude(&tail[..4410]);
+        let end = amplitude(&tail[tail.len() - 4410..]);
+        assert!(start > 0.01, "THE REVERB RINGS AFTER THE END: {}", start);
+        assert!(end < start / 100.0, "THE TAIL DIES OUT: {} AFTER {}", end, start);
+    }
+
+    #[test]
+    fn test_output_is_deterministic() {
+        let chain = EffectSettings {
+            preset: Some(Preset::Slowed),
+            pitch: Some(2.0),
+            eq: Some("200:4,5000:-3:2".to_string()),
+            gain: Some(-2.0),
+            ..Default::default()
+        }.chain().unwrap();
+
+        let input: Vec<i32> = sine(440.0, 0.3, 2, 20000).iter().zip(sine(3000.0, 0.2, 2, 20000))
+            .map(|(a, b)| a + b)
+            .collect();
+
+        let output = process(&chain, &input, 2);
+        assert_eq!(output, process(&chain, &input, 2), "THE SAME INPUT GIVES THE SAME SAMPLES");
+
+        // The reference PCM of this chain, any change of the DSP shows up here
+        let reference = [-2495, -2023, -4263, -3598, -5760, -4956, -6780, -5924,
+                         -7213, -6394, -7035, -6336, -6316, -5803, -5220, -4929];
+        assert_eq!(output[10000..10016], reference);
+    }
+}
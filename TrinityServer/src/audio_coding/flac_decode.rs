@@ -0,0 +1,960 @@
+// A file for reading the FLAC data back into the raw PCM
+// The server needs it to validate the uploaded FLAC, to know the duration
+// of it and to transcode it into something else
+// Everything is checked on the way: CRC-8 of the frame headers,
+// CRC-16 of the frames and MD5 of the whole decoded audio
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::time::Duration;
+
+use super::AudioFormat;
+
+// The types of the metadata blocks the server cares about
+pub const BLOCK_STREAMINFO: u8 = 0;
+pub const BLOCK_VORBIS_COMMENT: u8 = 4;
+
+
+
+// Everything that could go wrong while decoding
+// The offsets are the byte offsets in the decoded data
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlacDecodeError {
+    NotFlac,
+    UnexpectedEof,
+    MissingStreamInfo,
+    InvalidMetadata(String),
+    InvalidFrame { offset: usize, reason: String },
+    HeaderCrcMismatch { offset: usize },
+    FrameCrcMismatch { offset: usize },
+    Md5Mismatch,
+}
+
+impl std::fmt::Display for FlacDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlacDecodeError::NotFlac => write!(f, "The data is not FLAC"),
+            FlacDecodeError::UnexpectedEof => write!(f, "The FLAC data ends unexpectedly"),
+            FlacDecodeError::MissingStreamInfo => write!(f, "STREAMINFO block is missing"),
+            FlacDecodeError::InvalidMetadata(reason) => write!(f, "Invalid metadata: {}", reason),
+            FlacDecodeError::InvalidFrame { offset, reason } =>
+                write!(f, "Invalid frame at byte {}: {}", offset, reason),
+            FlacDecodeError::HeaderCrcMismatch { offset } =>
+                write!(f, "CRC-8 of the frame header at byte {} does not match", offset),
+            FlacDecodeError::FrameCrcMismatch { offset } =>
+                write!(f, "CRC-16 of the frame at byte {} does not match", offset),
+            FlacDecodeError::Md5Mismatch => write!(f, "MD5 of the decoded audio does not match"),
+        }
+    }
+}
+
+impl std::error::Error for FlacDecodeError {}
+
+fn invalid_frame(offset: usize, reason: &str) -> FlacDecodeError {
+    FlacDecodeError::InvalidFrame { offset, reason: reason.to_string() }
+}
+
+
+
+// The STREAMINFO block, the only mandatory metadata of FLAC
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    pub min_frame_size: u32,
+    pub max_frame_size: u32,
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub bits_per_sample: usize,
+    pub total_samples: u64,
+    pub md5: [u8; 16],
+}
+
+impl StreamInfo {
+    // The duration is known only when the encoder has written the amount of samples
+    pub fn duration(&self) -> Option<Duration> {
+        if self.total_samples == 0 || self.sample_rate == 0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(self.total_samples as f64 / self.sample_rate as f64))
+    }
+
+    pub fn format(&self) -> AudioFormat {
+        AudioFormat {
+            channels: self.channels,
+            bits_per_sample: self.bits_per_sample,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    // The 34 bytes of the block, as they are stored in the file
+    pub fn to_bytes(&self) -> [u8; 34] {
+        let mut bytes = [0u8; 34];
+        bytes[0..2].copy_from_slice(&self.min_block_size.to_be_bytes());
+        bytes[2..4].copy_from_slice(&self.max_block_size.to_be_bytes());
+        bytes[4..7].copy_from_slice(&self.min_frame_size.to_be_bytes()[1..]);
+        bytes[7..10].copy_from_slice(&self.max_frame_size.to_be_bytes()[1..]);
+
+        // 20 bits of the sample rate, 3 of the channels, 5 of the bit depth
+        // and 36 of the amount of samples
+        let packed = ((self.sample_rate as u64 & 0xfffff) << 44)
+            | (((self.channels as u64 - 1) & 0x7) << 41)
+            | (((self.bits_per_sample as u64 - 1) & 0x1f) << 36)
+            | (self.total_samples & 0xf_ffff_ffff);
+        bytes[10..18].copy_from_slice(&packed.to_be_bytes());
+        bytes[18..34].copy_from_slice(&self.md5);
+
+        bytes
+    }
+}
+
+// Any metadata block, the content is kept as it is
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataBlock {
+    pub block_type: u8,
+    pub data: Vec<u8>,
+}
+
+// The metadata part of the FLAC data
+// audio_offset is the byte offset of the first frame
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacMetadata {
+    pub info: StreamInfo,
+    pub blocks: Vec<MetadataBlock>,
+    pub audio_offset: usize,
+}
+
+// The position of a single frame, used for seeking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameInfo {
+    pub offset: usize,
+    pub first_sample: u64,
+    pub block_size: usize,
+}
+
+// The fully decoded FLAC, the samples are interleaved (L R L R ... for stereo)
+#[derive(Debug, Clone)]
+pub struct DecodedFlac {
+    pub metadata: FlacMetadata,
+    pub frames: Vec<FrameInfo>,
+    pub samples: Vec<i32>,
+}
+
+
+
+// MSB-first bit reader over a byte slice, FLAC stores everything this way
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        BitReader { data, pos, bit: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<u32, FlacDecodeError> {
+        let byte = *self.data.get(self.pos).ok_or(FlacDecodeError::UnexpectedEof)?;
+        let value = (byte >> (7 - self.bit)) & 1;
+
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+
+        Ok(value as u32)
+    }
+
+    // Up to 32 bits at once
+    fn read_bits(&mut self, count: u32) -> Result<u32, FlacDecodeError> {
+        let mut value: u64 = 0;
+        let mut left = count;
+
+        while left > 0 {
+            if self.bit == 0 && left >= 8 {
+                let byte = *self.data.get(self.pos).ok_or(FlacDecodeError::UnexpectedEof)?;
+                value = (value << 8) | byte as u64;
+                self.pos += 1;
+                left -= 8;
+            } else {
+                value = (value << 1) | self.read_bit()? as u64;
+                left -= 1;
+            }
+        }
+
+        Ok(value as u32)
+    }
+
+    fn read_signed(&mut self, count: u32) -> Result<i32, FlacDecodeError> {
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let value = self.read_bits(count)?;
+        let shift = 32 - count;
+        Ok(((value << shift) as i32) >> shift)
+    }
+
+    fn read_unary(&mut self) -> Result<u32, FlacDecodeError> {
+        let mut zeros = 0;
+        while self.read_bit()? == 0 {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+
+
+// CRC-8 (polynomial 0x07) of the frame header
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+// CRC-16 (polynomial 0x8005) of the whole frame
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+
+
+fn read_u24(data: &[u8]) -> usize {
+    ((data[0] as usize) << 16) | ((data[1] as usize) << 8) | data[2] as usize
+}
+
+fn parse_stream_info(data: &[u8]) -> Result<StreamInfo, FlacDecodeError> {
+    if data.len() < 34 {
+        return Err(FlacDecodeError::InvalidMetadata("STREAMINFO is too short".to_string()));
+    }
+
+    let mut reader = BitReader::new(data, 0);
+
+    let min_block_size = reader.read_bits(16)? as u16;
+    let max_block_size = reader.read_bits(16)? as u16;
+    let min_frame_size = reader.read_bits(24)?;
+    let max_frame_size = reader.read_bits(24)?;
+    let sample_rate = reader.read_bits(20)?;
+    let channels = reader.read_bits(3)? as usize + 1;
+    let bits_per_sample = reader.read_bits(5)? as usize + 1;
+    let total_samples = ((reader.read_bits(4)? as u64) << 32) | reader.read_bits(32)? as u64;
+
+    let mut md5 = [0u8; 16];
+    md5.copy_from_slice(&data[18..34]);
+
+    if sample_rate == 0 {
+        return Err(FlacDecodeError::InvalidMetadata("Sample rate is zero".to_string()));
+    }
+
+    if bits_per_sample < 4 {
+        return Err(FlacDecodeError::InvalidMetadata(
+            format!("{} bits per sample", bits_per_sample)));
+    }
+
+    Ok(StreamInfo {
+        min_block_size,
+        max_block_size,
+        min_frame_size,
+        max_frame_size,
+        sample_rate,
+        channels,
+        bits_per_sample,
+        total_samples,
+        md5,
+    })
+}
+
+// Reading the "fLaC" marker and all the metadata blocks after it
+// An ID3v2 tag in front of the FLAC data is skipped
+
+pub fn read_metadata(data: &[u8]) -> Result<FlacMetadata, FlacDecodeError> {
+    let mut pos = 0;
+
+    if data.len() >= 10 && &data[..3] == b"ID3" {
+        let size = data[6..10].iter().fold(0usize, |size, b| (size << 7) | (*b & 0x7f) as usize);
+        pos = 10 + size;
+    }
+
+    if data.get(pos..pos + 4) != Some(b"fLaC".as_slice()) {
+        return Err(FlacDecodeError::NotFlac);
+    }
+    pos += 4;
+
+    let mut info = None;
+    let mut blocks = Vec::new();
+
+    loop {
+        let header = data.get(pos..pos + 4).ok_or(FlacDecodeError::UnexpectedEof)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = read_u24(&header[1..]);
+        pos += 4;
+
+        let block = data.get(pos..pos + length).ok_or(FlacDecodeError::UnexpectedEof)?;
+        pos += length;
+
+        if block_type == 127 {
+            return Err(FlacDecodeError::InvalidMetadata("Invalid block type 127".to_string()));
+        }
+
+        if block_type == BLOCK_STREAMINFO {
+            if info.is_some() {
+                return Err(FlacDecodeError::InvalidMetadata("Repeated STREAMINFO".to_string()));
+            }
+            info = Some(parse_stream_info(block)?);
+        }
+
+        blocks.push(MetadataBlock { block_type, data: block.to_vec() });
+
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(FlacMetadata {
+        info: info.ok_or(FlacDecodeError::MissingStreamInfo)?,
+        blocks,
+        audio_offset: pos,
+    })
+}
+
+
+
+// The parsed frame header
+
+struct FrameHeader {
+    block_size: usize,
+    sample_rate: u32,
+    channel_assignment: u32,
+    bits_per_sample: usize,
+    number: u64,
+    variable_block_size: bool,
+}
+
+impl FrameHeader {
+    fn channels(&self) -> usize {
+        match self.channel_assignment {
+            0..=7 => self.channel_assignment as usize + 1,
+            _ => 2,
+        }
+    }
+}
+
+// The UTF-8-like coded frame (or sample) number
+fn read_coded_number(reader: &mut BitReader, offset: usize) -> Result<u64, FlacDecodeError> {
+    let first = reader.read_bits(8)?;
+    let leading = (first as u8).leading_ones();
+
+    if leading == 0 {
+        return Ok(first as u64);
+    }
+
+    if leading == 1 || leading > 7 {
+        return Err(invalid_frame(offset, "Invalid coded number"));
+    }
+
+    let mut value = (first & (0x7f >> leading)) as u64;
+    for _ in 1..leading {
+        let byte = reader.read_bits(8)?;
+        if byte & 0xc0 != 0x80 {
+            return Err(invalid_frame(offset, "Invalid coded number"));
+        }
+        value = (value << 6) | (byte & 0x3f) as u64;
+    }
+
+    Ok(value)
+}
+
+fn read_frame_header(data: &[u8], offset: usize, info: &StreamInfo)
+                                    -> Result<(FrameHeader, usize), FlacDecodeError> {
+    let mut reader = BitReader::new(data, offset);
+
+    if reader.read_bits(15)? != 0x7ffc {
+        return Err(invalid_frame(offset, "No frame sync code"));
+    }
+
+    let variable_block_size = reader.read_bit()? == 1;
+    let block_size_code = reader.read_bits(4)?;
+    let sample_rate_code = reader.read_bits(4)?;
+    let channel_assignment = reader.read_bits(4)?;
+    let sample_size_code = reader.read_bits(3)?;
+
+    if reader.read_bit()? != 0 {
+        return Err(invalid_frame(offset, "Reserved bit is set"));
+    }
+
+    let number = read_coded_number(&mut reader, offset)?;
+
+    let block_size = match block_size_code {
+        0 => return Err(invalid_frame(offset, "Reserved block size")),
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => reader.read_bits(8)? as usize + 1,
+        7 => reader.read_bits(16)? as usize + 1,
+        _ => 256 << (block_size_code - 8),
+    };
+
+    let sample_rate = match sample_rate_code {
+        0 => info.sample_rate,
+        1 => 88200,
+        2 => 176400,
+        3 => 192000,
+        4 => 8000,
+        5 => 16000,
+        6 => 22050,
+        7 => 24000,
+        8 => 32000,
+        9 => 44100,
+        10 => 48000,
+        11 => 96000,
+        12 => reader.read_bits(8)? * 1000,
+        13 => reader.read_bits(16)?,
+        14 => reader.read_bits(16)? * 10,
+        _ => return Err(invalid_frame(offset, "Invalid sample rate")),
+    };
+
+    if channel_assignment > 10 {
+        return Err(invalid_frame(offset, "Reserved channel assignment"));
+    }
+
+    let bits_per_sample = match sample_size_code {
+        0 => info.bits_per_sample,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return Err(invalid_frame(offset, "Reserved sample size")),
+    };
+
+    // The header is byte aligned here, the next byte is its CRC-8
+    let crc_pos = reader.pos;
+    let expected = *data.get(crc_pos).ok_or(FlacDecodeError::UnexpectedEof)?;
+
+    if crc8(&data[offset..crc_pos]) != expected {
+        return Err(FlacDecodeError::HeaderCrcMismatch { offset });
+    }
+
+    Ok((FrameHeader {
+        block_size,
+        sample_rate,
+        channel_assignment,
+        bits_per_sample,
+        number,
+        variable_block_size,
+    }, crc_pos + 1))
+}
+
+
+
+// The residual of the predictors, coded with Rice codes
+// The partitions split the block, the first one is shorter by the predictor order
+
+fn read_residual(reader: &mut BitReader, block_size: usize, order: usize,
+                 output: &mut Vec<i32>, offset: usize) -> Result<(), FlacDecodeError> {
+    let method = reader.read_bits(2)?;
+    let (param_bits, escape) = match method {
+        0 => (4, 0x0f),
+        1 => (5, 0x1f),
+        _ => return Err(invalid_frame(offset, "Reserved residual coding method")),
+    };
+
+    let partition_order = reader.read_bits(4)?;
+    let partitions = 1usize << partition_order;
+
+    if block_size % partitions != 0 || (block_size >> partition_order) < order {
+        return Err(invalid_frame(offset, "Invalid partition order"));
+    }
+
+    for partition in 0..partitions {
+        let count = if partition == 0 {
+            (block_size >> partition_order) - order
+        } else {
+            block_size >> partition_order
+        };
+
+        let param = reader.read_bits(param_bits)?;
+
+        if param == escape {
+            let bits = reader.read_bits(5)?;
+            for _ in 0..count {
+                output.push(reader.read_signed(bits)?);
+            }
+            continue;
+        }
+
+        for _ in 0..count {
+            let quotient = reader.read_unary()?;
+            let remainder = reader.read_bits(param)?;
+            let value = ((quotient as u64) << param) | remainder as u64;
+
+            // Zig-zag decoding of the signed value
+            let value = ((value >> 1) as i64) ^ -((value & 1) as i64);
+            output.push(value as i32);
+        }
+    }
+
+    Ok(())
+}
+
+// Restoring the samples from the warm-up samples, the predictor and the residual
+fn predict(samples: &mut Vec<i32>, residual: &[i32], coefficients: &[i64], shift: u32) {
+    let order = coefficients.len();
+
+    for r in residual {
+        let n = samples.len();
+        let prediction: i64 = coefficients.iter()
+            .enumerate()
+            .map(|(i, c)| c * samples[n - 1 - i] as i64)
+            .sum();
+
+        samples.push(((prediction >> shift) + *r as i64) as i32);
+    }
+
+    debug_assert!(samples.len() >= order);
+}
+
+fn read_subframe(reader: &mut BitReader, block_size: usize, bits_per_sample: usize,
+                 offset: usize) -> Result<Vec<i32>, FlacDecodeError> {
+    if reader.read_bit()? != 0 {
+        return Err(invalid_frame(offset, "Subframe padding bit is set"));
+    }
+
+    let subframe_type = reader.read_bits(6)?;
+
+    let wasted = if reader.read_bit()? == 1 {
+        reader.read_unary()? as usize + 1
+    } else {
+        0
+    };
+
+    // The samples are i32, they could not be shifted back by 32 bits or more
+    // (the side channel of 32-bit audio has 33 bits, so it would let it through)
+    if wasted >= bits_per_sample || wasted >= 32 {
+        return Err(invalid_frame(offset, "Too many wasted bits"));
+    }
+
+    // The side channel of 32-bit audio would need 33 bits, it is not supported
+    let bits = (bits_per_sample - wasted) as u32;
+    if bits > 32 {
+        return Err(invalid_frame(offset, "Unsupported sample size"));
+    }
+
+    let mut samples = Vec::with_capacity(block_size);
+
+    match subframe_type {
+        // Constant
+        0 => {
+            let value = reader.read_signed(bits)?;
+            samples.resize(block_size, value);
+        }
+
+        // Verbatim
+        1 => {
+            for _ in 0..block_size {
+                samples.push(reader.read_signed(bits)?);
+            }
+        }
+
+        // Fixed predictor of order 0 - 4
+        8..=12 => {
+            let order = (subframe_type - 8) as usize;
+            if order > block_size {
+                return Err(invalid_frame(offset, "Predictor order is bigger than the block"));
+            }
+
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)?);
+            }
+
+            let coefficients: &[i64] = match order {
+                0 => &[],
+                1 => &[1],
+                2 => &[2, -1],
+                3 => &[3, -3, 1],
+                _ => &[4, -6, 4, -1],
+            };
+
+            let mut residual = Vec::with_capacity(block_size - order);
+            read_residual(reader, block_size, order, &mut residual, offset)?;
+            predict(&mut samples, &residual, coefficients, 0);
+        }
+
+        // LPC of order 1 - 32
+        32..=63 => {
+            let order = (subframe_type - 31) as usize;
+            if order > block_size {
+                return Err(invalid_frame(offset, "Predictor order is bigger than the block"));
+            }
+
+            for _ in 0..order {
+                samples.push(reader.read_signed(bits)?);
+            }
+
+            let precision = reader.read_bits(4)?;
+            if precision == 0x0f {
+                return Err(invalid_frame(offset, "Invalid LPC precision"));
+            }
+
+            let shift = reader.read_signed(5)?;
+            if shift < 0 {
+                return Err(invalid_frame(offset, "Negative LPC shift"));
+            }
+
+            let mut coefficients = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefficients.push(reader.read_signed(precision + 1)? as i64);
+            }
+
+            let mut residual = Vec::with_capacity(block_size - order);
+            read_residual(reader, block_size, order, &mut residual, offset)?;
+            predict(&mut samples, &residual, &coefficients, shift as u32);
+        }
+
+        _ => return Err(invalid_frame(offset, "Reserved subframe type")),
+    }
+
+    if wasted > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= wasted;
+        }
+    }
+
+    Ok(samples)
+}
+
+// Decoding a single frame, the channels are decorrelated and interleaved into output
+// Returns the header and the offset right after the frame
+
+fn decode_frame(data: &[u8], offset: usize, info: &StreamInfo, output: &mut Vec<i32>)
+                                        -> Result<(FrameHeader, usize), FlacDecodeError> {
+    let (header, subframes_offset) = read_frame_header(data, offset, info)?;
+    let mut reader = BitReader::new(data, subframes_offset);
+
+    let channels = header.channels();
+    let mut decoded = Vec::with_capacity(channels);
+
+    for channel in 0..channels {
+        // The side channel has one bit more
+        let side = matches!((header.channel_assignment, channel), (8, 1) | (9, 0) | (10, 1));
+        let bits = header.bits_per_sample + side as usize;
+        decoded.push(read_subframe(&mut reader, header.block_size, bits, offset)?);
+    }
+
+    reader.align();
+    let crc_pos = reader.pos;
+    let footer = data.get(crc_pos..crc_pos + 2).ok_or(FlacDecodeError::UnexpectedEof)?;
+
+    if crc16(&data[offset..crc_pos]) != u16::from_be_bytes([footer[0], footer[1]]) {
+        return Err(FlacDecodeError::FrameCrcMismatch { offset });
+    }
+
+    // Stereo decorrelation
+    match header.channel_assignment {
+        8 => {
+            for i in 0..header.block_size {
+                decoded[1][i] = decoded[0][i].wrapping_sub(decoded[1][i]);
+            }
+        }
+        9 => {
+            for i in 0..header.block_size {
+                decoded[0][i] = decoded[0][i].wrapping_add(decoded[1][i]);
+            }
+        }
+        10 => {
+            for i in 0..header.block_size {
+                let side = decoded[1][i] as i64;
+                let mid = ((decoded[0][i] as i64) << 1) | (side & 1);
+                decoded[0][i] = ((mid + side) >> 1) as i32;
+                decoded[1][i] = ((mid - side) >> 1) as i32;
+            }
+        }
+        _ => {}
+    }
+
+    output.reserve(header.block_size * channels);
+    for i in 0..header.block_size {
+        for channel in &decoded {
+            output.push(channel[i]);
+        }
+    }
+
+    Ok((header, crc_pos + 2))
+}
+
+
+
+// MD5 of the decoded samples, the way the reference encoder computes it:
+// little-endian, as many bytes per sample as the bit depth needs
+// The context could be fed piece by piece, the recorder does so
+
+pub fn consume_samples_md5(context: &mut md5::Context, samples: &[i32], bits_per_sample: usize) {
+    let width = bits_per_sample.div_ceil(8);
+    let mut buffer = Vec::with_capacity(4096 * width);
+
+    for block in samples.chunks(4096) {
+        buffer.clear();
+        for sample in block {
+            buffer.extend_from_slice(&sample.to_le_bytes()[..width]);
+        }
+        context.consume(&buffer);
+    }
+}
+
+fn samples_md5(samples: &[i32], bits_per_sample: usize) -> [u8; 16] {
+    let mut context = md5::Context::new();
+    consume_samples_md5(&mut context, samples, bits_per_sample);
+    context.compute().0
+}
+
+
+
+// Writing the frame (or sample) number the same UTF-8-like way it is read
+fn write_coded_number(value: u64, output: &mut Vec<u8>) {
+    if value < 0x80 {
+        output.push(value as u8);
+        return;
+    }
+
+    // n bytes carry 5n + 1 bits, up to 36 bits in 7 bytes
+    let bytes = (2..=7u32).find(|n| value < 1u64 << (5 * n + 1)).unwrap_or(7);
+    let mark = !(0xffu8 >> bytes);
+    output.push(mark | (value >> (6 * (bytes - 1))) as u8);
+
+    for i in (0..bytes - 1).rev() {
+        output.push(0x80 | ((value >> (6 * i)) & 0x3f) as u8);
+    }
+}
+
+// Rewriting a single frame, so it says which sample it starts from
+// The frames of separately encoded FLAC chunks are all numbered from zero,
+// after this they could be glued into one variable block size stream
+// Both CRCs are recomputed, the subframes are copied as they are
+
+pub fn renumber_frame(frame: &[u8], first_sample: u64) -> Result<Vec<u8>, FlacDecodeError> {
+    if frame.len() < 7 || frame[0] != 0xff || frame[1] & 0xfe != 0xf8 {
+        return Err(invalid_frame(0, "No frame sync code"));
+    }
+
+    let number_len = match frame[4].leading_ones() {
+        0 => 1,
+        n @ 2..=7 => n as usize,
+        _ => return Err(invalid_frame(0, "Invalid coded number")),
+    };
+
+    // The optional block size and sample rate go after the number
+    let block_size_extra = match frame[2] >> 4 { 6 => 1, 7 => 2, _ => 0 };
+    let sample_rate_extra = match frame[2] & 0x0f { 12 => 1, 13 | 14 => 2, _ => 0 };
+    let crc_pos = 4 + number_len + block_size_extra + sample_rate_extra;
+
+    if frame.len() < crc_pos + 3 {
+        return Err(FlacDecodeError::UnexpectedEof);
+    }
+
+    let mut output = Vec::with_capacity(frame.len() + 6);
+    output.extend_from_slice(&[0xff, 0xf9, frame[2], frame[3]]);
+    write_coded_number(first_sample, &mut output);
+    output.extend_from_slice(&frame[4 + number_len..crc_pos]);
+    output.push(crc8(&output));
+    output.extend_from_slice(&frame[crc_pos + 1..frame.len() - 2]);
+    let crc = crc16(&output);
+    output.extend_from_slice(&crc.to_be_bytes());
+
+    Ok(output)
+}
+
+// Decoding the whole FLAC data into the interleaved PCM
+// All the CRCs are verified, MD5 as well (unless the encoder has left it empty)
+
+pub fn decode_flac(data: &[u8]) -> Result<DecodedFlac, FlacDecodeError> {
+    let metadata = read_metadata(data)?;
+    let info = &metadata.info;
+
+    let mut offset = metadata.audio_offset;
+    let mut frames = Vec::new();
+    // STREAMINFO is not trusted for the allocation, it could claim anything
+    let mut samples = Vec::with_capacity((info.total_samples as usize * info.channels).min(1 << 22));
+    let mut next_sample: u64 = 0;
+
+    while offset < data.len() {
+        let (header, next_offset) = decode_frame(data, offset, info, &mut samples)?;
+
+        if header.channels() != info.channels {
+            return Err(invalid_frame(offset, "Channel count differs from STREAMINFO"));
+        }
+
+        if header.bits_per_sample != info.bits_per_sample || header.sample_rate != info.sample_rate {
+            return Err(invalid_frame(offset, "Format differs from STREAMINFO"));
+        }
+
+        // The fixed block size streams count frames, the variable ones count samples
+        let first_sample = if header.variable_block_size {
+            header.number
+        } else {
+            next_sample
+        };
+
+        frames.push(FrameInfo {
+            offset,
+            first_sample,
+            block_size: header.block_size,
+        });
+
+        next_sample = first_sample + header.block_size as u64;
+        offset = next_offset;
+    }
+
+    if info.total_samples != 0 && info.total_samples != next_sample {
+        return Err(FlacDecodeError::InvalidMetadata(format!(
+            "STREAMINFO has {} samples, the frames have {}", info.total_samples, next_sample)));
+    }
+
+    if info.md5 != [0u8; 16] && samples_md5(&samples, info.bits_per_sample) != info.md5 {
+        return Err(FlacDecodeError::Md5Mismatch);
+    }
+
+    Ok(DecodedFlac {
+        metadata,
+        frames,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::{encode_pcm_to_flac, FlacEncodeOptions};
+
+    fn sine(format: &AudioFormat, frames: usize) -> Vec<i32> {
+        let amplitude = ((1i64 << (format.bits_per_sample - 1)) - 1) as f64 * 0.8;
+        (0..frames * format.channels)
+            .map(|i| {
+                let t = (i / format.channels) as f64 / format.sample_rate as f64;
+                let hz = 440.0 * ((i % format.channels) + 1) as f64;
+                (amplitude * (2.0 * std::f64::consts::PI * hz * t).sin()) as i32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_of_encoded_flac() {
+        let formats = [
+            AudioFormat::default(),
+            AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 44100 },
+            AudioFormat { channels: 2, bits_per_sample: 24, sample_rate: 48000 },
+        ];
+
+        for format in formats {
+            let pcm = sine(&format, 10000);
+            let flac = encode_pcm_to_flac(&pcm, &FlacEncodeOptions::new(format)).unwrap();
+            let decoded = decode_flac(&flac).expect("ENCODED FLAC IS DECODABLE");
+
+            assert_eq!(decoded.metadata.info.format(), format);
+            assert_eq!(decoded.samples, pcm, "FLAC IS LOSSLESS");
+            assert_eq!(decoded.frames[0].first_sample, 0);
+        }
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let format = AudioFormat::default();
+        let flac = encode_pcm_to_flac(&sine(&format, 8192), &FlacEncodeOptions::new(format)).unwrap();
+        let audio_offset = read_metadata(&flac).unwrap().audio_offset;
+
+        let mut corrupted = flac.clone();
+        let last = corrupted.len() - 3;
+        corrupted[last] ^= 0x55;
+        assert!(matches!(decode_flac(&corrupted),
+                         Err(FlacDecodeError::FrameCrcMismatch { .. })
+                         | Err(FlacDecodeError::InvalidFrame { .. })));
+
+        let mut corrupted = flac.clone();
+        corrupted[audio_offset + 2] ^= 0x10;
+        assert!(decode_flac(&corrupted).is_err());
+
+        assert_eq!(decode_flac(b"RIFF....WAVE").unwrap_err(), FlacDecodeError::NotFlac);
+        assert_eq!(decode_flac(&flac[..audio_offset - 1]).unwrap_err(), FlacDecodeError::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_renumbered_chunks_glue_together() {
+        let format = AudioFormat::default();
+        let pcm = sine(&format, 12000);
+        let mut options = FlacEncodeOptions::new(format);
+        options.block_size = 1024;
+
+        // Two separately encoded chunks, both numbered from zero
+        let halves = [&pcm[..12000], &pcm[12000..]];
+        let mut glued = Vec::new();
+        let mut info = None;
+        let mut next_sample = 0;
+
+        for half in halves {
+            let flac = encode_pcm_to_flac(half, &options).unwrap();
+            let decoded = decode_flac(&flac).unwrap();
+            let ends = decoded.frames.iter().skip(1).map(|f| f.offset).chain([flac.len()]);
+
+            for (frame, end) in decoded.frames.iter().zip(ends) {
+                glued.extend(renumber_frame(&flac[frame.offset..end], next_sample).unwrap());
+                next_sample += frame.block_size as u64;
+            }
+            info.get_or_insert(decoded.metadata.info);
+        }
+
+        let mut info = info.unwrap();
+        info.total_samples = next_sample;
+        info.md5 = samples_md5(&pcm, format.bits_per_sample);
+
+        let mut file = b"fLaC".to_vec();
+        file.extend_from_slice(&[0x80, 0, 0, 34]);
+        file.extend_from_slice(&info.to_bytes());
+        file.extend(glued);
+
+        let decoded = decode_flac(&file).expect("GLUED FLAC IS DECODABLE");
+        assert_eq!(decoded.metadata.info, info, "STREAMINFO SURVIVES THE ROUND TRIP");
+        assert_eq!(decoded.samples, pcm);
+        assert_eq!(decoded.frames.last().unwrap().first_sample + 
+                   decoded.frames.last().unwrap().block_size as u64, 12000);
+    }
+
+    #[test]
+    fn test_wasted_bits_of_the_side_channel_are_bounded() {
+        // A constant subframe of the 33-bit side channel, which claims 32 wasted bits
+        let subframe = [0b0000_0001, 0, 0, 0, 0b0000_0001, 0xff];
+        let result = read_subframe(&mut BitReader::new(&subframe, 0), 16, 33, 0);
+        assert!(matches!(result, Err(FlacDecodeError::InvalidFrame { .. })), "32 WASTED BITS ARE REJECTED");
+    }
+
+    #[test]
+    fn test_crc() {
+        // The check values of CRC-8/SMBUS and CRC-16/UMTS
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+}
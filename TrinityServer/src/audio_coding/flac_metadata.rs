@@ -0,0 +1,195 @@
+// A file for the FLAC metadata blocks the server reads and writes itself:
+// the Vorbis comments (title, artist, ...) and the seek table
+// The stored tracks get both, so seeking does not need to decode anything
+
+// Trinitypeer, 2025, by Trinitycore
+
+use super::flac_decode::{FlacDecodeError, FrameInfo, MetadataBlock, StreamInfo,
+                         BLOCK_STREAMINFO, BLOCK_VORBIS_COMMENT};
+
+pub const BLOCK_PADDING: u8 = 1;
+pub const BLOCK_SEEKTABLE: u8 = 3;
+
+// Seek points with this sample number are the placeholders, they point nowhere
+const PLACEHOLDER_POINT: u64 = u64::MAX;
+
+
+
+// A single point of the seek table
+// offset is counted from the first frame, not from the start of the file
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekPoint {
+    pub sample: u64,
+    pub offset: u64,
+    pub samples: u16,
+}
+
+fn invalid(reason: &str) -> FlacDecodeError {
+    FlacDecodeError::InvalidMetadata(reason.to_string())
+}
+
+// The Vorbis comment block, the only little-endian thing in FLAC
+// The tags are "NAME=value", the names are returned in the upper case
+
+pub fn parse_vorbis_comment(data: &[u8]) -> Result<Vec<(String, String)>, FlacDecodeError> {
+    let mut pos = 0;
+
+    let read_string = |pos: &mut usize| -> Result<&[u8], FlacDecodeError> {
+        let length = data.get(*pos..*pos + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| invalid("Vorbis comment is truncated"))?;
+        let value = data.get(*pos + 4..*pos + 4 + length)
+            .ok_or_else(|| invalid("Vorbis comment is truncated"))?;
+        *pos += 4 + length;
+        Ok(value)
+    };
+
+    // The vendor string goes first
+    read_string(&mut pos)?;
+
+    let count = data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("Vorbis comment is truncated"))?;
+    pos += 4;
+
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        let comment = String::from_utf8_lossy(read_string(&mut pos)?).into_owned();
+
+        if let Some((name, value)) = comment.split_once('=') {
+            tags.push((name.to_ascii_uppercase(), value.to_string()));
+        }
+    }
+
+    Ok(tags)
+}
+
+pub fn vorbis_comment_block(vendor: &str, tags: &[(String, String)]) -> MetadataBlock {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor.as_bytes());
+    data.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+
+    for (name, value) in tags {
+        let comment = format!("{}={}", name, value);
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+
+    MetadataBlock { block_type: BLOCK_VORBIS_COMMENT, data }
+}
+
+
+
+pub fn parse_seek_table(data: &[u8]) -> Result<Vec<SeekPoint>, FlacDecodeError> {
+    if data.len() % 18 != 0 {
+        return Err(invalid("Seek table size is not a multiple of 18"));
+    }
+
+    Ok(data.chunks_exact(18)
+        .map(|point| SeekPoint {
+            sample: u64::from_be_bytes(point[0..8].try_into().unwrap()),
+            offset: u64::from_be_bytes(point[8..16].try_into().unwrap()),
+            samples: u16::from_be_bytes([point[16], point[17]]),
+        })
+        .filter(|point| point.sample != PLACEHOLDER_POINT)
+        .collect())
+}
+
+// The seek table with a point at the first frame of every `interval` samples
+// The frame offsets are relative to the first frame already
+
+pub fn seek_table_block(frames: &[FrameInfo], interval: u64) -> MetadataBlock {
+    let first_offset = frames.first().map(|f| f.offset).unwrap_or(0);
+    let mut data = Vec::new();
+    let mut next_point = 0;
+
+    for frame in frames {
+        if frame.first_sample < next_point {
+            continue;
+        }
+
+        data.extend_from_slice(&frame.first_sample.to_be_bytes());
+        data.extend_from_slice(&((frame.offset - first_offset) as u64).to_be_bytes());
+        data.extend_from_slice(&(frame.block_size as u16).to_be_bytes());
+        next_point = frame.first_sample + interval.max(1);
+    }
+
+    MetadataBlock { block_type: BLOCK_SEEKTABLE, data }
+}
+
+// The seek point to start from, so the given sample is not missed
+pub fn find_seek_point(points: &[SeekPoint], sample: u64) -> Option<SeekPoint> {
+    points.iter()
+        .take_while(|point| point.sample <= sample)
+        .last()
+        .copied()
+}
+
+
+
+// Writing the "fLaC" marker, STREAMINFO and the given blocks
+// STREAMINFO always goes first, the last block is marked as the last one
+
+pub fn write_flac_header(info: &StreamInfo, blocks: &[MetadataBlock]) -> Vec<u8> {
+    let mut output = b"fLaC".to_vec();
+    let info_block = MetadataBlock { block_type: BLOCK_STREAMINFO, data: info.to_bytes().to_vec() };
+
+    let all: Vec<&MetadataBlock> = std::iter::once(&info_block)
+        .chain(blocks.iter().filter(|b| b.block_type != BLOCK_STREAMINFO))
+        .collect();
+
+    for (i, block) in all.iter().enumerate() {
+        let last = if i + 1 == all.len() { 0x80 } else { 0 };
+        output.push(last | block.block_type);
+        output.extend_from_slice(&(block.data.len() as u32).to_be_bytes()[1..]);
+        output.extend_from_slice(&block.data);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::read_metadata;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let info = StreamInfo {
+            min_block_size: 4096,
+            max_block_size: 4096,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            sample_rate: 44100,
+            channels: 2,
+            bits_per_sample: 16,
+            total_samples: 10000,
+            md5: [0u8; 16],
+        };
+
+        let tags = vec![("TITLE".to_string(), "Song = Good".to_string()),
+                        ("ARTIST".to_string(), "Somebody".to_string())];
+        let frames = [
+            FrameInfo { offset: 100, first_sample: 0, block_size: 4096 },
+            FrameInfo { offset: 900, first_sample: 4096, block_size: 4096 },
+            FrameInfo { offset: 1700, first_sample: 8192, block_size: 1808 },
+        ];
+
+        let header = write_flac_header(&info, &[vorbis_comment_block("trinitypeer", &tags),
+                                                seek_table_block(&frames, 8000)]);
+        let metadata = read_metadata(&header).unwrap();
+
+        assert_eq!(metadata.info, info);
+        assert_eq!(metadata.audio_offset, header.len());
+        assert_eq!(parse_vorbis_comment(&metadata.blocks[1].data).unwrap(), tags);
+
+        let points = parse_seek_table(&metadata.blocks[2].data).unwrap();
+        assert_eq!(points.len(), 2, "A POINT EVERY 8000 SAMPLES");
+        assert_eq!(points[1], SeekPoint { sample: 8192, offset: 1600, samples: 1808 });
+
+        assert_eq!(find_seek_point(&points, 8191), Some(points[0]));
+        assert_eq!(find_seek_point(&points, 9000), Some(points[1]));
+    }
+}
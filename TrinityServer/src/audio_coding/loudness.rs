@@ -0,0 +1,683 @@
+// A file for the loudness of the audio, as EBU R128 (ITU-R BS.1770) measures it
+// The meter gives the momentary (400 ms), the short-term (3 s) and the integrated
+// (gated, the whole programme) loudness in LUFS and the true peak in dBTP
+// The normalizer brings the stream to the target loudness with a slow gain,
+// and the lookahead limiter keeps the true peak under the ceiling
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use super::convert::{quantize, Dither};
+use super::{AudioCodingError, AudioFormat};
+use crate::shared::Slot;
+
+// The blocks quieter than this are not counted at all (the absolute gate)
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+// The blocks quieter than the programme by this much are not counted (the relative gate)
+const RELATIVE_GATE_LU: f64 = 10.0;
+
+// The integrated loudness is kept as a histogram of the blocks, so a stream
+// running for days takes the same memory. A bin is 0.1 LU wide
+const HISTOGRAM_MAX_LUFS: f64 = 10.0;
+const HISTOGRAM_BINS_PER_LU: f64 = 10.0;
+
+// The momentary block is 4 of the 100 ms sub-blocks, the short-term one is 30
+const MOMENTARY_SUB_BLOCKS: usize = 4;
+const SHORT_TERM_SUB_BLOCKS: usize = 30;
+
+// The half of the interpolation filter of the true peak, in the samples
+const TRUE_PEAK_HALF_TAPS: usize = 6;
+
+// The normalization never changes the level more than this
+pub const MAX_NORMALIZE_GAIN_DB: f64 = 20.0;
+
+// How fast the gain of the normalization follows the loudness
+const GAIN_SLEW_DB_PER_SEC: f64 = 2.0;
+
+// The limiter looks ahead this far and releases this slowly
+const LIMITER_LOOKAHEAD_SECS: f64 = 0.005;
+const LIMITER_RELEASE_SECS: f64 = 0.1;
+
+pub const DEFAULT_TARGET_LUFS: f64 = -16.0;
+pub const DEFAULT_TRUE_PEAK_DBTP: f64 = -1.0;
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+pub(crate) fn to_db(linear: f64) -> f64 {
+    20.0 * linear.log10()
+}
+
+pub(crate) fn from_db(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+
+
+// A second order IIR filter, the K-weighting is made of two of them
+// (the EQ of the effects uses it as well)
+
+#[derive(Clone, Debug)]
+pub(super) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    // The coefficients as the cookbooks give them, divided by a0
+    pub(super) fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            state: [0.0; 2],
+        }
+    }
+
+    pub(super) fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// The K-weighting of BS.1770 for any sample rate: the high shelf of the head
+// and the high-pass, which removes the lowest frequencies
+
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = from_db(gain);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+// The weight of the channel in the sum, the surround channels of 5.1 are louder
+// to the listener and the LFE is not counted
+
+fn channel_weight(channels: usize, channel: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+
+
+// The true peak, the peak of the signal between the samples as well
+// The signal is oversampled 4 times (2 times from 96 kHz), the peak is
+// reported for the position TRUE_PEAK_HALF_TAPS samples back
+
+#[derive(Clone, Debug)]
+struct TruePeak {
+    channels: usize,
+    phases: Vec<[f64; 2 * TRUE_PEAK_HALF_TAPS]>,
+    history: Vec<f64>,
+}
+
+impl TruePeak {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let factor = match sample_rate {
+            0..=95999 => 4,
+            96000..=191999 => 2,
+            _ => 1,
+        };
+
+        let taps = 2 * TRUE_PEAK_HALF_TAPS;
+        let radius = TRUE_PEAK_HALF_TAPS as f64 + 0.5;
+
+        let phases = (0..factor)
+            .map(|phase| {
+                let position = TRUE_PEAK_HALF_TAPS as f64 - 1.0 + phase as f64 / factor as f64;
+                let mut coefficients = [0.0; 2 * TRUE_PEAK_HALF_TAPS];
+
+                for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+                    let x = tap as f64 - position;
+                    let sinc = if x.abs() < 1e-9 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                    let w = PI * x / radius;
+                    *coefficient = sinc * (0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos());
+                }
+
+                let sum: f64 = coefficients.iter().sum();
+                coefficients.iter_mut().for_each(|c| *c /= sum);
+                coefficients
+            })
+            .collect();
+
+        TruePeak { channels, phases, history: vec![0.0; taps * channels] }
+    }
+
+    // The delay of the reported peak, in the frames
+    fn delay() -> usize {
+        TRUE_PEAK_HALF_TAPS
+    }
+
+    // Taking the next frame, returns the biggest absolute value of every channel
+    fn push(&mut self, frame: &[f64]) -> f64 {
+        let taps = 2 * TRUE_PEAK_HALF_TAPS;
+        self.history.drain(..self.channels);
+        self.history.extend_from_slice(frame);
+
+        let mut peak: f64 = 0.0;
+        for channel in 0..self.channels {
+            for coefficients in &self.phases {
+                let value: f64 = (0..taps)
+                    .map(|tap| coefficients[tap] * self.history[tap * self.channels + channel])
+                    .sum();
+                peak = peak.max(value.abs());
+            }
+        }
+        peak
+    }
+}
+
+
+
+// The loudness, as the API reports it
+// None is the silence (or not enough of the audio yet)
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LoudnessReport {
+    pub momentary_lufs: Option<f64>,
+    pub short_term_lufs: Option<f64>,
+    pub integrated_lufs: Option<f64>,
+    pub true_peak_dbtp: Option<f64>,
+}
+
+// The meter of the interleaved samples of a single format
+
+#[derive(Clone, Debug)]
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    true_peak: TruePeak,
+    peak: f64,
+    sub_block_frames: usize,
+    sub_block_filled: usize,
+    sub_block_sums: Vec<f64>,
+    // The energies of the last sub-blocks, for the momentary and the short-term loudness
+    sub_blocks: VecDeque<f64>,
+    // (blocks, sum of their energies) by the loudness of the block
+    histogram: Vec<(u64, f64)>,
+}
+
+impl LoudnessMeter {
+    pub fn new(format: &AudioFormat) -> Self {
+        let bins = ((HISTOGRAM_MAX_LUFS - ABSOLUTE_GATE_LUFS) * HISTOGRAM_BINS_PER_LU) as usize;
+
+        LoudnessMeter {
+            channels: format.channels,
+            filters: (0..format.channels).map(|_| k_weighting(format.sample_rate)).collect(),
+            true_peak: TruePeak::new(format.channels, format.sample_rate),
+            peak: 0.0,
+            sub_block_frames: (format.sample_rate as usize / 10).max(1),
+            sub_block_filled: 0,
+            sub_block_sums: vec![0.0; format.channels],
+            sub_blocks: VecDeque::with_capacity(SHORT_TERM_SUB_BLOCKS),
+            histogram: vec![(0, 0.0); bins],
+        }
+    }
+
+    // Measuring the samples from -1.0 to 1.0
+    pub fn add(&mut self, samples: &[f64]) {
+        for frame in samples.chunks_exact(self.channels) {
+            self.peak = self.peak.max(self.true_peak.push(frame));
+
+            for (channel, sample) in frame.iter().enumerate() {
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let weighted = high_pass.process(shelf.process(*sample));
+                self.sub_block_sums[channel] += weighted * weighted;
+            }
+
+            self.sub_block_filled += 1;
+            if self.sub_block_filled == self.sub_block_frames {
+                self.finish_sub_block();
+            }
+        }
+    }
+
+    // Measuring the integer PCM of the given size
+    pub fn add_pcm(&mut self, pcm: &[i32], bits_per_sample: usize) {
+        let scale = 1.0 / (1i64 << (bits_per_sample - 1)) as f64;
+        let samples: Vec<f64> = pcm.iter().map(|s| *s as f64 * scale).collect();
+        self.add(&samples);
+    }
+
+    fn finish_sub_block(&mut self) {
+        let frames = self.sub_block_frames as f64;
+        let energy: f64 = self.sub_block_sums.iter().enumerate()
+            .map(|(channel, sum)| channel_weight(self.channels, channel) * sum / frames)
+            .sum();
+
+        self.sub_block_sums.iter_mut().for_each(|sum| *sum = 0.0);
+        self.sub_block_filled = 0;
+
+        if self.sub_blocks.len() == SHORT_TERM_SUB_BLOCKS {
+            self.sub_blocks.pop_front();
+        }
+        self.sub_blocks.push_back(energy);
+
+        // Every sub-block finishes a gating block, they overlap by 75%
+        if let Some(block) = self.energy_of(MOMENTARY_SUB_BLOCKS) {
+            let loudness = to_lufs(block);
+            if loudness >= ABSOLUTE_GATE_LUFS {
+                let bin = self.bin(loudness);
+                self.histogram[bin].0 += 1;
+                self.histogram[bin].1 += block;
+            }
+        }
+    }
+
+    fn bin(&self, loudness: f64) -> usize {
+        let bin = ((loudness - ABSOLUTE_GATE_LUFS) * HISTOGRAM_BINS_PER_LU) as usize;
+        bin.min(self.histogram.len() - 1)
+    }
+
+    // The mean energy of the last sub-blocks, when there are enough of them
+    fn energy_of(&self, sub_blocks: usize) -> Option<f64> {
+        if self.sub_blocks.len() < sub_blocks {
+            return None;
+        }
+        Some(self.sub_blocks.iter().rev().take(sub_blocks).sum::<f64>() / sub_blocks as f64)
+    }
+
+    fn loudness_of(&self, sub_blocks: usize) -> Option<f64> {
+        self.energy_of(sub_blocks).map(to_lufs).filter(|l| *l >= ABSOLUTE_GATE_LUFS)
+    }
+
+    pub fn momentary(&self) -> Option<f64> {
+        self.loudness_of(MOMENTARY_SUB_BLOCKS)
+    }
+
+    pub fn short_term(&self) -> Option<f64> {
+        self.loudness_of(SHORT_TERM_SUB_BLOCKS)
+    }
+
+    // The gated loudness of everything measured, the silence and the quiet parts
+    // (10 LU under the rest) do not pull it down
+
+    pub fn integrated(&self) -> Option<f64> {
+        let mean = |from: usize| {
+            let (blocks, energy) = self.histogram[from..].iter()
+                .fold((0u64, 0.0), |(blocks, energy), bin| (blocks + bin.0, energy + bin.1));
+            Some(energy / blocks as f64).filter(|_| blocks > 0)
+        };
+
+        let relative_gate = to_lufs(mean(0)?) - RELATIVE_GATE_LU;
+        let from = if relative_gate > ABSOLUTE_GATE_LUFS { self.bin(relative_gate) } else { 0 };
+        mean(from).map(to_lufs)
+    }
+
+    pub fn true_peak(&self) -> Option<f64> {
+        Some(self.peak).filter(|peak| *peak > 0.0).map(to_db)
+    }
+
+    pub fn report(&self) -> LoudnessReport {
+        LoudnessReport {
+            momentary_lufs: self.momentary(),
+            short_term_lufs: self.short_term(),
+            integrated_lufs: self.integrated(),
+            true_peak_dbtp: self.true_peak(),
+        }
+    }
+}
+
+// The loudness of the whole PCM, as the tracks are measured
+
+pub fn measure_pcm(pcm: &[i32], format: &AudioFormat) -> LoudnessReport {
+    let mut meter = LoudnessMeter::new(format);
+    meter.add_pcm(pcm, format.bits_per_sample);
+    meter.report()
+}
+
+
+
+// The settings of the normalization
+// The target is the integrated loudness the listeners get,
+// the ceiling is the highest true peak the limiter lets through
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NormalizeSettings {
+    pub target_lufs: f64,
+    pub true_peak_dbtp: f64,
+}
+
+impl Default for NormalizeSettings {
+    fn default() -> Self {
+        NormalizeSettings { target_lufs: DEFAULT_TARGET_LUFS, true_peak_dbtp: DEFAULT_TRUE_PEAK_DBTP }
+    }
+}
+
+impl NormalizeSettings {
+    pub fn validated(self) -> Result<Self, String> {
+        if !(-40.0..=-5.0).contains(&self.target_lufs) {
+            return Err("The target loudness is from -40 to -5 LUFS".to_string());
+        }
+
+        if !(-12.0..=0.0).contains(&self.true_peak_dbtp) {
+            return Err("The true peak ceiling is from -12 to 0 dBTP".to_string());
+        }
+
+        Ok(self)
+    }
+}
+
+// The lookahead limiter of the true peak
+// The gain each frame needs is the smallest one over the lookahead, smoothed
+// with a moving average of the same length, so the gain is already down
+// when the peak comes. The audio is delayed by the lookahead for it
+
+#[derive(Debug)]
+struct Limiter {
+    ceiling: f64,
+    lookahead: usize,
+    detector: TruePeak,
+    index: u64,
+    // The needed gains as the increasing (index, gain) pairs, for the sliding minimum
+    minimum: VecDeque<(u64, f64)>,
+    averaged: VecDeque<f64>,
+    average_sum: f64,
+    gain: f64,
+    release: f64,
+    delay: VecDeque<f64>,
+    channels: usize,
+}
+
+impl Limiter {
+    fn new(format: &AudioFormat, ceiling_dbtp: f64) -> Self {
+        let rate = format.sample_rate as f64;
+        let lookahead = ((rate * LIMITER_LOOKAHEAD_SECS) as usize).max(1);
+
+        // The peak detector reports the frames a little later, the audio waits for it too
+        let delay_frames = lookahead - 1 + TruePeak::delay();
+
+        Limiter {
+            ceiling: from_db(ceiling_dbtp),
+            lookahead,
+            detector: TruePeak::new(format.channels, format.sample_rate),
+            index: 0,
+            minimum: VecDeque::new(),
+            averaged: VecDeque::from(vec![1.0; lookahead]),
+            average_sum: lookahead as f64,
+            gain: 1.0,
+            release: 1.0 - (-1.0 / (rate * LIMITER_RELEASE_SECS)).exp(),
+            delay: VecDeque::from(vec![0.0; delay_frames * format.channels]),
+            channels: format.channels,
+        }
+    }
+
+    fn process(&mut self, frame: &[f64], output: &mut Vec<f64>) {
+        let peak = self.detector.push(frame);
+        let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+        // The sliding minimum over the lookahead
+        while self.minimum.back().is_some_and(|(_, gain)| *gain >= needed) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.index, needed));
+        while self.minimum.front().is_some_and(|(index, _)| index + self.lookahead as u64 <= self.index) {
+            self.minimum.pop_front();
+        }
+        self.index += 1;
+
+        let minimum = self.minimum.front().map_or(1.0, |(_, gain)| *gain);
+        self.average_sum += minimum - self.averaged.pop_front().unwrap_or(1.0);
+        self.averaged.push_back(minimum);
+        let target = (self.average_sum / self.lookahead as f64).min(1.0);
+
+        self.gain = if target < self.gain { target } else { self.gain + (target - self.gain) * self.release };
+
+        self.delay.extend(frame);
+        output.extend(self.delay.drain(..self.channels).map(|sample| sample * self.gain));
+    }
+}
+
+// The normalization of a stream, the input is measured, the gain brings it
+// to the target and the limiter catches the peaks the gain has made too loud
+
+#[derive(Debug)]
+struct Normalizer {
+    settings: NormalizeSettings,
+    input: LoudnessMeter,
+    limiter: Limiter,
+    gain_db: f64,
+}
+
+impl Normalizer {
+    fn new(format: &AudioFormat, settings: NormalizeSettings) -> Self {
+        Normalizer {
+            settings,
+            input: LoudnessMeter::new(format),
+            limiter: Limiter::new(format, settings.true_peak_dbtp),
+            gain_db: 0.0,
+        }
+    }
+
+    fn process(&mut self, samples: &[f64], format: &AudioFormat) -> Vec<f64> {
+        self.input.add(samples);
+
+        // The gain moves slowly towards the one the integrated loudness asks for,
+        // and it changes smoothly over the chunk
+        let frames = samples.len() / format.channels;
+        let wanted = self.input.integrated()
+            .map(|loudness| (self.settings.target_lufs - loudness).clamp(-MAX_NORMALIZE_GAIN_DB, MAX_NORMALIZE_GAIN_DB))
+            .unwrap_or(self.gain_db);
+        let max_step = GAIN_SLEW_DB_PER_SEC * frames as f64 / format.sample_rate as f64;
+        let previous = from_db(self.gain_db);
+        self.gain_db += (wanted - self.gain_db).clamp(-max_step, max_step);
+        let next = from_db(self.gain_db);
+
+        let mut output = Vec::with_capacity(samples.len());
+        let mut frame = vec![0.0; format.channels];
+        for (index, input) in samples.chunks_exact(format.channels).enumerate() {
+            let gain = previous + (next - previous) * (index + 1) as f64 / frames as f64;
+            frame.iter_mut().zip(input).for_each(|(out, sample)| *out = sample * gain);
+            self.limiter.process(&frame, &mut output);
+        }
+        output
+    }
+}
+
+
+
+// The loudness of a stream as it goes out, with the normalization when it is on
+
+#[derive(Serialize)]
+pub struct NormalizationReport {
+    pub target_lufs: f64,
+    pub true_peak_dbtp: f64,
+    pub gain_db: f64,
+}
+
+#[derive(Serialize)]
+pub struct StreamLoudness {
+    #[serde(flatten)]
+    pub loudness: LoudnessReport,
+    pub normalization: Option<NormalizationReport>,
+}
+
+#[derive(Debug)]
+pub struct LoudnessProcessor {
+    format: AudioFormat,
+    output: LoudnessMeter,
+    normalizer: Option<Normalizer>,
+    dither: Dither,
+}
+
+impl LoudnessProcessor {
+    pub fn new(format: &AudioFormat, settings: Option<NormalizeSettings>) -> Result<Self, AudioCodingError> {
+        format.validate()?;
+
+        Ok(LoudnessProcessor {
+            format: *format,
+            output: LoudnessMeter::new(format),
+            normalizer: settings.map(|settings| Normalizer::new(format, settings)),
+            dither: Dither::new(),
+        })
+    }
+
+    pub fn is_for(&self, format: &AudioFormat, settings: Option<NormalizeSettings>) -> bool {
+        self.format == *format && self.normalizer.as_ref().map(|n| n.settings) == settings
+    }
+
+    // Measuring (and normalizing) the PCM before it is encoded
+    // Without the normalization the PCM is only measured and comes back as it is
+
+    pub fn process(&mut self, pcm: Vec<i32>) -> Vec<i32> {
+        let bits_per_sample = self.format.bits_per_sample;
+
+        let Some(normalizer) = self.normalizer.as_mut() else {
+            self.output.add_pcm(&pcm, bits_per_sample);
+            return pcm;
+        };
+
+        let scale = 1.0 / (1i64 << (bits_per_sample - 1)) as f64;
+        let samples: Vec<f64> = pcm.iter().map(|s| *s as f64 * scale).collect();
+        let normalized = normalizer.process(&samples, &self.format);
+        self.output.add(&normalized);
+
+        let normalized: Vec<f32> = normalized.iter().map(|s| *s as f32).collect();
+        quantize(&normalized, bits_per_sample, Some(&mut self.dither))
+    }
+
+    pub fn report(&self) -> StreamLoudness {
+        StreamLoudness {
+            loudness: self.output.report(),
+            normalization: self.normalizer.as_ref().map(|normalizer| NormalizationReport {
+                target_lufs: normalizer.settings.target_lufs,
+                true_peak_dbtp: normalizer.settings.true_peak_dbtp,
+                gain_db: normalizer.gain_db,
+            }),
+        }
+    }
+}
+
+// The loudness of a stream, kept between the chunks
+// It starts over, when the format or the settings of the stream change
+
+#[derive(Clone, Debug, Default)]
+pub struct LoudnessSlot {
+    processor: Slot<LoudnessProcessor>,
+}
+
+impl LoudnessSlot {
+    pub fn process(&self, pcm: Vec<i32>, format: &AudioFormat, settings: Option<NormalizeSettings>)
+                                                            -> Result<Vec<i32>, AudioCodingError> {
+        self.processor.with(|processor| processor.is_for(format, settings),
+                            || LoudnessProcessor::new(format, settings),
+                            |processor| Ok(processor.process(pcm)))
+    }
+
+    // None until the first PCM chunk is measured
+    pub fn report(&self) -> Option<StreamLoudness> {
+        self.processor.peek(LoudnessProcessor::report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::{sine, sine_at, to_pcm};
+
+    #[test]
+    fn test_meter_matches_the_reference_tone() {
+        // The EBU reference: the stereo 1 kHz sine at -23 dBFS is -23 LUFS
+        for rate in [44100, 48000] {
+            let format = AudioFormat { channels: 2, bits_per_sample: 24, sample_rate: rate };
+            let mut meter = LoudnessMeter::new(&format);
+            meter.add(&sine(1000.0, from_db(-23.0), rate, 2, rate as usize * 5));
+
+            let integrated = meter.integrated().unwrap();
+            assert!((integrated + 23.0).abs() < 0.1, "{} HZ: {} LUFS", rate, integrated);
+            assert!((meter.short_term().unwrap() + 23.0).abs() < 0.1);
+        }
+
+        // The silence in the middle does not pull the integrated loudness down
+        let format = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 24000 };
+        let mut meter = LoudnessMeter::new(&format);
+        meter.add(&sine(1000.0, 0.5, 24000, 1, 72000));
+        let loud = meter.integrated().unwrap();
+        meter.add(&vec![0.0; 24000 * 5]);
+        assert!((meter.integrated().unwrap() - loud).abs() < 0.3, "SILENCE IS GATED");
+        assert_eq!(meter.momentary(), None);
+    }
+
+    #[test]
+    fn test_true_peak_between_the_samples() {
+        // The sine at the quarter of the rate, sampled at 45 degrees,
+        // has its samples 3 dB under its peak
+        let format = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 48000 };
+        let tone = sine_at(12000.0, 0.9, PI / 4.0, 48000, 1, 24000);
+        let sample_peak = to_db(tone.iter().fold(0.0f64, |peak, s| peak.max(s.abs())));
+
+        let mut meter = LoudnessMeter::new(&format);
+        meter.add(&tone);
+        let true_peak = meter.true_peak().unwrap();
+
+        assert!((sample_peak - to_db(0.9 * 0.5f64.sqrt())).abs() < 0.1);
+        assert!((true_peak - to_db(0.9)).abs() < 0.3, "TRUE PEAK {}", true_peak);
+    }
+
+    #[test]
+    fn test_normalization_reaches_the_target() {
+        let format = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 24000 };
+        let settings = NormalizeSettings { target_lufs: -16.0, true_peak_dbtp: -1.0 };
+        let mut processor = LoudnessProcessor::new(&format, Some(settings)).unwrap();
+
+        // The mono sine 27 dB under the full scale is around -30 LUFS
+        let tone = to_pcm(&sine(1000.0, from_db(-27.0), 24000, 1, 288000), 16);
+        for chunk in tone.chunks(2400) {
+            assert_eq!(processor.process(chunk.to_vec()).len(), chunk.len(), "SAME AMOUNT OF SAMPLES");
+        }
+
+        let report = processor.report();
+        let short_term = report.loudness.short_term_lufs.unwrap();
+        assert!((short_term + 16.0).abs() < 0.5, "SHORT TERM {}", short_term);
+        assert!((report.normalization.unwrap().gain_db - 14.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_limiter_keeps_the_ceiling() {
+        let format = AudioFormat { channels: 2, bits_per_sample: 24, sample_rate: 24000 };
+        let settings = NormalizeSettings { target_lufs: -5.0, true_peak_dbtp: -6.0 };
+        let mut processor = LoudnessProcessor::new(&format, Some(settings)).unwrap();
+
+        let tone = to_pcm(&sine(440.0, from_db(-17.0), 24000, 2, 240000), 24);
+        let output: Vec<i32> = tone.chunks(4800).flat_map(|chunk| processor.process(chunk.to_vec())).collect();
+
+        let mut meter = LoudnessMeter::new(&format);
+        meter.add_pcm(&output, 24);
+        let true_peak = meter.true_peak().unwrap();
+        assert!(true_peak <= -5.9, "PEAK OVER THE CEILING: {}", true_peak);
+        assert!(true_peak > -7.0, "LIMITER HAS DONE TOO MUCH: {}", true_peak);
+    }
+}
@@ -0,0 +1,369 @@
+// Opus encoding of the raw PCM data and the Ogg packaging of Opus packets
+// Opus is not lossless, but it has a tiny latency (frames of 2.5 - 60 ms)
+// and it is the only audio codec every WebRTC peer has to support
+
+// Trinitypeer, 2025, by Trinitycore
+
+use audiopus::coder::{Decoder, Encoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use log::error;
+
+use super::{AudioCodingError, Rendition};
+use crate::shared::{lock, Slot};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+// Opus always works with 48 kHz internally, the granule positions
+// of Ogg/Opus are counted in 48 kHz samples regardless of the input rate
+pub const OPUS_GRANULE_RATE: u32 = 48000;
+
+// The biggest packet libopus is able to produce (recommended buffer size)
+const MAX_PACKET_SIZE: usize = 4000;
+
+// The longest packet is 120 ms, in 48 kHz samples of every channel
+const MAX_PACKET_FRAMES: usize = 5760;
+
+
+
+// The settings of the Opus encoder
+// Sample rate must be one of 8, 12, 16, 24 or 48 kHz
+// Frame size is in samples per channel, it must be 2.5, 5, 10, 20, 40 or 60 ms long
+// Bitrate is in bits per second, complexity is 0 (fastest) to 10 (best quality)
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpusEncodeOptions {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frame_size: usize,
+    pub bitrate: u32,
+    pub complexity: u8,
+}
+
+impl Default for OpusEncodeOptions {
+    // 20 ms stereo frames at 128 kbit/s, the same thing browsers send through WebRTC
+    fn default() -> Self {
+        OpusEncodeOptions {
+            sample_rate: 48000,
+            channels: 2,
+            frame_size: 960,
+            bitrate: 128000,
+            complexity: 10,
+        }
+    }
+}
+
+impl OpusEncodeOptions {
+    fn is_valid_frame_size(&self) -> bool {
+        // 2.5 ms is the smallest frame, the others are multiples of it
+        let smallest = self.sample_rate as usize / 400;
+        [1, 2, 4, 8, 16, 24].iter().any(|m| smallest * m == self.frame_size)
+    }
+}
+
+
+
+// The Opus encoder of a stream, kept from one chunk to the next
+// The PCM, which does not fill the last frame, waits for the next chunk
+// instead of being padded with silence, so the stream has no gaps in it
+
+pub struct OpusStreamEncoder {
+    encoder: Encoder,
+    options: OpusEncodeOptions,
+    pending: Vec<i16>,
+}
+
+impl std::fmt::Debug for OpusStreamEncoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpusStreamEncoder")
+            .field("options", &self.options)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl OpusStreamEncoder {
+    pub fn new(options: OpusEncodeOptions) -> Result<Self, AudioCodingError> {
+        let sample_rate = SampleRate::try_from(options.sample_rate as i32)
+            .map_err(|_| AudioCodingError::InvalidSampleRate(options.sample_rate))?;
+
+        let channels = match options.channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            other => return Err(AudioCodingError::InvalidChannels(other)),
+        };
+
+        if !options.is_valid_frame_size() {
+            return Err(AudioCodingError::InvalidFrameSize(options.frame_size));
+        }
+
+        if options.complexity > 10 {
+            return Err(AudioCodingError::InvalidComplexity(options.complexity));
+        }
+
+        let mut encoder = Encoder::new(sample_rate, channels, Application::Audio)
+            .map_err(|e| AudioCodingError::Config(e.to_string()))?;
+
+        encoder.set_bitrate(Bitrate::BitsPerSecond(options.bitrate as i32))
+            .and_then(|_| encoder.set_complexity(options.complexity))
+            .map_err(|e| AudioCodingError::Config(e.to_string()))?;
+
+        Ok(OpusStreamEncoder { encoder, options, pending: Vec::new() })
+    }
+
+    pub fn is_for(&self, options: &OpusEncodeOptions) -> bool {
+        self.options == *options
+    }
+
+    fn encode_frame(&self, frame: &[i16], output: &mut [u8]) -> Result<Vec<u8>, AudioCodingError> {
+        match self.encoder.encode(frame, output) {
+            Ok(len) => Ok(output[..len].to_vec()),
+            Err(e) => {
+                error!("Failed to encode Opus frame: {}", e);
+                Err(AudioCodingError::Encode(e.to_string()))
+            }
+        }
+    }
+
+    // Encoding the interleaved 16-bit PCM, every returned packet is a single frame
+    // The rest of the PCM is kept for the next call
+
+    pub fn encode(&mut self, pcm_data: &[i16]) -> Result<Vec<Vec<u8>>, AudioCodingError> {
+        let samples_per_frame = self.options.frame_size * self.options.channels;
+        self.pending.extend_from_slice(pcm_data);
+
+        let frames = self.pending.len() / samples_per_frame;
+        let mut packets = Vec::with_capacity(frames);
+        let mut output = [0u8; MAX_PACKET_SIZE];
+
+        for frame in self.pending.chunks_exact(samples_per_frame) {
+            packets.push(self.encode_frame(frame, &mut output)?);
+        }
+
+        self.pending.drain(..frames * samples_per_frame);
+        Ok(packets)
+    }
+}
+
+// The Opus encoders of the live stream, the one of the stream itself and
+// the ones of its Opus renditions, shared by the ingest requests
+// The encoder is made again, when the format of the PCM changes
+
+#[derive(Clone, Debug, Default)]
+pub struct OpusEncoders {
+    encoders: Arc<Mutex<HashMap<Rendition, Slot<OpusStreamEncoder>>>>,
+}
+
+impl OpusEncoders {
+    pub fn encode(&self, rendition: Rendition, options: OpusEncodeOptions, pcm_data: &[i16])
+                                                -> Result<Vec<Vec<u8>>, AudioCodingError> {
+        // The renditions are encoded at once, only the map is locked for all of them
+        let slot = lock(&self.encoders).entry(rendition).or_default().clone();
+
+        slot.with(|encoder| encoder.is_for(&options),
+                  || OpusStreamEncoder::new(options),
+                  |encoder| encoder.encode(pcm_data))
+    }
+}
+
+
+
+// The decoder of the Opus packets the broadcasters push, kept from one packet to the next
+// The packets are decoded at 48 kHz, the rate Opus works with inside
+
+pub struct OpusStreamDecoder {
+    decoder: Decoder,
+    channels: usize,
+    frame: Vec<i16>,
+}
+
+impl std::fmt::Debug for OpusStreamDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpusStreamDecoder").field("channels", &self.channels).finish()
+    }
+}
+
+impl OpusStreamDecoder {
+    pub fn new(channels: usize) -> Result<Self, AudioCodingError> {
+        let layout = match channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            other => return Err(AudioCodingError::InvalidChannels(other)),
+        };
+
+        let decoder = Decoder::new(SampleRate::Hz48000, layout)
+            .map_err(|e| AudioCodingError::Config(e.to_string()))?;
+
+        Ok(OpusStreamDecoder { decoder, channels, frame: vec![0; MAX_PACKET_FRAMES * channels] })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    // Decoding a single packet to the interleaved 16-bit PCM
+    pub fn decode(&mut self, packet: &[u8]) -> Result<Vec<i32>, AudioCodingError> {
+        let packet = Packet::try_from(packet).map_err(|e| AudioCodingError::Decode(e.to_string()))?;
+        let output = MutSignals::try_from(self.frame.as_mut_slice())
+            .map_err(|e| AudioCodingError::Decode(e.to_string()))?;
+
+        let frames = self.decoder.decode(Some(packet), output, false)
+            .map_err(|e| AudioCodingError::Decode(e.to_string()))?;
+
+        Ok(self.frame[..frames * self.channels].iter().map(|s| *s as i32).collect())
+    }
+}
+
+
+
+// The amount of 48 kHz samples in the Opus packet, read from its TOC byte
+// (RFC 6716, section 3.1), it is needed for the granule positions of Ogg
+
+pub fn opus_packet_samples(packet: &[u8]) -> u64 {
+    let toc = match packet.first() {
+        Some(toc) => *toc,
+        None => return 0,
+    };
+
+    let config = toc >> 3;
+
+    // Frame duration in 1/400 of a second (2.5 ms units)
+    let frame_units: u64 = match config {
+        0..=11 => [4, 8, 16, 24][(config % 4) as usize],
+        12..=15 => [4, 8][(config % 2) as usize],
+        _ => [1, 2, 4, 8][(config % 4) as usize],
+    };
+
+    let frames: u64 = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map(|b| (b & 0x3f) as u64).unwrap_or(0),
+    };
+
+    frames * frame_units * (OPUS_GRANULE_RATE as u64 / 400)
+}
+
+
+
+// Ogg/Opus packaging (RFC 7845) for the HTTP listeners
+// Every call returns the bytes to send right away, so every packet
+// is put into its own page to not add any latency
+
+pub struct OggOpusWriter {
+    writer: PacketWriter<Vec<u8>>,
+    serial: u32,
+    granule: u64,
+    channels: u8,
+    input_sample_rate: u32,
+}
+
+impl OggOpusWriter {
+    pub fn new(serial: u32, channels: u8, input_sample_rate: u32) -> Self {
+        OggOpusWriter {
+            writer: PacketWriter::new(Vec::new()),
+            serial,
+            granule: 0,
+            channels,
+            input_sample_rate,
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.inner_mut())
+    }
+
+    fn write(&mut self, packet: Vec<u8>, end: PacketWriteEndInfo) {
+        // Writing into the Vec can not fail
+        if let Err(e) = self.writer.write_packet(packet.into_boxed_slice(), 
+                                                 self.serial, end, self.granule) {
+            error!("Failed to write Ogg packet: {}", e);
+        }
+    }
+
+    // The identification and the comment headers, they must be sent
+    // before any audio, each of them on its own page
+
+    pub fn headers(&mut self) -> Vec<u8> {
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1);
+        head.push(self.channels);
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        self.write(head, PacketWriteEndInfo::EndPage);
+
+        let vendor = b"trinitypeer";
+        let mut tags = Vec::with_capacity(8 + 4 + vendor.len() + 4);
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor);
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        self.write(tags, PacketWriteEndInfo::EndPage);
+
+        self.take_output()
+    }
+
+    // A single Opus packet in its own page
+    pub fn packet(&mut self, packet: &[u8]) -> Vec<u8> {
+        self.granule += opus_packet_samples(packet);
+        self.write(packet.to_vec(), PacketWriteEndInfo::EndPage);
+        self.take_output()
+    }
+
+    // The last page of the stream, marked as the end of it
+    pub fn finish(&mut self, packet: &[u8]) -> Vec<u8> {
+        self.granule += opus_packet_samples(packet);
+        self.write(packet.to_vec(), PacketWriteEndInfo::EndStream);
+        self.take_output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_samples_from_toc() {
+        // CELT, 20 ms, one frame
+        assert_eq!(opus_packet_samples(&[0xfc, 0xff, 0xfe]), 960);
+        // SILK, 60 ms, two frames
+        assert_eq!(opus_packet_samples(&[(3 << 3) | 1]), 2 * 2880);
+        // CELT, 2.5 ms, arbitrary amount of frames (here 3)
+        assert_eq!(opus_packet_samples(&[(16 << 3) | 3, 3]), 3 * 120);
+        assert_eq!(opus_packet_samples(&[]), 0);
+    }
+
+    #[test]
+    fn test_encoder_carries_the_rest_to_the_next_chunk() {
+        let options = OpusEncodeOptions::default();
+        let frame = options.frame_size * options.channels;
+        let mut encoder = OpusStreamEncoder::new(options).unwrap();
+
+        // A frame and a half, then the other half, nothing is padded in between
+        assert_eq!(encoder.encode(&vec![1000; frame * 3 / 2]).unwrap().len(), 1);
+        assert_eq!(encoder.encode(&vec![1000; frame / 2]).unwrap().len(), 1);
+        assert!(encoder.pending.is_empty(), "NOTHING IS LEFT");
+
+        assert_eq!(encoder.encode(&vec![1000; frame / 4]).unwrap().len(), 0, "NO PADDED FRAME");
+        assert_eq!(encoder.pending.len(), frame / 4);
+    }
+
+    #[test]
+    fn test_ogg_headers_come_first() {
+        let mut writer = OggOpusWriter::new(7, 2, 48000);
+        let headers = writer.headers();
+
+        assert_eq!(&headers[..4], b"OggS");
+        assert!(headers.windows(8).any(|w| w == b"OpusHead"));
+        assert!(headers.windows(8).any(|w| w == b"OpusTags"));
+
+        let page = writer.packet(&[0xfc, 0xff, 0xfe]);
+        assert_eq!(&page[..4], b"OggS");
+        // Granule position of the page is at the offset 6
+        assert_eq!(u64::from_le_bytes(page[6..14].try_into().unwrap()), 960);
+    }
+}
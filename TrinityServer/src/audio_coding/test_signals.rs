@@ -0,0 +1,61 @@
+// A file for the signals the tests of the audio are made of,
+// so every test makes its tones and measures them the same way
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::f64::consts::PI;
+
+// The interleaved sine, the same in every channel, the full scale is 1
+pub fn sine_at(frequency: f64, amplitude: f64, phase: f64, rate: u32, channels: usize, frames: usize) -> Vec<f64> {
+    (0..frames)
+        .flat_map(|i| {
+            let value = amplitude * (2.0 * PI * frequency * i as f64 / rate as f64 + phase).sin();
+            std::iter::repeat_n(value, channels)
+        })
+        .collect()
+}
+
+pub fn sine(frequency: f64, amplitude: f64, rate: u32, channels: usize, frames: usize) -> Vec<f64> {
+    sine_at(frequency, amplitude, 0.0, rate, channels, frames)
+}
+
+// The integer samples, the full scale is the largest sample of the given bits
+pub fn to_pcm(samples: &[f64], bits_per_sample: usize) -> Vec<i32> {
+    let full_scale = ((1i64 << (bits_per_sample - 1)) - 1) as f64;
+    samples.iter().map(|s| (s * full_scale).round() as i32).collect()
+}
+
+pub fn rms<T: Copy + Into<f64>>(samples: &[T]) -> f64 {
+    (samples.iter().map(|s| (*s).into().powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+}
+
+// The PCM WAV file with the optional LIST chunk, the data is taken as it is
+pub fn wav_file(channels: u16, bits: u16, rate: u32, data: &[u8], list: &[u8]) -> Vec<u8> {
+    let block_align = channels * bits.div_ceil(8);
+    let mut fmt = Vec::new();
+    // WAVE_FORMAT_PCM
+    fmt.extend_from_slice(&1u16.to_le_bytes());
+    fmt.extend_from_slice(&channels.to_le_bytes());
+    fmt.extend_from_slice(&rate.to_le_bytes());
+    fmt.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&bits.to_le_bytes());
+
+    let mut body = b"WAVE".to_vec();
+    for (id, chunk) in [(b"fmt ", fmt.as_slice()), (b"LIST", list), (b"data", data)] {
+        if chunk.is_empty() {
+            continue;
+        }
+        body.extend_from_slice(id);
+        body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        body.extend_from_slice(chunk);
+        if chunk.len() % 2 == 1 {
+            body.push(0);
+        }
+    }
+
+    let mut file = b"RIFF".to_vec();
+    file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    file.extend(body);
+    file
+}
@@ -0,0 +1,216 @@
+// A file for reading the uploaded WAV files
+// Only the plain integer PCM is accepted (the same bit depths FLAC handles),
+// everything else is rejected before it reaches the encoder
+// The RIFF INFO list is read as well, it keeps the title, the artist and so on
+
+// Trinitypeer, 2025, by Trinitycore
+
+use super::{pcm_from_le_bytes, AudioCodingError, AudioFormat};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+
+
+// Everything that could go wrong while reading the WAV
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WavError {
+    NotWav,
+    UnexpectedEof,
+    MissingChunk(&'static str),
+    UnsupportedEncoding(u16),
+    InvalidFormat(AudioCodingError),
+}
+
+impl std::fmt::Display for WavError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WavError::NotWav => write!(f, "Not a RIFF/WAVE file"),
+            WavError::UnexpectedEof => write!(f, "Unexpected end of the WAV data"),
+            WavError::MissingChunk(chunk) => write!(f, "The WAV has no {} chunk", chunk),
+            WavError::UnsupportedEncoding(tag) =>
+                write!(f, "Unsupported WAV encoding {:#06x}, only integer PCM is accepted", tag),
+            WavError::InvalidFormat(e) => write!(f, "Invalid WAV format: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+
+
+// The decoded WAV, the samples are interleaved (L R L R ... for stereo)
+// info keeps the RIFF INFO entries as they are (INAM, IART, ...)
+
+#[derive(Debug, Clone)]
+pub struct DecodedWav {
+    pub format: AudioFormat,
+    pub samples: Vec<i32>,
+    pub info: Vec<(String, String)>,
+}
+
+// Whether the data looks like a WAV file at all
+pub fn is_wav(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WAVE"
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, WavError> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(WavError::UnexpectedEof)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, WavError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(WavError::UnexpectedEof)
+}
+
+// The entries of the LIST/INFO chunk, the values are zero-terminated strings
+fn read_info_list(data: &[u8], info: &mut Vec<(String, String)>) {
+    if data.len() < 4 || &data[..4] != b"INFO" {
+        return;
+    }
+
+    let mut pos = 4;
+    while pos + 8 <= data.len() {
+        let id = String::from_utf8_lossy(&data[pos..pos + 4]).into_owned();
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        let Some(value) = data.get(pos + 8..pos + 8 + size) else { break };
+
+        let value = String::from_utf8_lossy(value)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string();
+        if !value.is_empty() {
+            info.push((id, value));
+        }
+
+        // The chunks are padded to the even size
+        pos += 8 + size + (size & 1);
+    }
+}
+
+// Reading the whole WAV into the PCM samples
+
+pub fn decode_wav(data: &[u8]) -> Result<DecodedWav, WavError> {
+    if !is_wav(data) {
+        return Err(WavError::NotWav);
+    }
+
+    let mut format = None;
+    let mut samples = None;
+    let mut info = Vec::new();
+    let mut pos = 12;
+
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4)? as usize;
+        let body_start = pos + 8;
+
+        // The encoders streaming the WAV write the data size they do not know yet,
+        // the data is taken up to the end of the file then
+        let body_end = if id == b"data" {
+            body_start.saturating_add(size).min(data.len())
+        } else {
+            body_start.checked_add(size).filter(|end| *end <= data.len())
+                .ok_or(WavError::UnexpectedEof)?
+        };
+        let body = &data[body_start..body_end];
+
+        match id {
+            b"fmt " => {
+                let mut tag = read_u16(body, 0)?;
+                let channels = read_u16(body, 2)? as usize;
+                let sample_rate = read_u32(body, 4)?;
+                let bits_per_sample = read_u16(body, 14)? as usize;
+
+                // The extensible format keeps the real one in the first two bytes of the GUID
+                if tag == WAVE_FORMAT_EXTENSIBLE {
+                    tag = read_u16(body, 24)?;
+                }
+
+                if tag != WAVE_FORMAT_PCM {
+                    return Err(WavError::UnsupportedEncoding(tag));
+                }
+
+                let wav_format = AudioFormat { channels, bits_per_sample, sample_rate };
+                wav_format.validate().map_err(WavError::InvalidFormat)?;
+                format = Some(wav_format);
+            }
+            b"data" => {
+                let wav_format = format.ok_or(WavError::MissingChunk("fmt"))?;
+
+                // A truncated last sample frame is dropped
+                let frame = wav_format.bits_per_sample.div_ceil(8) * wav_format.channels;
+                let body = &body[..body.len() - body.len() % frame];
+
+                // 8-bit WAV is unsigned, unlike everything else
+                let mut pcm = if wav_format.bits_per_sample == 8 {
+                    body.iter().map(|b| *b as i32 - 128).collect()
+                } else {
+                    pcm_from_le_bytes(body, &wav_format).map_err(WavError::InvalidFormat)?
+                };
+
+                // 12 and 20 bits are stored left-justified in 16 and 24 bits
+                let shift = wav_format.bits_per_sample.div_ceil(8) * 8 - wav_format.bits_per_sample;
+                if shift > 0 {
+                    pcm.iter_mut().for_each(|s| *s >>= shift);
+                }
+
+                samples = Some(pcm);
+            }
+            b"LIST" => read_info_list(body, &mut info),
+            _ => {}
+        }
+
+        pos = body_end + (size & 1);
+    }
+
+    Ok(DecodedWav {
+        format: format.ok_or(WavError::MissingChunk("fmt"))?,
+        samples: samples.ok_or(WavError::MissingChunk("data"))?,
+        info,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::wav_file as wav;
+
+    #[test]
+    fn test_decode_wav_with_info() {
+        let mut list = b"INFO".to_vec();
+        list.extend_from_slice(b"INAM");
+        list.extend_from_slice(&5u32.to_le_bytes());
+        list.extend_from_slice(b"Song\0\0");
+        list.extend_from_slice(b"IART");
+        list.extend_from_slice(&4u32.to_le_bytes());
+        list.extend_from_slice(b"Who\0");
+
+        let data = [0x01, 0x00, 0xff, 0xff, 0x00, 0x80, 0xff, 0x7f];
+        let decoded = decode_wav(&wav(2, 16, 48000, &data, &list)).unwrap();
+
+        assert_eq!(decoded.format, AudioFormat { channels: 2, bits_per_sample: 16, sample_rate: 48000 });
+        assert_eq!(decoded.samples, vec![1, -1, -32768, 32767]);
+        assert_eq!(decoded.info, vec![("INAM".to_string(), "Song".to_string()),
+                                      ("IART".to_string(), "Who".to_string())]);
+
+        let decoded = decode_wav(&wav(1, 8, 8000, &[0, 128, 255], &[])).unwrap();
+        assert_eq!(decoded.samples, vec![-128, 0, 127], "8-BIT WAV IS UNSIGNED");
+    }
+
+    #[test]
+    fn test_rejects_invalid_wav() {
+        assert_eq!(decode_wav(b"fLaC").unwrap_err(), WavError::NotWav);
+
+        let mut float = wav(2, 32, 48000, &[0; 8], &[]);
+        float[20] = 3;
+        assert_eq!(decode_wav(&float).unwrap_err(), WavError::UnsupportedEncoding(3));
+
+        let no_data = wav(2, 16, 48000, &[], &[]);
+        assert_eq!(decode_wav(&no_data).unwrap_err(), WavError::MissingChunk("data"));
+    }
+}
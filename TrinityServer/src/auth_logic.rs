@@ -1,2 +1,3 @@
 pub(crate) mod jwt_functions;
-pub(crate) mod models;
\ No newline at end of file
+pub(crate) mod models;
+pub(crate) mod stream_keys;
\ No newline at end of file
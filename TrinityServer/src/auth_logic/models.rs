@@ -45,6 +45,18 @@ pub struct AuthenticatedUser {
     pub username: String
 }
 
+impl AuthenticatedUser {
+    // The admins are listed by their usernames in ADMIN_USERNAMES (comma separated)
+    // They are allowed to control any stream, not only their own ones
+    pub fn is_admin(&self) -> bool {
+        dotenv().ok();
+
+        env::var("ADMIN_USERNAMES")
+            .map(|admins| admins.split(',').any(|admin| admin.trim() == self.username))
+            .unwrap_or(false)
+    }
+}
+
 // Function for validation and checking token 
 // (runs automaticly when function with parameter type of AuthenticatedUser is running)
 impl FromRequest for AuthenticatedUser {
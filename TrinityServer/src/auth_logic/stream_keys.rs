@@ -0,0 +1,157 @@
+// Long-lived stream keys for the encoders, which can not refresh the JWT
+// (hardware encoders, OBS-like tools and so on)
+// The key belongs to the owner and the name of the stream, only its SHA-256
+// is stored in the database, the key itself is shown to the owner once
+
+// Trinitypeer, 2025, by Trinitycore
+
+use actix_web::{dev::Payload, Error, FromRequest, HttpRequest};
+use futures_util::future::{ready, Ready};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::db::init_db;
+
+use super::jwt_functions::decode_jwt;
+use super::models::AuthenticatedUser;
+
+// All the stream keys start with it, so they are not mistaken for the JWT
+pub const STREAM_KEY_PREFIX: &str = "sk_";
+
+// The header for the stream key, the encoders could use the bearer token as well
+pub const STREAM_KEY_HEADER: &str = "X-Stream-Key";
+
+// 32 random bytes, as hex
+pub fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", STREAM_KEY_PREFIX, hex)
+}
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+
+// Whether the owner already has a working key for the stream
+// The key is generated only once, the next broadcasts use the same one
+pub async fn has_active_key(owner: &str, stream_name: &str) -> Result<bool, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM stream_keys
+        WHERE owner = $1 AND stream_name = $2 AND revoked_at IS NULL)")
+        .bind(owner)
+        .bind(stream_name)
+        .fetch_one(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Creating the new key, the old ones keep working until they are revoked
+// Returns the key, it is not possible to get it back afterwards
+pub async fn create_key(owner: &str, stream_name: &str) -> Result<String, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+    let key = generate_key();
+
+    sqlx::query(
+        "INSERT INTO stream_keys (id, owner, stream_name, key_hash, created_at)
+        VALUES ($1, $2, $3, $4, NOW());")
+        .bind(Uuid::new_v4())
+        .bind(owner)
+        .bind(stream_name)
+        .bind(hash_key(&key))
+        .execute(&pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(key)
+}
+
+// Revoking all the keys of the stream
+// Without the owner (for admins) the keys of every owner are revoked
+// Returns the amount of the revoked keys
+pub async fn revoke_keys(owner: Option<&str>, stream_name: &str) -> Result<u64, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query(
+        "UPDATE stream_keys SET revoked_at = NOW()
+        WHERE stream_name = $1 AND ($2::TEXT IS NULL OR owner = $2) AND revoked_at IS NULL")
+        .bind(stream_name)
+        .bind(owner)
+        .execute(&pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(|e| e.to_string())
+}
+
+// Checking the key against the owner and the name of the stream
+pub async fn verify_key(key_hash: &str, owner: &str, stream_name: &str) -> Result<bool, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM stream_keys
+        WHERE key_hash = $1 AND owner = $2 AND stream_name = $3 AND revoked_at IS NULL)")
+        .bind(key_hash)
+        .bind(owner)
+        .bind(stream_name)
+        .fetch_one(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+
+
+// The credentials of the ingest requests: the usual access token of the user
+// or the stream key (in X-Stream-Key or as the bearer token)
+// The key is checked against the stream later, the extractor only reads it
+
+pub enum IngestAuth {
+    User(AuthenticatedUser),
+    StreamKey(String),
+}
+
+impl FromRequest for IngestAuth {
+    type Error = Error;
+    type Future = Ready<Result<IngestAuth, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let header = |name| req.headers().get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(key) = header(STREAM_KEY_HEADER) {
+            return ready(Ok(IngestAuth::StreamKey(key.trim().to_string())));
+        }
+
+        if let Some(token) = header("Authorization").and_then(|auth| auth.strip_prefix("Bearer ")) {
+            if token.starts_with(STREAM_KEY_PREFIX) {
+                return ready(Ok(IngestAuth::StreamKey(token.trim().to_string())));
+            }
+
+            if let Ok(claims) = decode_jwt(token) {
+                return ready(Ok(IngestAuth::User(AuthenticatedUser { username: claims.sub })));
+            }
+        }
+
+        ready(Err(actix_web::error::ErrorUnauthorized("Invalid or missing token or stream key")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_random_and_hashed() {
+        let key = generate_key();
+        assert!(key.starts_with(STREAM_KEY_PREFIX));
+        assert_eq!(key.len(), STREAM_KEY_PREFIX.len() + 64);
+        assert_ne!(key, generate_key(), "KEYS ARE RANDOM");
+
+        assert_eq!(hash_key("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(hash_key(&key), key);
+    }
+}
@@ -0,0 +1,238 @@
+// A file for the listeners, which can not keep up with the stream
+// The listener reads the ring of the stream forward, so the unread part of the
+// ring is its queue. When the queue is longer than the allowed lag, the policy
+// of the listener decides: the oldest chunks are dropped, the listener jumps
+// to the live edge, or the listener is disconnected
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_coding::Codec;
+use crate::streamer::Chunk;
+
+// How far behind the live edge the listener could fall by default
+// LISTENER_MAX_LAG_MS changes it, the listener could ask for its own with ?max_lag_ms=
+
+pub const DEFAULT_MAX_LAG: Duration = Duration::from_secs(15);
+
+// The lag is always allowed to be a little longer than the pre-roll,
+// otherwise a new listener would be cut right after joining
+const MIN_LAG_MARGIN: Duration = Duration::from_secs(1);
+
+// The part of the full ring the lag could take, the policy fires
+// before the ring drops the chunks the listener has not read yet
+const MAX_RING_SHARE: f64 = 0.75;
+
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    #[default]
+    DropOldest,
+    SkipToLive,
+    Disconnect,
+}
+
+// What the listener should do with the chunks it has just read
+#[derive(Debug, PartialEq, Eq)]
+pub enum LagAction {
+    Keep,
+    // The amount of the chunks to skip from the start of the read
+    Drop(usize),
+    Disconnect,
+}
+
+// The policy of a single listener and the lag it is allowed
+#[derive(Clone, Copy, Debug)]
+pub struct Backpressure {
+    pub policy: LagPolicy,
+    pub max_lag: Duration,
+}
+
+impl Backpressure {
+    // The settings of the listener, the missing ones are taken from the environment
+    // The lag is never shorter than the pre-roll of the stream
+
+    pub fn new(policy: Option<LagPolicy>, max_lag: Option<Duration>, preroll: Duration) -> Self {
+        let env_max_lag = std::env::var("LISTENER_MAX_LAG_MS").ok()
+            .and_then(|ms| ms.trim().parse::<u64>().ok())
+            .map(Duration::from_millis);
+
+        let env_policy = std::env::var("LISTENER_LAG_POLICY").ok()
+            .and_then(|policy| serde_json::from_value(serde_json::Value::String(policy)).ok());
+
+        Backpressure {
+            policy: policy.or(env_policy).unwrap_or_default(),
+            max_lag: max_lag.or(env_max_lag)
+                .unwrap_or(DEFAULT_MAX_LAG)
+                .max(preroll + MIN_LAG_MARGIN),
+        }
+    }
+
+    // The lag could not be longer than the ring, the listener past it misses the chunks
+    // ring_span is the time the full ring covers, None until the ring is full
+
+    pub fn within(&self, ring_span: Option<Duration>) -> Self {
+        Backpressure {
+            policy: self.policy,
+            max_lag: match ring_span {
+                Some(span) => self.max_lag.min(span.mul_f64(MAX_RING_SHARE)),
+                None => self.max_lag,
+            },
+        }
+    }
+
+    // Checking the chunks the listener has read, they go up to the live edge
+    // The lag is the time between the push of the first chunk and the newest one
+    // After the drop the listener starts from a chunk the player could decode
+
+    pub fn check(&self, chunks: &[Chunk], codec: Codec) -> LagAction {
+        let newest = match chunks.last() {
+            Some(chunk) => chunk.timestamp,
+            None => return LagAction::Keep,
+        };
+        let lag_of = |chunk: &Chunk| newest.duration_since(chunk.timestamp).unwrap_or_default();
+
+        if lag_of(&chunks[0]) <= self.max_lag {
+            return LagAction::Keep;
+        }
+
+        let decodable = |chunk: &Chunk| codec.is_decodable_start(&chunk.data);
+
+        let start = match self.policy {
+            LagPolicy::Disconnect => return LagAction::Disconnect,
+            LagPolicy::DropOldest => chunks.iter()
+                .position(|chunk| lag_of(chunk) <= self.max_lag && decodable(chunk)),
+            LagPolicy::SkipToLive => chunks.iter().rposition(decodable),
+        };
+
+        LagAction::Drop(start.unwrap_or(chunks.len()))
+    }
+}
+
+
+
+// How often every policy has fired on the server, and how many chunks it dropped
+
+struct PolicyCounter {
+    fired: AtomicU64,
+    dropped_chunks: AtomicU64,
+}
+
+impl PolicyCounter {
+    const fn new() -> Self {
+        PolicyCounter { fired: AtomicU64::new(0), dropped_chunks: AtomicU64::new(0) }
+    }
+
+    fn snapshot(&self) -> PolicyMetrics {
+        PolicyMetrics {
+            fired: self.fired.load(Ordering::Relaxed),
+            dropped_chunks: self.dropped_chunks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static DROP_OLDEST: PolicyCounter = PolicyCounter::new();
+static SKIP_TO_LIVE: PolicyCounter = PolicyCounter::new();
+static DISCONNECT: PolicyCounter = PolicyCounter::new();
+
+fn counter(policy: LagPolicy) -> &'static PolicyCounter {
+    match policy {
+        LagPolicy::DropOldest => &DROP_OLDEST,
+        LagPolicy::SkipToLive => &SKIP_TO_LIVE,
+        LagPolicy::Disconnect => &DISCONNECT,
+    }
+}
+
+// Counting the policy, which has fired, with the chunks the listener has not got
+pub fn record(policy: LagPolicy, dropped_chunks: usize) {
+    let counter = counter(policy);
+    counter.fired.fetch_add(1, Ordering::Relaxed);
+    counter.dropped_chunks.fetch_add(dropped_chunks as u64, Ordering::Relaxed);
+}
+
+#[derive(Debug, Serialize)]
+pub struct PolicyMetrics {
+    pub fired: u64,
+    pub dropped_chunks: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LagMetrics {
+    pub drop_oldest: PolicyMetrics,
+    pub skip_to_live: PolicyMetrics,
+    pub disconnect: PolicyMetrics,
+}
+
+pub fn metrics() -> LagMetrics {
+    LagMetrics {
+        drop_oldest: DROP_OLDEST.snapshot(),
+        skip_to_live: SKIP_TO_LIVE.snapshot(),
+        disconnect: DISCONNECT.snapshot(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+    use std::time::SystemTime;
+
+    // A chunk pushed the given amount of seconds after the first one
+    fn chunk(seq: u64, secs: u64, data: &'static [u8]) -> Chunk {
+        Chunk {
+            seq,
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            position: None,
+            segment: 0,
+            data: Bytes::from_static(data),
+        }
+    }
+
+    #[test]
+    fn test_policies_on_lagging_listener() {
+        let frame: &'static [u8] = &[0xff, 0xf8];
+        let chunks = vec![
+            chunk(0, 0, frame),
+            chunk(1, 10, frame),
+            chunk(2, 20, &[0x00]),
+            chunk(3, 25, frame),
+            chunk(4, 30, frame),
+        ];
+        let backpressure = |policy| Backpressure { policy, max_lag: Duration::from_secs(12) };
+
+        assert_eq!(backpressure(LagPolicy::DropOldest).check(&chunks[2..], Codec::Flac),
+                   LagAction::Keep, "LAG IS WITHIN THE LIMIT");
+
+        // 20 s of lag is kept by the time, but the chunk of 20 s is not decodable
+        assert_eq!(backpressure(LagPolicy::DropOldest).check(&chunks, Codec::Flac), LagAction::Drop(3));
+        assert_eq!(backpressure(LagPolicy::SkipToLive).check(&chunks, Codec::Flac), LagAction::Drop(4));
+        assert_eq!(backpressure(LagPolicy::Disconnect).check(&chunks, Codec::Flac), LagAction::Disconnect);
+
+        let before = metrics().skip_to_live.fired;
+        record(LagPolicy::SkipToLive, 4);
+        assert!(metrics().skip_to_live.fired > before);
+    }
+
+    #[test]
+    fn test_lag_is_longer_than_preroll() {
+        let backpressure = Backpressure::new(Some(LagPolicy::Disconnect), Some(Duration::from_secs(1)),
+                                             Duration::from_secs(3));
+        assert_eq!(backpressure.max_lag, Duration::from_secs(4));
+        assert_eq!(backpressure.policy, LagPolicy::Disconnect);
+    }
+
+    #[test]
+    fn test_lag_is_shorter_than_ring() {
+        let backpressure = Backpressure { policy: LagPolicy::DropOldest, max_lag: Duration::from_secs(60) };
+
+        assert_eq!(backpressure.within(None).max_lag, Duration::from_secs(60), "THE RING IS NOT FULL YET");
+        assert_eq!(backpressure.within(Some(Duration::from_secs(20))).max_lag, Duration::from_secs(15));
+        assert_eq!(backpressure.within(Some(Duration::from_secs(100))).max_lag, Duration::from_secs(60));
+    }
+}
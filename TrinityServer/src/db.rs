@@ -26,6 +26,91 @@ pub async fn init_db() -> Option<PgPool> {
 }
 
 
+// Creating the tables the server owns, in case they do not exist yet
+// Called once on the start of the server
+
+pub async fn create_tables() {
+    let pool = match init_db().await {
+        Some(pool) => pool,
+        None => {
+            warn!("The tables were not checked, the DB is not available");
+            return;
+        }
+    };
+
+    let tables = [
+        "CREATE TABLE IF NOT EXISTS recordings (
+            id UUID PRIMARY KEY,
+            stream_name TEXT NOT NULL,
+            codec TEXT NOT NULL,
+            path TEXT NOT NULL,
+            size_bytes BIGINT NOT NULL,
+            duration_ms BIGINT,
+            started_at TIMESTAMPTZ NOT NULL,
+            ended_at TIMESTAMPTZ NOT NULL
+        );",
+        "CREATE TABLE IF NOT EXISTS tracks (
+            id UUID PRIMARY KEY,
+            owner TEXT NOT NULL,
+            title TEXT,
+            artist TEXT,
+            album TEXT,
+            genre TEXT,
+            original_format TEXT NOT NULL,
+            path TEXT NOT NULL,
+            size_bytes BIGINT NOT NULL,
+            duration_ms BIGINT NOT NULL,
+            sample_rate INTEGER NOT NULL,
+            channels INTEGER NOT NULL,
+            bits_per_sample INTEGER NOT NULL,
+            uploaded_at TIMESTAMPTZ NOT NULL
+        );",
+        "CREATE TABLE IF NOT EXISTS stream_keys (
+            id UUID PRIMARY KEY,
+            owner TEXT NOT NULL,
+            stream_name TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            created_at TIMESTAMPTZ NOT NULL,
+            revoked_at TIMESTAMPTZ
+        );",
+        "CREATE TABLE IF NOT EXISTS audience_stats (
+            id UUID PRIMARY KEY,
+            stream_name TEXT NOT NULL,
+            owner TEXT NOT NULL,
+            period_start TIMESTAMPTZ NOT NULL,
+            period_end TIMESTAMPTZ NOT NULL,
+            joins BIGINT NOT NULL,
+            leaves BIGINT NOT NULL,
+            listen_ms BIGINT NOT NULL,
+            peak_listeners INTEGER NOT NULL
+        );",
+        "CREATE INDEX IF NOT EXISTS audience_stats_stream
+            ON audience_stats (stream_name, owner, period_start);",
+        "CREATE TABLE IF NOT EXISTS follows (
+            follower TEXT NOT NULL,
+            followed TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (follower, followed)
+        );",
+        "ALTER TABLE tracks ADD COLUMN IF NOT EXISTS loudness_lufs DOUBLE PRECISION;",
+        "ALTER TABLE tracks ADD COLUMN IF NOT EXISTS true_peak_dbtp DOUBLE PRECISION;",
+        "CREATE TABLE IF NOT EXISTS loudness_preferences (
+            username TEXT PRIMARY KEY,
+            normalize BOOLEAN NOT NULL,
+            target_lufs DOUBLE PRECISION NOT NULL,
+            true_peak_dbtp DOUBLE PRECISION NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL
+        );",
+    ];
+
+    for table in tables {
+        if let Err(e) = sqlx::query(table).execute(&pool).await {
+            error!("Failed to create the table: {}", e);
+        }
+    }
+}
+
+
 async fn get_db_url() -> (String) {
     // This closure is needed to reduce redundant complexity within the code 
     // Returns either on success the desired value OR the default one    
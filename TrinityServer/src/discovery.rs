@@ -0,0 +1,216 @@
+// A file for the discovery of the live streams, the front page of the service
+// The streams are picked at random, by their trending (the growth of the
+// listeners) or with the streamers the user follows going first
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::collections::HashSet;
+
+use actix_web::HttpResponse;
+use log::error;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::auth_logic::models::AuthenticatedUser;
+use crate::db::init_db;
+use crate::streamer::{ActiveStreams, StreamListing};
+
+// The amount of the streams, unless the client asks for another one
+pub const DEFAULT_DISCOVERY_LIMIT: usize = 10;
+
+// The most streams the client could ask for at once
+pub const MAX_DISCOVERY_LIMIT: usize = 50;
+
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryMode {
+    #[default]
+    Random,
+    Trending,
+    Following,
+}
+
+// The query of GET /discover
+// ?mode=random|trending|following&limit=10
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DiscoveryQuery {
+    #[serde(default)]
+    pub mode: DiscoveryMode,
+    pub limit: Option<usize>,
+}
+
+
+
+// A random sample of the streams
+
+pub fn random_streams<R: Rng>(mut streams: Vec<StreamListing>, limit: usize, rng: &mut R) -> Vec<StreamListing> {
+    streams.shuffle(rng);
+    streams.truncate(limit);
+    streams
+}
+
+// The streams, which are gaining the listeners the fastest
+// The bigger audience goes first, when the growth is the same
+
+pub fn trending_streams(mut streams: Vec<StreamListing>, limit: usize) -> Vec<StreamListing> {
+    streams.sort_by(|a, b| b.listener_growth.cmp(&a.listener_growth)
+        .then_with(|| b.listeners.cmp(&a.listeners))
+        .then_with(|| a.name.cmp(&b.name)));
+    streams.truncate(limit);
+    streams
+}
+
+// The streams of the followed streamers first (the biggest audience first),
+// the rest of the place is filled with the random ones
+
+pub fn followed_first<R: Rng>(streams: Vec<StreamListing>, followed: &HashSet<String>,
+                              limit: usize, rng: &mut R) -> Vec<StreamListing> {
+    let (mut picked, others): (Vec<_>, Vec<_>) = streams.into_iter()
+        .partition(|info| followed.contains(&info.owner));
+
+    picked.sort_by(|a, b| b.listeners.cmp(&a.listeners).then_with(|| a.name.cmp(&b.name)));
+    picked.truncate(limit);
+
+    let left = limit - picked.len();
+    picked.extend(random_streams(others, left, rng));
+    picked
+}
+
+
+
+// The streamers the user follows
+
+pub async fn followed_users(follower: &str) -> Result<HashSet<String>, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_scalar::<_, String>("SELECT followed FROM follows WHERE follower = $1")
+        .bind(follower)
+        .fetch_all(&pool)
+        .await
+        .map(|users| users.into_iter().collect())
+        .map_err(|e| e.to_string())
+}
+
+// Following the streamer, returns false in case there is no such user
+
+pub async fn follow(follower: &str, followed: &str) -> Result<bool, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query(
+        "INSERT INTO follows (follower, followed, created_at)
+        SELECT $1, name, NOW() FROM users WHERE name = $2
+        ON CONFLICT DO NOTHING;")
+        .bind(follower)
+        .bind(followed)
+        .execute(&pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE name = $1)")
+        .bind(followed)
+        .fetch_one(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn unfollow(follower: &str, followed: &str) -> Result<(), String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query("DELETE FROM follows WHERE follower = $1 AND followed = $2")
+        .bind(follower)
+        .bind(followed)
+        .execute(&pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+
+
+// The discovery itself, the following mode needs the user to be logged in
+// The anonymous listeners get the random streams instead
+
+pub async fn discover(stream_list: &ActiveStreams, query: &DiscoveryQuery,
+                      user: Option<&AuthenticatedUser>) -> HttpResponse {
+    let limit = query.limit.unwrap_or(DEFAULT_DISCOVERY_LIMIT).clamp(1, MAX_DISCOVERY_LIMIT);
+    let streams = stream_list.stream_listings();
+
+    let picked = match (query.mode, user) {
+        (DiscoveryMode::Trending, _) => trending_streams(streams, limit),
+        (DiscoveryMode::Following, Some(user)) => {
+            let followed = match followed_users(&user.username).await {
+                Ok(followed) => followed,
+                Err(e) => {
+                    error!("Failed to get the followed users of {}: {}", user.username, e);
+                    return HttpResponse::InternalServerError().body("Failed to get the followed users");
+                }
+            };
+            followed_first(streams, &followed, limit, &mut rand::thread_rng())
+        }
+        _ => random_streams(streams, limit, &mut rand::thread_rng()),
+    };
+
+    HttpResponse::Ok().json(picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::audio_coding::{Codec, Rendition};
+
+    fn info(name: &str, owner: &str, listeners: usize, listener_growth: i64) -> StreamListing {
+        StreamListing {
+            name: name.to_string(),
+            title: None,
+            description: None,
+            owner: owner.to_string(),
+            tags: Vec::new(),
+            codec: Codec::Opus,
+            sample_rate: Some(48000),
+            started_at: Utc::now(),
+            listeners,
+            peak_listeners: listeners,
+            listener_growth,
+            cover_art_url: None,
+            recording: false,
+            renditions: vec![Rendition::OpusHigh],
+        }
+    }
+
+    fn names(streams: &[StreamListing]) -> Vec<&str> {
+        streams.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_discovery_orders() {
+        let streams = vec![
+            info("a", "anna", 50, 2),
+            info("b", "bob", 10, 8),
+            info("c", "carl", 3, 8),
+            info("d", "anna", 70, -5),
+        ];
+        let mut rng = StdRng::seed_from_u64(7);
+
+        assert_eq!(names(&trending_streams(streams.clone(), 3)), vec!["b", "c", "a"],
+                   "FASTEST GROWTH FIRST");
+
+        let random = random_streams(streams.clone(), 2, &mut rng);
+        assert_eq!(random.len(), 2);
+        assert_ne!(random[0].name, random[1].name, "NO STREAM TWICE");
+
+        let followed = HashSet::from(["anna".to_string()]);
+        let picked = followed_first(streams.clone(), &followed, 3, &mut rng);
+        assert_eq!(names(&picked[..2]), vec!["d", "a"], "FOLLOWED STREAMERS FIRST");
+        assert!(picked[2].owner != "anna");
+
+        assert_eq!(names(&followed_first(streams, &followed, 1, &mut rng)), vec!["d"]);
+    }
+}
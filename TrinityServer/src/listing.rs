@@ -0,0 +1,185 @@
+// A file for the listing of the live streams
+// The streams are filtered by their category (tag), owner and text,
+// sorted by the listeners or by the start time and split into pages
+
+// Trinitypeer, 2025, by Trinitycore
+
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::streamer::{ActiveStreams, StreamListing};
+
+// The page size, unless the client asks for another one
+pub const DEFAULT_PER_PAGE: usize = 20;
+
+// The biggest page the client could ask for
+pub const MAX_PER_PAGE: usize = 100;
+
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamSort {
+    #[default]
+    Listeners,
+    StartedAt,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+// The query of GET /streams
+// ?category=jazz&owner=name&q=text&sort=listeners|started_at&order=asc|desc&page=1&per_page=20
+// The pages start from 1
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    pub category: Option<String>,
+    pub owner: Option<String>,
+    pub q: Option<String>,
+    #[serde(default)]
+    pub sort: StreamSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamPage {
+    pub streams: Vec<StreamListing>,
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+
+
+// Whether the stream fits the filters of the query
+// The category matches one of the tags, the text is searched in the name,
+// the title, the description and the tags, all of them case-insensitive
+
+fn matches(info: &StreamListing, query: &StreamQuery) -> bool {
+    if let Some(category) = query.category.as_deref().map(str::trim).filter(|c| !c.is_empty()) {
+        if !info.tags.iter().any(|tag| tag.eq_ignore_ascii_case(category)) {
+            return false;
+        }
+    }
+
+    if let Some(owner) = query.owner.as_deref().map(str::trim).filter(|o| !o.is_empty()) {
+        if info.owner != owner {
+            return false;
+        }
+    }
+
+    if let Some(text) = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let text = text.to_lowercase();
+        let found = std::iter::once(&info.name)
+            .chain(info.title.as_ref())
+            .chain(info.description.as_ref())
+            .chain(info.tags.iter())
+            .any(|field| field.to_lowercase().contains(&text));
+
+        if !found {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Filtering, sorting and cutting out the asked page
+// The streams with the same key are ordered by the name, so the pages are stable
+
+pub fn list_streams(mut streams: Vec<StreamListing>, query: &StreamQuery) -> StreamPage {
+    streams.retain(|info| matches(info, query));
+
+    streams.sort_by(|a, b| {
+        let ordering = match query.sort {
+            StreamSort::Listeners => a.listeners.cmp(&b.listeners),
+            StreamSort::StartedAt => a.started_at.cmp(&b.started_at),
+        };
+        let ordering = match query.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+        ordering.then_with(|| a.name.cmp(&b.name))
+    });
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let total = streams.len();
+
+    let streams = streams.into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+
+    StreamPage { streams, total, page, per_page }
+}
+
+pub fn list(stream_list: &ActiveStreams, query: &StreamQuery) -> HttpResponse {
+    HttpResponse::Ok().json(list_streams(stream_list.stream_listings(), query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    use crate::audio_coding::{Codec, Rendition};
+
+    fn info(name: &str, owner: &str, tags: &[&str], listeners: usize, age_secs: i64) -> StreamListing {
+        StreamListing {
+            name: name.to_string(),
+            title: Some(format!("{} live", name)),
+            description: None,
+            owner: owner.to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            codec: Codec::Flac,
+            sample_rate: Some(44100),
+            started_at: Utc::now() - Duration::seconds(age_secs),
+            listeners,
+            peak_listeners: listeners,
+            listener_growth: 0,
+            cover_art_url: None,
+            recording: false,
+            renditions: vec![Rendition::Lossless],
+        }
+    }
+
+    #[test]
+    fn test_filter_sort_and_paginate() {
+        let streams = vec![
+            info("morning", "anna", &["jazz"], 5, 300),
+            info("night", "bob", &["jazz", "ambient"], 12, 100),
+            info("rock-hour", "anna", &["rock"], 30, 200),
+        ];
+        let names = |page: &StreamPage| page.streams.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+
+        let page = list_streams(streams.clone(), &StreamQuery::default());
+        assert_eq!(names(&page), vec!["rock-hour", "night", "morning"], "MOST LISTENERS FIRST");
+        assert_eq!(page.total, 3);
+
+        let query = StreamQuery { category: Some("Jazz".to_string()), ..Default::default() };
+        assert_eq!(names(&list_streams(streams.clone(), &query)), vec!["night", "morning"]);
+
+        let query = StreamQuery { owner: Some("anna".to_string()), sort: StreamSort::StartedAt,
+                                  order: SortOrder::Asc, ..Default::default() };
+        assert_eq!(names(&list_streams(streams.clone(), &query)), vec!["morning", "rock-hour"],
+                   "OLDEST FIRST");
+
+        let query = StreamQuery { q: Some("HOUR".to_string()), ..Default::default() };
+        assert_eq!(names(&list_streams(streams.clone(), &query)), vec!["rock-hour"]);
+
+        let query = StreamQuery { page: Some(2), per_page: Some(2), ..Default::default() };
+        let page = list_streams(streams, &query);
+        assert_eq!(names(&page), vec!["morning"], "LAST PAGE HAS THE REST");
+        assert_eq!(page.total, 3);
+    }
+}
@@ -4,6 +4,18 @@ mod audio_coding;
 mod server;
 mod db;
 mod auth_logic;
+mod manifests;
+mod rtc;
+mod recorder;
+mod tracks;
+mod listing;
+mod audience;
+mod discovery;
+mod backpressure;
+mod adaptive;
+mod normalization;
+mod analysis;
+mod shared;
 use dotenv::dotenv;
 
 use log::{error, info, warn};
@@ -27,6 +39,17 @@ async fn main() {
     // Initialize the fragment length for the live stream
     let fragment_len = 1;
 
+    // Making sure the tables of the server are there
+    db::create_tables().await;
+
+    // The streams nobody pushes to anymore (or only the silence) are ended in the background
+    let idle_timeout = streamer::idle_timeout();
+    if idle_timeout.is_some() || analysis::silence_timeout().is_some() {
+        tokio::spawn(streamer::run_reaper(streams.clone(), idle_timeout));
+    }
+
+    // The audience of the live streams is written for the dashboards
+    tokio::spawn(audience::run_recorder(streams.clone()));
 
 
     // Initialize the HTTP Server
@@ -0,0 +1,47 @@
+// Manifests for the standard players, built from the chunk history
+// of the live streams, so the streams could be played not only by our client
+// Both HLS and DASH point to the same fragmented MP4 segments (fmp4.rs)
+
+pub(crate) mod hls;
+pub(crate) mod dash;
+pub(crate) mod fmp4;
+
+use std::time::SystemTime;
+
+use crate::audio_coding::{AudioFormat, Codec};
+use crate::streamer::{ActiveStreams, ChunkFeed};
+
+
+
+// Everything the manifests and the segments are built from
+// The stream, which has just ended, is still there for a while,
+// so the players get the last segments and the end of the playlist
+
+#[derive(Clone, Debug)]
+pub struct PackagedStream {
+    pub feed: ChunkFeed,
+    pub codec: Codec,
+    pub format: Option<AudioFormat>,
+    pub started_at: SystemTime,
+    pub ended: bool,
+}
+
+pub async fn packaged_stream(stream_list: &ActiveStreams, stream_name: &str) -> Option<PackagedStream> {
+    if let Some(stream) = stream_list.get_stream(stream_name).await {
+        return Some(PackagedStream {
+            feed: stream.feed(),
+            codec: stream.codec(),
+            format: stream.format(),
+            started_at: stream.started_at(),
+            ended: stream.feed().is_closed(),
+        });
+    }
+
+    stream_list.ended_stream(stream_name).map(|ended| PackagedStream {
+        feed: ended.feed,
+        codec: ended.codec,
+        format: ended.format,
+        started_at: ended.started_at,
+        ended: true,
+    })
+}
@@ -0,0 +1,139 @@
+// A file intended for the MPEG-DASH output of the live streams
+// The manifest is a dynamic (live profile) MPD with a SegmentTemplate,
+// the segments are the same fragmented MP4 ones, which are served for HLS
+// Trinitypeer, 2025, by Trinitycore
+
+use actix_web::HttpResponse;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::warn;
+use std::time::{Duration, SystemTime};
+
+use super::fmp4::{segments, track_of, Segment};
+use super::packaged_stream;
+use crate::audio_coding::{Codec, Rendition};
+use crate::streamer::{ActiveStreams, SEGMENT_DURATION};
+
+// How often the player should refetch the manifest
+const MINIMUM_UPDATE_PERIOD: Duration = Duration::from_secs(2);
+
+
+
+fn iso_datetime(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn iso_duration(duration: Duration) -> String {
+    format!("PT{:.3}S", duration.as_secs_f64())
+}
+
+// Building the MPD from the segments of the stream
+// The segments are a bit longer than SEGMENT_DURATION, so instead of a fixed
+// duration the SegmentTimeline lists every one of them in the media time of the track
+// The manifest of the ended stream gets its duration and is not reloaded anymore
+
+pub fn render_mpd(started_at: SystemTime, codec: Codec, timescale: u32, segments: &[Segment],
+                  ended: bool, now: SystemTime) -> String {
+    let mut timeline = String::new();
+
+    for segment in segments {
+        timeline.push_str(&format!("          <S t=\"{}\" d=\"{}\"/>\n", segment.start, segment.duration.max(1)));
+    }
+
+    let seconds = |time: u64| Duration::from_secs_f64(time as f64 / timescale.max(1) as f64);
+    let buffer_depth = seconds(segments.iter().map(|segment| segment.duration).sum());
+
+    let updates = if ended {
+        let end = segments.last().map_or(0, |segment| segment.start + segment.duration);
+        format!("mediaPresentationDuration=\"{}\"", iso_duration(seconds(end)))
+    } else {
+        format!("minimumUpdatePeriod=\"{}\"", iso_duration(MINIMUM_UPDATE_PERIOD))
+    };
+
+    let start_number = segments.first().map(|s| s.number).unwrap_or(0);
+
+    format!(
+r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="{start}" publishTime="{publish}" {updates} timeShiftBufferDepth="{depth}" minBufferTime="{min_buffer}" suggestedPresentationDelay="{delay}">
+  <Period id="0" start="PT0S">
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="{codec}" segmentAlignment="true">
+      <Representation id="audio" bandwidth="{bandwidth}">
+        <SegmentTemplate timescale="{timescale}" initialization="init.mp4" media="seg/$Number$" startNumber="{start_number}">
+          <SegmentTimeline>
+{timeline}          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>
+"#,
+        start = iso_datetime(started_at),
+        publish = iso_datetime(now),
+        updates = updates,
+        depth = iso_duration(buffer_depth),
+        min_buffer = iso_duration(SEGMENT_DURATION * 2),
+        delay = iso_duration(crate::streamer::LATE_JOIN_DELAY),
+        codec = match codec { Codec::Flac => "flac", Codec::Opus => "opus" },
+        bandwidth = Rendition::of_codec(codec).bitrate(),
+        timescale = timescale,
+        start_number = start_number,
+        timeline = timeline,
+    )
+}
+
+
+
+// Responding with the manifest of the stream
+// The init and media segments are served next to the HLS playlist
+
+pub async fn manifest(stream_list: &ActiveStreams, stream_name: &str) -> HttpResponse {
+    let stream = match packaged_stream(stream_list, stream_name).await {
+        Some(stream) => stream,
+        None => {
+            warn!("DASH manifest requested for unknown stream {}", stream_name);
+            return HttpResponse::NotFound().body("Stream not found");
+        }
+    };
+
+    let chunks = stream.feed.history().await;
+    let timescale = track_of(stream.codec, stream.format, &chunks).map_or(1000, |track| track.timescale());
+    let segments = segments(stream.codec, timescale, &chunks, stream.ended);
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-cache"))
+        .content_type("application/dash+xml")
+        .body(render_mpd(stream.started_at, stream.codec, timescale, &segments, stream.ended, SystemTime::now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streamer::{Chunk, ChunkRing};
+
+    #[test]
+    fn test_mpd_timeline_starts_at_oldest_segment() {
+        let started_at = SystemTime::now();
+
+        // 20 ms packets, the ring has already lost the first one
+        let mut ring = ChunkRing::new(300);
+        for i in 0..301u64 {
+            ring.push_at(vec![0xfc], Some(Duration::from_millis(20 * i)));
+        }
+        let chunks: Vec<Chunk> = ring.iter().cloned().collect();
+
+        let live = render_mpd(started_at, Codec::Opus, 48000, &segments(Codec::Opus, 48000, &chunks, false),
+                              false, SystemTime::now());
+        assert!(live.contains("type=\"dynamic\""));
+        assert!(live.contains("initialization=\"init.mp4\""), "THE INIT SEGMENT IS REFERENCED");
+        assert!(live.contains("startNumber=\"1\""), "THE FIRST SEGMENT IS INCOMPLETE");
+        assert_eq!(live.matches("<S ").count(), 2, "THE NEWEST SEGMENT IS STILL GROWING");
+        assert!(live.contains(&format!("<S t=\"{}\" d=\"{}\"/>", 96000, 96000)));
+        assert!(live.contains("minimumUpdatePeriod"));
+        assert!(live.contains(&format!("availabilityStartTime=\"{}\"", iso_datetime(started_at))));
+
+        let ended = render_mpd(started_at, Codec::Opus, 48000, &segments(Codec::Opus, 48000, &chunks, true),
+                               true, SystemTime::now());
+        assert_eq!(ended.matches("<S ").count(), 3);
+        assert!(ended.contains("mediaPresentationDuration=\"PT6.020S\""));
+        assert!(!ended.contains("minimumUpdatePeriod"));
+    }
+}
@@ -0,0 +1,460 @@
+// A file for the fragmented MP4 (CMAF) segments of the HLS and DASH outputs
+// The init segment describes the audio track (fLaC with dfLa, or Opus with dOps),
+// every media segment is a moof + mdat with a few seconds of the chunks
+// FLAC chunks are complete FLAC streams, so their frames are taken out of them
+// and renumbered, Opus chunks are single packets and go as they are
+// Trinitypeer, 2025, by Trinitycore
+
+use actix_web::HttpResponse;
+use actix_web::web;
+use log::{error, warn};
+use std::time::Duration;
+
+use crate::audio_coding::opus::OPUS_GRANULE_RATE;
+use crate::audio_coding::{decode_flac, opus_packet_samples, read_metadata, renumber_frame,
+                          AudioFormat, Codec, FlacDecodeError, StreamInfo};
+use crate::streamer::{ActiveStreams, Chunk};
+
+use super::packaged_stream;
+
+// The only track of the segments
+const TRACK_ID: u32 = 1;
+
+// The pre-skip of the Opus encoder, the same one OggOpusWriter declares
+const OPUS_PRE_SKIP: u16 = 312;
+
+// The identity matrix of mvhd and tkhd
+const MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+
+
+// The audio track of the segments
+// FLAC keeps the STREAMINFO of the chunks, Opus needs only the channels
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Track {
+    Flac(StreamInfo),
+    Opus { channels: u8, input_sample_rate: u32 },
+}
+
+impl Track {
+    // The media time is counted in the samples of the track
+    pub fn timescale(&self) -> u32 {
+        match self {
+            Track::Flac(info) => info.sample_rate,
+            Track::Opus { .. } => OPUS_GRANULE_RATE,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self {
+            Track::Flac(info) => info.channels,
+            Track::Opus { channels, .. } => *channels as usize,
+        }
+    }
+}
+
+// The track of the stream
+// FLAC takes the STREAMINFO of the first chunk, the length and MD5 are unknown for the live stream
+// Opus takes the channels of the stream format, otherwise of the stereo flag of the first packet
+
+pub fn track_of(codec: Codec, format: Option<AudioFormat>, chunks: &[Chunk]) -> Option<Track> {
+    match codec {
+        Codec::Flac => {
+            let metadata = chunks.iter().find_map(|chunk| read_metadata(&chunk.data).ok())?;
+            Some(Track::Flac(StreamInfo {
+                min_frame_size: 0,
+                max_frame_size: 0,
+                total_samples: 0,
+                md5: [0; 16],
+                ..metadata.info
+            }))
+        }
+        Codec::Opus => {
+            let channels = match format {
+                Some(format) => format.channels.clamp(1, 2) as u8,
+                None => match chunks.first().and_then(|chunk| chunk.data.first()) {
+                    Some(toc) if toc & 0x04 == 0 => 1,
+                    _ => 2,
+                },
+            };
+
+            Some(Track::Opus {
+                channels,
+                input_sample_rate: format.map_or(OPUS_GRANULE_RATE, |format| format.sample_rate),
+            })
+        }
+    }
+}
+
+fn to_timescale(duration: Duration, timescale: u32) -> u64 {
+    (duration.as_secs_f64() * timescale as f64).round() as u64
+}
+
+
+
+// A segment made of the chunks, which got the same segment number in the ring
+// The start and the duration are in the timescale of the track
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub number: u64,
+    pub start: u64,
+    pub duration: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+// Grouping the chunk history into the segments
+// The chunks without the media position are not a part of any segment
+// The oldest segment could have already lost its first chunks, so it is
+// skipped, the newest one is still growing until the stream has ended
+
+pub fn segments(codec: Codec, timescale: u32, chunks: &[Chunk], ended: bool) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let position = match chunk.position {
+            Some(position) => position,
+            None => continue,
+        };
+
+        // The FLAC chunk without the amount of samples lasts until the next one
+        let duration = codec.chunk_duration(&chunk.data)
+            .or_else(|| chunks.get(i + 1)
+                .and_then(|next| next.position)
+                .map(|next| next.saturating_sub(position)))
+            .unwrap_or_default();
+
+        match segments.last_mut() {
+            Some(segment) if segment.number == chunk.segment => {
+                segment.duration += to_timescale(duration, timescale);
+                segment.chunks.push(chunk.clone());
+            }
+            _ => segments.push(Segment {
+                number: chunk.segment,
+                start: to_timescale(position, timescale),
+                duration: to_timescale(duration, timescale),
+                chunks: vec![chunk.clone()],
+            }),
+        }
+    }
+
+    if chunks.first().is_some_and(|chunk| chunk.seq > 0) && !segments.is_empty() {
+        segments.remove(0);
+    }
+
+    if !ended {
+        segments.pop();
+    }
+
+    segments
+}
+
+
+
+// Writing the ISO BMFF boxes
+
+fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(payload.len() + 8);
+    output.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    output.extend_from_slice(kind);
+    output.extend_from_slice(payload);
+    output
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(payload.len() + 4);
+    full.push(version);
+    full.extend_from_slice(&flags.to_be_bytes()[1..]);
+    full.extend_from_slice(payload);
+    mp4_box(kind, &full)
+}
+
+fn matrix() -> Vec<u8> {
+    MATRIX.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+// The sample entry with its codec specific box
+// The sample rate is 16.16, FLAC over 65535 Hz leaves it empty and tells it in dfLa
+
+fn sample_entry(track: &Track) -> Vec<u8> {
+    let (kind, sample_size, config) = match track {
+        Track::Flac(info) => {
+            // The STREAMINFO is the last (and the only) metadata block
+            let mut blocks = vec![0x80, 0, 0, 34];
+            blocks.extend_from_slice(&info.to_bytes());
+            (b"fLaC", info.bits_per_sample as u16, full_box(b"dfLa", 0, 0, &blocks))
+        }
+        Track::Opus { channels, input_sample_rate } => {
+            let mut ops = vec![0, *channels];
+            ops.extend_from_slice(&OPUS_PRE_SKIP.to_be_bytes());
+            ops.extend_from_slice(&input_sample_rate.to_be_bytes());
+            ops.extend_from_slice(&0i16.to_be_bytes());
+            ops.push(0);
+            (b"Opus", 16, mp4_box(b"dOps", &ops))
+        }
+    };
+
+    let rate = track.timescale();
+    let mut entry = vec![0u8; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes());
+    entry.extend_from_slice(&[0u8; 8]);
+    entry.extend_from_slice(&(track.channels() as u16).to_be_bytes());
+    entry.extend_from_slice(&sample_size.to_be_bytes());
+    entry.extend_from_slice(&[0u8; 4]);
+    entry.extend_from_slice(&(if rate <= 0xffff { rate << 16 } else { 0 }).to_be_bytes());
+    entry.extend_from_slice(&config);
+
+    mp4_box(kind, &entry)
+}
+
+// The init segment, ftyp and moov with the empty sample tables
+// The samples themselves are in the fragments, as mvex says
+
+pub fn init_segment(track: &Track) -> Vec<u8> {
+    let timescale = track.timescale().to_be_bytes();
+
+    let ftyp = mp4_box(b"ftyp", &[b"iso6".as_slice(), &0u32.to_be_bytes(), b"iso6", b"cmfc", b"mp41"].concat());
+
+    let mvhd = full_box(b"mvhd", 0, 0, &[
+        &[0u8; 8][..], &timescale, &[0u8; 4], &0x00010000u32.to_be_bytes(), &0x0100u16.to_be_bytes(),
+        &[0u8; 10], &matrix(), &[0u8; 24], &(TRACK_ID + 1).to_be_bytes(),
+    ].concat());
+
+    // Enabled and in the movie
+    let tkhd = full_box(b"tkhd", 0, 0x000003, &[
+        &[0u8; 8][..], &TRACK_ID.to_be_bytes(), &[0u8; 4], &[0u8; 4], &[0u8; 8], &[0u8; 4],
+        &0x0100u16.to_be_bytes(), &[0u8; 2], &matrix(), &[0u8; 8],
+    ].concat());
+
+    // The language is "und" packed into 5 bits per letter
+    let mdhd = full_box(b"mdhd", 0, 0, &[
+        &[0u8; 8][..], &timescale, &[0u8; 4], &0x55c4u16.to_be_bytes(), &[0u8; 2],
+    ].concat());
+    let hdlr = full_box(b"hdlr", 0, 0, &[&[0u8; 4][..], b"soun", &[0u8; 12], b"SoundHandler\0"].concat());
+
+    let dref = full_box(b"dref", 0, 0, &[&1u32.to_be_bytes()[..], &full_box(b"url ", 0, 1, &[])].concat());
+    let stbl = mp4_box(b"stbl", &[
+        full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes()[..], &sample_entry(track)].concat()),
+        full_box(b"stts", 0, 0, &[0u8; 4]),
+        full_box(b"stsc", 0, 0, &[0u8; 4]),
+        full_box(b"stsz", 0, 0, &[0u8; 8]),
+        full_box(b"stco", 0, 0, &[0u8; 4]),
+    ].concat());
+    let minf = mp4_box(b"minf", &[
+        full_box(b"smhd", 0, 0, &[0u8; 4]),
+        mp4_box(b"dinf", &dref),
+        stbl,
+    ].concat());
+
+    let trak = mp4_box(b"trak", &[tkhd, mp4_box(b"mdia", &[mdhd, hdlr, minf].concat())].concat());
+    let trex = full_box(b"trex", 0, 0, &[
+        &TRACK_ID.to_be_bytes()[..], &1u32.to_be_bytes(), &[0u8; 12],
+    ].concat());
+
+    [ftyp, mp4_box(b"moov", &[mvhd, trak, mp4_box(b"mvex", &trex)].concat())].concat()
+}
+
+// The samples of the segment with their durations
+// The FLAC frames are numbered from the media position of their chunk,
+// so the frames of all the chunks form one variable block size stream
+
+fn segment_samples(track: &Track, segment: &Segment) -> Result<Vec<(Vec<u8>, u32)>, FlacDecodeError> {
+    let mut samples = Vec::new();
+
+    for chunk in &segment.chunks {
+        match track {
+            Track::Flac(info) => {
+                let decoded = decode_flac(&chunk.data)?;
+                if decoded.metadata.info.sample_rate != info.sample_rate
+                        || decoded.metadata.info.channels != info.channels {
+                    return Err(FlacDecodeError::InvalidMetadata(
+                        "The chunk has another format than the stream".to_string()));
+                }
+
+                let first_sample = chunk.position
+                    .map_or(segment.start, |position| to_timescale(position, track.timescale()));
+                let ends = decoded.frames.iter().skip(1).map(|frame| frame.offset).chain([chunk.data.len()]);
+
+                for (frame, end) in decoded.frames.iter().zip(ends) {
+                    let renumbered = renumber_frame(&chunk.data[frame.offset..end],
+                                                    first_sample + frame.first_sample)?;
+                    samples.push((renumbered, frame.block_size as u32));
+                }
+            }
+            Track::Opus { .. } => samples.push((chunk.data.to_vec(), opus_packet_samples(&chunk.data) as u32)),
+        }
+    }
+
+    Ok(samples)
+}
+
+// A single media segment, moof with the sample table and mdat with the samples
+// The data offset of trun points from the start of moof to the first sample
+
+pub fn media_segment(track: &Track, segment: &Segment) -> Result<Vec<u8>, FlacDecodeError> {
+    let samples = segment_samples(track, segment)?;
+
+    let moof = |data_offset: u32| {
+        // data-offset, sample-duration and sample-size are present
+        let mut trun = Vec::with_capacity(8 + samples.len() * 8);
+        trun.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        trun.extend_from_slice(&data_offset.to_be_bytes());
+        for (data, duration) in &samples {
+            trun.extend_from_slice(&duration.to_be_bytes());
+            trun.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        }
+
+        // The sequence numbers of the fragments start from 1
+        let mfhd = full_box(b"mfhd", 0, 0, &(segment.number as u32 + 1).to_be_bytes());
+        // default-base-is-moof
+        let tfhd = full_box(b"tfhd", 0, 0x020000, &TRACK_ID.to_be_bytes());
+        let tfdt = full_box(b"tfdt", 1, 0, &segment.start.to_be_bytes());
+        let traf = mp4_box(b"traf", &[tfhd, tfdt, full_box(b"trun", 0, 0x000301, &trun)].concat());
+
+        mp4_box(b"moof", &[mfhd, traf].concat())
+    };
+
+    // The size of moof does not depend on the offset, so it is built twice
+    let moof = moof(moof(0).len() as u32 + 8);
+    let mdat: Vec<u8> = samples.into_iter().flat_map(|(data, _)| data).collect();
+
+    Ok([moof, mp4_box(b"mdat", &mdat)].concat())
+}
+
+
+
+// Responding with the init segment of the stream
+// It is available once the stream has the first chunk to take the track from
+
+pub async fn init(stream_list: &ActiveStreams, stream_name: &str) -> HttpResponse {
+    let stream = match packaged_stream(stream_list, stream_name).await {
+        Some(stream) => stream,
+        None => return HttpResponse::NotFound().body("Stream not found"),
+    };
+
+    match track_of(stream.codec, stream.format, &stream.feed.history().await) {
+        Some(track) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "max-age=60"))
+            .content_type("audio/mp4")
+            .body(init_segment(&track)),
+        None => HttpResponse::NotFound().body("The stream has no audio yet"),
+    }
+}
+
+// Responding with a single media segment
+// The segment is gone, when its chunks have already left the ring,
+// and it is not there yet, while the stream is still adding to it
+
+pub async fn segment(stream_list: &ActiveStreams, stream_name: &str, number: u64) -> HttpResponse {
+    let stream = match packaged_stream(stream_list, stream_name).await {
+        Some(stream) => stream,
+        None => return HttpResponse::NotFound().body("Stream not found"),
+    };
+
+    let chunks = stream.feed.history().await;
+    let track = match track_of(stream.codec, stream.format, &chunks) {
+        Some(track) => track,
+        None => return HttpResponse::NotFound().body(format!("Segment {} is not available", number)),
+    };
+
+    let segment = match segments(stream.codec, track.timescale(), &chunks, stream.ended)
+            .into_iter().find(|segment| segment.number == number) {
+        Some(segment) => segment,
+        None => return HttpResponse::NotFound().body(format!("Segment {} is not available", number)),
+    };
+
+    // Decoding the FLAC frames is heavy, so it is done on the blocking thread pool
+    match web::block(move || media_segment(&track, &segment)).await {
+        Ok(Ok(data)) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "max-age=60"))
+            .content_type("audio/mp4")
+            .body(data),
+        Ok(Err(e)) => {
+            warn!("Segment {} of {} could not be packaged: {}", number, stream_name, e);
+            HttpResponse::InternalServerError().body("Failed to package the segment")
+        }
+        Err(e) => {
+            error!("Packaging of the segment failed: {}", e);
+            HttpResponse::InternalServerError().body("Failed to package the segment")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streamer::{ChunkRing, SEGMENT_DURATION};
+
+    // The top level boxes with their payloads
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            boxes.push((data[offset + 4..offset + 8].try_into().unwrap(), &data[offset + 8..offset + size]));
+            offset += size;
+        }
+        assert_eq!(offset, data.len(), "THE BOXES COVER THE WHOLE DATA");
+        boxes
+    }
+
+    // 20 ms CELT packets, pushed the way the ingest pushes them
+    fn opus_ring(packets: usize) -> Vec<Chunk> {
+        let mut ring = ChunkRing::new(packets);
+        for i in 0..packets {
+            ring.push_at(vec![0xfc, i as u8], Some(Duration::from_millis(20 * i as u64)));
+        }
+        ring.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_chunks_are_grouped_into_segments() {
+        let per_segment = (SEGMENT_DURATION.as_millis() / 20) as usize;
+        let chunks = opus_ring(per_segment * 3 + 5);
+
+        // The newest segment is still growing
+        let live = segments(Codec::Opus, OPUS_GRANULE_RATE, &chunks, false);
+        assert_eq!(live.iter().map(|s| s.number).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(live.iter().all(|s| s.chunks.len() == per_segment));
+        assert_eq!(live[1].start, live[0].start + live[0].duration, "SEGMENTS FOLLOW EACH OTHER");
+        assert_eq!(live[0].duration, OPUS_GRANULE_RATE as u64 * SEGMENT_DURATION.as_millis() as u64 / 1000);
+
+        let ended = segments(Codec::Opus, OPUS_GRANULE_RATE, &chunks, true);
+        assert_eq!(ended.len(), 4);
+        assert_eq!(ended[3].chunks.len(), 5);
+
+        // The oldest segment has lost its first chunk
+        assert_eq!(segments(Codec::Opus, OPUS_GRANULE_RATE, &chunks[1..], true)[0].number, 1);
+    }
+
+    #[test]
+    fn test_opus_init_and_media_segment() {
+        let track = track_of(Codec::Opus, None, &opus_ring(1)).unwrap();
+        assert_eq!(track, Track::Opus { channels: 2, input_sample_rate: 48000 });
+
+        let init = init_segment(&track);
+        let top: Vec<[u8; 4]> = boxes(&init).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(top, vec![*b"ftyp", *b"moov"]);
+        assert!(init.windows(4).any(|w| w == b"dOps"), "OPUS CONFIG IS IN THE SAMPLE ENTRY");
+        assert!(init.windows(4).any(|w| w == b"trex"), "THE TRACK IS FRAGMENTED");
+
+        let chunks = opus_ring(250);
+        let segment = &segments(Codec::Opus, track.timescale(), &chunks, false)[1];
+        let data = media_segment(&track, segment).unwrap();
+        let top = boxes(&data);
+        assert_eq!(top[0].0, *b"moof");
+        assert_eq!(top[1].0, *b"mdat");
+        assert_eq!(top[1].1.len(), 2 * segment.chunks.len());
+
+        // trun of the only traf: the amount of samples, then the offset into mdat
+        let trun = data.windows(4).position(|w| w == b"trun").unwrap() + 8;
+        let count = u32::from_be_bytes(data[trun..trun + 4].try_into().unwrap()) as usize;
+        let offset = u32::from_be_bytes(data[trun + 4..trun + 8].try_into().unwrap()) as usize;
+        assert_eq!(count, segment.chunks.len());
+        assert_eq!(&data[offset..offset + 2], &[0xfc, segment.chunks[0].data[1]], "OFFSET POINTS TO THE FIRST SAMPLE");
+
+        let tfdt = data.windows(4).position(|w| w == b"tfdt").unwrap() + 8;
+        assert_eq!(u64::from_be_bytes(data[tfdt..tfdt + 8].try_into().unwrap()), segment.start);
+    }
+}
@@ -0,0 +1,118 @@
+// A file intended for the HLS output of the live streams
+// The chunks in the ring of the stream are grouped into the segments of a few seconds,
+// served as fragmented MP4 after the init segment (fmp4.rs)
+// The number of the segment is its media sequence
+// Trinitypeer, 2025, by Trinitycore
+
+use actix_web::HttpResponse;
+use log::warn;
+
+use super::fmp4::{segments, track_of, Segment};
+use super::packaged_stream;
+use crate::streamer::ActiveStreams;
+
+// The amount of the newest segments listed in the rolling playlist
+pub const PLAYLIST_WINDOW: usize = 6;
+
+
+
+// Building the media playlist from the segments of the stream
+// Segment URIs are relative, so they resolve to /stream/{id}/seg/{n} and /stream/{id}/init.mp4
+// The playlist of the ended stream is closed, the player stops reloading it
+
+pub fn render_playlist(segments: &[Segment], timescale: u32, ended: bool) -> String {
+    let start = segments.len().saturating_sub(PLAYLIST_WINDOW);
+    let seconds = |segment: &Segment| segment.duration as f64 / timescale.max(1) as f64;
+
+    let target_duration = segments[start..].iter()
+        .map(|segment| seconds(segment).ceil() as u64)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+
+    let media_sequence = segments.get(start).map(|s| s.number).unwrap_or(0);
+
+    let mut playlist = String::new();
+    playlist.push_str("#EXTM3U\n");
+    playlist.push_str("#EXT-X-VERSION:7\n");
+    playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
+    playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", media_sequence));
+    playlist.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+    playlist.push_str("#EXT-X-MAP:URI=\"init.mp4\"\n");
+
+    for segment in &segments[start..] {
+        playlist.push_str(&format!("#EXTINF:{:.3},\n", seconds(segment)));
+        playlist.push_str(&format!("seg/{}\n", segment.number));
+    }
+
+    if ended {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+
+    playlist
+}
+
+
+
+// Responding with the rolling playlist of the stream
+
+pub async fn playlist(stream_list: &ActiveStreams, stream_name: &str) -> HttpResponse {
+    let stream = match packaged_stream(stream_list, stream_name).await {
+        Some(stream) => stream,
+        None => {
+            warn!("HLS playlist requested for unknown stream {}", stream_name);
+            return HttpResponse::NotFound().body("Stream not found");
+        }
+    };
+
+    let chunks = stream.feed.history().await;
+    let timescale = track_of(stream.codec, stream.format, &chunks).map_or(1, |track| track.timescale());
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-cache"))
+        .content_type("application/vnd.apple.mpegurl")
+        .body(render_playlist(&segments(stream.codec, timescale, &chunks, stream.ended), timescale, stream.ended))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::Codec;
+    use crate::streamer::{Chunk, ChunkRing, SEGMENT_DURATION};
+    use std::time::Duration;
+
+    // Chunks of a second each, measured by the position of the next one
+    fn chunks(amount: u64) -> Vec<Chunk> {
+        let mut ring = ChunkRing::new(64);
+        for i in 0..amount {
+            ring.push_at(vec![0xff, 0xf8], Some(Duration::from_secs(i)));
+        }
+        ring.iter().cloned().collect()
+    }
+
+    #[test]
+    fn test_playlist_lists_newest_segments() {
+        let per_segment = SEGMENT_DURATION.as_secs();
+        let chunks = chunks(per_segment * (PLAYLIST_WINDOW as u64 + 3));
+        let segments = segments(Codec::Flac, 1000, &chunks, false);
+        let playlist = render_playlist(&segments, 1000, false);
+
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"init.mp4\"\n"), "THE INIT SEGMENT GOES FIRST");
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:2\n"), "OLDEST SEGMENTS LEFT THE WINDOW");
+        assert_eq!(playlist.matches("#EXTINF:").count(), PLAYLIST_WINDOW);
+        assert!(playlist.contains(&format!("#EXTINF:{}.000,\n", per_segment)));
+        assert!(playlist.ends_with(&format!("seg/{}\n", PLAYLIST_WINDOW + 1)), "THE NEWEST SEGMENT IS STILL GROWING");
+        assert!(!playlist.contains("#EXT-X-ENDLIST"));
+
+        let ended = render_playlist(&super::segments(Codec::Flac, 1000, &chunks, true), 1000, true);
+        assert!(ended.ends_with(&format!("seg/{}\n#EXT-X-ENDLIST\n", PLAYLIST_WINDOW + 2)));
+    }
+
+    #[test]
+    fn test_empty_playlist() {
+        let playlist = render_playlist(&[], 48000, false);
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(!playlist.contains("#EXTINF"));
+    }
+}
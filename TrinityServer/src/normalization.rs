@@ -0,0 +1,85 @@
+// A file for the loudness normalization preferences of the streamers
+// The preference is the default of every new stream of the user,
+// the stream could still turn it on or off (or change the target) on its creation
+
+// Trinitypeer, 2025, by Trinitycore
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::audio_coding::loudness::{DEFAULT_TARGET_LUFS, DEFAULT_TRUE_PEAK_DBTP};
+use crate::audio_coding::NormalizeSettings;
+use crate::db::init_db;
+
+#[derive(Clone, Copy, Debug, PartialEq, FromRow, Serialize, Deserialize)]
+pub struct LoudnessPreference {
+    pub normalize: bool,
+    pub target_lufs: f64,
+    pub true_peak_dbtp: f64,
+}
+
+// The normalization is off, unless the user turns it on
+impl Default for LoudnessPreference {
+    fn default() -> Self {
+        LoudnessPreference {
+            normalize: false,
+            target_lufs: DEFAULT_TARGET_LUFS,
+            true_peak_dbtp: DEFAULT_TRUE_PEAK_DBTP,
+        }
+    }
+}
+
+impl LoudnessPreference {
+    // The target and the ceiling are checked even when the normalization is off,
+    // so turning it on later could not fail
+    pub fn validated(self) -> Result<Self, String> {
+        self.settings().validated()?;
+        Ok(self)
+    }
+
+    fn settings(&self) -> NormalizeSettings {
+        NormalizeSettings { target_lufs: self.target_lufs, true_peak_dbtp: self.true_peak_dbtp }
+    }
+
+    // The settings of the stream, None when the normalization is off
+    pub fn stream_settings(&self) -> Result<Option<NormalizeSettings>, String> {
+        if !self.normalize {
+            return Ok(None);
+        }
+        self.settings().validated().map(Some)
+    }
+}
+
+
+
+// The preference of the user, the default one when it was never saved
+
+pub async fn load_preference(username: &str) -> Result<LoudnessPreference, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_as::<_, LoudnessPreference>(
+        "SELECT normalize, target_lufs, true_peak_dbtp FROM loudness_preferences WHERE username = $1")
+        .bind(username)
+        .fetch_optional(&pool)
+        .await
+        .map(Option::unwrap_or_default)
+        .map_err(|e| e.to_string())
+}
+
+pub async fn save_preference(username: &str, preference: &LoudnessPreference) -> Result<(), String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query(
+        "INSERT INTO loudness_preferences (username, normalize, target_lufs, true_peak_dbtp, updated_at)
+        VALUES ($1, $2, $3, $4, NOW())
+        ON CONFLICT (username) DO UPDATE
+        SET normalize = $2, target_lufs = $3, true_peak_dbtp = $4, updated_at = NOW();")
+        .bind(username)
+        .bind(preference.normalize)
+        .bind(preference.target_lufs)
+        .bind(preference.true_peak_dbtp)
+        .execute(&pool)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,535 @@
+// A file for recording the streams to the disk
+// The recording is opt-in, the streamer asks for it when the stream is created
+// Every chunk the stream carries is appended to a single FLAC or Ogg/Opus file,
+// when the stream ends the file is finalized and registered in the database,
+// so the listeners could replay the broadcast they have missed (VOD)
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use sqlx::FromRow;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::audio_coding::opus::{opus_packet_samples, OPUS_GRANULE_RATE};
+use crate::audio_coding::{consume_samples_md5, decode_flac, renumber_frame, Codec,
+                          OggOpusWriter, StreamInfo};
+use crate::db::init_db;
+use crate::streamer::ChunkFeed;
+
+// The directory for the recordings, unless RECORDINGS_DIR says otherwise
+pub const DEFAULT_RECORDINGS_DIR: &str = "recordings";
+
+pub fn recordings_dir() -> PathBuf {
+    env::var("RECORDINGS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_RECORDINGS_DIR))
+}
+
+
+
+// The finished recording, as it is stored in the recordings table
+// The path is never sent to the client, it is the business of the server
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Recording {
+    pub id: Uuid,
+    pub stream_name: String,
+    pub codec: String,
+    #[serde(skip_serializing)]
+    pub path: String,
+    pub size_bytes: i64,
+    pub duration_ms: Option<i64>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+}
+
+impl Recording {
+    pub fn mime_type(&self) -> &'static str {
+        match self.codec.as_str() {
+            "opus" => Codec::Opus.mime_type(),
+            _ => Codec::Flac.mime_type(),
+        }
+    }
+}
+
+
+
+// Appending the FLAC chunks into one FLAC file
+// Every chunk is a complete FLAC stream with its frames numbered from zero,
+// so the frames are renumbered by the first sample and the file becomes
+// a variable block size stream. STREAMINFO is written as a placeholder
+// and filled in when the recording stops, together with MD5 of the audio
+
+struct FlacRecording {
+    file: BufWriter<File>,
+    info: Option<StreamInfo>,
+    md5: md5::Context,
+}
+
+impl FlacRecording {
+    fn create(file: File) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(file);
+        file.write_all(b"fLaC")?;
+        // STREAMINFO is the only and the last metadata block
+        file.write_all(&[0x80, 0, 0, 34])?;
+        file.write_all(&[0u8; 34])?;
+
+        Ok(FlacRecording {
+            file,
+            info: None,
+            md5: md5::Context::new(),
+        })
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        let decoded = match decode_flac(chunk) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("Skipping the chunk, which is not a valid FLAC: {}", e);
+                return Ok(());
+            }
+        };
+
+        let chunk_info = &decoded.metadata.info;
+
+        if let Some(info) = &self.info {
+            if info.format() != chunk_info.format() {
+                warn!("Skipping the chunk, the format has changed during the recording");
+                return Ok(());
+            }
+        }
+
+        let mut info = self.info.take().unwrap_or_else(|| StreamInfo {
+            min_block_size: u16::MAX,
+            max_block_size: 0,
+            min_frame_size: u32::MAX,
+            max_frame_size: 0,
+            total_samples: 0,
+            md5: [0u8; 16],
+            ..chunk_info.clone()
+        });
+
+        // All the frames are renumbered first, so a broken chunk
+        // is never written halfway
+        let ends = decoded.frames.iter().skip(1).map(|f| f.offset).chain([chunk.len()]);
+        let mut frames = Vec::with_capacity(decoded.frames.len());
+        let mut next_sample = info.total_samples;
+
+        for (frame, end) in decoded.frames.iter().zip(ends) {
+            match renumber_frame(&chunk[frame.offset..end], next_sample) {
+                Ok(renumbered) => frames.push((renumbered, frame.block_size)),
+                Err(e) => {
+                    warn!("Skipping the chunk, its frame could not be renumbered: {}", e);
+                    self.info = Some(info);
+                    return Ok(());
+                }
+            }
+            next_sample += frame.block_size as u64;
+        }
+
+        for (frame, block_size) in frames {
+            self.file.write_all(&frame)?;
+
+            info.min_block_size = info.min_block_size.min(block_size as u16);
+            info.max_block_size = info.max_block_size.max(block_size as u16);
+            info.min_frame_size = info.min_frame_size.min(frame.len() as u32);
+            info.max_frame_size = info.max_frame_size.max(frame.len() as u32);
+        }
+
+        info.total_samples = next_sample;
+        consume_samples_md5(&mut self.md5, &decoded.samples, info.bits_per_sample);
+        self.info = Some(info);
+
+        Ok(())
+    }
+
+    // Writing the real STREAMINFO over the placeholder
+    // Returns the duration, or None when nothing was recorded at all
+    fn finish(mut self) -> std::io::Result<Option<Duration>> {
+        let mut info = match self.info.take() {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        info.md5 = self.md5.compute().0;
+
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(8))?;
+        file.write_all(&info.to_bytes())?;
+        file.sync_all()?;
+
+        Ok(info.duration())
+    }
+}
+
+
+
+// Appending the Opus packets into one Ogg/Opus file
+// The last packet is held back, so it could be written with the end of stream flag
+
+struct OpusRecording {
+    file: BufWriter<File>,
+    writer: OggOpusWriter,
+    pending: Option<Vec<u8>>,
+    samples: u64,
+}
+
+impl OpusRecording {
+    fn create(file: File, serial: u32) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(file);
+        let mut writer = OggOpusWriter::new(serial, 2, OPUS_GRANULE_RATE);
+        file.write_all(&writer.headers())?;
+
+        Ok(OpusRecording {
+            file,
+            writer,
+            pending: None,
+            samples: 0,
+        })
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(packet) = self.pending.replace(chunk.to_vec()) {
+            self.file.write_all(&self.writer.packet(&packet))?;
+        }
+        self.samples += opus_packet_samples(chunk);
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<Option<Duration>> {
+        let packet = match self.pending.take() {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+
+        self.file.write_all(&self.writer.finish(&packet))?;
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        Ok(Some(Duration::from_secs_f64(self.samples as f64 / OPUS_GRANULE_RATE as f64)))
+    }
+}
+
+
+
+enum RecordingWriter {
+    Flac(FlacRecording),
+    Opus(OpusRecording),
+}
+
+impl RecordingWriter {
+    fn write_chunk(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        match self {
+            RecordingWriter::Flac(recording) => recording.write_chunk(chunk),
+            RecordingWriter::Opus(recording) => recording.write_chunk(chunk),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Option<Duration>> {
+        match self {
+            RecordingWriter::Flac(recording) => recording.finish(),
+            RecordingWriter::Opus(recording) => recording.finish(),
+        }
+    }
+}
+
+
+
+// The handle of the running recorder, it lives in the Stream
+// Stopping it finalizes the file and registers it in the database
+
+#[derive(Debug)]
+pub struct RecorderHandle {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<Option<Recording>>,
+}
+
+impl RecorderHandle {
+    // Starting the recorder of the stream, it reads the feed from the very first chunk
+    // The file is created right away, so a wrong directory is noticed by the streamer
+
+    pub fn start(stream_name: &str, codec: Codec, feed: ChunkFeed,
+                 started_at: SystemTime) -> std::io::Result<Self> {
+        let dir = recordings_dir();
+        std::fs::create_dir_all(&dir)?;
+
+        // The stream name comes from the URL, it must not escape the directory
+        let safe_name: String = stream_name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let id = Uuid::new_v4();
+        let extension = match codec {
+            Codec::Flac => "flac",
+            Codec::Opus => "opus",
+        };
+        let path = dir.join(format!("{}-{}.{}", safe_name, id, extension));
+
+        let file = File::create(&path)?;
+        let writer = match codec {
+            Codec::Flac => RecordingWriter::Flac(FlacRecording::create(file)?),
+            Codec::Opus => RecordingWriter::Opus(OpusRecording::create(file, id.as_fields().0)?),
+        };
+
+        info!("Recording the stream {} to {}", stream_name, path.display());
+
+        let (stop, stop_rx) = watch::channel(false);
+        let recording = Recording {
+            id,
+            stream_name: stream_name.to_string(),
+            codec: extension.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            size_bytes: 0,
+            duration_ms: None,
+            started_at: DateTime::<Utc>::from(started_at),
+            ended_at: DateTime::<Utc>::from(started_at),
+        };
+
+        let task = tokio::spawn(record(writer, feed, stop_rx, recording));
+
+        Ok(RecorderHandle { stop, task })
+    }
+
+    // Stopping the recorder, the chunks which are already in the ring
+    // are still written before the file is finalized
+    pub async fn stop(self) -> Option<Recording> {
+        let _ = self.stop.send(true);
+
+        match self.task.await {
+            Ok(recording) => recording,
+            Err(e) => {
+                error!("The recorder task has failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// The recorder task, it follows the feed the same way the listeners do
+// The file is written on the blocking thread pool, the writer goes there and back
+
+async fn record(mut writer: RecordingWriter, feed: ChunkFeed,
+                mut stop: watch::Receiver<bool>, mut recording: Recording) -> Option<Recording> {
+    let mut next_seq = 0;
+
+    loop {
+        let read = feed.read_from(next_seq).await;
+
+        if read.missed > 0 {
+            warn!("Recording of {} has lost {} chunks", recording.stream_name, read.missed);
+        }
+
+        if read.chunks.is_empty() {
+            // The closed feed gets no more chunks, there is nothing to wait for
+            if *stop.borrow() || feed.is_closed() {
+                break;
+            }
+
+            tokio::select! {
+                _ = feed.wait_for(next_seq) => {}
+                changed = stop.changed() => {
+                    // The handle is gone without stopping, there is no one to wait for
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+
+        for chunk in read.chunks {
+            next_seq = chunk.seq + 1;
+
+            let written = tokio::task::spawn_blocking(move || {
+                let result = writer.write_chunk(&chunk.data);
+                (writer, result)
+            }).await;
+
+            match written {
+                Ok((returned, Ok(()))) => writer = returned,
+                Ok((_, Err(e))) => {
+                    error!("Recording of {} has failed: {}", recording.stream_name, e);
+                    return None;
+                }
+                Err(e) => {
+                    error!("Recording of {} has failed: {}", recording.stream_name, e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    let path = PathBuf::from(&recording.path);
+    let finished = tokio::task::spawn_blocking(move || writer.finish()).await;
+
+    let duration = match finished {
+        Ok(Ok(Some(duration))) => duration,
+        Ok(Ok(None)) => {
+            info!("Nothing was recorded from {}, removing the file", recording.stream_name);
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        }
+        Ok(Err(e)) => {
+            error!("Failed to finalize the recording of {}: {}", recording.stream_name, e);
+            return None;
+        }
+        Err(e) => {
+            error!("Failed to finalize the recording of {}: {}", recording.stream_name, e);
+            return None;
+        }
+    };
+
+    recording.size_bytes = tokio::fs::metadata(&path).await.map(|m| m.len() as i64).unwrap_or(0);
+    recording.duration_ms = Some(duration.as_millis() as i64);
+    recording.ended_at = Utc::now();
+
+    if let Err(e) = register_recording(&recording).await {
+        error!("Failed to register the recording of {}: {}", recording.stream_name, e);
+    }
+
+    info!("Recording of {} is finished: {}", recording.stream_name, path.display());
+    Some(recording)
+}
+
+
+
+// Putting the finished recording into the recordings table
+
+async fn register_recording(recording: &Recording) -> Result<(), String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query(
+        "INSERT INTO recordings
+            (id, stream_name, codec, path, size_bytes, duration_ms, started_at, ended_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8);")
+        .bind(recording.id)
+        .bind(&recording.stream_name)
+        .bind(&recording.codec)
+        .bind(&recording.path)
+        .bind(recording.size_bytes)
+        .bind(recording.duration_ms)
+        .bind(recording.started_at)
+        .bind(recording.ended_at)
+        .execute(&pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// A single recording by its ID
+pub async fn find_recording(id: Uuid) -> Result<Option<Recording>, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_as::<_, Recording>(
+        "SELECT id, stream_name, codec, path, size_bytes, duration_ms, started_at, ended_at
+        FROM recordings WHERE id = $1")
+        .bind(id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// The recordings, the newest first, optionally of a single stream only
+pub async fn list_recordings(stream_name: Option<&str>) -> Result<Vec<Recording>, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_as::<_, Recording>(
+        "SELECT id, stream_name, codec, path, size_bytes, duration_ms, started_at, ended_at
+        FROM recordings WHERE $1::TEXT IS NULL OR stream_name = $1
+        ORDER BY ended_at DESC")
+        .bind(stream_name)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::{sine, to_pcm};
+    use crate::audio_coding::{encode_pcm_to_flac, AudioFormat, FlacEncodeOptions};
+
+    #[test]
+    fn test_flac_recording_is_finalized() {
+        let dir = std::env::temp_dir().join(format!("trinitypeer-rec-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.flac");
+
+        let format = AudioFormat::default();
+        let mut options = FlacEncodeOptions::new(format);
+        options.block_size = 1024;
+
+        // Three chunks, all numbered from zero, the last frame of every chunk is shorter
+        let pcm = to_pcm(&sine(440.0, 0.5, format.sample_rate, format.channels, 7000), format.bits_per_sample);
+        let mut writer = RecordingWriter::Flac(FlacRecording::create(File::create(&path).unwrap()).unwrap());
+        for frames in [(0, 2500), (2500, 3000), (3000, 7000)] {
+            let chunk = &pcm[frames.0 * format.channels..frames.1 * format.channels];
+            writer.write_chunk(&encode_pcm_to_flac(chunk, &options).unwrap()).unwrap();
+        }
+
+        let duration = writer.finish().unwrap().expect("SOMETHING WAS RECORDED");
+        assert_eq!(duration, Duration::from_secs_f64(7000.0 / format.sample_rate as f64));
+
+        // The MD5 of STREAMINFO is checked by the decoder
+        let data = std::fs::read(&path).unwrap();
+        let decoded = decode_flac(&data).expect("RECORDING IS A VALID FLAC");
+        let info = &decoded.metadata.info;
+        assert_eq!(info.total_samples, 7000);
+        assert_ne!(info.md5, [0u8; 16], "MD5 IS FILLED IN");
+        assert_eq!(decoded.samples, pcm, "RECORDING IS LOSSLESS");
+
+        let mut next_sample = 0;
+        for frame in &decoded.frames {
+            assert_eq!(frame.first_sample, next_sample, "FRAMES ARE CONTIGUOUS");
+            next_sample += frame.block_size as u64;
+        }
+
+        let ends = decoded.frames.iter().skip(1).map(|f| f.offset).chain([data.len()]);
+        let sizes: Vec<usize> = decoded.frames.iter().zip(ends).map(|(f, end)| end - f.offset).collect();
+        assert_eq!((info.min_block_size, info.max_block_size), (452, 1024));
+        assert_eq!(info.min_frame_size as usize, *sizes.iter().min().unwrap());
+        assert_eq!(info.max_frame_size as usize, *sizes.iter().max().unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_opus_recording_is_finalized() {
+        let dir = std::env::temp_dir().join(format!("trinitypeer-rec-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.opus");
+
+        let mut recording = OpusRecording::create(File::create(&path).unwrap(), 7).unwrap();
+        // CELT, 20 ms, one frame
+        for _ in 0..50 {
+            recording.write_chunk(&[0xfc, 0xff, 0xfe]).unwrap();
+        }
+        let duration = recording.finish().unwrap();
+        assert_eq!(duration, Some(Duration::from_secs(1)));
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(&data[..4], b"OggS");
+        // The last page carries the end of stream flag
+        let last_page = data.windows(4).rposition(|w| w == b"OggS").unwrap();
+        assert_eq!(data[last_page + 5] & 0x04, 0x04, "LAST PAGE IS THE END OF STREAM");
+
+        let empty = OpusRecording::create(File::create(dir.join("empty.opus")).unwrap(), 8).unwrap();
+        assert_eq!(empty.finish().unwrap(), None, "NOTHING WAS RECORDED");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+// WebRTC half of the hybrid, the peer connections of the streamers and listeners
+// Everything received or sent through WebRTC goes through the same chunk ring
+// as the HTTP streams, so both ways could be mixed freely
+// Trinitypeer, 2025, by Trinitycore
+
+pub(crate) mod ingest;
+pub(crate) mod egress;
+
+use std::env;
+
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::{APIBuilder, API};
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+// The duration of a single Opus packet, browsers and OBS send 20 ms frames
+pub const OPUS_FRAME_DURATION: std::time::Duration = std::time::Duration::from_millis(20);
+
+
+
+// The settings of the peer connections, normally taken from the environment
+// STUN_SERVER changes the STUN server, an empty one disables it
+// Loopback candidates are skipped, unless RTC_LOOPBACK_CANDIDATES is set
+// (needed only when both peers run on the same machine, e.g. in tests)
+
+#[derive(Clone, Debug)]
+pub struct RtcSettings {
+    pub stun_server: Option<String>,
+    pub loopback_candidates: bool,
+}
+
+impl RtcSettings {
+    pub fn from_env() -> Self {
+        let stun = env::var("STUN_SERVER")
+            .unwrap_or_else(|_| "stun:stun.l.google.com:19302".to_string());
+
+        RtcSettings {
+            stun_server: Some(stun).filter(|stun| !stun.is_empty()),
+            loopback_candidates: env::var("RTC_LOOPBACK_CANDIDATES").is_ok(),
+        }
+    }
+}
+
+// Building the WebRTC API with the default codecs (Opus included)
+// and the default interceptors (NACK, RTCP reports)
+
+pub fn build_api(settings: &RtcSettings) -> Result<API, webrtc::Error> {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+
+    let mut setting_engine = SettingEngine::default();
+    setting_engine.set_include_loopback_candidate(settings.loopback_candidates);
+
+    Ok(APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(setting_engine)
+        .build())
+}
+
+// The configuration of the peer connections, with the STUN server if there is one
+
+pub fn rtc_configuration(settings: &RtcSettings) -> RTCConfiguration {
+    let ice_servers = match &settings.stun_server {
+        Some(stun) => vec![RTCIceServer {
+            urls: vec![stun.clone()],
+            ..Default::default()
+        }],
+        None => Vec::new(),
+    };
+
+    RTCConfiguration {
+        ice_servers,
+        ..Default::default()
+    }
+}
+
+// Answering the SDP offer of the remote peer
+// The answer is returned only after ICE gathering is complete,
+// as WHIP / WHEP clients do not support trickle ICE in most cases
+
+pub async fn answer_offer(connection: &RTCPeerConnection, offer_sdp: String) 
+                                                  -> Result<String, webrtc::Error> {
+    let offer = RTCSessionDescription::offer(offer_sdp)?;
+    connection.set_remote_description(offer).await?;
+
+    let answer = connection.create_answer(None).await?;
+    let mut gathering_complete = connection.gathering_complete_promise().await;
+    connection.set_local_description(answer).await?;
+    let _ = gathering_complete.recv().await;
+
+    match connection.local_description().await {
+        Some(description) => Ok(description.sdp),
+        None => Err(webrtc::Error::Other("No local description after answering".to_string())),
+    }
+}
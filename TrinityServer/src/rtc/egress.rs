@@ -0,0 +1,301 @@
+// WHEP-style playback, the listener sends its SDP offer and gets the answer back
+// The Opus packets of the stream (from the WebRTC ingest, or of its Opus rendition,
+// when the stream itself is FLAC) are written to a send-only track of the listener connection
+// Trinitypeer, 2025, by Trinitycore
+
+use std::sync::Arc;
+
+use actix_web::HttpResponse;
+use log::{error, info, warn};
+use tokio::sync::Notify;
+use webrtc::api::media_engine::MIME_TYPE_OPUS;
+use webrtc::media::Sample;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+use crate::audience::ListenerGuard;
+use crate::audio_coding::Codec;
+use crate::streamer::{ActiveStreams, ChunkFeed};
+
+use super::{answer_offer, build_api, rtc_configuration, RtcSettings, OPUS_FRAME_DURATION};
+
+
+
+// The reasons the listener could not be connected
+#[derive(Debug)]
+pub enum EgressError {
+    StreamNotFound,
+    // The stream has no Opus to send, WebRTC carries nothing else
+    NoOpus(Codec),
+    Negotiation(webrtc::Error),
+}
+
+impl std::fmt::Display for EgressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EgressError::StreamNotFound => write!(f, "Stream not found"),
+            EgressError::NoOpus(codec) => 
+                write!(f, "The stream is {:?} without an Opus rendition, WebRTC could not carry it", codec),
+            EgressError::Negotiation(e) => write!(f, "WebRTC negotiation failed: {}", e),
+        }
+    }
+}
+
+impl From<webrtc::Error> for EgressError {
+    fn from(e: webrtc::Error) -> Self {
+        EgressError::Negotiation(e)
+    }
+}
+
+// The connected listener, the answer goes back to the client
+pub struct ListenerSession {
+    pub connection: Arc<RTCPeerConnection>,
+    pub answer: String,
+}
+
+
+
+// Writing the chunks of the stream to the track of the listener
+// Stops when the listener connection is closed or the stream is removed
+// The listener is counted in the audience until the forwarding stops
+
+async fn forward_chunks(stream_name: String, feed: ChunkFeed, track: Arc<TrackLocalStaticSample>,
+                        closed: Arc<Notify>, _listener: ListenerGuard) {
+    let mut next_seq = feed.join_seq(Codec::Opus).await;
+
+    loop {
+        let read = feed.read_from(next_seq).await;
+
+        if read.chunks.is_empty() {
+            if feed.is_closed() {
+                break;
+            }
+
+            tokio::select! {
+                _ = feed.wait_for(next_seq) => continue,
+                _ = closed.notified() => break,
+            }
+        }
+
+        for chunk in read.chunks {
+            next_seq = chunk.seq + 1;
+
+            let sample = Sample {
+                data: chunk.data,
+                duration: OPUS_FRAME_DURATION,
+                ..Default::default()
+            };
+
+            if let Err(e) = track.write_sample(&sample).await {
+                warn!("Failed to write a sample to the listener of {}: {}", stream_name, e);
+            }
+        }
+    }
+
+    info!("WebRTC listener of {} has stopped", stream_name);
+}
+
+// Creating a send-only connection for the listener and starting to forward
+// the stream into it, the connection is kept on the stream until it is closed
+// The FLAC stream is sent through its Opus rendition, the best one goes first
+
+pub async fn connect_listener(stream_list: &ActiveStreams, stream_name: &str, offer_sdp: String,
+                              settings: &RtcSettings) -> Result<ListenerSession, EgressError> {
+    // The feed of the stream tells, whether it is still the same stream, when the answer is ready
+    let (stream_feed, feed, audience) = match stream_list.get_stream(stream_name).await {
+        Some(stream) => {
+            let opus = stream.rendition_feeds().into_iter()
+                .filter(|(rendition, _)| rendition.codec() == Codec::Opus)
+                .max_by_key(|(rendition, _)| rendition.bitrate());
+
+            match opus {
+                Some((_, feed)) => (stream.feed(), feed, stream.audience()),
+                None => return Err(EgressError::NoOpus(stream.codec())),
+            }
+        }
+        None => return Err(EgressError::StreamNotFound),
+    };
+
+    let api = build_api(settings)?;
+    let connection = Arc::new(api.new_peer_connection(rtc_configuration(settings)).await?);
+
+    let track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        },
+        "audio".to_owned(),
+        format!("trinitypeer-{}", stream_name),
+    ));
+
+    let sender = connection
+        .add_track(Arc::clone(&track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await?;
+
+    // The RTCP packets must be read, otherwise the interceptors would stall
+    tokio::spawn(async move {
+        let mut rtcp_buf = vec![0u8; 1500];
+        while sender.read(&mut rtcp_buf).await.is_ok() {}
+    });
+
+    // When the listener is gone, the forwarding is stopped
+    // and the connection is forgotten by the stream
+    let closed = Arc::new(Notify::new());
+    let state_closed = closed.clone();
+    let state_streams = stream_list.clone();
+    let state_stream_name = stream_name.to_string();
+    let state_connection = Arc::downgrade(&connection);
+
+    connection.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+        let finished = matches!(state, RTCPeerConnectionState::Failed 
+                                     | RTCPeerConnectionState::Closed);
+        if finished {
+            state_closed.notify_one();
+
+            if let Some(connection) = state_connection.upgrade() {
+                if let Some(mut stream) = state_streams.get_stream_ref_mut(&state_stream_name) {
+                    stream.remove_listener_connection(&connection);
+                }
+            }
+        }
+        Box::pin(async {})
+    }));
+
+    let answer = match answer_offer(&connection, offer_sdp).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            let _ = connection.close().await;
+            return Err(e.into());
+        }
+    };
+
+    match stream_list.get_stream_ref_mut(stream_name) {
+        Some(mut stream) if stream.feed().same_as(&stream_feed) => stream.add_listener_connection(connection.clone()),
+        _ => {
+            let _ = connection.close().await;
+            return Err(EgressError::StreamNotFound);
+        }
+    }
+
+    tokio::spawn(forward_chunks(stream_name.to_string(), feed, track, closed, audience.join()));
+
+    Ok(ListenerSession { connection, answer })
+}
+
+// Accepting the SDP offer of the listener
+
+pub async fn accept_offer(stream_list: &ActiveStreams, stream_name: &str, offer_sdp: String) 
+                                                                        -> HttpResponse {
+    match connect_listener(stream_list, stream_name, offer_sdp, &RtcSettings::from_env()).await {
+        Ok(session) => {
+            info!("WebRTC listener joined the stream {}", stream_name);
+            HttpResponse::Created()
+                .append_header(("Location", format!("/stream/{}/whep", stream_name)))
+                .content_type("application/sdp")
+                .body(session.answer)
+        }
+        Err(EgressError::StreamNotFound) => {
+            warn!("WHEP offer for unknown stream {}", stream_name);
+            HttpResponse::NotFound().body("Stream not found")
+        }
+        Err(e @ EgressError::NoOpus(_)) => {
+            warn!("WHEP offer for the stream {} without Opus", stream_name);
+            HttpResponse::Conflict().body(e.to_string())
+        }
+        Err(e) => {
+            error!("Failed to connect the listener of {}: {}", stream_name, e);
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+    use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+    use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+    use crate::streamer::Stream;
+
+    // Two in-process peers on loopback: the listener is a plain webrtc-rs peer,
+    // the server side is created by connect_listener
+
+    #[tokio::test]
+    async fn test_listener_receives_stream_over_loopback() {
+        let settings = RtcSettings { stun_server: None, loopback_candidates: true };
+
+        let streams = ActiveStreams::new(4);
+        let mut stream = Stream::new(0, "tester".to_string(), "loopback".to_string(), None);
+        stream.set_codec(Codec::Opus);
+        streams.add_stream(stream).await.unwrap();
+
+        let api = build_api(&settings).unwrap();
+        let listener = Arc::new(api.new_peer_connection(rtc_configuration(&settings)).await.unwrap());
+        listener.add_transceiver_from_kind(RTPCodecType::Audio, Some(RTCRtpTransceiverInit {
+            direction: RTCRtpTransceiverDirection::Recvonly,
+            send_encodings: Vec::new(),
+        })).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(64);
+        listener.on_track(Box::new(move |track, _receiver, _transceiver| {
+            let tx = tx.clone();
+            Box::pin(async move {
+                while let Ok((packet, _)) = track.read_rtp().await {
+                    if tx.send(packet.payload.to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        }));
+
+        let offer = listener.create_offer(None).await.unwrap();
+        let mut gathering_complete = listener.gathering_complete_promise().await;
+        listener.set_local_description(offer).await.unwrap();
+        let _ = gathering_complete.recv().await;
+        let offer_sdp = listener.local_description().await.unwrap().sdp;
+
+        let session = connect_listener(&streams, "loopback", offer_sdp, &settings).await.unwrap();
+        listener.set_remote_description(RTCSessionDescription::answer(session.answer).unwrap())
+            .await.unwrap();
+
+        // An Opus packet of a silent 20 ms stereo frame
+        let packet = vec![0xfc, 0xff, 0xfe];
+        let feed = streams.get_stream("loopback").await.unwrap().feed();
+
+        let received = tokio::time::timeout(Duration::from_secs(15), async {
+            loop {
+                feed.push(packet.clone()).await;
+                tokio::select! {
+                    Some(payload) = rx.recv() => return payload,
+                    _ = tokio::time::sleep(OPUS_FRAME_DURATION) => {}
+                }
+            }
+        }).await.expect("LISTENER DID NOT RECEIVE THE STREAM");
+
+        assert_eq!(received, packet);
+
+        assert!(streams.remove_stream("loopback", &feed).await);
+        assert_eq!(session.connection.connection_state(), RTCPeerConnectionState::Closed,
+                   "LISTENER CONNECTION IS CLOSED WITH THE STREAM");
+
+        listener.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flac_stream_without_opus_is_refused() {
+        let streams = ActiveStreams::new(4);
+        streams.add_stream(Stream::new(0, "tester".to_string(), "lossless".to_string(), None)).await.unwrap();
+
+        let settings = RtcSettings { stun_server: None, loopback_candidates: true };
+        let refused = connect_listener(&streams, "lossless", String::new(), &settings).await;
+        assert!(matches!(refused, Err(EgressError::NoOpus(Codec::Flac))), "FLAC IS NOT SENT AS OPUS");
+    }
+}
@@ -0,0 +1,160 @@
+// WHIP-style ingest, the streamer sends its SDP offer and gets the answer back
+// Every RTP packet of the incoming Opus track becomes a chunk of the stream
+// Trinitypeer, 2025, by Trinitycore
+
+use std::sync::Arc;
+
+use actix_web::HttpResponse;
+use log::{error, info, warn};
+use webrtc::api::media_engine::MIME_TYPE_OPUS;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc::track::track_remote::TrackRemote;
+
+use crate::audio_coding::Codec;
+use crate::streamer::{ActiveStreams, ChunkFeed};
+
+use super::{answer_offer, build_api, rtc_configuration, RtcSettings, OPUS_FRAME_DURATION};
+
+// Every packet is a chunk (20 ms), so the ring of the WebRTC stream
+// is made bigger to still keep around a minute of the audio
+
+pub const RTP_RING_CAPACITY: usize = 3000;
+
+
+
+// Reading the RTP packets of the track until the connection is gone
+// and pushing their payloads (the Opus packets) to the stream
+// Every packet moves the media position of the stream by its duration,
+// it is decoded after it is pushed for the loudness and the analysis of the stream
+
+async fn forward_track(track: Arc<TrackRemote>, stream_list: ActiveStreams, feed: ChunkFeed, 
+                       stream_name: String) {
+    let codec = track.codec();
+    if !codec.capability.mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+        warn!("Stream {} sent an unsupported track: {}", stream_name, codec.capability.mime_type);
+        return;
+    }
+
+    info!("Receiving Opus track for the stream {}", stream_name);
+
+    while let Ok((packet, _)) = track.read_rtp().await {
+        if packet.payload.is_empty() {
+            continue;
+        }
+
+        let duration = Codec::Opus.chunk_duration(&packet.payload).unwrap_or(OPUS_FRAME_DURATION);
+        let (position, format, monitor, loudness, analysis) = match stream_list.get_stream_ref_mut(&stream_name) {
+            Some(mut stream) if stream.feed().same_as(&feed) => {
+                (stream.advance_ingest(duration), stream.format(), stream.opus_monitor(),
+                 stream.loudness(), stream.analysis())
+            }
+            // The stream is gone, or another one has taken its name
+            _ => break,
+        };
+
+        feed.push_at(packet.payload.to_vec(), position).await;
+
+        // The packet, which is not decodable, is still forwarded, the peers decide on their own
+        let payload = packet.payload;
+        match tokio::task::spawn_blocking(move || monitor.decode(&payload, format, &loudness)).await {
+            Ok(Ok(Some(chunk_analysis))) => {
+                analysis.publish_until(chunk_analysis, position + duration);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => warn!("Failed to decode the Opus packet of the stream {}: {}", stream_name, e),
+            Err(e) => error!("Decoding of the Opus packet of the stream {} failed: {}", stream_name, e),
+        }
+    }
+
+    info!("Opus track of the stream {} has ended", stream_name);
+}
+
+async fn create_connection(stream_list: ActiveStreams, feed: ChunkFeed, stream_name: String, 
+                           offer_sdp: String) 
+                        -> Result<(Arc<RTCPeerConnection>, String), webrtc::Error> {
+    let settings = RtcSettings::from_env();
+    let api = build_api(&settings)?;
+    let connection = Arc::new(api.new_peer_connection(rtc_configuration(&settings)).await?);
+
+    // The server only receives the audio from the streamer
+    connection.add_transceiver_from_kind(RTPCodecType::Audio, Some(RTCRtpTransceiverInit {
+        direction: RTCRtpTransceiverDirection::Recvonly,
+        send_encodings: Vec::new(),
+    })).await?;
+
+    let track_stream_name = stream_name.clone();
+    connection.on_track(Box::new(move |track, _receiver, _transceiver| {
+        let (stream_list, feed) = (stream_list.clone(), feed.clone());
+        let stream_name = track_stream_name.clone();
+        Box::pin(async move {
+            if track.kind() == RTPCodecType::Audio {
+                forward_track(track, stream_list, feed, stream_name).await;
+            }
+        })
+    }));
+
+    connection.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+        info!("Ingest connection of {} is now {}", stream_name, state);
+        Box::pin(async {})
+    }));
+
+    let answer = match answer_offer(&connection, offer_sdp).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            let _ = connection.close().await;
+            return Err(e);
+        }
+    };
+
+    Ok((connection, answer))
+}
+
+// Accepting the SDP offer of the streamer
+// The feed and the codec are taken from the stream the streamer controls,
+// the connection is stored on the stream with that feed only, the previous one is closed
+// WebRTC streamers always send Opus, so only the streams created with ?codec=opus take them
+// (their ring is already sized for the packets), the FLAC ones keep their listeners and recording
+
+pub async fn accept_offer(stream_list: &ActiveStreams, stream_name: &str, feed: ChunkFeed, codec: Codec,
+                          offer_sdp: String) -> HttpResponse {
+    if codec != Codec::Opus {
+        warn!("WHIP offer for the FLAC stream {}", stream_name);
+        return HttpResponse::Conflict()
+            .body("WebRTC sends Opus, the stream has to be created with ?codec=opus");
+    }
+
+    let negotiated = create_connection(stream_list.clone(), feed.clone(), stream_name.to_string(), offer_sdp).await;
+    let (connection, answer) = match negotiated {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Failed to negotiate the ingest of {}: {}", stream_name, e);
+            return HttpResponse::BadRequest().body(format!("WebRTC negotiation failed: {}", e));
+        }
+    };
+
+    // The RefMut is dropped right away, the old connection is closed after it
+    let previous = match stream_list.get_stream_ref_mut(stream_name) {
+        Some(mut stream) if stream.feed().same_as(&feed) => stream.set_connection(Some(connection.clone())),
+        _ => {
+            // The stream was removed (or replaced by another one) while negotiating
+            let _ = connection.close().await;
+            return HttpResponse::NotFound().body("Stream not found");
+        }
+    };
+    if let Some(previous) = previous {
+        info!("Replacing the ingest connection of {}", stream_name);
+        let _ = previous.close().await;
+    }
+
+    info!("WebRTC ingest of {} is negotiated, {} ms packets", stream_name, 
+          OPUS_FRAME_DURATION.as_millis());
+
+    HttpResponse::Created()
+        .append_header(("Location", format!("/stream/{}/whip", stream_name)))
+        .content_type("application/sdp")
+        .body(answer)
+}
@@ -2,12 +2,34 @@
 // Also, routes the people from the stream_id to the function
 // Which is processing the stream in streamer.rs file
 
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{web, App, Either, HttpRequest, HttpResponse, HttpServer};
 use argon2::password_hash::{self, rand_core::impls};
 use serde_json::json;
 use crate::{auth_logic::{jwt_functions::{decode_jwt}, models::{AuthenticatedUser, 
     RegistrationRequest, User}}, db::init_db, streamer::{perform_stream, ActiveStreams}};
+use crate::audio_coding::{analyze_pcm, decode_flac, encode_pcm, encode_rendition, AudioFormat, Codec, ConverterSlot,
+                          EffectSettings, InputFormat, LoudnessSlot, NormalizeSettings, OpusEncoders, PcmAnalysis, Rendition,
+                          SampleFormat, OPUS_SAMPLE_RATE};
+use crate::recorder::{find_recording, list_recordings};
+use crate::tracks::TrackStreamQuery;
+use crate::listing::StreamQuery;
+use crate::audience::audience_history;
+use crate::discovery::{follow, unfollow, DiscoveryQuery};
+use crate::backpressure::{Backpressure, LagPolicy};
+use crate::adaptive::RenditionChoice;
+use crate::normalization::{load_preference, save_preference, LoudnessPreference};
+use crate::streamer::{ChunkFeed, Stream};
+use crate::analysis::OpusMonitor;
+use std::time::Duration;
+use crate::streamer::StreamMetadata;
+use dashmap::mapref::one::RefMut;
+use crate::auth_logic::stream_keys::{create_key, has_active_key, hash_key, revoke_keys, 
+                                     verify_key, IngestAuth};
+use actix_multipart::Multipart;
 use actix_web::Responder;
+use actix_files::NamedFile;
+use actix_web::http::header::{self, HeaderValue};
+use uuid::Uuid;
 
 use log::{error, info, warn};
 
@@ -20,6 +42,11 @@ use bcrypt::{verify, hash};
 // Import of function which creates jwt token after successful authorization
 use crate::auth_logic::jwt_functions::create_jwt;
 
+// The biggest binary chunk the streamer could send to the ingest
+// 8 MB is around 40 seconds of the 16-bit stereo PCM at 48 kHz
+
+const MAX_INGEST_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
 // The main function for manipulating the server
 // There are some main routers which users can use for their needs
 // This server is called in the main function right from the start
@@ -30,9 +57,13 @@ pub async fn launch_server(stream_list : ActiveStreams, fragment_len: u8)
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(stream_list.clone()))
+            .app_data(web::PayloadConfig::new(MAX_INGEST_CHUNK_SIZE))
             .service(index)
             .service(create_stream)
             .service(load_chunk_to_srv)
+            .service(ingest_pcm)
+            .service(rotate_stream_key)
+            .service(revoke_stream_keys)
             .service(login)
             .service(user_data)
             .service(protectedArea)
@@ -40,6 +71,32 @@ pub async fn launch_server(stream_list : ActiveStreams, fragment_len: u8)
             .service(refreshToken)
             .service(get_all_active_streams)
             .route("/stream/{id}", web::get().to(stream))
+            .route("/stream/{id}/index.m3u8", web::get().to(hls_playlist))
+            .route("/stream/{id}/init.mp4", web::get().to(media_init))
+            .route("/stream/{id}/seg/{n}", web::get().to(media_segment))
+            .route("/stream/{id}/manifest.mpd", web::get().to(dash_manifest))
+            .route("/stream/{id}", web::delete().to(end_stream))
+            .route("/stream/{id}/info", web::get().to(stream_info))
+            .route("/stream/{id}/info", web::put().to(update_stream_info))
+            .route("/stream/{id}/stats", web::get().to(stream_stats))
+            .route("/stream/{id}/loudness", web::get().to(stream_loudness))
+            .route("/stream/{id}/analysis", web::get().to(stream_analysis))
+            .route("/preferences/loudness", web::get().to(loudness_preference))
+            .route("/preferences/loudness", web::put().to(update_loudness_preference))
+            .route("/streams", web::get().to(stream_listing))
+            .route("/discover", web::get().to(discover_streams))
+            .route("/metrics/listeners", web::get().to(listener_metrics))
+            .route("/follow/{username}", web::post().to(follow_user))
+            .route("/follow/{username}", web::delete().to(unfollow_user))
+            .route("/stream/{id}/whip", web::post().to(whip_ingest))
+            .route("/stream/{id}/whip", web::delete().to(whip_end_session))
+            .route("/stream/{id}/whep", web::post().to(whep_playback))
+            .route("/vod", web::get().to(vod_list))
+            .route("/vod/{id}", web::get().to(vod_replay))
+            .route("/tracks", web::post().to(upload_track))
+            .route("/tracks", web::get().to(track_list))
+            .route("/tracks/{id}", web::get().to(track_info))
+            .route("/tracks/{id}/stream", web::get().to(track_stream))
     })
     .bind(("0.0.0.0", 13412))?
     .run()
@@ -61,66 +118,1041 @@ async fn index () -> HttpResponse {
 // (which is currently not implemented yet, but will be in the future)
 
 #[actix_web::post("/load_chunk/{stream_id}")]
-async fn load_chunk_to_srv(stream_id: web::Path<String>, 
+async fn load_chunk_to_srv(auth: IngestAuth,
+                           stream_id: web::Path<String>, 
                            stream_list: web::Data<ActiveStreams>,
                            chunk: web::Json<Vec<u8>>) -> HttpResponse {
     let stream_id = stream_id.into_inner();
-    let mut stream = stream_list.get_stream_ref_mut(&stream_id);
 
     info!("Someone is trying to load a chunk to the stream ID: {:?}", stream_id);
 
-    // In case the reference mut is found, the stream is found as well, so the streamer is
-    // Sending the chunks to a valid stream
+    // In case the stream is found, the streamer is sending the chunks to a valid stream
     // Otherwise the stream is not found, so pushing of the chunk is not possible
+    // The DashMap is not held while the chunk is decoded
+
+    let (feed, codec, format, renditions, converter, encoders, leveling, monitor, analysis) = 
+            match controlled_stream(&stream_list, &stream_id, &auth).await {
+        Ok(s) => (s.feed(), s.codec(), s.format(), s.extra_renditions(), s.converters().1, s.opus_encoders(),
+                  (s.loudness(), s.normalization()), s.opus_monitor(), s.analysis()),
+        Err(response) => return response,
+    };
+
+    info!("The chunk is loading into the stream");
+    let chunk = chunk.into_inner();
+
+    // The zero length chunk would be the end of the stream for the FLAC listeners
+    if chunk.is_empty() {
+        warn!("Rejected the empty chunk for the stream {}", stream_id);
+        return HttpResponse::BadRequest().body("The chunk is empty");
+    }
+
+    // The FLAC chunks are decoded for their duration, the loudness, the analysis and the other renditions
+    // The Opus packet tells its duration in the TOC byte, it is decoded for the loudness and the analysis
+    let (duration, chunk_analysis, normalized, encoded) = match codec {
+        Codec::Flac => match process_flac_chunk(&stream_id, chunk.clone(), &renditions, 
+                                                converter, encoders, leveling).await {
+            Ok(processed) => processed,
+            Err(response) => return response,
+        },
+        Codec::Opus => match process_opus_chunk(&stream_id, chunk.clone(), format, monitor, leveling.0).await {
+            Ok(chunk_analysis) => (codec.chunk_duration(&chunk).unwrap_or_default(), chunk_analysis, None, Vec::new()),
+            Err(response) => return response,
+        },
+    };
+
+    // The chunk starts where the previous one has ended, the same in every rendition
+    // The stream could be replaced by another one with the same name while decoding
+    let position = match stream_list.get_stream_ref_mut(&stream_id) {
+        Some(mut stream) if stream.feed().same_as(&feed) => stream.advance_ingest(duration),
+        _ => return HttpResponse::NotFound().body(format!("Stream ID: {:?} not found", stream_id)),
+    };
+
+    feed.push_at(normalized.unwrap_or(chunk), position).await;
+
+    if let Some(chunk_analysis) = chunk_analysis {
+        match codec {
+            Codec::Flac => analysis.publish(chunk_analysis, position),
+            // The analysis of the Opus packets is of the window, which ends with the packet
+            Codec::Opus => analysis.publish_until(chunk_analysis, position + duration),
+        };
+    }
+
+    push_renditions(&renditions, encoded, position).await;
+
+    HttpResponse::Ok().body(format!("Chunk loaded to stream ID: {:?}", stream_id))
+}
+
+// Encoding the PCM for the renditions, the failed ones are skipped
+// so a single rendition could not break the stream itself
+// The Opus renditions get the PCM resampled to 48 kHz, when the stream has another rate
+
+fn encode_renditions(renditions: &[Rendition], pcm: &[i32], format: &AudioFormat,
+                     converter: &ConverterSlot, encoders: &OpusEncoders) -> Vec<(Rendition, Vec<Vec<u8>>)> {
+    let needs_opus = renditions.iter().any(|r| r.codec() == Codec::Opus);
+    let opus_format = AudioFormat { sample_rate: OPUS_SAMPLE_RATE, ..*format };
 
-    if let Some(mut s) = stream {
-        info!("The chunk is loading into the stream");
-        s.load_chunk(chunk.into_inner()).await;
-        HttpResponse::Ok().body(format!("Chunk loaded to stream ID: {:?}", stream_id))
+    let opus_pcm = if needs_opus && format.sample_rate != OPUS_SAMPLE_RATE {
+        match converter.convert_pcm(*format, opus_format, pcm) {
+            Ok(resampled) => Some(resampled),
+            Err(e) => {
+                warn!("Failed to resample the PCM for the Opus renditions: {}", e);
+                None
+            }
+        }
     } else {
+        None
+    };
+
+    renditions.iter()
+        .map(|rendition| {
+            let (pcm, format) = match (&opus_pcm, rendition.codec()) {
+                (Some(resampled), Codec::Opus) => (resampled.as_slice(), &opus_format),
+                _ => (pcm, format),
+            };
+            (rendition, encode_rendition(*rendition, pcm, format, encoders))
+        })
+        .filter_map(|(rendition, encoded)| match encoded {
+            Ok(chunks) => Some((*rendition, chunks)),
+            Err(e) => {
+                warn!("Failed to encode the {:?} rendition: {}", rendition, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Pushing the encoded chunks one after another from the media position of the audio
+// The position is the same in every rendition, so the listeners could switch between them
+
+async fn push_encoded(feed: &ChunkFeed, codec: Codec, chunks: Vec<Vec<u8>>, 
+                      mut position: Duration) -> Option<u64> {
+    let mut last_seq = None;
+    for chunk in chunks {
+        let duration = codec.chunk_duration(&chunk).unwrap_or_default();
+        last_seq = Some(feed.push_at(chunk, position).await);
+        position += duration;
+    }
+    last_seq
+}
+
+// Pushing the encoded renditions next to the stream, from the same media position
+
+async fn push_renditions(feeds: &[(Rendition, ChunkFeed)], encoded: Vec<(Rendition, Vec<Vec<u8>>)>,
+                         position: Duration) {
+    for (rendition, chunks) in encoded {
+        if let Some((_, feed)) = feeds.iter().find(|(r, _)| *r == rendition) {
+            push_encoded(feed, rendition.codec(), chunks, position).await;
+        }
+    }
+}
+
+// The FLAC chunk is pushed as it is, it is decoded once for its duration, its loudness,
+// its analysis and the renditions. The chunk, which is not decodable, is rejected,
+// the listeners and the segments could not play it either
+// When the stream is normalized, the normalized PCM is encoded again and replaces
+// the chunk of the broadcaster, the analysis and the renditions get the same PCM
+
+async fn process_flac_chunk(stream_id: &str, chunk: Vec<u8>, feeds: &[(Rendition, ChunkFeed)], 
+                            converter: ConverterSlot, encoders: OpusEncoders, 
+                            (loudness, normalization): (LoudnessSlot, Option<NormalizeSettings>))
+                            -> Result<(Duration, Option<PcmAnalysis>, Option<Vec<u8>>, 
+                                       Vec<(Rendition, Vec<Vec<u8>>)>), HttpResponse> {
+    let renditions: Vec<Rendition> = feeds.iter().map(|(r, _)| *r).collect();
+
+    let processed = web::block(move || {
+        let decoded = decode_flac(&chunk)
+            .map_err(|e| format!("The chunk is not a valid FLAC stream: {}", e))?;
+        let format = decoded.metadata.info.format();
+        let frames = decoded.samples.len() / format.channels.max(1);
+        let duration = Duration::from_secs_f64(frames as f64 / format.sample_rate.max(1) as f64);
+
+        // The formats, which the meter does not take (32-bit FLAC), are passed as they are
+        let (pcm, normalized) = match format.validate() {
+            Ok(()) => {
+                let pcm = loudness.process(decoded.samples, &format, normalization).map_err(|e| e.to_string())?;
+                let normalized = match normalization {
+                    Some(_) => encode_pcm(Codec::Flac, &pcm, &format, &encoders)
+                        .map_err(|e| e.to_string())?.pop(),
+                    None => None,
+                };
+                (pcm, normalized)
+            }
+            Err(_) => (decoded.samples, None),
+        };
+
+        Ok::<_, String>((duration, Some(analyze_pcm(&pcm, &format)), normalized,
+            encode_renditions(&renditions, &pcm, &format, &converter, &encoders)))
+    }).await;
+
+    match processed {
+        Ok(Ok(processed)) => Ok(processed),
+        Ok(Err(e)) => {
+            warn!("Rejected FLAC chunk for the stream {}: {}", stream_id, e);
+            Err(HttpResponse::BadRequest().body(e))
+        }
+        Err(e) => {
+            error!("Decoding of the FLAC chunk failed: {}", e);
+            Err(HttpResponse::InternalServerError().body("Decoding failed"))
+        }
+    }
+}
+
+// The Opus packet is decoded for the loudness and the analysis of the stream,
+// the packet, which is not decodable, is rejected like the broken FLAC chunk
+// Returns the analysis, once the packets have filled its window
+
+async fn process_opus_chunk(stream_id: &str, chunk: Vec<u8>, format: Option<AudioFormat>, monitor: OpusMonitor,
+                            loudness: LoudnessSlot) -> Result<Option<PcmAnalysis>, HttpResponse> {
+    match web::block(move || monitor.decode(&chunk, format, &loudness)).await {
+        Ok(Ok(chunk_analysis)) => Ok(chunk_analysis),
+        Ok(Err(e)) => {
+            warn!("Rejected Opus chunk for the stream {}: {}", stream_id, e);
+            Err(HttpResponse::BadRequest().body(format!("The chunk is not a valid Opus packet: {}", e)))
+        }
+        Err(e) => {
+            error!("Decoding of the Opus chunk failed: {}", e);
+            Err(HttpResponse::InternalServerError().body("Decoding failed"))
+        }
+    }
+}
+
+// Only the owner of the stream (or an admin) is allowed to push the audio into it
+// The encoders could use the stream key of the owner instead of the access token
+// Returns the stream to change, the check is made under the same RefMut,
+// so the stream could not be replaced by another one with the same name in between
+// Otherwise returns the response to send back in case the pusher is not allowed to
+
+async fn controlled_stream<'a>(stream_list: &'a ActiveStreams, stream_id: &str, 
+                               auth: &IngestAuth) -> Result<RefMut<'a, String, Stream>, HttpResponse> {
+    let not_found = || {
         warn!("Stream ID: {:?} not found", stream_id);
         HttpResponse::NotFound().body(format!("Stream ID: {:?} not found", stream_id))
+    };
+    let forbidden = || HttpResponse::Forbidden().body("The stream belongs to another user");
+
+    match auth {
+        IngestAuth::User(user) => {
+            let stream = stream_list.get_stream_ref_mut(stream_id).ok_or_else(not_found)?;
+
+            if stream.is_owned_by(&user.username) || user.is_admin() {
+                return Ok(stream);
+            }
+
+            warn!("{} tried to control the stream {}, which is not theirs", user.username, stream_id);
+            Err(forbidden())
+        }
+        IngestAuth::StreamKey(key) => {
+            let key_hash = hash_key(key);
+
+            // The RefMut is not held while the key is checked in the database
+            let (owner, feed, generation) = {
+                let stream = stream_list.get_stream_ref_mut(stream_id).ok_or_else(not_found)?;
+                if stream.is_key_verified(&key_hash) {
+                    return Ok(stream);
+                }
+                (stream.owner().to_string(), stream.feed(), stream.key_generation())
+            };
+
+            match verify_key(&key_hash, &owner, stream_id).await {
+                // The key is of the stream, which was there before the check
+                // The keys revoked during the check are not remembered, the next push asks again
+                Ok(true) => match stream_list.get_stream_ref_mut(stream_id) {
+                    Some(mut stream) if stream.feed().same_as(&feed) => {
+                        stream.set_verified_key(key_hash, generation);
+                        Ok(stream)
+                    }
+                    _ => Err(not_found()),
+                },
+                Ok(false) => {
+                    warn!("Invalid stream key was used for the stream {}", stream_id);
+                    Err(forbidden())
+                }
+                Err(e) => {
+                    error!("Failed to check the stream key of {}: {}", stream_id, e);
+                    Err(HttpResponse::InternalServerError().body("Failed to check the stream key"))
+                }
+            }
+        }
     }
 }
 
+// Reading the format of the PCM from the headers of the ingest request
+// The missing headers fall back to 16-bit stereo 44.1 kHz
+// X-Audio-Sample-Format (i16|i24|f32) is taken over X-Audio-Bits-Per-Sample
+
+fn input_format_from_headers(req: &HttpRequest) -> Result<InputFormat, String> {
+    let default = AudioFormat::default();
+
+    let header = |name: &str, default: usize| -> Result<usize, String> {
+        match req.headers().get(name) {
+            Some(value) => value.to_str().ok()
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .ok_or_else(|| format!("Invalid {} header", name)),
+            None => Ok(default),
+        }
+    };
+
+    let sample_format = match req.headers().get("X-Audio-Sample-Format") {
+        Some(value) => value.to_str().ok()
+            .and_then(SampleFormat::from_name)
+            .ok_or("Invalid X-Audio-Sample-Format header, it is i16, i24 or f32")?,
+        None => SampleFormat::Int(header("X-Audio-Bits-Per-Sample", default.bits_per_sample)?),
+    };
+
+    Ok(InputFormat {
+        channels: header("X-Audio-Channels", default.channels)?,
+        sample_format,
+        sample_rate: header("X-Audio-Sample-Rate", default.sample_rate as usize)? as u32,
+    })
+}
+
+// The binary ingest of the raw PCM (application/octet-stream)
+// The body is interleaved little-endian PCM, the format is declared in the
+// X-Audio-Channels, X-Audio-Bits-Per-Sample (or X-Audio-Sample-Format) and X-Audio-Sample-Rate headers
+// The PCM is converted to the format of the stream (resampled, mixed to mono or stereo
+// and dithered), its loudness is measured (and normalized), it is analyzed for
+// /stream/{id}/analysis and encoded with the codec of the stream before it reaches the listeners
+
+#[actix_web::post("/ingest/{stream_id}")]
+async fn ingest_pcm(req: HttpRequest,
+                    auth: IngestAuth,
+                    stream_id: web::Path<String>, 
+                    stream_list: web::Data<ActiveStreams>,
+                    body: web::Bytes) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+
+    let mut stream = match controlled_stream(&stream_list, &stream_id, &auth).await {
+        Ok(stream) => stream,
+        Err(response) => return response,
+    };
+
+    let input = match input_format_from_headers(&req) {
+        Ok(input) => input,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    if body.is_empty() {
+        return HttpResponse::BadRequest().body("The PCM chunk is empty");
+    }
+
+    // The stream without the declared format takes the one of the first chunk
+    // It is checked first, a broken first chunk could not leave the stream without the PCM ingest
+    let format = match stream.format() {
+        Some(format) => format,
+        None => {
+            let format = input.stream_format(stream.codec());
+            if let Err(e) = input.validate().and_then(|_| format.validate()) {
+                warn!("Rejected the format of the first PCM chunk of {}: {}", stream_id, e);
+                return HttpResponse::BadRequest().body(e.to_string());
+            }
+            stream.set_format(format);
+            format
+        }
+    };
+
+    // The DashMap is not held while the PCM is encoded
+    let (feed, codec, feeds, (converter, rendition_converter), encoders, normalization, loudness, analysis) = 
+        (stream.feed(), stream.codec(), stream.extra_renditions(), stream.converters(),
+         stream.opus_encoders(), stream.normalization(), stream.loudness(), stream.analysis());
+    drop(stream);
+
+    // Encoding is heavy, so it is done on the blocking thread pool
+    // The renditions are encoded from the same PCM next to the stream itself
+    let renditions: Vec<Rendition> = feeds.iter().map(|(r, _)| *r).collect();
+
+    let encoded = web::block(move || {
+        let pcm = converter.convert(input, format, &body)?;
+
+        // The resampler could keep the whole of a tiny chunk until the next one
+        if pcm.is_empty() {
+            return Ok((Vec::new(), Vec::new(), Duration::ZERO, None));
+        }
+
+        // Measured (and normalized, when the stream wants it) before anything is encoded,
+        // the analysis is of what the listeners hear
+        let pcm = loudness.process(pcm, &format, normalization)?;
+        let chunk_analysis = analyze_pcm(&pcm, &format);
+
+        let chunks = encode_pcm(codec, &pcm, &format, &encoders)?;
+
+        // The duration of the converted PCM moves the media position of the stream
+        let frames = pcm.len() / format.channels;
+        let duration = Duration::from_secs_f64(frames as f64 / format.sample_rate as f64);
+
+        Ok::<_, crate::audio_coding::AudioCodingError>(
+            (chunks, encode_renditions(&renditions, &pcm, &format, &rendition_converter, &encoders), duration,
+             Some(chunk_analysis)))
+    }).await;
+
+    let (chunks, encoded, duration, chunk_analysis) = match encoded {
+        Ok(Ok(chunks)) => chunks,
+        Ok(Err(e)) => {
+            warn!("Rejected PCM chunk for the stream {}: {}", stream_id, e);
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+        Err(e) => {
+            error!("Encoding of the PCM chunk failed: {}", e);
+            return HttpResponse::InternalServerError().body("Encoding failed");
+        }
+    };
+
+    // The PCM is accepted, so the media position moves forward,
+    // unless the stream has been replaced by another one in the meantime
+    let position = match stream_list.get_stream_ref_mut(&stream_id) {
+        Some(mut stream) if stream.feed().same_as(&feed) => stream.advance_ingest(duration),
+        _ => return HttpResponse::NotFound().body(format!("Stream ID: {:?} not found", stream_id)),
+    };
+
+    if let Some(chunk_analysis) = chunk_analysis {
+        analysis.publish(chunk_analysis, position);
+    }
+
+    let last_seq = push_encoded(&feed, codec, chunks, position).await;
+    push_renditions(&feeds, encoded, position).await;
+
+    HttpResponse::Ok().json(json!({ "seq": last_seq }))
+}
+
 // The stream creation function, one of the main routes here
 // It creates a new stream with the given name and ID
+// The stream belongs to the logged in user, who creates it
+
+// The codec could be chosen with ?codec=flac|opus, FLAC is the default one
+// With ?record=true the stream is recorded and could be replayed from /vod later
+// ?renditions=lossless,opus_high,opus_low adds the versions of the stream for the slower
+// connections, they are encoded from the PCM ingest and from the FLAC chunks
+// (the Opus chunks and WHIP do not fill them), the listeners get them once they are filled
+// ?sample_rate=&channels=&bits_per_sample= declare the format of the stream, the PCM ingest
+// is converted to it, otherwise the format of the first PCM chunk is used
+// ?normalize=true&target_lufs=-16&true_peak_dbtp=-1 bring the PCM ingest and the FLAC chunks to the same loudness,
+// the missing ones are taken from the loudness preference of the user
+// The Opus chunks and WHIP are passed as they are sent, so the Opus streams are normalized
+// on the PCM ingest only, the response tells which ingests the normalization applies to
+// The optional JSON body is the metadata of the stream:
+// {"title", "description", "tags": [...], "cover_art_url"}
+
+#[derive(serde::Deserialize)]
+struct CreateStreamQuery {
+    codec: Option<Codec>,
+    record: Option<bool>,
+    renditions: Option<String>,
+    sample_rate: Option<u32>,
+    channels: Option<usize>,
+    bits_per_sample: Option<usize>,
+    normalize: Option<bool>,
+    target_lufs: Option<f64>,
+    true_peak_dbtp: Option<f64>,
+}
+
+impl CreateStreamQuery {
+    // The declared format of the stream, Opus is always 48 kHz with up to two channels
+    fn format(&self, codec: Codec) -> Result<Option<AudioFormat>, String> {
+        if self.sample_rate.is_none() && self.channels.is_none() && self.bits_per_sample.is_none() {
+            return Ok(None);
+        }
+
+        let default = match codec {
+            Codec::Flac => AudioFormat::default(),
+            Codec::Opus => AudioFormat { sample_rate: OPUS_SAMPLE_RATE, ..AudioFormat::default() },
+        };
+        let format = AudioFormat {
+            channels: self.channels.unwrap_or(default.channels),
+            bits_per_sample: self.bits_per_sample.unwrap_or(default.bits_per_sample),
+            sample_rate: self.sample_rate.unwrap_or(default.sample_rate),
+        };
+        format.validate().map_err(|e| e.to_string())?;
+
+        if codec == Codec::Opus && (format.sample_rate != OPUS_SAMPLE_RATE || format.channels > 2) {
+            return Err("Opus streams are 48 kHz with one or two channels".to_string());
+        }
+
+        Ok(Some(format))
+    }
+
+    // The preference of the user with the values of the query over it
+    fn loudness(&self, preference: LoudnessPreference) -> LoudnessPreference {
+        LoudnessPreference {
+            normalize: self.normalize.unwrap_or(preference.normalize),
+            target_lufs: self.target_lufs.unwrap_or(preference.target_lufs),
+            true_peak_dbtp: self.true_peak_dbtp.unwrap_or(preference.true_peak_dbtp),
+        }
+    }
+}
 
 #[actix_web::post("/create_stream/{streamname}")]
-async fn create_stream(streamname :web::Path<String>, 
-                       stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+async fn create_stream(user: AuthenticatedUser,
+                       streamname :web::Path<String>, 
+                       query: web::Query<CreateStreamQuery>,
+                       stream_list: web::Data<ActiveStreams>,
+                       body: web::Bytes) -> HttpResponse {
     let streamname = streamname.into_inner(); 
-    let stream = crate::streamer::Stream::new(0, streamname.clone(), None);
+    let codec = query.codec.unwrap_or(Codec::Flac);
+
+    let format = match query.format(codec) {
+        Ok(format) => format,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let metadata = if body.is_empty() {
+        StreamMetadata::default()
+    } else {
+        match serde_json::from_slice::<StreamMetadata>(&body).map_err(|e| e.to_string())
+                .and_then(StreamMetadata::validated) {
+            Ok(metadata) => metadata,
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        }
+    };
+
+    // The ID of the owner is taken from the database, the token has the username only
+    let pool = match init_db().await {
+        Some(pool) => pool,
+        None => return HttpResponse::InternalServerError().body("Failed to connect to the database."),
+    };
+
+    let user_id = sqlx::query_scalar::<_, i32>("SELECT id FROM users WHERE name = $1")
+        .bind(&user.username)
+        .fetch_optional(&pool)
+        .await;
+
+    let user_id = match user_id {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            warn!("User {} from the token does not exist", user.username);
+            return HttpResponse::Unauthorized().body("User not found!");
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Server error: {}", e)),
+    };
+
+    let preference = match load_preference(&user.username).await {
+        Ok(preference) => preference,
+        Err(e) => {
+            warn!("Failed to get the loudness preference of {}: {}", user.username, e);
+            LoudnessPreference::default()
+        }
+    };
+
+    let normalization = match query.loudness(preference).stream_settings() {
+        Ok(normalization) => normalization,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let mut stream = crate::streamer::Stream::new(user_id as usize, user.username.clone(), 
+                                                  streamname.clone(), None);
+    stream.set_codec(codec);
+    if let Some(format) = format {
+        stream.set_format(format);
+    }
+    stream.set_normalization(normalization);
+
+    // Every Opus packet is a chunk of 20 ms, so the ring is made as big
+    // as the WebRTC one, otherwise it would not even fit the pre-roll
+    if stream.codec() == Codec::Opus {
+        stream.feed().set_capacity(crate::rtc::ingest::RTP_RING_CAPACITY).await;
+    }
+    stream.set_metadata(metadata);
+
+    for name in query.renditions.iter().flat_map(|names| names.split(',')).filter(|n| !n.trim().is_empty()) {
+        match Rendition::from_name(name) {
+            Some(rendition) => stream.add_rendition(rendition),
+            None => return HttpResponse::BadRequest().body(format!("Unknown rendition {}", name)),
+        }
+    }
+
+    let feed = stream.feed();
     if stream_list.add_stream(stream).await.is_err() {
         error!("Stream with name {} already exists", streamname);
         return HttpResponse::BadRequest()
                 .body(format!("Stream with name {} already exists", streamname));
     }
 
-    HttpResponse::Ok().body(format!("Stream created with ID: {:?}", streamname))
+    // The recording is started only for the stream, which got in,
+    // so the refused one does not leave its file behind
+    if query.record.unwrap_or(false) {
+        let started = match stream_list.get_stream_ref_mut(&streamname) {
+            Some(mut stream) if stream.feed().same_as(&feed) => stream.start_recording(),
+            _ => return HttpResponse::NotFound().body(format!("Stream ID: {:?} not found", streamname)),
+        };
+
+        if let Err(e) = started {
+            error!("Failed to start the recording of {}: {}", streamname, e);
+            stream_list.remove_stream(&streamname, &feed).await;
+            return HttpResponse::InternalServerError().body("Failed to start the recording");
+        }
+    }
+
+    // The stream key is generated for the first broadcast with this name only,
+    // the encoder keeps using it until the owner rotates or revokes it
+    // The stream is not left without a key, when the database fails
+    let stream_key = match has_active_key(&user.username, &streamname).await {
+        Ok(true) => None,
+        Ok(false) => match create_key(&user.username, &streamname).await {
+            Ok(key) => Some(key),
+            Err(e) => {
+                error!("Failed to create the stream key of {}: {}", streamname, e);
+                stream_list.remove_stream(&streamname, &feed).await;
+                return HttpResponse::InternalServerError().body("Failed to create the stream key");
+            }
+        },
+        Err(e) => {
+            error!("Failed to check the stream key of {}: {}", streamname, e);
+            stream_list.remove_stream(&streamname, &feed).await;
+            return HttpResponse::InternalServerError().body("Failed to check the stream key");
+        }
+    };
+
+    let normalized_ingests = match codec {
+        Codec::Flac => vec!["pcm", "flac"],
+        Codec::Opus => vec!["pcm"],
+    };
+
+    HttpResponse::Ok().json(json!({
+        "message": format!("Stream created with ID: {:?}", streamname),
+        "stream_key": stream_key,
+        "normalization": normalization.map(|settings| json!({
+            "target_lufs": settings.target_lufs,
+            "true_peak_dbtp": settings.true_peak_dbtp,
+            "applies_to": normalized_ingests,
+        })),
+    }))
+}
+
+// Replacing the stream keys of the owner with a new one
+// The old keys stop working right away, the new key is shown only once
+// Only the access token works here, the stream key can not rotate itself
+
+#[actix_web::post("/stream/{id}/keys/rotate")]
+async fn rotate_stream_key(user: AuthenticatedUser, stream_id: web::Path<String>,
+                           stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+
+    if let Err(e) = revoke_keys(Some(&user.username), &stream_id).await {
+        error!("Failed to revoke the stream keys of {}: {}", stream_id, e);
+        return HttpResponse::InternalServerError().body("Failed to revoke the stream keys");
+    }
+    forget_verified_key(&stream_list, &stream_id);
+
+    match create_key(&user.username, &stream_id).await {
+        Ok(key) => HttpResponse::Ok().json(json!({ "stream_key": key })),
+        Err(e) => {
+            error!("Failed to create the stream key of {}: {}", stream_id, e);
+            HttpResponse::InternalServerError().body("Failed to create the stream key")
+        }
+    }
+}
+
+// Revoking the stream keys, the admins revoke the keys of every owner
+
+#[actix_web::delete("/stream/{id}/keys")]
+async fn revoke_stream_keys(user: AuthenticatedUser, stream_id: web::Path<String>,
+                            stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+    let owner = if user.is_admin() { None } else { Some(user.username.as_str()) };
+
+    match revoke_keys(owner, &stream_id).await {
+        Ok(revoked) => {
+            forget_verified_key(&stream_list, &stream_id);
+            HttpResponse::Ok().json(json!({ "revoked": revoked }))
+        }
+        Err(e) => {
+            error!("Failed to revoke the stream keys of {}: {}", stream_id, e);
+            HttpResponse::InternalServerError().body("Failed to revoke the stream keys")
+        }
+    }
+}
+
+// The live stream remembers the checked key, it has to be asked again
+fn forget_verified_key(stream_list: &ActiveStreams, stream_id: &str) {
+    if let Some(mut stream) = stream_list.get_stream_ref_mut(stream_id) {
+        stream.forget_verified_key();
+    }
+}
+
+// The metadata of the live stream, the same one as in the listing
+
+async fn stream_info(stream_id: web::Path<String>, 
+                     stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    match stream_list.get_stream(&stream_id).await {
+        Some(stream) => HttpResponse::Ok().json(stream.listing()),
+        None => HttpResponse::NotFound().body("Stream not found"),
+    }
+}
+
+// Replacing the metadata of the stream, the title could change with every song
+// The encoder is allowed to do it with the stream key as well
+
+async fn update_stream_info(auth: IngestAuth, stream_id: web::Path<String>,
+                            stream_list: web::Data<ActiveStreams>,
+                            metadata: web::Json<StreamMetadata>) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+
+    let metadata = match metadata.into_inner().validated() {
+        Ok(metadata) => metadata,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    match controlled_stream(&stream_list, &stream_id, &auth).await {
+        Ok(mut stream) => {
+            stream.set_metadata(metadata);
+            HttpResponse::Ok().json(stream.listing())
+        }
+        Err(response) => response,
+    }
+}
+
+// The audience of the stream for the dashboard of its owner
+// ?hours=24 is how far back the history goes, up to 30 days
+// The history is kept per minute, the live counts are there while the stream goes on
+
+#[derive(serde::Deserialize)]
+struct StreamStatsQuery {
+    hours: Option<i64>,
+}
+
+async fn stream_stats(user: AuthenticatedUser, stream_id: web::Path<String>,
+                      query: web::Query<StreamStatsQuery>,
+                      stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+    let hours = query.hours.unwrap_or(24).clamp(1, 24 * 30);
+    let since = chrono::Utc::now() - chrono::Duration::hours(hours);
+
+    // The admins see the audience of every owner of the stream name
+    let owner = if user.is_admin() { None } else { Some(user.username.as_str()) };
+
+    let live = stream_list.get_stream(&stream_id).await
+        .filter(|stream| owner.map_or(true, |owner| stream.is_owned_by(owner)))
+        .map(|stream| {
+            let audience = stream.audience();
+            json!({ "listeners": audience.current(), "peak_listeners": audience.peak() })
+        });
+
+    let periods = match audience_history(owner, &stream_id, since).await {
+        Ok(periods) => periods,
+        Err(e) => {
+            error!("Failed to get the audience of {}: {}", stream_id, e);
+            return HttpResponse::InternalServerError().body("Failed to get the audience");
+        }
+    };
+
+    HttpResponse::Ok().json(json!({
+        "stream": stream_id,
+        "live": live,
+        "joins": periods.iter().map(|p| p.joins).sum::<i64>(),
+        "leaves": periods.iter().map(|p| p.leaves).sum::<i64>(),
+        "listen_minutes": periods.iter().map(|p| p.listen_minutes).sum::<f64>(),
+        "peak_listeners": periods.iter().map(|p| p.peak_listeners).max().unwrap_or(0),
+        "periods": periods,
+    }))
+}
+
+// The listing of the live streams with the filters and the pages, see listing.rs
+
+async fn stream_listing(query: web::Query<StreamQuery>, 
+                        stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    crate::listing::list(&stream_list, &query)
+}
+
+// ?rendition=lossless|opus_high|opus_low picks the version of the stream,
+// ?rendition=auto switches between the Opus ones by how the listener keeps up with the live edge
+// The listener chooses what happens when it falls behind the live edge:
+// ?policy=drop_oldest|skip_to_live|disconnect and ?max_lag_ms= for the allowed lag
+// LISTENER_LAG_POLICY and LISTENER_MAX_LAG_MS are used otherwise
+
+#[derive(serde::Deserialize)]
+struct ListenQuery {
+    policy: Option<LagPolicy>,
+    max_lag_ms: Option<u64>,
+    rendition: Option<String>,
 }
 
 async fn stream(stream_id: web::Path<String>, active_streams: web::Data<ActiveStreams>,
-                                             ) -> impl Responder {
+                query: web::Query<ListenQuery>) -> Either<HttpResponse, impl Responder> {
     let stream_id = stream_id.into_inner();
 
-    // The fragment length is the length of the chunk which is sent to the user
+    let choice = match query.rendition.as_deref().map(RenditionChoice::from_name) {
+        Some(None) => return Either::Left(HttpResponse::BadRequest().body("Unknown rendition")),
+        choice => choice.flatten(),
+    };
 
-    let fragment_len = 0.5; 
+    let backpressure = Backpressure::new(query.policy, 
+                                         query.max_lag_ms.map(std::time::Duration::from_millis),
+                                         crate::streamer::preroll());
 
     // Perform the streaming operation
+    // The chunks are pushed to the user the moment the streamer loads them
     // This function is defined in the streamer.rs file in the case of wondering
-    perform_stream(active_streams, stream_id, fragment_len).await
+    Either::Right(perform_stream(active_streams, stream_id, backpressure, choice).await)
+}
+
+// How often the slow listeners were handled by every policy since the start
+
+async fn listener_metrics() -> HttpResponse {
+    HttpResponse::Ok().json(crate::backpressure::metrics())
+}
+
+// HLS playlist of the live stream, so it could be played with ffplay / VLC / browsers
+// The segments are the chunks from the history of the stream, packaged as fragmented MP4
+
+async fn hls_playlist(stream_id: web::Path<String>, 
+                      active_streams: web::Data<ActiveStreams>) -> impl Responder {
+    crate::manifests::hls::playlist(&active_streams, &stream_id.into_inner()).await
+}
+
+async fn media_init(stream_id: web::Path<String>, 
+                    active_streams: web::Data<ActiveStreams>) -> impl Responder {
+    crate::manifests::fmp4::init(&active_streams, &stream_id.into_inner()).await
 }
 
-/*
-async fn get_10_active_streams() -> impl Responder {
-    // This function is needed to get the current
-    // It wil return 10 random currently going streams
+async fn media_segment(path: web::Path<(String, u64)>, 
+                       active_streams: web::Data<ActiveStreams>) -> impl Responder {
+    let (stream_id, number) = path.into_inner();
+    crate::manifests::fmp4::segment(&active_streams, &stream_id, number).await
+}
+
+// MPEG-DASH manifest of the live stream, it uses the same segments as HLS
 
+async fn dash_manifest(stream_id: web::Path<String>, 
+                       active_streams: web::Data<ActiveStreams>) -> impl Responder {
+    crate::manifests::dash::manifest(&active_streams, &stream_id.into_inner()).await
+}
+
+// WHIP-style ingest, the streamer posts its SDP offer (application/sdp)
+// and gets the SDP answer, the audio then comes through WebRTC
+// WHIP sends the bearer token as well (the access token or the stream key),
+// only the owner could take over the stream, which has to be an Opus one
+
+async fn whip_ingest(auth: IngestAuth, stream_id: web::Path<String>, 
+                     active_streams: web::Data<ActiveStreams>, offer: String) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+
+    // The feed of the controlled stream goes along, so the connection
+    // could not end up on another stream with the same name
+    let (feed, codec) = match controlled_stream(&active_streams, &stream_id, &auth).await {
+        Ok(stream) => (stream.feed(), stream.codec()),
+        Err(response) => return response,
+    };
+
+    crate::rtc::ingest::accept_offer(&active_streams, &stream_id, feed, codec, offer).await
+}
+
+// Ending the WHIP session, the way the WHIP clients do it (DELETE of the Location)
+// Only the ingest connection is closed, the stream waits for the next session
+// and is ended by the reaper in case no one comes
+
+async fn whip_end_session(auth: IngestAuth, stream_id: web::Path<String>, 
+                          active_streams: web::Data<ActiveStreams>) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+
+    let connection = match controlled_stream(&active_streams, &stream_id, &auth).await {
+        Ok(mut stream) => stream.set_connection(None),
+        Err(response) => return response,
+    };
+
+    if let Some(connection) = connection {
+        if let Err(e) = connection.close().await {
+            warn!("Failed to close the ingest connection of {}: {}", stream_id, e);
+        }
+    }
 
+    HttpResponse::Ok().finish()
+}
+
+// Ending the stream by its owner (or an admin)
+// The listeners get the rest of the chunks and the end of the stream,
+// the recording (if any) is finalized
+
+async fn end_stream(auth: IngestAuth, stream_id: web::Path<String>, 
+                    active_streams: web::Data<ActiveStreams>) -> HttpResponse {
+    let stream_id = stream_id.into_inner();
+
+    let feed = match controlled_stream(&active_streams, &stream_id, &auth).await {
+        Ok(stream) => stream.feed(),
+        Err(response) => return response,
+    };
+
+    // The RefMut could not be held while the stream is removed, the feed tells
+    // whether it is still the same stream
+    if !active_streams.remove_stream(&stream_id, &feed).await {
+        return HttpResponse::NotFound().body("Stream not found");
+    }
+    HttpResponse::Ok().body(format!("Stream {:?} has ended", stream_id))
+}
+
+// WHEP-style playback, the listener posts its SDP offer
+// and receives the stream through a send-only WebRTC connection
+
+async fn whep_playback(stream_id: web::Path<String>, 
+                       active_streams: web::Data<ActiveStreams>, offer: String) -> impl Responder {
+    crate::rtc::egress::accept_offer(&active_streams, &stream_id.into_inner(), offer).await
+}
+
+// The finished recordings of the streams, the newest first
+// ?stream=name shows the recordings of a single stream only
+
+#[derive(serde::Deserialize)]
+struct VodListQuery {
+    stream: Option<String>,
+}
+
+async fn vod_list(query: web::Query<VodListQuery>) -> impl Responder {
+    match list_recordings(query.stream.as_deref()).await {
+        Ok(recordings) => HttpResponse::Ok().json(recordings),
+        Err(e) => {
+            error!("Failed to list the recordings: {}", e);
+            HttpResponse::InternalServerError().body("Failed to list the recordings")
+        }
+    }
+}
+
+// Replay of the recorded stream, the file is served as it is
+// NamedFile handles the Range requests, so the players are able to seek
+
+async fn vod_replay(req: HttpRequest, recording_id: web::Path<String>) -> HttpResponse {
+    let id = match Uuid::parse_str(&recording_id.into_inner()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid recording ID"),
+    };
+
+    let recording = match find_recording(id).await {
+        Ok(Some(recording)) => recording,
+        Ok(None) => return HttpResponse::NotFound().body("Recording not found"),
+        Err(e) => {
+            error!("Failed to find the recording {}: {}", id, e);
+            return HttpResponse::InternalServerError().body("Failed to find the recording");
+        }
+    };
+
+    match NamedFile::open_async(&recording.path).await {
+        Ok(file) => {
+            let mut response = file.into_response(&req);
+            response.headers_mut().insert(header::CONTENT_TYPE, 
+                                          HeaderValue::from_static(recording.mime_type()));
+            response
+        }
+        Err(e) => {
+            error!("The file of the recording {} is not readable: {}", id, e);
+            HttpResponse::NotFound().body("Recording file not found")
+        }
+    }
+}
+
+// The track library, the uploads need the user to be logged in
+// The upload is a multipart form, see tracks.rs for its fields
+
+async fn upload_track(user: AuthenticatedUser, payload: Multipart) -> impl Responder {
+    crate::tracks::upload(user, payload).await
+}
+
+async fn track_list() -> impl Responder {
+    crate::tracks::list().await
+}
+
+async fn track_info(track_id: web::Path<String>) -> impl Responder {
+    crate::tracks::info(&track_id.into_inner()).await
+}
+
+// The track supports the Range requests, and ?t=seconds to start from the given second
+// The effects are in the same query: ?preset=slowed, ?speed=, ?tempo=, ?pitch=, ?reverb=, ?eq=, ?gain=
+
+async fn track_stream(req: HttpRequest, track_id: web::Path<String>, query: web::Query<TrackStreamQuery>,
+                      effects: web::Query<EffectSettings>) -> impl Responder {
+    crate::tracks::stream(&req, &track_id.into_inner(), &query, &effects).await
+}
+
+// The live loudness of the stream, measured on the PCM ingest, the FLAC chunks and the Opus packets
+// (the normalization is applied to the PCM ingest and the FLAC chunks only)
+
+async fn stream_loudness(stream_id: web::Path<String>,
+                         stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    let loudness = match stream_list.get_stream(&stream_id).await {
+        Some(stream) => stream.loudness(),
+        None => return HttpResponse::NotFound().body("Stream not found"),
+    };
+
+    match loudness.report() {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+// The live analysis of the stream as the server-sent events, one for every decoded chunk:
+// the RMS and the peak of every channel, the spectrum bands and the silence of the stream
+
+async fn stream_analysis(stream_id: web::Path<String>,
+                         stream_list: web::Data<ActiveStreams>) -> HttpResponse {
+    let analysis = match stream_list.get_stream(&stream_id).await {
+        Some(stream) => stream.analysis(),
+        None => return HttpResponse::NotFound().body("Stream not found"),
+    };
+
+    crate::analysis::events(&analysis)
+}
+
+// The loudness normalization the new streams of the user start with
+
+async fn loudness_preference(user: AuthenticatedUser) -> HttpResponse {
+    match load_preference(&user.username).await {
+        Ok(preference) => HttpResponse::Ok().json(preference),
+        Err(e) => {
+            error!("Failed to get the loudness preference of {}: {}", user.username, e);
+            HttpResponse::InternalServerError().body("Failed to get the preference")
+        }
+    }
+}
+
+async fn update_loudness_preference(user: AuthenticatedUser,
+                                    preference: web::Json<LoudnessPreference>) -> HttpResponse {
+    let preference = match preference.into_inner().validated() {
+        Ok(preference) => preference,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    match save_preference(&user.username, &preference).await {
+        Ok(()) => HttpResponse::Ok().json(preference),
+        Err(e) => {
+            error!("Failed to save the loudness preference of {}: {}", user.username, e);
+            HttpResponse::InternalServerError().body("Failed to save the preference")
+        }
+    }
+}
+
+// The discovery of the streams, 10 random currently going streams by default
+// ?mode=trending shows the streams gaining the listeners the fastest,
+// ?mode=following puts the streamers the user follows first (the token is needed)
+// ?limit=N changes the amount of the streams
+
+async fn discover_streams(user: Option<AuthenticatedUser>, query: web::Query<DiscoveryQuery>,
+                          stream_list: web::Data<ActiveStreams>) -> impl Responder {
+    crate::discovery::discover(&stream_list, &query, user.as_ref()).await
+}
+
+// Following and unfollowing the streamers, used by the discovery
+
+async fn follow_user(user: AuthenticatedUser, username: web::Path<String>) -> HttpResponse {
+    let username = username.into_inner();
+
+    if username == user.username {
+        return HttpResponse::BadRequest().body("It is not possible to follow yourself");
+    }
+
+    match follow(&user.username, &username).await {
+        Ok(true) => HttpResponse::Ok().json(json!({ "following": username })),
+        Ok(false) => HttpResponse::NotFound().body("User not found!"),
+        Err(e) => {
+            error!("Failed to follow {}: {}", username, e);
+            HttpResponse::InternalServerError().body("Failed to follow the user")
+        }
+    }
+}
+
+async fn unfollow_user(user: AuthenticatedUser, username: web::Path<String>) -> HttpResponse {
+    match unfollow(&user.username, &username).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            error!("Failed to unfollow {}: {}", username, e);
+            HttpResponse::InternalServerError().body("Failed to unfollow the user")
+        }
+    }
 }
-*/
 
 
 // This function is responsible for returning user new access token.
@@ -0,0 +1,63 @@
+// A file for the state shared by the requests of a stream behind a Mutex
+// (the audience counter, the analysis, the converters, the encoders...)
+// The lock is held only for a quick change, which a panic never leaves
+// half done, so the poisoning of the lock is ignored everywhere
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+
+
+// The state kept between the chunks of a stream (a converter, a meter, an encoder)
+// It is made again, when it does not fit the chunk (the format has changed...)
+
+pub struct Slot<T> {
+    value: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> Slot<T> {
+    // Running use_it with the kept value, or with a new one, when the kept one does not fit
+    // The value, which fails to be made, leaves the slot empty
+    pub fn with<R, E>(&self, fits: impl FnOnce(&T) -> bool, make: impl FnOnce() -> Result<T, E>,
+                      use_it: impl FnOnce(&mut T) -> Result<R, E>) -> Result<R, E> {
+        let mut slot = lock(&self.value);
+
+        let value = match slot.take() {
+            Some(value) if fits(&value) => value,
+            _ => make()?,
+        };
+
+        use_it(slot.insert(value))
+    }
+
+    // None until the first value is made
+    pub fn peek<R>(&self, look: impl FnOnce(&T) -> R) -> Option<R> {
+        lock(&self.value).as_ref().map(look)
+    }
+}
+
+// Derived, these would need T to be Clone and Default too
+
+impl<T> Clone for Slot<T> {
+    fn clone(&self) -> Self {
+        Slot { value: self.value.clone() }
+    }
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Slot { value: Arc::new(Mutex::new(None)) }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Slot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Slot").field(&self.value).finish()
+    }
+}
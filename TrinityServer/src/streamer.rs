@@ -2,23 +2,454 @@
 // Trinitypeer, 2025, by Trinitycore
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::current;
+use std::collections::VecDeque;
+use std::time::SystemTime;
 use actix_web::{web, Responder};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use actix_web::web::Bytes;
 use webrtc::peer_connection::RTCPeerConnection;
 use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::one::{Ref, RefMut};
 
+use crate::audio_coding::{AudioFormat, Codec, ConverterSlot, LoudnessSlot, NormalizeSettings, OggOpusWriter, OpusEncoders,
+                          Rendition};
+use crate::recorder::RecorderHandle;
+use crate::audience::{Audience, TRENDING_WINDOW};
+use crate::backpressure::{self, Backpressure, LagAction, LagPolicy};
+use crate::adaptive::{AdaptiveSelector, RenditionChoice};
+use crate::analysis::{silence_timeout, AnalysisFeed, OpusMonitor};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
 use actix_web::HttpResponse;
 
 use log::{error, info, warn};
 
-use tokio::time::{interval, Duration};
+use tokio::time::Duration;
 use tokio::sync::{Notify, RwLock};
 
 
 
+// The amount of chunks every stream keeps in its history
+// The oldest ones are dropped, when the new chunk does not fit anymore
+// With 1-2 secs per chunk it is around a minute of the audio
+
+pub const CHUNK_RING_CAPACITY: usize = 64;
+
+// How much of the audio a new listener gets right away (the pre-roll)
+// The player fills its buffer with it and starts without waiting for the next push
+// STREAM_PREROLL_MS changes it, up to MAX_PREROLL, 0 starts from the live edge
+
+pub const LATE_JOIN_DELAY: Duration = Duration::from_secs(3);
+pub const MAX_PREROLL: Duration = Duration::from_secs(10);
+
+// How long a stream could stay without a single chunk before it is ended
+// STREAM_IDLE_TIMEOUT_SECS changes it, 0 turns the reaping off
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How often the reaper looks for the idle streams
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+
+// The chunks are grouped into the HLS / DASH segments of at least this much audio,
+// the segment is a bit longer, as the chunk is never split between two of them
+pub const SEGMENT_DURATION: Duration = Duration::from_secs(2);
+
+// How long the feed of the ended stream is kept for the HLS / DASH players,
+// so they get the end of the playlist instead of losing the stream
+pub const ENDED_STREAM_RETENTION: Duration = Duration::from_secs(60);
+
+
+
+// A single chunk of the stream with its own sequence number
+// The sequence numbers only grow, so the listener always knows
+// which chunk it has played last and whether something was lost
+// The position is the media time of the chunk from the start of the stream,
+// it is the same in every rendition, when the chunk is encoded on the server
+// The segment is the number of the HLS / DASH segment the chunk belongs to
+
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub seq: u64,
+    pub timestamp: SystemTime,
+    pub position: Option<Duration>,
+    pub segment: u64,
+    pub data: Bytes,
+}
+
+impl Chunk {
+    // The byte length of the chunk payload
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+
+
+// The result of reading the ring from some sequence number
+// missed is the amount of chunks, which were already dropped from the ring
+// before the listener has managed to read them
+// ring_span is the time the full ring covers, None until the ring is full
+
+#[derive(Debug, Default)]
+pub struct ChunkRead {
+    pub chunks: Vec<Chunk>,
+    pub missed: u64,
+    pub ring_span: Option<Duration>,
+}
+
+
+
+// A bounded ring of the numbered chunks
+// Replaces the single overwritten chunk, so the listeners could
+// read forward from the place they have stopped
+
+#[derive(Debug)]
+pub struct ChunkRing {
+    chunks: VecDeque<Chunk>,
+    capacity: usize,
+    next_seq: u64,
+    // The current segment and the media position it has started at
+    segment: Option<(u64, Duration)>,
+}
+
+impl ChunkRing {
+    pub fn new(capacity: usize) -> Self {
+        ChunkRing {
+            chunks: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            next_seq: 0,
+            segment: None,
+        }
+    }
+
+    // Pushing the new chunk, the oldest one is dropped in case the ring is full
+    // Returns the sequence number given to the chunk
+
+    pub fn push(&mut self, data: Vec<u8>) -> u64 {
+        self.push_at(data, None)
+    }
+
+    pub fn push_at(&mut self, data: Vec<u8>, position: Option<Duration>) -> u64 {
+        // The listeners send the zero length chunk as the end of the stream
+        debug_assert!(!data.is_empty(), "The empty chunk was pushed into the ring");
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if self.chunks.len() == self.capacity {
+            self.chunks.pop_front();
+        }
+
+        // The numbers are given once, so the segment stays the same on every playlist reload
+        // The chunks without the position stay in the current segment
+        let segment = match (self.segment, position) {
+            (None, _) => (0, position.unwrap_or_default()),
+            (Some((number, start)), Some(position)) if position >= start + SEGMENT_DURATION => 
+                (number + 1, position),
+            (Some(current), _) => current,
+        };
+        self.segment = Some(segment);
+
+        self.chunks.push_back(Chunk {
+            seq,
+            timestamp: SystemTime::now(),
+            position,
+            segment: segment.0,
+            data: Bytes::from(data),
+        });
+
+        seq
+    }
+
+    // Changing the amount of the kept chunks, the oldest ones are dropped
+    // in case the ring is shrinking
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.chunks.len() > self.capacity {
+            self.chunks.pop_front();
+        }
+    }
+
+    // The first chunk, which starts at the given media position or later
+    // The listener switching the rendition continues from there
+    pub fn seq_at(&self, position: Duration) -> u64 {
+        self.chunks.iter()
+            .find(|chunk| chunk.position.is_some_and(|p| p >= position))
+            .map(|chunk| chunk.seq)
+            .unwrap_or(self.next_seq)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.iter()
+    }
+
+    // Reading all the chunks starting from the given sequence number
+    // In case some of them are already gone, the gap is reported in missed
+
+    pub fn read_from(&self, seq: u64) -> ChunkRead {
+        let oldest = match self.chunks.front() {
+            Some(chunk) => chunk.seq,
+            None => return ChunkRead::default(),
+        };
+
+        let start = seq.max(oldest);
+
+        let ring_span = match (self.chunks.front(), self.chunks.back()) {
+            (Some(first), Some(last)) if self.chunks.len() == self.capacity =>
+                Some(last.timestamp.duration_since(first.timestamp).unwrap_or_default()),
+            _ => None,
+        };
+
+        ChunkRead {
+            chunks: self.chunks.iter()
+                .skip((start - oldest) as usize)
+                .cloned()
+                .collect(),
+            missed: start - seq,
+            ring_span,
+        }
+    }
+
+    // The sequence number a new listener should start from
+    // The chunks are counted back from the live edge until they have the pre-roll
+    // of the audio, then the start is moved forward to the chunk the player
+    // could decode from. The newest chunk is always sent, even when it is longer
+
+    pub fn join_seq(&self, preroll: Duration, codec: Codec) -> u64 {
+        if preroll.is_zero() {
+            return self.next_seq;
+        }
+
+        let mut start = self.chunks.len();
+        let mut buffered = Duration::ZERO;
+        let mut pushed_after: Option<SystemTime> = None;
+        let mut last_gap = Duration::ZERO;
+
+        for (index, chunk) in self.chunks.iter().enumerate().rev() {
+            // The chunks without the duration are measured by the pace of the pushes
+            let gap = pushed_after
+                .and_then(|after| after.duration_since(chunk.timestamp).ok())
+                .unwrap_or(last_gap);
+            let duration = codec.chunk_duration(&chunk.data).unwrap_or(gap);
+
+            pushed_after = Some(chunk.timestamp);
+            last_gap = gap;
+            buffered += duration;
+            start = index;
+
+            if buffered >= preroll {
+                break;
+            }
+        }
+
+        self.chunks.iter()
+            .skip(start)
+            .find(|chunk| codec.is_decodable_start(&chunk.data))
+            .map(|chunk| chunk.seq)
+            .unwrap_or(self.next_seq)
+    }
+}
+
+
+
+// A shared handle to the chunk ring of the stream
+// The listeners clone it once and do not touch the DashMap afterwards
+// Every pushed chunk wakes all the waiting listeners through the Notify,
+// so the chunks are delivered the moment they arrive, without any polling
+
+#[derive(Clone, Debug)]
+pub struct ChunkFeed {
+    chunks: Arc<RwLock<ChunkRing>>,
+    change: Arc<Notify>,
+    closed: Arc<AtomicBool>,
+    fed: Arc<AtomicBool>,
+}
+
+impl ChunkFeed {
+    pub fn new(capacity: usize) -> Self {
+        ChunkFeed {
+            chunks: Arc::new(RwLock::new(ChunkRing::new(capacity))),
+            change: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+            fed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Whether both handles are of the same stream
+    pub fn same_as(&self, other: &ChunkFeed) -> bool {
+        Arc::ptr_eq(&self.chunks, &other.chunks)
+    }
+
+    // Pushing the chunk and waking up everyone, who waits for it
+    pub async fn push(&self, chunk: Vec<u8>) -> u64 {
+        let seq = self.chunks.write().await.push(chunk);
+        self.fed.store(true, Ordering::SeqCst);
+        self.change.notify_waiters();
+        seq
+    }
+
+    // Pushing the chunk encoded on the server, its media position is known
+    pub async fn push_at(&self, chunk: Vec<u8>, position: Duration) -> u64 {
+        let seq = self.chunks.write().await.push_at(chunk, Some(position));
+        self.fed.store(true, Ordering::SeqCst);
+        self.change.notify_waiters();
+        seq
+    }
+
+    pub async fn seq_at(&self, position: Duration) -> u64 {
+        self.chunks.read().await.seq_at(position)
+    }
+
+    pub async fn read_from(&self, seq: u64) -> ChunkRead {
+        self.chunks.read().await.read_from(seq)
+    }
+
+    pub async fn join_seq(&self, codec: Codec) -> u64 {
+        self.chunks.read().await.join_seq(preroll(), codec)
+    }
+
+    // All the chunks, which are currently kept in the ring
+    pub async fn history(&self) -> Vec<Chunk> {
+        self.chunks.read().await.iter().cloned().collect()
+    }
+
+    pub async fn set_capacity(&self, capacity: usize) {
+        self.chunks.write().await.set_capacity(capacity);
+    }
+
+    // The moment the newest chunk was pushed, None before the first one
+    pub async fn last_push(&self) -> Option<SystemTime> {
+        self.chunks.read().await.iter().last().map(|chunk| chunk.timestamp)
+    }
+
+    // Waiting until there is a chunk with the given sequence number (or newer)
+    // or until the feed is closed
+    // The Notified future is enabled before checking the ring,
+    // otherwise a push between the check and the await could be lost
+
+    pub async fn wait_for(&self, seq: u64) {
+        loop {
+            let notified = self.change.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_closed() || self.chunks.read().await.next_seq > seq {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    // Closing the feed, when the stream has ended
+    // Everyone waiting is woken up, the listeners read what is left and stop
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.change.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Whether anything was ever pushed, the renditions nothing fills stay empty
+    pub fn is_fed(&self) -> bool {
+        self.fed.load(Ordering::SeqCst)
+    }
+}
+
+
+
+// The description of the stream, set by its owner
+// Shown in the listing, the tags are the categories / genres of the stream
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StreamMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub cover_art_url: Option<String>,
+}
+
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_URL_LENGTH: usize = 2048;
+
+impl StreamMetadata {
+    // Checking the metadata from the owner, the tags are trimmed and lowercased,
+    // so the filtering by the category does not depend on the spelling
+
+    pub fn validated(mut self) -> Result<Self, String> {
+        let trim = |value: Option<String>| value
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+
+        self.title = trim(self.title);
+        self.description = trim(self.description);
+        self.cover_art_url = trim(self.cover_art_url);
+
+        if self.title.as_ref().is_some_and(|t| t.chars().count() > MAX_TITLE_LENGTH) {
+            return Err(format!("The title is longer than {} characters", MAX_TITLE_LENGTH));
+        }
+        if self.description.as_ref().is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LENGTH) {
+            return Err(format!("The description is longer than {} characters", MAX_DESCRIPTION_LENGTH));
+        }
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.tags.iter().map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()) {
+            if tag.chars().count() > MAX_TAG_LENGTH {
+                return Err(format!("The tag {} is longer than {} characters", tag, MAX_TAG_LENGTH));
+            }
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(format!("A stream could have up to {} tags", MAX_TAGS));
+        }
+        self.tags = tags;
+
+        if let Some(url) = &self.cover_art_url {
+            let scheme_ok = url.starts_with("http://") || url.starts_with("https://");
+            if !scheme_ok || url.len() > MAX_URL_LENGTH {
+                return Err("The cover art must be an http(s) URL".to_string());
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+
+
+// The public view of the live stream, as it is shown in the listing
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamListing {
+    pub name: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub owner: String,
+    pub tags: Vec<String>,
+    pub codec: Codec,
+    pub sample_rate: Option<u32>,
+    pub started_at: DateTime<Utc>,
+    pub listeners: usize,
+    pub peak_listeners: usize,
+    pub listener_growth: i64,
+    pub cover_art_url: Option<String>,
+    pub recording: bool,
+    pub renditions: Vec<Rendition>,
+}
+
+
+
 // A structure to store the stream data
 // There is connection with WebRTC to make sure we have our person saved
 // And will not do everytime a new one.
@@ -28,40 +459,295 @@ use tokio::sync::{Notify, RwLock};
 #[derive(Debug)]
 pub struct Stream {
     streamer_id: usize,
+    owner: String,
     stream_name: String,
     connection: Option<Arc<RTCPeerConnection>>,
-    current_chunk: Arc<RwLock<Vec<u8>>>,
-    change: Arc<Notify>,
+    listener_connections: Vec<Arc<RTCPeerConnection>>,
+    feed: ChunkFeed,
+    started_at: SystemTime,
+    codec: Codec,
+    format: Option<AudioFormat>,
+    recorder: Option<RecorderHandle>,
+    verified_key_hash: Option<String>,
+    key_generation: u64,
+    metadata: StreamMetadata,
+    audience: Audience,
+    renditions: Vec<(Rendition, ChunkFeed)>,
+    ingested: Duration,
+    ingest_converter: ConverterSlot,
+    rendition_converter: ConverterSlot,
+    opus_encoders: OpusEncoders,
+    normalization: Option<NormalizeSettings>,
+    loudness: LoudnessSlot,
+    analysis: AnalysisFeed,
+    opus_monitor: OpusMonitor,
 }
 
 impl Stream {
     // New stream creation:
+    // streamer_id is the ID of the owner in the users table, owner is the username
     
-    pub fn new(streamer_id: usize, stream_name: String, 
+    pub fn new(streamer_id: usize, owner: String, stream_name: String, 
                connection: Option<Arc<RTCPeerConnection>>) -> Self {
+        Self::with_silence_timeout(streamer_id, owner, stream_name, connection, silence_timeout())
+    }
+
+    // The same with the given silence timeout instead of STREAM_SILENCE_TIMEOUT_SECS,
+    // None never flags the stream as silent
 
-        
+    pub fn with_silence_timeout(streamer_id: usize, owner: String, stream_name: String, 
+                                connection: Option<Arc<RTCPeerConnection>>, 
+                                silence_timeout: Option<Duration>) -> Self {
         Stream {
             streamer_id,
+            owner,
             stream_name,
             connection,
-            current_chunk: Arc::new(RwLock::new(Vec::new())),
-            change: Arc::new(Notify::new()),
+            listener_connections: Vec::new(),
+            feed: ChunkFeed::new(CHUNK_RING_CAPACITY),
+            started_at: SystemTime::now(),
+            codec: Codec::Flac,
+            format: None,
+            recorder: None,
+            verified_key_hash: None,
+            key_generation: 0,
+            metadata: StreamMetadata::default(),
+            audience: Audience::new(),
+            renditions: Vec::new(),
+            ingested: Duration::ZERO,
+            ingest_converter: ConverterSlot::default(),
+            rendition_converter: ConverterSlot::default(),
+            opus_encoders: OpusEncoders::default(),
+            normalization: None,
+            loudness: LoudnessSlot::default(),
+            analysis: AnalysisFeed::new(silence_timeout),
+            opus_monitor: OpusMonitor::default(),
         }
     }
 
-    // Push the current streamed chunk
-    pub async fn load_chunk(&mut self, chunk: Vec<u8>) {
-        *self.current_chunk.write().await = chunk;
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    // Only the owner (or an admin) is allowed to push the audio and to end the stream
+    pub fn is_owned_by(&self, username: &str) -> bool {
+        self.owner == username
+    }
+
+    // The hash of the stream key, which was already checked in the database
+    // The encoders push the chunks all the time, the database is asked only once
+    pub fn is_key_verified(&self, key_hash: &str) -> bool {
+        self.verified_key_hash.as_deref() == Some(key_hash)
+    }
+
+    // Every revoke of the keys starts a new generation, the key checked in the database
+    // before the revoke is not remembered after it
+    pub fn key_generation(&self) -> u64 {
+        self.key_generation
+    }
+
+    // Remembering the checked key, unless the keys were revoked since the check began
+    // Returns whether the key is remembered
+    pub fn set_verified_key(&mut self, key_hash: String, generation: u64) -> bool {
+        if generation != self.key_generation {
+            return false;
+        }
+        self.verified_key_hash = Some(key_hash);
+        true
+    }
+
+    pub fn forget_verified_key(&mut self) {
+        self.verified_key_hash = None;
+        self.key_generation += 1;
+    }
+
+    // Get the handle to the chunks of the stream
+    pub fn feed(&self) -> ChunkFeed {
+        self.feed.clone()
     }
 
-    // Get the current chunk
-    pub async fn get_chunk(&self) -> Vec<u8> {
-        self.current_chunk.read().await.clone()
+    // The moment the stream was created
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    // The codec of the chunks, FLAC unless the streamer says otherwise
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    // The format of the PCM, which is encoded into the stream
+    // Declared with the creation of the stream, otherwise taken from the first PCM chunk
+    pub fn format(&self) -> Option<AudioFormat> {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: AudioFormat) {
+        self.format = Some(format);
+    }
+
+    // The converters of the PCM kept between the chunks, one from the broadcaster
+    // to the format of the stream, the other one for the Opus renditions
+    pub fn converters(&self) -> (ConverterSlot, ConverterSlot) {
+        (self.ingest_converter.clone(), self.rendition_converter.clone())
+    }
+
+    // The Opus encoders of the stream and its renditions, kept between the chunks
+    pub fn opus_encoders(&self) -> OpusEncoders {
+        self.opus_encoders.clone()
+    }
+
+    // The loudness normalization of the PCM ingest and the FLAC chunks, None keeps the level as it comes
+    pub fn normalization(&self) -> Option<NormalizeSettings> {
+        self.normalization
+    }
+
+    pub fn set_normalization(&mut self, settings: Option<NormalizeSettings>) {
+        self.normalization = settings;
+    }
+
+    // The live loudness of the stream, measured on everything the broadcaster pushes
+    pub fn loudness(&self) -> LoudnessSlot {
+        self.loudness.clone()
+    }
+
+    // The levels and the spectrum of the decoded chunks, with the silence of the stream
+    pub fn analysis(&self) -> AnalysisFeed {
+        self.analysis.clone()
+    }
+
+    // The decoder of the Opus packets the broadcaster pushes, for the analysis and the loudness
+    pub fn opus_monitor(&self) -> OpusMonitor {
+        self.opus_monitor.clone()
+    }
+
+    // Starting to record the stream to the disk
+    // The recorder reads the feed from the first chunk, so it should be started
+    // before the streamer pushes anything
+    pub fn start_recording(&mut self) -> std::io::Result<()> {
+        if self.recorder.is_none() {
+            self.recorder = Some(RecorderHandle::start(&self.stream_name, self.codec,
+                                                       self.feed(), self.started_at)?);
+        }
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // The stream itself is the rendition of its codec, the others get their own feeds
+    // They are filled by the server ingests only, where the PCM is there to encode
+    // (the FLAC chunks and the PCM ingest), the Opus chunks and WHIP do not fill them
+
+    pub fn add_rendition(&mut self, rendition: Rendition) {
+        let exists = rendition == Rendition::of_codec(self.codec)
+            || self.renditions.iter().any(|(r, _)| *r == rendition);
+        if exists {
+            return;
+        }
+
+        // Every Opus packet is a chunk of 20 ms, so its ring is the WebRTC sized one
+        let capacity = match rendition.codec() {
+            Codec::Flac => CHUNK_RING_CAPACITY,
+            Codec::Opus => crate::rtc::ingest::RTP_RING_CAPACITY,
+        };
+        self.renditions.push((rendition, ChunkFeed::new(capacity)));
+    }
+
+    // The feeds of the renditions besides the stream itself
+    pub fn extra_renditions(&self) -> Vec<(Rendition, ChunkFeed)> {
+        self.renditions.clone()
+    }
+
+    // All the renditions the listeners could get, the stream itself goes first
+    // The other renditions are there once something has filled them
+    pub fn rendition_feeds(&self) -> Vec<(Rendition, ChunkFeed)> {
+        std::iter::once((Rendition::of_codec(self.codec), self.feed()))
+            .chain(self.renditions.iter().filter(|(_, feed)| feed.is_fed()).cloned())
+            .collect()
+    }
+
+    // Moving the media position of the ingest by the duration of the pushed audio
+    // Returns the position the pushed audio starts at
+    pub fn advance_ingest(&mut self, duration: Duration) -> Duration {
+        let position = self.ingested;
+        self.ingested += duration;
+        position
+    }
+
+    // The counter of the listeners, shared with the listener responses
+    pub fn audience(&self) -> Audience {
+        self.audience.clone()
+    }
+
+    pub fn set_metadata(&mut self, metadata: StreamMetadata) {
+        self.metadata = metadata;
+    }
+
+    // The snapshot of the stream for the listing
+    // Opus is always 48 kHz, the PCM ingest tells the rate with the first chunk
+
+    pub fn listing(&self) -> StreamListing {
+        let sample_rate = match self.codec {
+            Codec::Opus => Some(48000),
+            Codec::Flac => self.format.map(|format| format.sample_rate),
+        };
+
+        StreamListing {
+            name: self.stream_name.clone(),
+            title: self.metadata.title.clone(),
+            description: self.metadata.description.clone(),
+            owner: self.owner.clone(),
+            tags: self.metadata.tags.clone(),
+            codec: self.codec,
+            sample_rate,
+            started_at: DateTime::<Utc>::from(self.started_at),
+            listeners: self.audience.current(),
+            peak_listeners: self.audience.peak(),
+            listener_growth: self.audience.growth(TRENDING_WINDOW),
+            cover_art_url: self.metadata.cover_art_url.clone(),
+            recording: self.is_recording(),
+            renditions: self.rendition_feeds().into_iter().map(|(r, _)| r).collect(),
+        }
+    }
+
+    // Attaching the WebRTC connection of the streamer
+    // The previous one (if any) is returned, so the caller could close it
+    pub fn set_connection(&mut self, connection: Option<Arc<RTCPeerConnection>>) 
+                                                  -> Option<Arc<RTCPeerConnection>> {
+        std::mem::replace(&mut self.connection, connection)
+    }
+
+    // The WebRTC connections of the listeners live as long as the stream,
+    // they are all closed when the stream is removed
+    pub fn add_listener_connection(&mut self, connection: Arc<RTCPeerConnection>) {
+        self.listener_connections.push(connection);
+    }
+
+    pub fn remove_listener_connection(&mut self, connection: &Arc<RTCPeerConnection>) {
+        self.listener_connections.retain(|c| !Arc::ptr_eq(c, connection));
     }
 }
 
 
+// The stream, which has ended not long ago
+// The HLS / DASH players still get its segments and the end of the playlist
+
+#[derive(Clone, Debug)]
+pub struct EndedStream {
+    pub feed: ChunkFeed,
+    pub codec: Codec,
+    pub format: Option<AudioFormat>,
+    pub started_at: SystemTime,
+    pub ended_at: SystemTime,
+}
+
+
 // A structure to store all the active streams
 // Represents a single datatype with an impl methods
 // to simplify the code for maintaining the streams
@@ -69,6 +755,7 @@ impl Stream {
 #[derive(Clone, Debug)]
 pub struct ActiveStreams {
     pub streams: Arc<DashMap<String, Stream>>,
+    ended: Arc<DashMap<String, EndedStream>>,
 }
 
 impl ActiveStreams {
@@ -84,6 +771,7 @@ impl ActiveStreams {
     pub fn new(shard_amount : usize) -> Self {
         ActiveStreams {
             streams: Arc::new(DashMap::with_shard_amount(shard_amount)),
+            ended: Arc::new(DashMap::new()),
         }
     }
 
@@ -91,9 +779,109 @@ impl ActiveStreams {
 
     // A method to remove an existing stream, which was already stopped
     // By the user either by system considerations to save the resources
+    // The feed tells whether it is still the same stream, it could be replaced
+    // by a new one with the same name, returns whether the stream was there to end
+
+    pub async fn remove_stream(&self, stream_id: &str, feed: &ChunkFeed) -> bool {
+        match self.streams.remove_if(stream_id, |_, s| s.feed.same_as(feed)) {
+            Some((_, stream)) => {
+                self.end_stream(stream_id, stream).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Everything which has to be done, when the stream is gone from the map
+    async fn end_stream(&self, stream_id: &str, stream: Stream) {
+        info!("Ending the stream {}", stream_id);
+
+        // The listeners are waiting for the next chunk, closing the feed
+        // lets them send what is left and finish
+        stream.feed.close();
+        for (_, feed) in &stream.renditions {
+            feed.close();
+        }
+
+        // The feed is kept for a while for the HLS / DASH players, the old ones are dropped
+        let now = SystemTime::now();
+        self.ended.retain(|_, ended| now.duration_since(ended.ended_at)
+            .map_or(true, |age| age < ENDED_STREAM_RETENTION));
+        self.ended.insert(stream_id.to_string(), EndedStream {
+            feed: stream.feed.clone(),
+            codec: stream.codec,
+            format: stream.format,
+            started_at: stream.started_at,
+            ended_at: now,
+        });
+
+        for connection in stream.connection.into_iter().chain(stream.listener_connections) {
+            if let Err(e) = connection.close().await {
+                warn!("Failed to close the WebRTC connection of {}: {}", stream_id, e);
+            }
+        }
+
+        // The recording is finalized with whatever is left in the ring
+        if let Some(recorder) = stream.recorder {
+            recorder.stop().await;
+        }
+
+        // The last minute of the audience is written when the listeners are gone
+        tokio::spawn(crate::audience::finish(stream_id.to_string(), stream.owner, stream.audience));
+    }
+
+    // Ending the streams, which have not got any chunk for the given time
+    // The streams without a single chunk are counted from their creation
+    // Returns the names of the ended streams
+
+    pub async fn reap_idle(&self, timeout: Duration) -> Vec<String> {
+        // The feeds are taken first, the DashMap is not held during the awaits
+        let candidates: Vec<(String, ChunkFeed, SystemTime)> = self.streams.iter()
+            .map(|r| (r.key().clone(), r.feed(), r.started_at()))
+            .collect();
+
+        let now = SystemTime::now();
+        let mut reaped = Vec::new();
+
+        for (stream_id, feed, started_at) in candidates {
+            let last_activity = feed.last_push().await.unwrap_or(started_at);
+            let idle = now.duration_since(last_activity).unwrap_or_default();
+
+            if idle < timeout {
+                continue;
+            }
+
+            // The stream could be replaced by a new one with the same name meanwhile
+            if let Some((_, stream)) = self.streams.remove_if(&stream_id, |_, s| s.feed.same_as(&feed)) {
+                warn!("Stream {} was idle for {} s", stream_id, idle.as_secs());
+                self.end_stream(&stream_id, stream).await;
+                reaped.push(stream_id);
+            }
+        }
+
+        reaped
+    }
+
+    // Ending the streams, which have been silent for longer than the silence timeout
+    // Returns the names of the ended streams
+
+    pub async fn reap_silent(&self) -> Vec<String> {
+        let candidates: Vec<(String, ChunkFeed)> = self.streams.iter()
+            .filter(|r| r.analysis.is_flagged())
+            .map(|r| (r.key().clone(), r.feed()))
+            .collect();
+
+        let mut reaped = Vec::new();
+
+        for (stream_id, feed) in candidates {
+            if let Some((_, stream)) = self.streams.remove_if(&stream_id, |_, s| s.feed.same_as(&feed)) {
+                warn!("Stream {} was silent for {} s", stream_id, stream.analysis.silent_for().as_secs());
+                self.end_stream(&stream_id, stream).await;
+                reaped.push(stream_id);
+            }
+        }
 
-    pub async fn remove_stream(&self, stream_id: String) {
-        self.streams.remove(&stream_id);
+        reaped
     }
 
 
@@ -103,18 +891,19 @@ impl ActiveStreams {
     // And should be called by the main controller of the streams
     
     pub async fn add_stream(&self, c_stream: Stream) -> Result<(), Box<dyn std::error::Error>> {
-        // Check if the stream already exists
-        if self.get_stream(&c_stream.stream_name).await.is_some() {
-            error!("Stream with name {} already exists", c_stream.stream_name);
-            return Err(Box::from(format!("Stream with name {} already exists", c_stream.stream_name)));
+        // Check if the stream already exists, the entry is held until the stream is in,
+        // so two streams with the same name could not both get created
+        match self.streams.entry(c_stream.stream_name.clone()) {
+            Entry::Occupied(_) => {
+                error!("Stream with name {} already exists", c_stream.stream_name);
+                Err(Box::from(format!("Stream with name {} already exists", c_stream.stream_name)))
+            }
+            Entry::Vacant(entry) => {
+                info!("Creating stream with name {}", c_stream.stream_name);
+                entry.insert(c_stream);
+                Ok(())
+            }
         }
-
-        // In case the stream indeed could be created, creating it
-
-        info!("Creating stream with name {}", c_stream.stream_name);
-        self.streams.insert(c_stream.stream_name.clone(), c_stream);
-
-        Ok(())
     }
 
 
@@ -131,15 +920,36 @@ impl ActiveStreams {
 
 
 
+    // The stream, which has ended within ENDED_STREAM_RETENTION
+    pub fn ended_stream(&self, stream_name: &str) -> Option<EndedStream> {
+        self.ended.get(stream_name)
+            .map(|ended| ended.clone())
+            .filter(|ended| SystemTime::now().duration_since(ended.ended_at)
+                .map_or(true, |age| age < ENDED_STREAM_RETENTION))
+    }
+
+
+
     // Getting all the streams, currently existing in the system
     // Used in the route for rust presentation
-    // FUTURE: Considering filtering the streams by their category
 
     pub async fn get_streams(&self) -> Vec<String> {
         self.streams.iter().map(|r| 
             r.value().stream_name.clone()).collect()
     }
 
+    // The snapshots of all the streams, the filtering is done in listing.rs
+    pub fn stream_listings(&self) -> Vec<StreamListing> {
+        self.streams.iter().map(|r| r.value().listing()).collect()
+    }
+
+    // The listener counters of all the streams with their names and owners
+    pub fn audiences(&self) -> Vec<(String, String, Audience)> {
+        self.streams.iter()
+            .map(|r| (r.key().clone(), r.owner.clone(), r.audience()))
+            .collect()
+    }
+
 
 
     // For altering the Stream (for side of the streamer) this function becomes very handy
@@ -152,67 +962,381 @@ impl ActiveStreams {
     }
 }
 
+// The pre-roll of the new listeners from STREAM_PREROLL_MS
+pub fn preroll() -> Duration {
+    std::env::var("STREAM_PREROLL_MS").ok()
+        .and_then(|ms| ms.trim().parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(LATE_JOIN_DELAY)
+        .min(MAX_PREROLL)
+}
+
+// The idle timeout from STREAM_IDLE_TIMEOUT_SECS, None when the reaping is off
+pub fn idle_timeout() -> Option<Duration> {
+    let timeout = std::env::var("STREAM_IDLE_TIMEOUT_SECS").ok()
+        .and_then(|secs| secs.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+
+    Some(timeout).filter(|timeout| !timeout.is_zero())
+}
+
+// The background task, which ends the idle streams and the ones flagged as silent
+// The broadcasters disconnect without telling (or leave the silence on),
+// so the streams would live forever otherwise
+// Without the idle timeout only the silent streams are ended
+
+pub async fn run_reaper(stream_list: ActiveStreams, idle_timeout: Option<Duration>) {
+    let period = idle_timeout.map_or(REAPER_INTERVAL, |timeout| REAPER_INTERVAL.min(timeout));
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        if let Some(timeout) = idle_timeout {
+            let reaped = stream_list.reap_idle(timeout).await;
+            if !reaped.is_empty() {
+                info!("Reaped {} idle streams", reaped.len());
+            }
+        }
+
+        let reaped = stream_list.reap_silent().await;
+        if !reaped.is_empty() {
+            info!("Reaped {} silent streams", reaped.len());
+        }
+    }
+}
+
 // A function to perform the stream
 // This one is called by the main controller of the streams
+// The listener, who falls behind, is handled by its backpressure policy
+// Without the choice of the rendition the stream itself is sent
 
-pub async fn perform_stream(stream_list: web::Data<ActiveStreams>, stream_name: String, fragment_len: f32) -> impl Responder {
-    let stream = stream_list.get_stream(&stream_name).await;
+pub async fn perform_stream(stream_list: web::Data<ActiveStreams>, stream_name: String,
+                            backpressure: Backpressure, choice: Option<RenditionChoice>) 
+                                                                    -> impl Responder {
+    // Taking the feeds once, the listener does not hold the DashMap afterwards
+    let (feeds, audience) = match stream_list.get_stream(&stream_name).await {
+        Some(stream) => (stream.rendition_feeds(), stream.audience()),
+        None => {
+            warn!("Stream not found");
+            return HttpResponse::NotFound().body("Stream not found");
+        }
+    };
 
-    if stream.is_none() {
-        warn!("Stream not found");
-        return HttpResponse::NotFound().body("Stream not found");
-    }
+    // The automatic choice needs the Opus renditions, the stream itself is sent otherwise
+    let renditions: Vec<Rendition> = feeds.iter().map(|(r, _)| *r).collect();
+    let mut selector = match choice {
+        Some(RenditionChoice::Auto) => AdaptiveSelector::new(&renditions),
+        _ => None,
+    };
 
-    let mut ticker = interval(Duration::from_millis((fragment_len * 1000.0) as u64));
+    let rendition = match (choice, &selector) {
+        (_, Some(selector)) => selector.current(),
+        (Some(RenditionChoice::Fixed(rendition)), None) => rendition,
+        _ => renditions[0],
+    };
 
-    let stream_list = stream_list.clone();
+    let mut feed = match feeds.iter().find(|(r, _)| *r == rendition) {
+        Some((_, feed)) => feed.clone(),
+        None => return HttpResponse::NotFound().body("The stream has no such rendition"),
+    };
+    let codec = rendition.codec();
 
+    // The listener is counted as long as the response is being sent
+    let listener = audience.join();
 
     let async_stream_thread = async_stream::stream! {
-    
-    let mut prev_chunk_hash : u64 = 0;
+
+    // Moved into the stream, so it is dropped together with the response
+    let _listener = listener;
+
+    // The listener reads the ring forward from the last chunk it has got
+    // New listeners get a few seconds of the pre-roll right away
+    let mut next_seq = feed.join_seq(codec).await;
+
+    // Opus packets are packed into Ogg, the headers go first
+    // FLAC chunks keep the custom length-prefixed framing
+    let mut ogg = match codec {
+        Codec::Opus => {
+            let serial = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let mut writer = OggOpusWriter::new(serial, 2, 48000);
+            yield Ok::<_, actix_web::Error>(Bytes::from(writer.headers()));
+            Some(writer)
+        }
+        Codec::Flac => None,
+    };
 
     loop {
-        ticker.tick().await;
+        let read = feed.read_from(next_seq).await;
+        let mut lagging = read.missed > 0;
 
-        if let Some(current_stream) = stream_list.get_stream(&stream_name).await {
-            let chunk = current_stream.get_chunk().await;
+        if read.missed > 0 {
+            warn!("Listener of {} missed {} chunks", stream_name, read.missed);
+            backpressure::record(backpressure.policy, read.missed as usize);
 
-            let chunk_len = chunk.len() as u32;
+            // The listener is behind the whole ring, it is surely too far
+            if backpressure.policy == LagPolicy::Disconnect {
+                break;
+            }
+        }
 
-            let current_chunk_hash = hash_vecu8(&chunk);
+        if read.chunks.is_empty() {
+            // Nothing new, so the listener sleeps until the streamer pushes
+            // In case the stream has ended, the listener is told so and stopped
+            if feed.is_closed() {
+                info!("Stream {} has ended, finishing the playback", stream_name);
 
-            if current_chunk_hash == prev_chunk_hash {
-                info!("No new chunk to stream");
-                continue;
+                // The zero length frame is the end of the FLAC stream,
+                // Ogg/Opus simply ends with the response
+                if ogg.is_none() {
+                    yield Ok::<_, actix_web::Error>(Bytes::from_static(&[0u8; 4]));
+                }
+                break;
+            }
+
+            feed.wait_for(next_seq).await;
+            continue;
+        }
+
+        // The chunks are read only when the client has taken the previous ones,
+        // so everything up to the live edge is the queue of the listener
+        let mut chunks = read.chunks;
+
+        // How long the oldest chunk has waited for the listener, its growth means
+        // the connection could not take the rendition
+        let lag = SystemTime::now().duration_since(chunks[0].timestamp).unwrap_or_default();
+
+        match backpressure.within(read.ring_span).check(&chunks, codec) {
+            LagAction::Keep => {}
+            LagAction::Drop(dropped) => {
+                warn!("Listener of {} is lagging, {:?} dropped {} chunks", 
+                      stream_name, backpressure.policy, dropped);
+                backpressure::record(backpressure.policy, dropped);
+                lagging = true;
+
+                if let Some(last) = chunks.last() {
+                    next_seq = last.seq + 1;
+                }
+                chunks.drain(..dropped);
             }
+            LagAction::Disconnect => {
+                warn!("Listener of {} is lagging too far, disconnecting", stream_name);
+                backpressure::record(backpressure.policy, chunks.len());
+                break;
+            }
+        }
 
-            prev_chunk_hash = current_chunk_hash;
+        let mut sent_until = None;
+
+        for chunk in chunks {
+            next_seq = chunk.seq + 1;
+            sent_until = chunk.position
+                .map(|position| position + codec.chunk_duration(&chunk.data).unwrap_or_default());
+
+            if let Some(writer) = ogg.as_mut() {
+                yield Ok::<_, actix_web::Error>(Bytes::from(writer.packet(&chunk.data)));
+                continue;
+            }
 
             // Preparing the custom chunk
-            let mut custom_chunk : Vec<u8> = Vec::new();
+            let chunk_len = chunk.len() as u32;
+            let mut custom_chunk : Vec<u8> = Vec::with_capacity(chunk.len() + 4);
             custom_chunk.extend_from_slice(&chunk_len.to_be_bytes());
-            custom_chunk.extend_from_slice(&chunk);
+            custom_chunk.extend_from_slice(&chunk.data);
 
             yield Ok::<_, actix_web::Error>(actix_web::web::Bytes::from(custom_chunk));
-        } 
-            else {
-                warn!("Stream disappeared during playback");
-                break;
-            }
         }
+
+        // Switching the rendition, the new one continues right where the old one has stopped
+        // Without the position the new rendition starts from its next chunk
+        // The rendition nothing has filled yet is never switched into
+        let switch = selector.as_mut().and_then(|selector| selector.on_read(
+            lag, lagging, std::time::Instant::now()));
+
+        let next = switch.and_then(|r| feeds.iter().find(|(f, feed)| *f == r && feed.is_fed()));
+        if let Some((rendition, next_feed)) = next {
+            info!("Listener of {} is switched to {:?}", stream_name, rendition);
+            next_seq = next_feed.seq_at(sent_until.unwrap_or(Duration::MAX)).await;
+            feed = next_feed.clone();
+        }
+    }
     };
 
     HttpResponse::Ok()
         .append_header(("Connection", "keep-alive"))
-        .content_type("audio/flac")
+        .content_type(codec.stream_content_type())
         .streaming(async_stream_thread)
 }
 
-fn hash_vecu8(vec : &Vec<u8>) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    vec.hash(&mut hasher);
-    let hash = hasher.finish();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    hash
+    #[test]
+    fn test_ring_drops_oldest_and_reports_gap() {
+        let mut ring = ChunkRing::new(3);
+
+        for i in 0..5u8 {
+            assert_eq!(ring.push(vec![i; 4]), i as u64);
+            assert_eq!(ring.read_from(0).ring_span.is_some(), i >= 2, "THE SPAN IS KNOWN WHEN THE RING IS FULL");
+        }
+
+        let read = ring.read_from(0);
+        assert_eq!(read.missed, 2, "TWO CHUNKS WERE DROPPED");
+        assert_eq!(read.chunks.iter().map(|c| c.seq).collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        let read = ring.read_from(4);
+        assert_eq!(read.missed, 0);
+        assert_eq!(read.chunks.len(), 1);
+        assert_eq!(read.chunks[0].len(), 4);
+
+        assert!(ring.read_from(5).chunks.is_empty(), "NOTHING NEW AFTER THE LIVE EDGE");
+    }
+
+    #[test]
+    fn test_join_seq_starts_behind_live() {
+        let mut ring = ChunkRing::new(512);
+        assert_eq!(ring.join_seq(LATE_JOIN_DELAY, Codec::Opus), 0);
+
+        // 20 ms Opus packets (config 1, a single frame), 5 seconds of them
+        for _ in 0..250 {
+            ring.push(vec![0x08, 0xff]);
+        }
+        assert_eq!(ring.join_seq(Duration::from_secs(2), Codec::Opus), 150, "100 PACKETS OF PRE-ROLL");
+        assert_eq!(ring.join_seq(Duration::ZERO, Codec::Opus), 250, "NO PRE-ROLL STARTS LIVE");
+        assert_eq!(ring.join_seq(MAX_PREROLL, Codec::Opus), 0, "WHOLE RING IS SHORTER");
+    }
+
+    #[test]
+    fn test_join_seq_starts_on_decodable_chunk() {
+        let mut ring = ChunkRing::new(8);
+
+        // A single long chunk is sent anyway, the player would wait for nothing otherwise
+        ring.push(vec![0xff, 0xf8, 0x69]);
+        assert_eq!(ring.join_seq(LATE_JOIN_DELAY, Codec::Flac), 0);
+
+        // The bytes, which are not the start of a frame, are skipped
+        ring.push(vec![0x12, 0x34]);
+        ring.push(b"fLaC".to_vec());
+        ring.push(vec![0x56]);
+        assert_eq!(ring.join_seq(LATE_JOIN_DELAY, Codec::Flac), 0);
+
+        ring.set_capacity(3);
+        assert_eq!(ring.join_seq(LATE_JOIN_DELAY, Codec::Flac), 2, "STARTS ON THE STREAM MARKER");
+
+        ring.set_capacity(1);
+        assert_eq!(ring.join_seq(LATE_JOIN_DELAY, Codec::Flac), 4, "NOTHING TO DECODE FROM");
+    }
+
+    #[tokio::test]
+    async fn test_feed_wakes_waiting_listener() {
+        let feed = ChunkFeed::new(4);
+        let listener_feed = feed.clone();
+
+        let listener = tokio::spawn(async move {
+            listener_feed.wait_for(0).await;
+            listener_feed.read_from(0).await
+        });
+
+        tokio::task::yield_now().await;
+        feed.push(vec![7, 7]).await;
+
+        let read = tokio::time::timeout(Duration::from_secs(1), listener)
+            .await
+            .expect("LISTENER WAS NOT WOKEN UP")
+            .unwrap();
+        assert_eq!(read.chunks.len(), 1);
+        assert_eq!(read.chunks[0].seq, 0);
+    }
+
+    #[tokio::test]
+    async fn test_closed_feed_releases_listeners() {
+        let feed = ChunkFeed::new(4);
+        let listener_feed = feed.clone();
+
+        let listener = tokio::spawn(async move { listener_feed.wait_for(0).await });
+
+        tokio::task::yield_now().await;
+        feed.close();
+
+        tokio::time::timeout(Duration::from_secs(1), listener)
+            .await
+            .expect("LISTENER WAS NOT RELEASED")
+            .unwrap();
+        assert!(feed.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_only_fed_renditions_are_offered() {
+        let mut stream = Stream::new(0, "a".to_string(), "renditions".to_string(), None);
+        stream.add_rendition(Rendition::OpusLow);
+
+        let offered = |stream: &Stream| stream.rendition_feeds().into_iter().map(|(r, _)| r).collect::<Vec<_>>();
+        assert_eq!(offered(&stream), vec![Rendition::Lossless], "NOTHING HAS FILLED THE OPUS RENDITION");
+
+        stream.extra_renditions()[0].1.push_at(vec![0x08, 0xff], Duration::ZERO).await;
+        assert_eq!(offered(&stream), vec![Rendition::Lossless, Rendition::OpusLow]);
+    }
+
+    #[test]
+    fn test_key_revoked_during_the_check_is_not_remembered() {
+        let mut stream = Stream::new(0, "a".to_string(), "keyed".to_string(), None);
+
+        // The check began, then the keys were revoked before it ended
+        let generation = stream.key_generation();
+        stream.forget_verified_key();
+        assert!(!stream.set_verified_key("hash".to_string(), generation));
+        assert!(!stream.is_key_verified("hash"), "THE REVOKED KEY IS NOT CACHED");
+
+        assert!(stream.set_verified_key("hash".to_string(), stream.key_generation()));
+        assert!(stream.is_key_verified("hash"));
+    }
+
+    #[tokio::test]
+    async fn test_idle_streams_are_reaped() {
+        let streams = ActiveStreams::new(4);
+        streams.add_stream(Stream::new(0, "a".to_string(), "idle".to_string(), None)).await.unwrap();
+        streams.add_stream(Stream::new(0, "a".to_string(), "busy".to_string(), None)).await.unwrap();
+
+        let idle_feed = streams.get_stream("idle").await.unwrap().feed();
+        streams.get_stream("busy").await.unwrap().feed().push(vec![1]).await;
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        streams.get_stream("busy").await.unwrap().feed().push(vec![2]).await;
+
+        assert_eq!(streams.reap_idle(Duration::from_millis(20)).await, vec!["idle".to_string()]);
+        assert!(streams.get_stream("idle").await.is_none());
+        assert!(streams.get_stream("busy").await.is_some());
+        assert!(idle_feed.is_closed(), "LISTENERS OF THE REAPED STREAM ARE TOLD");
+    }
+
+    #[tokio::test]
+    async fn test_silent_streams_are_reaped() {
+        use crate::audio_coding::analysis::FLOOR_DBFS;
+        use crate::audio_coding::PcmAnalysis;
+
+        let streams = ActiveStreams::new(4);
+        let timeout = Some(Duration::from_secs(60));
+        for name in ["silent", "loud"] {
+            let stream = Stream::with_silence_timeout(0, "a".to_string(), name.to_string(), None, timeout);
+            streams.add_stream(stream).await.unwrap();
+        }
+
+        let chunk = |peak: f64| PcmAnalysis {
+            duration_ms: 2 * 60 * 1000,
+            rms_dbfs: vec![peak],
+            peak_dbfs: vec![peak],
+            bands: Vec::new(),
+        };
+        streams.get_stream("silent").await.unwrap().analysis().publish(chunk(FLOOR_DBFS), Duration::ZERO);
+        streams.get_stream("loud").await.unwrap().analysis().publish(chunk(-10.0), Duration::ZERO);
+
+        assert_eq!(streams.reap_silent().await, vec!["silent".to_string()]);
+        assert!(streams.get_stream("silent").await.is_none());
+        assert!(streams.get_stream("loud").await.is_some(), "THE LOUD STREAM IS KEPT");
+    }
 }
@@ -0,0 +1,1067 @@
+// A file for the track library, the pre-recorded FLAC/WAV music
+// The tracks are uploaded once, validated, stored on the disk as FLAC
+// (WAV is transcoded on the way) and then streamed to the listeners
+// Every stored track gets a seek table, so the listener could start
+// from any second of it without the server decoding anything
+// The listener could ask for the effects as well, then the track is decoded,
+// processed and encoded again on the way
+// The loudness of every track is measured on the upload, so the players
+// could bring the tracks to the same level
+
+// Trinitypeer, 2025, by Trinitycore
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use log::{error, info, warn};
+use serde::Serialize;
+use sqlx::FromRow;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::audio_coding::convert::ResampleQuality;
+use crate::audio_coding::flac_metadata::{find_seek_point, parse_seek_table, parse_vorbis_comment,
+                                         seek_table_block, vorbis_comment_block, write_flac_header,
+                                         SeekPoint, BLOCK_PADDING, BLOCK_SEEKTABLE};
+use crate::audio_coding::flac_decode::{FlacMetadata, MetadataBlock, BLOCK_VORBIS_COMMENT};
+use crate::audio_coding::{consume_samples_md5, decode_flac, decode_wav, encode_pcm_to_flac, is_wav,
+                          measure_pcm, read_metadata, renumber_frame, AudioCodingError, AudioFormat, Codec,
+                          EffectChain, EffectProcessor, EffectSettings, FlacDecodeError, FlacEncodeOptions,
+                          LoudnessReport, StreamInfo, WavError};
+use crate::auth_logic::models::AuthenticatedUser;
+use crate::db::init_db;
+
+// The directory for the tracks, unless TRACKS_DIR says otherwise
+pub const DEFAULT_TRACKS_DIR: &str = "tracks";
+
+// The biggest track the server accepts
+// 128 MB is around 12 minutes of the 24-bit stereo WAV at 96 kHz,
+// the track is decoded in the memory as a whole, so it could not be much bigger
+pub const MAX_TRACK_SIZE: usize = 128 * 1024 * 1024;
+
+// The uploads taken at once, the others are told to come back later
+const MAX_CONCURRENT_UPLOADS: usize = 2;
+static UPLOAD_SLOTS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_UPLOADS);
+
+// The text fields of the upload form are short, anything longer is cut
+const MAX_TAG_LENGTH: usize = 1024;
+
+// A seek point for every second of the track
+const SEEK_POINT_INTERVAL_SECS: u64 = 1;
+
+// The metadata of the stored file must fit into this, the pictures included
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+// The seconds of the track decoded at once, when it is played with the effects
+const EFFECT_BLOCK_SECS: u64 = 5;
+
+pub fn tracks_dir() -> PathBuf {
+    env::var("TRACKS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_TRACKS_DIR))
+}
+
+
+
+// Everything that could go wrong with the uploaded track
+
+#[derive(Debug)]
+pub enum TrackError {
+    TooLarge,
+    MissingFile,
+    UnknownFormat,
+    Empty,
+    Flac(FlacDecodeError),
+    Wav(WavError),
+    Encode(AudioCodingError),
+}
+
+impl std::fmt::Display for TrackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackError::TooLarge => write!(f, "The track is larger than {} bytes", MAX_TRACK_SIZE),
+            TrackError::MissingFile => write!(f, "The upload has no file field"),
+            TrackError::UnknownFormat => write!(f, "Only FLAC and WAV tracks are accepted"),
+            TrackError::Empty => write!(f, "The track has no audio"),
+            TrackError::Flac(e) => write!(f, "Invalid FLAC: {}", e),
+            TrackError::Wav(e) => write!(f, "Invalid WAV: {}", e),
+            TrackError::Encode(e) => write!(f, "Transcoding failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TrackError {}
+
+
+
+// The tags the library cares about, the rest of them stays in the file only
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+}
+
+impl TrackTags {
+    // From the Vorbis comments of FLAC
+    fn from_vorbis(tags: &[(String, String)]) -> Self {
+        let find = |name: &str| tags.iter()
+            .find(|(n, v)| n == name && !v.trim().is_empty())
+            .map(|(_, v)| v.trim().to_string());
+
+        TrackTags {
+            title: find("TITLE"),
+            artist: find("ARTIST"),
+            album: find("ALBUM"),
+            genre: find("GENRE"),
+        }
+    }
+
+    // From the RIFF INFO list of WAV
+    fn from_riff(info: &[(String, String)]) -> Self {
+        let find = |id: &str| info.iter()
+            .find(|(i, _)| i == id)
+            .map(|(_, v)| v.clone());
+
+        TrackTags {
+            title: find("INAM"),
+            artist: find("IART"),
+            album: find("IPRD"),
+            genre: find("IGNR"),
+        }
+    }
+
+    fn to_vorbis(&self) -> Vec<(String, String)> {
+        [("TITLE", &self.title), ("ARTIST", &self.artist),
+         ("ALBUM", &self.album), ("GENRE", &self.genre)]
+            .into_iter()
+            .filter_map(|(name, value)| value.as_ref().map(|v| (name.to_string(), v.clone())))
+            .collect()
+    }
+
+    // The tags given in the upload form win over the ones in the file
+    fn merge(self, overrides: TrackTags) -> Self {
+        TrackTags {
+            title: overrides.title.or(self.title),
+            artist: overrides.artist.or(self.artist),
+            album: overrides.album.or(self.album),
+            genre: overrides.genre.or(self.genre),
+        }
+    }
+}
+
+
+
+// The validated track, ready to be stored
+
+#[derive(Debug)]
+pub struct PreparedTrack {
+    pub flac: Vec<u8>,
+    pub format: AudioFormat,
+    pub duration: Duration,
+    pub tags: TrackTags,
+    pub original_format: &'static str,
+    pub loudness: LoudnessReport,
+}
+
+fn seek_interval(format: &AudioFormat) -> u64 {
+    format.sample_rate as u64 * SEEK_POINT_INTERVAL_SECS
+}
+
+// Sniffing the format of the upload, validating it and producing the FLAC to store
+// FLAC is decoded completely (every CRC and MD5 is checked) and kept as it is,
+// only the metadata is rewritten. WAV is encoded into FLAC
+
+pub fn prepare_track(data: &[u8]) -> Result<PreparedTrack, TrackError> {
+    if is_wav(data) {
+        return prepare_wav(data);
+    }
+
+    match read_metadata(data) {
+        Ok(_) => prepare_flac(data),
+        Err(FlacDecodeError::NotFlac) => Err(TrackError::UnknownFormat),
+        Err(e) => Err(TrackError::Flac(e)),
+    }
+}
+
+fn prepare_flac(data: &[u8]) -> Result<PreparedTrack, TrackError> {
+    let decoded = decode_flac(data).map_err(TrackError::Flac)?;
+
+    if decoded.frames.is_empty() {
+        return Err(TrackError::Empty);
+    }
+
+    let mut info = decoded.metadata.info.clone();
+    let format = info.format();
+    let last = decoded.frames[decoded.frames.len() - 1];
+
+    // The encoders are allowed to leave the amount of samples and MD5 empty,
+    // the stored track has them filled in
+    info.total_samples = last.first_sample + last.block_size as u64;
+    if info.md5 == [0u8; 16] {
+        let mut context = md5::Context::new();
+        consume_samples_md5(&mut context, &decoded.samples, info.bits_per_sample);
+        info.md5 = context.compute().0;
+    }
+
+    let tags = decoded.metadata.blocks.iter()
+        .filter(|b| b.block_type == BLOCK_VORBIS_COMMENT)
+        .find_map(|b| parse_vorbis_comment(&b.data).ok())
+        .map(|tags| TrackTags::from_vorbis(&tags))
+        .unwrap_or_default();
+
+    // The old seek table would point to the wrong places, the padding is useless
+    let mut blocks: Vec<_> = decoded.metadata.blocks.iter()
+        .filter(|b| b.block_type != BLOCK_SEEKTABLE && b.block_type != BLOCK_PADDING)
+        .cloned()
+        .collect();
+    blocks.push(seek_table_block(&decoded.frames, seek_interval(&format)));
+
+    let mut flac = write_flac_header(&info, &blocks);
+    flac.extend_from_slice(&data[decoded.metadata.audio_offset..]);
+
+    Ok(PreparedTrack {
+        duration: info.duration().unwrap_or_default(),
+        loudness: measure_pcm(&decoded.samples, &format),
+        flac,
+        format,
+        tags,
+        original_format: "flac",
+    })
+}
+
+fn prepare_wav(data: &[u8]) -> Result<PreparedTrack, TrackError> {
+    let wav = decode_wav(data).map_err(TrackError::Wav)?;
+
+    if wav.samples.is_empty() {
+        return Err(TrackError::Empty);
+    }
+
+    let encoded = encode_pcm_to_flac(&wav.samples, &FlacEncodeOptions::new(wav.format))
+        .map_err(TrackError::Encode)?;
+
+    // Decoding it back gives the frame positions for the seek table,
+    // and makes sure the encoder has not lost anything on the way
+    let decoded = decode_flac(&encoded).map_err(TrackError::Flac)?;
+    if decoded.samples != wav.samples {
+        return Err(TrackError::Encode(AudioCodingError::Encode(
+            "The transcoded FLAC differs from the WAV".to_string())));
+    }
+
+    let info = decoded.metadata.info;
+    let tags = TrackTags::from_riff(&wav.info);
+    let blocks = [
+        vorbis_comment_block("trinitypeer", &tags.to_vorbis()),
+        seek_table_block(&decoded.frames, seek_interval(&wav.format)),
+    ];
+
+    let mut flac = write_flac_header(&info, &blocks);
+    flac.extend_from_slice(&encoded[decoded.metadata.audio_offset..]);
+
+    Ok(PreparedTrack {
+        duration: info.duration().unwrap_or_default(),
+        loudness: measure_pcm(&wav.samples, &wav.format),
+        flac,
+        format: wav.format,
+        tags,
+        original_format: "wav",
+    })
+}
+
+
+
+// The track, as it is stored in the tracks table
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Track {
+    pub id: Uuid,
+    pub owner: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub original_format: String,
+    #[serde(skip_serializing)]
+    pub path: String,
+    pub size_bytes: i64,
+    pub duration_ms: i64,
+    pub sample_rate: i32,
+    pub channels: i32,
+    pub bits_per_sample: i32,
+    pub uploaded_at: DateTime<Utc>,
+    // The integrated loudness (EBU R128) and the true peak, None for the silent tracks
+    pub loudness_lufs: Option<f64>,
+    pub true_peak_dbtp: Option<f64>,
+}
+
+const TRACK_COLUMNS: &str = "id, owner, title, artist, album, genre, original_format, path,
+    size_bytes, duration_ms, sample_rate, channels, bits_per_sample, uploaded_at,
+    loudness_lufs, true_peak_dbtp";
+
+async fn insert_track(track: &Track) -> Result<(), String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query(&format!(
+        "INSERT INTO tracks ({})
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16);", TRACK_COLUMNS))
+        .bind(track.id)
+        .bind(&track.owner)
+        .bind(&track.title)
+        .bind(&track.artist)
+        .bind(&track.album)
+        .bind(&track.genre)
+        .bind(&track.original_format)
+        .bind(&track.path)
+        .bind(track.size_bytes)
+        .bind(track.duration_ms)
+        .bind(track.sample_rate)
+        .bind(track.channels)
+        .bind(track.bits_per_sample)
+        .bind(track.uploaded_at)
+        .bind(track.loudness_lufs)
+        .bind(track.true_peak_dbtp)
+        .execute(&pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+pub async fn find_track(id: Uuid) -> Result<Option<Track>, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_as::<_, Track>(&format!("SELECT {} FROM tracks WHERE id = $1", TRACK_COLUMNS))
+        .bind(id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn list_tracks() -> Result<Vec<Track>, String> {
+    let pool = init_db().await.ok_or("Failed to connect to the database")?;
+
+    sqlx::query_as::<_, Track>(&format!("SELECT {} FROM tracks ORDER BY uploaded_at DESC", TRACK_COLUMNS))
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+
+
+// The file of the upload is written to the disk while it comes,
+// so the slow uploads do not keep the whole track in the memory
+// The spool is removed together with the struct, whatever happens to the upload
+
+struct SpooledUpload {
+    path: PathBuf,
+    file: tokio::fs::File,
+}
+
+impl SpooledUpload {
+    async fn create(dir: &Path) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!(".upload-{}.part", Uuid::new_v4()));
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(SpooledUpload { path, file })
+    }
+}
+
+impl Drop for SpooledUpload {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// The upload (multipart/form-data)
+// The "file" field is the FLAC or WAV itself, the optional "title", "artist",
+// "album" and "genre" fields replace the tags found in the file
+
+pub async fn upload(user: AuthenticatedUser, mut payload: Multipart) -> HttpResponse {
+    // The slot is held until the track is stored
+    let Ok(_slot) = UPLOAD_SLOTS.try_acquire() else {
+        return HttpResponse::ServiceUnavailable()
+            .append_header((header::RETRY_AFTER, "10"))
+            .body("Too many uploads at once, try again later");
+    };
+
+    let mut file: Option<SpooledUpload> = None;
+    let mut overrides = TrackTags::default();
+
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid upload: {}", e)),
+        };
+
+        let name = field.name().unwrap_or_default().to_string();
+        let limit = if name == "file" { MAX_TRACK_SIZE } else { MAX_TAG_LENGTH };
+        let mut data = Vec::new();
+        let mut size = 0;
+
+        // The file goes to the spool, the short text fields stay in the memory
+        let mut spool = None;
+        if name == "file" {
+            match SpooledUpload::create(&tracks_dir()).await {
+                Ok(created) => spool = Some(created),
+                Err(e) => {
+                    error!("Failed to spool the upload of {}: {}", user.username, e);
+                    return HttpResponse::InternalServerError().body("Failed to store the track");
+                }
+            }
+        }
+
+        loop {
+            match field.try_next().await {
+                Ok(Some(bytes)) => {
+                    size += bytes.len();
+                    if size > limit {
+                        if name == "file" {
+                            return HttpResponse::PayloadTooLarge().body(TrackError::TooLarge.to_string());
+                        }
+                        return HttpResponse::BadRequest().body(format!("The {} field is too long", name));
+                    }
+
+                    let written = match spool.as_mut() {
+                        Some(spool) => spool.file.write_all(&bytes).await,
+                        None => {
+                            data.extend_from_slice(&bytes);
+                            Ok(())
+                        }
+                    };
+
+                    if let Err(e) = written {
+                        error!("Failed to spool the upload of {}: {}", user.username, e);
+                        return HttpResponse::InternalServerError().body("Failed to store the track");
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => return HttpResponse::BadRequest().body(format!("Invalid upload: {}", e)),
+            }
+        }
+
+        let text = || Some(String::from_utf8_lossy(&data).trim().to_string()).filter(|t| !t.is_empty());
+
+        match name.as_str() {
+            "file" => file = spool,
+            "title" => overrides.title = text(),
+            "artist" => overrides.artist = text(),
+            "album" => overrides.album = text(),
+            "genre" => overrides.genre = text(),
+            _ => warn!("Ignoring the unknown upload field {}", name),
+        }
+    }
+
+    let Some(mut file) = file else {
+        return HttpResponse::BadRequest().body(TrackError::MissingFile.to_string());
+    };
+
+    if let Err(e) = file.file.flush().await {
+        error!("Failed to spool the upload of {}: {}", user.username, e);
+        return HttpResponse::InternalServerError().body("Failed to store the track");
+    }
+
+    // Decoding and transcoding are heavy, so they are done on the blocking thread pool
+    let spool_path = file.path.clone();
+    let prepared = match web::block(move || std::fs::read(spool_path).map(|data| prepare_track(&data))).await {
+        Ok(Ok(Ok(prepared))) => prepared,
+        Ok(Ok(Err(e))) => {
+            warn!("Rejected the track of {}: {}", user.username, e);
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+        Ok(Err(e)) => {
+            error!("Failed to read the spooled upload of {}: {}", user.username, e);
+            return HttpResponse::InternalServerError().body("Preparing the track failed");
+        }
+        Err(e) => {
+            error!("Preparing the track failed: {}", e);
+            return HttpResponse::InternalServerError().body("Preparing the track failed");
+        }
+    };
+
+    let id = Uuid::new_v4();
+    let dir = tracks_dir();
+    let path = dir.join(format!("{}.flac", id));
+
+    let written = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(&path, &prepared.flac).await
+    }.await;
+
+    if let Err(e) = written {
+        error!("Failed to store the track {}: {}", path.display(), e);
+        return HttpResponse::InternalServerError().body("Failed to store the track");
+    }
+
+    let tags = prepared.tags.merge(overrides);
+    let track = Track {
+        id,
+        owner: user.username,
+        title: tags.title,
+        artist: tags.artist,
+        album: tags.album,
+        genre: tags.genre,
+        original_format: prepared.original_format.to_string(),
+        path: path.to_string_lossy().into_owned(),
+        size_bytes: prepared.flac.len() as i64,
+        duration_ms: prepared.duration.as_millis() as i64,
+        sample_rate: prepared.format.sample_rate as i32,
+        channels: prepared.format.channels as i32,
+        bits_per_sample: prepared.format.bits_per_sample as i32,
+        uploaded_at: Utc::now(),
+        loudness_lufs: prepared.loudness.integrated_lufs,
+        true_peak_dbtp: prepared.loudness.true_peak_dbtp,
+    };
+
+    if let Err(e) = insert_track(&track).await {
+        error!("Failed to register the track {}: {}", id, e);
+        let _ = tokio::fs::remove_file(&path).await;
+        return HttpResponse::InternalServerError().body("Failed to register the track");
+    }
+
+    info!("Track {} uploaded by {}", id, track.owner);
+    HttpResponse::Created().json(track)
+}
+
+
+
+// Reading just the metadata of the stored file, the audio is not needed for seeking
+// The buffer grows until all the metadata blocks fit into it
+
+async fn read_file_metadata(file: &mut tokio::fs::File) -> std::io::Result<FlacMetadata> {
+    let mut buffer = Vec::new();
+    let mut want = 64 * 1024;
+
+    loop {
+        let mut chunk = vec![0u8; want - buffer.len()];
+        let read = file.read(&mut chunk).await?;
+        buffer.extend_from_slice(&chunk[..read]);
+
+        match read_metadata(&buffer) {
+            Ok(metadata) => return Ok(metadata),
+            Err(FlacDecodeError::UnexpectedEof) if read > 0 && want < MAX_METADATA_SIZE => {
+                want = (want * 2).min(MAX_METADATA_SIZE);
+            }
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+}
+
+// The stored file, opened for streaming from the given second
+// The new header has no seek table and no amount of samples, as they do not
+// describe the rest of the track anymore
+
+struct OpenedTrack {
+    file: tokio::fs::File,
+    metadata: FlacMetadata,
+    // The seek points from the one right before the asked second
+    points: Vec<SeekPoint>,
+    // STREAMINFO and the tags of the new header
+    info: StreamInfo,
+    blocks: Vec<MetadataBlock>,
+}
+
+async fn open_from(track: &Track, seconds: f64) -> Result<OpenedTrack, HttpResponse> {
+    let mut file = match tokio::fs::File::open(&track.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("The file of the track {} is not readable: {}", track.id, e);
+            return Err(HttpResponse::NotFound().body("Track file not found"));
+        }
+    };
+
+    let metadata = match read_file_metadata(&mut file).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("The metadata of the track {} is broken: {}", track.id, e);
+            return Err(HttpResponse::InternalServerError().body("The track is broken"));
+        }
+    };
+
+    let points = metadata.blocks.iter()
+        .filter(|b| b.block_type == BLOCK_SEEKTABLE)
+        .find_map(|b| parse_seek_table(&b.data).ok())
+        .unwrap_or_default();
+
+    let sample = (seconds.max(0.0) * metadata.info.sample_rate as f64) as u64;
+    let point = match find_seek_point(&points, sample) {
+        Some(point) => point,
+        None => return Err(HttpResponse::InternalServerError().body("The track has no seek table")),
+    };
+
+    let mut info = metadata.info.clone();
+    info.total_samples = 0;
+    info.md5 = [0u8; 16];
+
+    let blocks: Vec<_> = metadata.blocks.iter()
+        .filter(|b| b.block_type == BLOCK_VORBIS_COMMENT)
+        .cloned()
+        .collect();
+
+    let start = metadata.audio_offset as u64 + point.offset;
+    if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
+        error!("Failed to seek in the track {}: {}", track.id, e);
+        return Err(HttpResponse::InternalServerError().body("Failed to seek in the track"));
+    }
+
+    let points = points.into_iter().filter(|p| p.sample >= point.sample).collect();
+    Ok(OpenedTrack { file, metadata, points, info, blocks })
+}
+
+fn start_seconds(opened: &OpenedTrack) -> String {
+    let sample = opened.points.first().map_or(0, |point| point.sample);
+    format!("{:.3}", sample as f64 / opened.metadata.info.sample_rate as f64)
+}
+
+// Streaming the track from the given second
+// The frames are sent as they are, starting from the seek point right before the asked second
+
+async fn stream_from(track: &Track, seconds: f64) -> HttpResponse {
+    let opened = match open_from(track, seconds).await {
+        Ok(opened) => opened,
+        Err(response) => return response,
+    };
+
+    let start = start_seconds(&opened);
+    let header = write_flac_header(&opened.info, &opened.blocks);
+    let mut file = opened.file;
+
+    let body = async_stream::stream! {
+        yield Ok::<_, actix_web::Error>(Bytes::from(header));
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match file.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(read) => yield Ok(Bytes::copy_from_slice(&buffer[..read])),
+                Err(e) => {
+                    error!("Reading the track failed: {}", e);
+                    break;
+                }
+            }
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(Codec::Flac.mime_type())
+        .append_header(("X-Track-Start", start))
+        .streaming(body)
+}
+
+// Decoding a piece of the track, running it through the effects and encoding it back
+// Returns the frames and the amount of the samples in them
+
+fn process_effect_block(data: &[u8], processor: &mut EffectProcessor, format: &AudioFormat,
+                        first_sample: u64) -> Result<(Vec<u8>, u64), TrackError> {
+    let decoded = decode_flac(data).map_err(TrackError::Flac)?;
+    encode_effect_output(&processor.process(&decoded.samples), format, first_sample)
+}
+
+// Encoding the output of the effects, the frames are renumbered
+// to continue the ones sent before them
+
+fn encode_effect_output(pcm: &[i32], format: &AudioFormat, first_sample: u64) -> Result<(Vec<u8>, u64), TrackError> {
+    if pcm.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+
+    let encoded = encode_pcm_to_flac(pcm, &FlacEncodeOptions::new(*format)).map_err(TrackError::Encode)?;
+    let frames = decode_flac(&encoded).map_err(TrackError::Flac)?.frames;
+
+    let ends = frames.iter().skip(1).map(|f| f.offset).chain([encoded.len()]);
+    let mut output = Vec::with_capacity(encoded.len());
+    let mut next_sample = first_sample;
+
+    for (frame, end) in frames.iter().zip(ends) {
+        output.extend(renumber_frame(&encoded[frame.offset..end], next_sample).map_err(TrackError::Flac)?);
+        next_sample += frame.block_size as u64;
+    }
+
+    Ok((output, next_sample - first_sample))
+}
+
+// Streaming the track through the effects, from the given second
+// The track is read between its seek points, a few seconds at once,
+// every piece is decoded with the header of the track in front of it
+// The output is a single variable block size FLAC stream of the same format,
+// X-Duration-Ratio tells the player how much longer a second of the track gets
+
+async fn stream_with_effects(track: &Track, seconds: f64, chain: &EffectChain) -> HttpResponse {
+    let opened = match open_from(track, seconds).await {
+        Ok(opened) => opened,
+        Err(response) => return response,
+    };
+
+    let format = opened.metadata.info.format();
+    let mut processor = match EffectProcessor::new(&format, chain, ResampleQuality::from_env()) {
+        Ok(processor) => processor,
+        Err(e) => return HttpResponse::BadRequest().body(format!("The effects do not fit the track: {}", e)),
+    };
+
+    let start = start_seconds(&opened);
+    let OpenedTrack { mut file, points, mut info, blocks, .. } = opened;
+
+    // The pieces are decoded with the bare STREAMINFO, the listener gets
+    // the block sizes of the encoder and the unknown frame sizes
+    let piece_header = write_flac_header(&info, &[]);
+
+    info.min_block_size = 16;
+    info.max_block_size = FlacEncodeOptions::default().block_size as u16;
+    info.min_frame_size = 0;
+    info.max_frame_size = 0;
+    let header = write_flac_header(&info, &blocks);
+
+    // The lengths of the pieces in the bytes, the last one goes to the end of the file
+    let offsets: Vec<u64> = points.iter().step_by(EFFECT_BLOCK_SECS as usize).map(|p| p.offset).collect();
+    let mut lengths: Vec<Option<u64>> = offsets.windows(2).map(|pair| Some(pair[1] - pair[0])).collect();
+    lengths.push(None);
+
+    let track_id = track.id;
+    let body = async_stream::stream! {
+        yield Ok::<_, actix_web::Error>(Bytes::from(header));
+
+        let mut next_sample = 0;
+        for length in lengths {
+            let mut data = piece_header.clone();
+            let read = match length {
+                Some(length) => (&mut file).take(length).read_to_end(&mut data).await,
+                None => file.read_to_end(&mut data).await,
+            };
+
+            if let Err(e) = read {
+                error!("Reading the track {} failed: {}", track_id, e);
+                return;
+            }
+
+            let processed = web::block(move || {
+                let result = process_effect_block(&data, &mut processor, &format, next_sample);
+                (processor, result)
+            }).await;
+
+            match processed {
+                Ok((returned, Ok((frames, samples)))) => {
+                    processor = returned;
+                    next_sample += samples;
+                    if !frames.is_empty() {
+                        yield Ok(Bytes::from(frames));
+                    }
+                }
+                Ok((_, Err(e))) => {
+                    error!("The effects failed on the track {}: {}", track_id, e);
+                    return;
+                }
+                Err(e) => {
+                    error!("The effects failed on the track {}: {}", track_id, e);
+                    return;
+                }
+            }
+        }
+
+        // The end of the track is still inside of the effects (the stretch, the speed and the reverb tail)
+        let finished = web::block(move || encode_effect_output(&processor.finish(), &format, next_sample)).await;
+
+        match finished {
+            Ok(Ok((frames, _))) => {
+                if !frames.is_empty() {
+                    yield Ok(Bytes::from(frames));
+                }
+            }
+            Ok(Err(e)) => error!("The effects failed at the end of the track {}: {}", track_id, e),
+            Err(e) => error!("The effects failed at the end of the track {}: {}", track_id, e),
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(Codec::Flac.mime_type())
+        .append_header(("X-Track-Start", start))
+        .append_header(("X-Duration-Ratio", format!("{:.6}", chain.duration_ratio())))
+        .streaming(body)
+}
+
+// The track itself, with the byte ranges (handled by NamedFile)
+// or from the given second with ?t=seconds
+// With any of the effects (see EffectSettings) the track goes through them
+
+#[derive(serde::Deserialize)]
+pub struct TrackStreamQuery {
+    t: Option<f64>,
+}
+
+pub async fn stream(req: &HttpRequest, id: &str, query: &TrackStreamQuery,
+                    effects: &EffectSettings) -> HttpResponse {
+    let chain = match effects.chain() {
+        Ok(chain) => chain,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let id = match Uuid::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid track ID"),
+    };
+
+    let track = match find_track(id).await {
+        Ok(Some(track)) => track,
+        Ok(None) => return HttpResponse::NotFound().body("Track not found"),
+        Err(e) => {
+            error!("Failed to find the track {}: {}", id, e);
+            return HttpResponse::InternalServerError().body("Failed to find the track");
+        }
+    };
+
+    let seconds = query.t.filter(|t| t.is_finite() && *t > 0.0);
+
+    if !chain.is_identity() {
+        return stream_with_effects(&track, seconds.unwrap_or(0.0), &chain).await;
+    }
+
+    if let Some(seconds) = seconds {
+        return stream_from(&track, seconds).await;
+    }
+
+    match NamedFile::open_async(&track.path).await {
+        Ok(file) => {
+            let mut response = file.into_response(req);
+            response.headers_mut().insert(header::CONTENT_TYPE,
+                                          HeaderValue::from_static(Codec::Flac.mime_type()));
+            response
+        }
+        Err(e) => {
+            error!("The file of the track {} is not readable: {}", id, e);
+            HttpResponse::NotFound().body("Track file not found")
+        }
+    }
+}
+
+pub async fn info(id: &str) -> HttpResponse {
+    let Ok(id) = Uuid::parse_str(id) else {
+        return HttpResponse::BadRequest().body("Invalid track ID");
+    };
+
+    match find_track(id).await {
+        Ok(Some(track)) => HttpResponse::Ok().json(track),
+        Ok(None) => HttpResponse::NotFound().body("Track not found"),
+        Err(e) => {
+            error!("Failed to find the track {}: {}", id, e);
+            HttpResponse::InternalServerError().body("Failed to find the track")
+        }
+    }
+}
+
+pub async fn list() -> HttpResponse {
+    match list_tracks().await {
+        Ok(tracks) => HttpResponse::Ok().json(tracks),
+        Err(e) => {
+            error!("Failed to list the tracks: {}", e);
+            HttpResponse::InternalServerError().body("Failed to list the tracks")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_coding::test_signals::{sine, to_pcm, wav_file};
+
+    // Three seconds of the mono 16-bit tone at 8 kHz, the encoder makes the frames of 4096
+    const FORMAT: AudioFormat = AudioFormat { channels: 1, bits_per_sample: 16, sample_rate: 8000 };
+
+    fn tone() -> Vec<i32> {
+        to_pcm(&sine(440.0, 0.5, FORMAT.sample_rate, FORMAT.channels, 24000), FORMAT.bits_per_sample)
+    }
+
+    fn seek_points(flac: &[u8]) -> Vec<SeekPoint> {
+        read_metadata(flac).unwrap().blocks.iter()
+            .find(|b| b.block_type == BLOCK_SEEKTABLE)
+            .map(|b| parse_seek_table(&b.data).unwrap())
+            .expect("THE TRACK HAS A SEEK TABLE")
+    }
+
+    // A point for every second, at the first frame of it, counted from the first frame
+    fn assert_seek_table(flac: &[u8]) {
+        let decoded = decode_flac(flac).unwrap();
+        let first_offset = decoded.frames[0].offset as u64;
+        let points = seek_points(flac);
+
+        assert_eq!(points.iter().map(|p| p.sample).collect::<Vec<_>>(), vec![0, 8192, 16384]);
+        for point in points {
+            let frame = decoded.frames.iter().find(|f| f.first_sample == point.sample).unwrap();
+            assert_eq!(point.offset, frame.offset as u64 - first_offset, "THE POINT IS AT ITS FRAME");
+            assert_eq!(point.samples as usize, frame.block_size);
+        }
+    }
+
+    fn track_at(path: &Path) -> Track {
+        Track {
+            id: Uuid::new_v4(),
+            owner: "owner".to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            genre: None,
+            original_format: "wav".to_string(),
+            path: path.to_string_lossy().into_owned(),
+            size_bytes: 0,
+            duration_ms: 3000,
+            sample_rate: FORMAT.sample_rate as i32,
+            channels: FORMAT.channels as i32,
+            bits_per_sample: FORMAT.bits_per_sample as i32,
+            uploaded_at: Utc::now(),
+            loudness_lufs: None,
+            true_peak_dbtp: None,
+        }
+    }
+
+    fn wav_track(pcm: &[i32]) -> Vec<u8> {
+        let mut list = b"INFO".to_vec();
+        for (id, value) in [(b"INAM", b"Song\0\0"), (b"IART", b"Who\0\0\0")] {
+            list.extend_from_slice(id);
+            list.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            list.extend_from_slice(value);
+        }
+
+        let data: Vec<u8> = pcm.iter().flat_map(|s| (*s as i16).to_le_bytes()).collect();
+        wav_file(1, 16, FORMAT.sample_rate, &data, &list)
+    }
+
+    #[test]
+    fn test_wav_is_transcoded_to_flac() {
+        let pcm = tone();
+        let prepared = prepare_track(&wav_track(&pcm)).unwrap();
+
+        assert_eq!(prepared.original_format, "wav");
+        assert_eq!(prepared.format, FORMAT);
+        assert_eq!(prepared.duration, Duration::from_secs(3));
+        assert_eq!(prepared.tags, TrackTags { title: Some("Song".to_string()), artist: Some("Who".to_string()),
+                                              ..Default::default() });
+
+        // The stored FLAC has the samples of the WAV and the tags of its INFO list
+        let decoded = decode_flac(&prepared.flac).expect("THE STORED TRACK IS A VALID FLAC");
+        assert_eq!(decoded.samples, pcm);
+        assert_eq!(decoded.metadata.info.total_samples, 24000);
+        let comments = decoded.metadata.blocks.iter()
+            .find(|b| b.block_type == BLOCK_VORBIS_COMMENT)
+            .map(|b| parse_vorbis_comment(&b.data).unwrap())
+            .unwrap();
+        assert!(comments.contains(&("TITLE".to_string(), "Song".to_string())));
+
+        assert_seek_table(&prepared.flac);
+    }
+
+    #[test]
+    fn test_flac_header_is_rewritten() {
+        let pcm = tone();
+        let encoded = encode_pcm_to_flac(&pcm, &FlacEncodeOptions::new(FORMAT)).unwrap();
+        let original = decode_flac(&encoded).unwrap();
+
+        // The encoder, which has left the amount of samples and MD5 empty,
+        // a seek table pointing nowhere and the padding
+        let mut info = original.metadata.info.clone();
+        info.total_samples = 0;
+        info.md5 = [0u8; 16];
+        let tags = [("TITLE".to_string(), "Old".to_string())];
+        let mut old_point = [0u64.to_be_bytes(), 12345u64.to_be_bytes()].concat();
+        old_point.extend_from_slice(&4096u16.to_be_bytes());
+        let blocks = [
+            vorbis_comment_block("encoder", &tags),
+            MetadataBlock { block_type: BLOCK_SEEKTABLE, data: old_point },
+            MetadataBlock { block_type: BLOCK_PADDING, data: vec![0; 100] },
+        ];
+        let mut upload = write_flac_header(&info, &blocks);
+        upload.extend_from_slice(&encoded[original.metadata.audio_offset..]);
+
+        let prepared = prepare_track(&upload).unwrap();
+        assert_eq!(prepared.original_format, "flac");
+        assert_eq!(prepared.tags.title.as_deref(), Some("Old"));
+
+        // MD5 is checked by the decoder, once it is there
+        let decoded = decode_flac(&prepared.flac).expect("THE STORED TRACK IS A VALID FLAC");
+        assert_eq!(decoded.metadata.info.total_samples, 24000, "THE AMOUNT OF SAMPLES IS FILLED IN");
+        assert_ne!(decoded.metadata.info.md5, [0u8; 16], "MD5 IS FILLED IN");
+        assert_eq!(decoded.samples, pcm);
+
+        let types: Vec<u8> = decoded.metadata.blocks.iter().map(|b| b.block_type).collect();
+        assert!(!types.contains(&BLOCK_PADDING), "THE PADDING IS DROPPED");
+        assert_eq!(types.iter().filter(|t| **t == BLOCK_SEEKTABLE).count(), 1, "THE OLD SEEK TABLE IS REPLACED");
+        assert_seek_table(&prepared.flac);
+    }
+
+    #[tokio::test]
+    async fn test_open_from_starts_at_the_seek_point() {
+        let dir = std::env::temp_dir().join(format!("trinitypeer-track-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("track.flac");
+
+        let pcm = tone();
+        std::fs::write(&path, prepare_track(&wav_track(&pcm)).unwrap().flac).unwrap();
+        let track = track_at(&path);
+
+        // 1.5 s is in the frame from 1.024 s, the stream starts with that frame
+        let mut opened = open_from(&track, 1.5).await.unwrap();
+        assert_eq!(start_seconds(&opened), "1.024");
+        assert_eq!(opened.points.first().map(|p| p.sample), Some(8192));
+        assert_eq!(opened.info.total_samples, 0, "THE REST OF THE TRACK HAS NO LENGTH IN THE HEADER");
+
+        let mut rest = write_flac_header(&opened.info, &opened.blocks);
+        opened.file.read_to_end(&mut rest).await.unwrap();
+        let decoded = decode_flac(&rest).expect("THE REST OF THE TRACK IS A VALID FLAC");
+        assert_eq!(decoded.frames[0].first_sample, 8192);
+        assert_eq!(decoded.samples, pcm[8192..], "NOTHING IS MISSED FROM THE SEEK POINT");
+
+        let opened = open_from(&track, 0.0).await.unwrap();
+        assert_eq!(start_seconds(&opened), "0.000");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tags_from_files_and_form() {
+        let vorbis = vec![("TITLE".to_string(), "Song".to_string()),
+                          ("ARTIST".to_string(), " ".to_string()),
+                          ("GENRE".to_string(), "Jazz".to_string())];
+        let tags = TrackTags::from_vorbis(&vorbis);
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist, None, "EMPTY TAGS ARE IGNORED");
+
+        let riff = vec![("IART".to_string(), "Band".to_string())];
+        let merged = TrackTags::from_riff(&riff).merge(TrackTags {
+            title: Some("Form title".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(merged.title.as_deref(), Some("Form title"));
+        assert_eq!(merged.artist.as_deref(), Some("Band"));
+        assert_eq!(merged.to_vorbis().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_spooled_upload_is_removed() {
+        let dir = std::env::temp_dir().join(format!("trinitypeer-spool-{}", Uuid::new_v4()));
+        let mut spool = SpooledUpload::create(&dir).await.unwrap();
+        spool.file.write_all(b"fLaC").await.unwrap();
+        spool.file.flush().await.unwrap();
+
+        let path = spool.path.clone();
+        assert_eq!(std::fs::read(&path).unwrap(), b"fLaC");
+
+        drop(spool);
+        assert!(!path.exists(), "THE SPOOL IS GONE WITH THE UPLOAD");
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_unknown_formats_are_rejected() {
+        assert!(matches!(prepare_track(b"ID3\x03\x00\x00\x00\x00\x00\x00OggS"),
+                         Err(TrackError::UnknownFormat)));
+        assert!(matches!(prepare_track(b"OggS\x00\x02"), Err(TrackError::UnknownFormat)));
+    }
+}